@@ -0,0 +1,245 @@
+// Delegated permissions for the network frontends, which serve remote
+// users rather than local processes, e.g. an image exposed read-only to
+// students and read-write to staff.
+//
+// An `Authorizer` maps the name a remote user goes by, the uname of a 9P
+// attach or the X-Remote-User header set by a proxy in front of the HTTP
+// server, to a uid and gid, and decides which mutations each may make. The
+// frontends consult it before every mutation, which fails with Denied when
+// refused, and the files and directories a user creates are owned by that
+// user, unless the directory has defaults. `Table` is loaded from the file
+// the servers are given with --users, one user per line:
+//
+//   # name  uid   gid   access
+//   staff   0     0     rw
+//   alice   1000  1000  ro
+//   bob     1001  1001  create,mkdir,write
+//   *       65534 65534 ro
+//
+// where `rw` allows every operation, `ro` none, and `*` stands for anyone
+// else, who is refused without it.
+
+use error::{Error, Result};
+use inode::UnlockedInode;
+use logging::Transaction;
+use ops;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+  Create,
+  Mkdir,
+  Write,
+  Remove,
+  Rename,
+  Setattr,
+}
+
+const OPS: [(&str, Op); 6] = [
+  ("create", Op::Create),
+  ("mkdir", Op::Mkdir),
+  ("write", Op::Write),
+  ("remove", Op::Remove),
+  ("rename", Op::Rename),
+  ("setattr", Op::Setattr),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+  pub name: String,
+  pub uid: u32,
+  pub gid: u32,
+}
+
+pub trait Authorizer: Send + Sync {
+  // Return who the remote user `name` is, None to refuse the user.
+  fn identify(&self, name: &str) -> Option<Identity>;
+
+  // Return true if `who` may do `op`.
+  fn allow(&self, who: &Identity, op: Op) -> bool;
+}
+
+// Everyone may do anything as root, as the servers did before.
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+  fn identify(&self, name: &str) -> Option<Identity> {
+    Some(Identity {
+      name: name.to_string(),
+      uid: 0,
+      gid: 0,
+    })
+  }
+
+  fn allow(&self, _who: &Identity, _op: Op) -> bool {
+    true
+  }
+}
+
+struct Entry {
+  uid: u32,
+  gid: u32,
+  ops: Vec<Op>,
+}
+
+// The users of a --users file.
+pub struct Table {
+  users: HashMap<String, Entry>,
+}
+
+impl Table {
+  pub fn parse(text: &str) -> Result<Self> {
+    let mut users = HashMap::new();
+
+    for line in text.lines() {
+      let line = line.split('#').next().unwrap();
+      let fields: Vec<&str> = line.split_whitespace().collect();
+
+      if fields.is_empty() {
+        continue;
+      }
+      if fields.len() != 4 {
+        return Err(Error::Invalid);
+      }
+      let ops = match fields[3] {
+        "rw" => OPS.iter().map(|&(_, op)| op).collect(),
+        "ro" => vec![],
+        ops => ops
+          .split(',')
+          .map(|name| {
+            OPS
+              .iter()
+              .find(|&&(s, _)| s == name)
+              .map(|&(_, op)| op)
+              .ok_or(Error::Invalid)
+          })
+          .collect::<Result<Vec<Op>>>()?,
+      };
+      let entry = Entry {
+        uid: fields[1].parse().map_err(|_| Error::Invalid)?,
+        gid: fields[2].parse().map_err(|_| Error::Invalid)?,
+        ops,
+      };
+      users.insert(fields[0].to_string(), entry);
+    }
+    Ok(Table { users })
+  }
+
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    Table::parse(&fs::read_to_string(path)?).map_err(|_| {
+      io::Error::new(io::ErrorKind::InvalidData, "bad users file")
+    })
+  }
+
+  fn entry(&self, name: &str) -> Option<&Entry> {
+    self.users.get(name).or_else(|| self.users.get("*"))
+  }
+}
+
+impl Authorizer for Table {
+  fn identify(&self, name: &str) -> Option<Identity> {
+    self.entry(name).map(|entry| Identity {
+      name: name.to_string(),
+      uid: entry.uid,
+      gid: entry.gid,
+    })
+  }
+
+  fn allow(&self, who: &Identity, op: Op) -> bool {
+    match self.entry(&who.name) {
+      Some(entry) => entry.ops.contains(&op),
+      None => false,
+    }
+  }
+}
+
+// Remove --users <file> from the arguments of a server, and return the
+// table of that file, or AllowAll without it.
+pub fn from_args(args: &mut Vec<String>) -> io::Result<Arc<dyn Authorizer>> {
+  let i = match args.iter().position(|arg| arg == "--users") {
+    Some(i) => i,
+    None => return Ok(Arc::new(AllowAll)),
+  };
+  if i + 1 == args.len() {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "no users file"));
+  }
+  let path = args.remove(i + 1);
+
+  args.remove(i);
+  Ok(Arc::new(Table::load(path)?))
+}
+
+// Fail with Denied unless `auth` lets `who` do `op`.
+pub fn check(auth: &dyn Authorizer, who: &Identity, op: Op) -> Result<()> {
+  if auth.allow(who, op) {
+    Ok(())
+  } else {
+    Err(Error::Denied)
+  }
+}
+
+// Give `inode`, just created in `dir` on behalf of `who`, to `who`, unless
+// `dir` has defaults, which take precedence.
+pub fn own<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  inode: &UnlockedInode,
+  who: &Identity,
+) -> Result<()> {
+  if ops::defaults(txn, dir)?.is_some() {
+    return Ok(());
+  }
+  ops::chown(txn, inode, who.uid, who.gid)
+}
+
+#[cfg(test)]
+mod test {
+  use auth::{self, AllowAll, Authorizer, Op, Table};
+  use error::Error;
+  use fs::FileType;
+  use logging::LOGGING;
+  use ops;
+  use testfs;
+
+  #[test]
+  fn test() {
+    let table = Table::parse(
+      "# name uid gid access\n\
+       staff 0 0 rw\n\
+       alice 1000 100 ro  # a student\n\
+       bob 1001 100 create,write\n",
+    ).unwrap();
+    let staff = table.identify("staff").unwrap();
+    let alice = table.identify("alice").unwrap();
+    let bob = table.identify("bob").unwrap();
+
+    assert!(table.identify("eve").is_none());
+    assert!((alice.uid, alice.gid) == (1000, 100));
+    assert!(table.allow(&staff, Op::Rename));
+    assert!(!table.allow(&alice, Op::Write));
+    assert!(table.allow(&bob, Op::Write) && !table.allow(&bob, Op::Remove));
+    let denied = auth::check(&table, &alice, Op::Create);
+    assert!(denied.err() == Some(Error::Denied));
+
+    let table = Table::parse("* 65534 65534 ro").unwrap();
+    assert!(table.identify("eve").unwrap().uid == 65534);
+    assert!(Table::parse("alice 1000 100 delete").is_err());
+    assert!(Table::parse("alice 1000").is_err());
+    assert!(AllowAll.allow(&alice, Op::Remove));
+
+    testfs::test::mount();
+    LOGGING.init().unwrap();
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let name = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+
+    auth::own(&txn, &root, &file, &bob).unwrap();
+    let stat = ops::stat(&txn, &file);
+    assert!((stat.uid, stat.gid) == (1001, 100));
+  }
+}
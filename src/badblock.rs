@@ -0,0 +1,222 @@
+// Remapping of blocks that can no longer be read, so that images on flaky
+// storage degrade gracefully.
+//
+// mkfs reserves the bad-block table at `sb.badblk` and `sb.nspares` spare
+// blocks after it, at the end of the image. A data or indirect block found
+// bad is copied to the next spare, as far as it could still be read, and
+// every inode referring to it is made to refer to the spare instead. A bad
+// block that is free is merely retired. Either way the incident is recorded
+// in the table and the bad block stays in use, so that it is never
+// allocated again. The other metadata blocks have no spare.
+//
+// Like dedup, this takes a transaction per inode. The incident is recorded
+// first, so that remapping the block again after a crash completes it.
+
+use bitmap::Bitmap;
+use buffer::BCACHE;
+use error::{Error, Result};
+use fs::{BadBlock, BadBlockTable, DiskInode, FileType, IPB, MAXBLOCKS,
+         NBADBLOCKS};
+use inode::ICACHE;
+use logging::LOGGING;
+use refcount;
+use std::cmp::min;
+use std::mem::transmute;
+
+// Return the incidents recorded so far, in order.
+pub fn table() -> Vec<BadBlock> {
+  let sb = BCACHE.sb();
+
+  if sb.badblk == 0 {
+    return vec![];
+  }
+  let txn = LOGGING.new_txn();
+  let buf = txn.read(sb.badblk as usize).unwrap();
+  let table = from_block!(&buf.data, BadBlockTable);
+
+  table.entries[..min(table.n as usize, NBADBLOCKS)].to_vec()
+}
+
+// Return the inode numbers of all inodes in use.
+fn inodes() -> Vec<usize> {
+  let txn = LOGGING.new_txn();
+  let sb = BCACHE.sb();
+  let mut result = vec![];
+
+  for inum in 1..sb.ninodes as usize {
+    let buf = txn.read(sb.iblock(inum)).unwrap();
+    let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
+
+    if inodes[inum % IPB].file_type != FileType::None {
+      result.push(inum);
+    }
+  }
+  result
+}
+
+// Record `blockno` in the table, copying it to a spare if it is in use,
+// and return its entry. One recorded already is returned as is.
+fn record(blockno: usize) -> Result<BadBlock> {
+  let sb = BCACHE.sb();
+  let txn = LOGGING.new_txn();
+  let mut buf = txn.read(sb.badblk as usize).unwrap();
+  let mut table = from_block!(&buf.data, BadBlockTable);
+  let n = min(table.n as usize, NBADBLOCKS);
+
+  if let Some(entry) = table.entries[..n]
+    .iter()
+    .find(|entry| entry.blockno as usize == blockno)
+  {
+    return Ok(*entry);
+  }
+  if n == NBADBLOCKS {
+    return Err(Error::NoSpace);
+  }
+  let used = table.entries[..n]
+    .iter()
+    .filter(|entry| entry.spare != 0)
+    .count();
+
+  // A free block is just taken out of use.
+  let spare = if Bitmap::take(&txn, blockno) {
+    0
+  } else if used == sb.nspares as usize {
+    return Err(Error::NoSpace);
+  } else {
+    let spare = sb.badblk as usize + 1 + used;
+    let data = txn.read(blockno).unwrap().data;
+    let mut spare_buf = txn.read(spare).unwrap();
+
+    spare_buf.data = data;
+    txn.write(&mut spare_buf);
+    // The spare takes over the references of a shared block.
+    for _ in 0..refcount::get(&txn, blockno) {
+      refcount::inc(&txn, spare)?;
+      refcount::release(&txn, blockno);
+    }
+    spare
+  };
+  table.entries[n] = BadBlock {
+    blockno: blockno as u32,
+    spare: spare as u32,
+  };
+  table.n = n as u32 + 1;
+  buf.data = to_block!(&table, BadBlockTable);
+  txn.write(&mut buf);
+  Ok(table.entries[n])
+}
+
+// Remap data or indirect block `blockno`, which can no longer be read, to a
+// spare block. Return the spare, None if the block was free.
+pub fn remap(blockno: usize) -> Result<Option<usize>> {
+  let sb = BCACHE.sb();
+
+  if sb.badblk == 0 {
+    return Err(Error::Unsupported);
+  }
+  if blockno < sb.data_start() || blockno >= sb.data_end() {
+    return Err(Error::Invalid);
+  }
+  let spare = match record(blockno)?.spare as usize {
+    0 => {
+      warn!("retired bad block {}", blockno);
+      return Ok(None);
+    },
+    spare => spare,
+  };
+
+  for inum in inodes() {
+    let txn = LOGGING.new_txn();
+    let inode = ICACHE.get(inum).unwrap();
+    let mut dinode = ICACHE.lock(&txn, &inode);
+
+    dinode.remap_indirect(&txn, blockno, spare)?;
+    for n in 0..MAXBLOCKS {
+      if dinode.mapped_block(&txn, n) == Ok(Some(blockno)) {
+        dinode.set_nth_block(&txn, n, spare);
+      }
+    }
+  }
+  warn!("remapped bad block {} to {}", blockno, spare);
+  Ok(Some(spare))
+}
+
+// Remap every one of `blocknos` that can be, logging the others.
+pub fn remap_all(blocknos: &[usize]) {
+  for blockno in blocknos {
+    if let Err(e) = remap(*blockno) {
+      warn!("cannot remap bad block {}: {:?}", blockno, e);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use badblock;
+  use buffer::BCACHE;
+  use disk::{BSIZE, Disk};
+  use error::Error;
+  use fs::{BadBlock, FileType};
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use mkfs::{self, Options};
+  use ops;
+  use testfs;
+
+  #[test]
+  fn test() {
+    let mut disk = Disk::new(400);
+    let opts = Options {
+      ninodes: 20,
+      max_inodes: 0,
+      case_insensitive: false,
+      spares: 1,
+      integrity: false,
+      log_segments: 1,
+      label: String::new(),
+    };
+    mkfs::mkfs(&mut disk, &opts).unwrap();
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+
+    let (a, b) = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let name = ops::to_name(b"f").unwrap();
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+      let mut data = vec![1; BSIZE];
+
+      data.extend_from_slice(&[2; BSIZE]);
+      ops::write(&txn, &file, 0, &data).unwrap();
+      let dinode = ICACHE.lock(&txn, &file);
+      (dinode.addrs[0] as usize, dinode.addrs[1] as usize)
+    };
+    let sb = BCACHE.sb();
+    let spare = sb.badblk as usize + 1;
+
+    assert!(badblock::remap(a).unwrap() == Some(spare));
+    // Remapping again is a no-op.
+    assert!(badblock::remap(a).unwrap() == Some(spare));
+    assert!(badblock::remap(b).err() == Some(Error::NoSpace));
+    assert!(badblock::remap(1).err() == Some(Error::Invalid));
+    assert!(badblock::remap(sb.badblk as usize - 1).unwrap() == None);
+    assert!(
+      badblock::table() ==
+        vec![
+          BadBlock {
+            blockno: a as u32,
+            spare: spare as u32,
+          },
+          BadBlock {
+            blockno: sb.badblk - 1,
+            spare: 0,
+          },
+        ]
+    );
+
+    let txn = LOGGING.new_txn();
+    let file = ops::resolve(&txn, b"/f").unwrap();
+    assert!(ops::read(&txn, &file, 0, 2).unwrap() == [1, 1]);
+    assert!(ICACHE.lock(&txn, &file).addrs[0] as usize == spare);
+  }
+}
@@ -0,0 +1,231 @@
+// Several operations made atomic with respect to crashes, for embedders.
+//
+//   batch::run(|b| {
+//     b.mkdir(b"/d")?;
+//     b.create(b"/d/f")?;
+//     b.write(b"/d/f", 0, b"hello")?;
+//     b.rename(b"/d/f", b"/d/g")
+//   })
+//
+// The operations of a batch share one transaction, which reserves the whole
+// log while it is open, so other operations wait for the batch. A batch
+// that does not fit in the log is split into several transactions between
+// operations, each of them atomic on its own, and a write larger than
+// MAXWRITE counts as several operations. Nothing is undone when an
+// operation fails, the ones before it are committed as usual.
+
+use auth::{self, Identity};
+use error::{Error, Result};
+use fs::{DIRSIZE, FileType};
+use inode::{ICACHE, UnlockedInode};
+use logging::{LOGGING, Transaction};
+use ops::{self, MAXWRITE, Stat};
+use std::cmp::min;
+
+// Remove the file or empty directory `name` of `dir`.
+fn remove_entry<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<()> {
+  let file_type = ops::stat(txn, &ops::lookup(txn, dir, name)?).file_type;
+
+  match file_type {
+    FileType::Directory => ops::rmdir(txn, dir, name),
+    _ => ops::unlink(txn, dir, name),
+  }
+}
+
+pub struct Batch {
+  txn: Option<Transaction<'static>>,
+  // Number of operations in `txn` so far.
+  nops: usize,
+}
+
+impl Batch {
+  // Return the transaction of the next operation, which is a new one if
+  // the current one is full.
+  fn txn(&mut self) -> &Transaction<'static> {
+    if self.nops == LOGGING.max_ops() {
+      // Commit before waiting for the log to have room again.
+      self.txn = None;
+    }
+    if self.txn.is_none() {
+      self.txn = Some(LOGGING.new_batch_txn(LOGGING.max_ops()));
+      self.nops = 0;
+    }
+    self.nops += 1;
+    self.txn.as_ref().unwrap()
+  }
+
+  fn mknod(&mut self, path: &[u8], file_type: FileType) -> Result<()> {
+    let txn = self.txn();
+    let (dir, name) = ops::resolve_parent(txn, path)?;
+    ops::create(txn, &dir, &name, file_type)?;
+    Ok(())
+  }
+
+  pub fn stat(&mut self, path: &[u8]) -> Result<Stat> {
+    let txn = self.txn();
+    let inode = ops::resolve(txn, path)?;
+    Ok(ops::stat(txn, &inode))
+  }
+
+  // Create an empty file at `path`.
+  pub fn create(&mut self, path: &[u8]) -> Result<()> {
+    self.mknod(path, FileType::File)
+  }
+
+  pub fn mkdir(&mut self, path: &[u8]) -> Result<()> {
+    self.mknod(path, FileType::Directory)
+  }
+
+  // Give the file or directory just created at `path` to `who`, see
+  // auth::own.
+  pub fn own(&mut self, path: &[u8], who: &Identity) -> Result<()> {
+    let txn = self.txn();
+    let (dir, _) = ops::resolve_parent(txn, path)?;
+    let inode = ops::resolve(txn, path)?;
+    auth::own(txn, &dir, &inode, who)
+  }
+
+  pub fn write(
+    &mut self,
+    path: &[u8],
+    offset: usize,
+    data: &[u8],
+  ) -> Result<usize> {
+    let mut written = 0;
+
+    while written < data.len() {
+      let n = min(data.len() - written, MAXWRITE);
+      let txn = self.txn();
+      let inode = ops::resolve(txn, path)?;
+
+      written += ops::write(
+        txn,
+        &inode,
+        offset + written,
+        &data[written..written + n],
+      )?;
+    }
+    Ok(written)
+  }
+
+  pub fn rename(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+    let txn = self.txn();
+    let (dir, name) = ops::resolve_parent(txn, from)?;
+    let (newdir, newname) = ops::resolve_parent(txn, to)?;
+    ops::rename(txn, &dir, &name, &newdir, &newname)
+  }
+
+  // Remove the file or empty directory at `path`.
+  pub fn remove(&mut self, path: &[u8]) -> Result<()> {
+    let txn = self.txn();
+    let (dir, name) = ops::resolve_parent(txn, path)?;
+    remove_entry(txn, &dir, &name)
+  }
+
+  // Remove `path` and everything below it, one entry per operation.
+  pub fn remove_recursive(&mut self, path: &[u8]) -> Result<()> {
+    let (dir, name) = {
+      let txn = self.txn();
+      let (dir, name) = ops::resolve_parent(txn, path)?;
+      (dir.no(), name)
+    };
+    self.remove_tree(dir, &name)
+  }
+
+  // Like `remove_recursive`, for the entry `name` of directory `dir`. The
+  // subtree should not be modified meanwhile, an entry created in one of
+  // its directories makes removing that directory fail with NotEmpty.
+  pub fn remove_tree(
+    &mut self,
+    dir: usize,
+    name: &[u8; DIRSIZE],
+  ) -> Result<()> {
+    if ops::from_name(name) == b"." || ops::from_name(name) == b".." {
+      return Err(Error::Invalid);
+    }
+    let (inum, children) = {
+      let txn = self.txn();
+      let inode = ops::lookup(txn, &ICACHE.get(dir).unwrap(), name)?;
+      let mut children = vec![];
+
+      if ops::stat(txn, &inode).file_type == FileType::Directory {
+        for (_, child) in ops::readdir(txn, &inode)? {
+          if ops::from_name(&child) != b"." && ops::from_name(&child) != b".."
+          {
+            children.push(child);
+          }
+        }
+      }
+      (inode.no(), children)
+    };
+
+    for child in children {
+      self.remove_tree(inum, &child)?;
+    }
+    let txn = self.txn();
+    remove_entry(txn, &ICACHE.get(dir).unwrap(), name)
+  }
+}
+
+// Run the operations of `f` as a batch, and commit it once `f` returns.
+pub fn run<F, T>(f: F) -> Result<T>
+where
+  F: FnOnce(&mut Batch) -> Result<T>,
+{
+  let mut batch = Batch { txn: None, nops: 0 };
+  let result = f(&mut batch);
+  result
+}
+
+#[cfg(test)]
+mod test {
+  use batch;
+  use error::Error;
+  use logging::LOGGING;
+  use ops;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+
+    batch::run(|b| {
+      b.mkdir(b"/d")?;
+      b.create(b"/d/f")?;
+      assert!(b.write(b"/d/f", 0, b"hello")? == 5);
+      b.rename(b"/d/f", b"/g")
+    }).unwrap();
+
+    // More operations than fit in one transaction.
+    let data = vec![42; 3 * ops::MAXWRITE];
+    batch::run(|b| {
+      for name in &[b"/a", b"/b", b"/c"] {
+        b.create(*name)?;
+        b.write(*name, 0, &data)?;
+      }
+      b.remove(b"/b")
+    }).unwrap();
+
+    assert!(batch::run(|b| b.remove(b"/d/f")).err() == Some(Error::NotFound));
+
+    batch::run(|b| {
+      b.mkdir(b"/d/e")?;
+      b.create(b"/d/e/f")?;
+      b.create(b"/d/f")
+    }).unwrap();
+    assert!(batch::run(|b| b.remove(b"/d")).err() == Some(Error::NotEmpty));
+    batch::run(|b| b.remove_recursive(b"/d")).unwrap();
+
+    let txn = LOGGING.new_txn();
+    let g = ops::resolve(&txn, b"/g").unwrap();
+    assert!(ops::read(&txn, &g, 0, 5).unwrap() == b"hello");
+    let c = ops::resolve(&txn, b"/c").unwrap();
+    assert!(ops::stat(&txn, &c).size as usize == data.len());
+    assert!(ops::resolve(&txn, b"/b").err() == Some(Error::NotFound));
+    assert!(ops::resolve(&txn, b"/d").err() == Some(Error::NotFound));
+  }
+}
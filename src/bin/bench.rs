@@ -0,0 +1,95 @@
+extern crate xv6fs;
+
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use xv6fs::disk::{DISK, Disk};
+use xv6fs::fs::FileType;
+use xv6fs::logging::{LOGGING, Transaction};
+use xv6fs::mkfs::{self, Options};
+use xv6fs::ops;
+
+// Measures getattr and cached reads on an in-memory image, as served by the
+// daemon, in full transactions and in read transactions.
+//
+//   bench [--threads <n>] [--ops <n>]
+
+const FILE_SIZE: usize = 64 * 1024;
+const READ_SIZE: usize = 4096;
+
+fn usage() -> ! {
+  eprintln!("usage: bench [--threads <n>] [--ops <n>]");
+  process::exit(2);
+}
+
+// Stat and read the file `n` times, each in a transaction of `new_txn`.
+fn run<F>(nthreads: usize, n: usize, new_txn: F) -> f64
+where
+  F: Fn() -> Transaction<'static> + Send + Sync + 'static,
+{
+  let new_txn = Arc::new(new_txn);
+  let start = Instant::now();
+  let threads: Vec<_> = (0..nthreads)
+    .map(|i| {
+      let new_txn = new_txn.clone();
+      thread::spawn(move || for j in 0..n {
+        let txn = new_txn();
+        let file = ops::resolve(&txn, b"/f").unwrap();
+        let offset = (i + j) * READ_SIZE % FILE_SIZE;
+
+        ops::stat(&txn, &file);
+        ops::read(&txn, &file, offset, READ_SIZE).unwrap();
+      })
+    })
+    .collect();
+
+  for thread in threads {
+    thread.join().unwrap();
+  }
+  let elapsed = start.elapsed();
+  (nthreads * n) as f64 /
+    (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9)
+}
+
+fn main() {
+  let args: Vec<String> = env::args().collect();
+  let (mut nthreads, mut n) = (4, 20000);
+  let mut i = 1;
+
+  while i < args.len() {
+    let value = match args.get(i + 1).map(|value| value.parse()) {
+      Some(Ok(value)) if value > 0 => value,
+      _ => usage(),
+    };
+    match args[i].as_str() {
+      "--threads" => nthreads = value,
+      "--ops" => n = value,
+      _ => usage(),
+    }
+    i += 2;
+  }
+
+  let mut disk = Disk::new(20000);
+  mkfs::mkfs(&mut disk, &Options::default()).unwrap();
+  DISK.mount(disk);
+  LOGGING.init().unwrap();
+  {
+    let txn = LOGGING.new_txn();
+    let name = ops::to_name(b"f").unwrap();
+    ops::create(&txn, &ops::root(), &name, FileType::File).unwrap();
+  }
+  for offset in (0..FILE_SIZE).step_by(READ_SIZE) {
+    let txn = LOGGING.new_txn();
+    let file = ops::resolve(&txn, b"/f").unwrap();
+    ops::write(&txn, &file, offset, &[42; READ_SIZE]).unwrap();
+  }
+
+  let full = run(nthreads, n, || LOGGING.new_txn());
+  let read = run(nthreads, n, || LOGGING.new_read_txn());
+  println!("{} threads, {} stat+read each", nthreads, n);
+  println!("full transactions: {:.0} ops/s", full);
+  println!("read transactions: {:.0} ops/s", read);
+  DISK.unmount();
+}
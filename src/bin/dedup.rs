@@ -0,0 +1,51 @@
+extern crate env_logger;
+extern crate xv6fs;
+
+use std::env;
+use std::process;
+use xv6fs::dedup;
+use xv6fs::disk::{self, BSIZE, DISK, Disk};
+use xv6fs::logging::LOGGING;
+
+// Deduplicate the file data blocks of an image, which must not be mounted
+// meanwhile, or given --force.
+//
+//   dedup fs.img [--force]
+
+fn main() {
+  env_logger::init();
+
+  let args = disk::parse_force(env::args().collect());
+  let fsimg = match args.get(1) {
+    Some(fsimg) => fsimg,
+    None => {
+      eprintln!("usage: dedup fs.img [--force]");
+      process::exit(2);
+    },
+  };
+
+  DISK.mount(Disk::load(fsimg).unwrap_or_else(|e| {
+    eprintln!("dedup: {}: {}", fsimg, e);
+    process::exit(1);
+  }));
+  if let Err(e) = LOGGING.init() {
+    eprintln!("dedup: {}: {:?}", fsimg, e);
+    process::exit(1);
+  }
+
+  match dedup::run() {
+    Ok(report) => println!(
+      "{} blocks scanned, {} shared, {} bytes reclaimed",
+      report.scanned,
+      report.shared,
+      report.freed * BSIZE
+    ),
+    Err(e) => {
+      eprintln!("dedup: {:?}", e);
+      process::exit(1);
+    },
+  }
+
+  // Saved as it is flushed, see Disk::flush.
+  DISK.unmount();
+}
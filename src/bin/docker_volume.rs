@@ -0,0 +1,369 @@
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate xv6fs;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use xv6fs::util::json::{self, Value, quote};
+
+// Docker volume plugin, see
+// https://docs.docker.com/engine/extend/plugins_volume/.
+//
+// Every volume is an image `<root>/volumes/<name>.img` created by `mkfs`,
+// and mounted at `<root>/mnt/<name>` by spawning `daemon` while at least
+// one container uses it.
+
+const CONTENT_TYPE: &str = "application/vnd.docker.plugins.v1.2+json";
+
+// Largest request body, past which requests are refused, as docker sends
+// small ones only.
+const MAXBODY: usize = 64 * 1024;
+
+// How long a daemon may take to mount its volume.
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Return true if a file system is mounted at `path`, whose device then
+// differs from that of its parent.
+fn is_mounted(path: &Path) -> bool {
+  let dev = |path: &Path| fs::metadata(path).map(|metadata| metadata.dev());
+
+  match (dev(path), path.parent().map(dev)) {
+    (Ok(dev), Some(Ok(parent))) => dev != parent,
+    _ => false,
+  }
+}
+
+// Wait for `daemon` to mount its volume at `mountpoint`, killing it if it
+// does not in time.
+fn wait_mounted(daemon: &mut Child, mountpoint: &Path) -> Result<(), String> {
+  let start = Instant::now();
+
+  while !is_mounted(mountpoint) {
+    if let Some(status) = daemon.try_wait().map_err(|e| e.to_string())? {
+      return Err(format!("daemon failed: {}", status));
+    }
+    if start.elapsed() > MOUNT_TIMEOUT {
+      let _ = daemon.kill();
+      let _ = daemon.wait();
+      return Err("daemon did not mount in time".to_string());
+    }
+    thread::sleep(Duration::from_millis(10));
+  }
+  Ok(())
+}
+
+struct Volume {
+  // IDs of the mount requests currently using this volume.
+  ids: HashSet<String>,
+  daemon: Option<Child>,
+}
+
+struct Plugin {
+  root: PathBuf,
+  bindir: PathBuf,
+  volumes: Mutex<HashMap<String, Volume>>,
+}
+
+impl Plugin {
+  fn new(root: PathBuf) -> Self {
+    let bindir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let mut volumes = HashMap::new();
+
+    fs::create_dir_all(root.join("volumes")).unwrap();
+    fs::create_dir_all(root.join("mnt")).unwrap();
+    for entry in fs::read_dir(root.join("volumes")).unwrap() {
+      let path = entry.unwrap().path();
+      if path.extension().map_or(false, |ext| ext == "img") {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        volumes.insert(
+          name,
+          Volume {
+            ids: HashSet::new(),
+            daemon: None,
+          },
+        );
+      }
+    }
+
+    Plugin {
+      root,
+      bindir,
+      volumes: Mutex::new(volumes),
+    }
+  }
+
+  fn image(&self, name: &str) -> PathBuf {
+    self.root.join("volumes").join(format!("{}.img", name))
+  }
+
+  fn mountpoint(&self, name: &str) -> PathBuf {
+    self.root.join("mnt").join(name)
+  }
+
+  fn volume_json(&self, name: &str, volume: &Volume) -> String {
+    let mountpoint = if volume.daemon.is_some() {
+      let mountpoint = self.mountpoint(name);
+      format!(",\"Mountpoint\":{}", quote(&mountpoint.to_string_lossy()))
+    } else {
+      String::new()
+    };
+    format!("{{\"Name\":{}{}}}", quote(name), mountpoint)
+  }
+
+  fn create(&self, name: &str, opts: Option<&Value>) -> Result<String, String> {
+    let mut volumes = self.volumes.lock().unwrap();
+    let has_opts = |opts: &Value| match *opts {
+      Value::Null => false,
+      Value::Object(ref members) => !members.is_empty(),
+      _ => true,
+    };
+
+    if opts.map_or(false, has_opts) {
+      return Err("xv6fs volumes take no options".to_string());
+    }
+    if volumes.contains_key(name) {
+      return Ok(String::new());
+    }
+    let status = Command::new(self.bindir.join("mkfs"))
+      .arg(self.image(name))
+      .status()
+      .map_err(|e| e.to_string())?;
+    if !status.success() {
+      return Err(format!("mkfs failed: {}", status));
+    }
+    volumes.insert(
+      name.to_string(),
+      Volume {
+        ids: HashSet::new(),
+        daemon: None,
+      },
+    );
+    Ok(String::new())
+  }
+
+  fn remove(&self, name: &str) -> Result<String, String> {
+    let mut volumes = self.volumes.lock().unwrap();
+
+    match volumes.get(name) {
+      None => return Err(format!("no such volume: {}", name)),
+      Some(volume) => {
+        if !volume.ids.is_empty() {
+          return Err(format!("volume {} is in use", name));
+        }
+      },
+    }
+    fs::remove_file(self.image(name)).map_err(|e| e.to_string())?;
+    volumes.remove(name);
+    Ok(String::new())
+  }
+
+  fn mount(&self, name: &str, id: &str) -> Result<String, String> {
+    let mut volumes = self.volumes.lock().unwrap();
+    let volume = volumes
+      .get_mut(name)
+      .ok_or(format!("no such volume: {}", name))?;
+    let mountpoint = self.mountpoint(name);
+
+    if volume.daemon.is_none() {
+      fs::create_dir_all(&mountpoint).map_err(|e| e.to_string())?;
+      let mut daemon = Command::new(self.bindir.join("daemon"))
+        .arg(&mountpoint)
+        .arg(self.image(name))
+        .spawn()
+        .map_err(|e| e.to_string())?;
+      // Not to have docker bind the empty directory.
+      wait_mounted(&mut daemon, &mountpoint)?;
+      volume.daemon = Some(daemon);
+    }
+    volume.ids.insert(id.to_string());
+    Ok(format!(",\"Mountpoint\":{}", quote(&mountpoint.to_string_lossy())))
+  }
+
+  fn unmount(&self, name: &str, id: &str) -> Result<String, String> {
+    let mut volumes = self.volumes.lock().unwrap();
+    let volume = volumes
+      .get_mut(name)
+      .ok_or(format!("no such volume: {}", name))?;
+
+    volume.ids.remove(id);
+    if volume.ids.is_empty() {
+      if let Some(mut daemon) = volume.daemon.take() {
+        // The daemon exits once the file system is unmounted.
+        let status = Command::new("fusermount")
+          .arg("-u")
+          .arg(self.mountpoint(name))
+          .status()
+          .map_err(|e| e.to_string())?;
+        if !status.success() {
+          volume.daemon = Some(daemon);
+          return Err(format!("fusermount failed: {}", status));
+        }
+        daemon.wait().map_err(|e| e.to_string())?;
+      }
+    }
+    Ok(String::new())
+  }
+
+  fn handle(&self, path: &str, body: &[u8]) -> Result<String, String> {
+    let req = if body.is_empty() {
+      Value::Null
+    } else {
+      str::from_utf8(body)
+        .map_err(|e| e.to_string())
+        .and_then(json::parse)
+        .map_err(|e| format!("malformed request: {}", e))?
+    };
+    let name = req.get("Name").and_then(|name| name.as_str());
+    let name = || -> Result<&str, String> {
+      let name = name.ok_or("missing volume name".to_string())?;
+
+      if name.is_empty() || name.starts_with('.') ||
+        !name.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+      {
+        return Err(format!("invalid volume name: {}", name));
+      }
+      Ok(name)
+    };
+    let id = || req.get("ID").and_then(|id| id.as_str()).unwrap_or("");
+
+    match path {
+      "/Plugin.Activate" => {
+        return Ok("{\"Implements\":[\"VolumeDriver\"]}".to_string())
+      },
+      "/VolumeDriver.Capabilities" => {
+        return Ok("{\"Capabilities\":{\"Scope\":\"local\"}}".to_string())
+      },
+      "/VolumeDriver.Create" => self.create(name()?, req.get("Opts")),
+      "/VolumeDriver.Remove" => self.remove(name()?),
+      "/VolumeDriver.Mount" => self.mount(name()?, id()),
+      "/VolumeDriver.Unmount" => self.unmount(name()?, id()),
+      "/VolumeDriver.Path" => {
+        let name = name()?;
+        let volumes = self.volumes.lock().unwrap();
+        match volumes.get(name) {
+          None => Err(format!("no such volume: {}", name)),
+          Some(volume) if volume.daemon.is_some() => Ok(format!(
+            ",\"Mountpoint\":{}",
+            quote(&self.mountpoint(name).to_string_lossy())
+          )),
+          Some(_) => Ok(String::new()),
+        }
+      },
+      "/VolumeDriver.Get" => {
+        let name = name()?;
+        let volumes = self.volumes.lock().unwrap();
+        match volumes.get(name) {
+          None => Err(format!("no such volume: {}", name)),
+          Some(volume) => {
+            Ok(format!(",\"Volume\":{}", self.volume_json(name, volume)))
+          },
+        }
+      },
+      "/VolumeDriver.List" => {
+        let volumes = self.volumes.lock().unwrap();
+        let list: Vec<String> = volumes
+          .iter()
+          .map(|(name, volume)| self.volume_json(name, volume))
+          .collect();
+        Ok(format!(",\"Volumes\":[{}]", list.join(",")))
+      },
+      _ => Err(format!("unknown endpoint: {}", path)),
+    }.map(|fields| format!("{{\"Err\":\"\"{}}}", fields))
+  }
+}
+
+fn serve(plugin: &Plugin, stream: UnixStream) {
+  let mut reader = BufReader::new(stream);
+  let mut line = String::new();
+  if reader.read_line(&mut line).is_err() {
+    return;
+  }
+  let path = line.split_whitespace().nth(1).unwrap_or("").to_string();
+
+  let mut content_length = 0;
+  loop {
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() {
+      return;
+    }
+    let line = line.trim();
+    if line.is_empty() {
+      break;
+    }
+    if let Some(i) = line.find(':') {
+      if line[..i].eq_ignore_ascii_case("content-length") {
+        content_length = line[i + 1..].trim().parse().unwrap_or(0);
+      }
+    }
+  }
+  if content_length > MAXBODY {
+    warn!("[volume] {}: a body of {} bytes", path, content_length);
+    let body = format!("{{\"Err\":{}}}", quote("request too large"));
+    reply(reader.into_inner(), "413 Payload Too Large", &body);
+    return;
+  }
+  let mut body = vec![0; content_length];
+  if reader.read_exact(&mut body).is_err() {
+    return;
+  }
+
+  info!("[volume] {} {}", path, String::from_utf8_lossy(&body));
+  let body = match plugin.handle(&path, &body) {
+    Ok(body) => body,
+    Err(e) => {
+      warn!("[volume] {}: {}", path, e);
+      format!("{{\"Err\":{}}}", quote(&e))
+    },
+  };
+
+  reply(reader.into_inner(), "200 OK", &body);
+}
+
+fn reply(mut stream: UnixStream, status: &str, body: &str) {
+  let _ = write!(
+    stream,
+    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+     Connection: close\r\n\r\n{}",
+    status,
+    CONTENT_TYPE,
+    body.len(),
+    body
+  );
+}
+
+fn main() {
+  env_logger::init();
+
+  let socket = env::args_os()
+    .nth(1)
+    .unwrap_or("/run/docker/plugins/xv6fs.sock".into());
+  let root = env::args_os()
+    .nth(2)
+    .unwrap_or("/var/lib/docker-xv6fs".into());
+  let plugin = Arc::new(Plugin::new(PathBuf::from(root)));
+
+  let _ = fs::remove_file(&socket);
+  let listener = UnixListener::bind(&socket).unwrap();
+  info!("serving docker volume plugin on {:?}", socket);
+
+  for stream in listener.incoming() {
+    match stream {
+      Ok(stream) => {
+        let plugin = plugin.clone();
+        thread::spawn(move || serve(&plugin, stream));
+      },
+      Err(e) => println!("{}", e),
+    }
+  }
+}
@@ -0,0 +1,376 @@
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate xv6fs;
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use xv6fs::auth::{self, Authorizer, Identity, Op};
+use xv6fs::batch;
+use xv6fs::disk::{DISK, Disk};
+use xv6fs::disk;
+use xv6fs::error::{Error, Result};
+use xv6fs::fs::{FileType, MAXFILESIZE};
+use xv6fs::inode::ICACHE;
+use xv6fs::logging::LOGGING;
+use xv6fs::ops;
+use xv6fs::reclaim;
+use xv6fs::util::json::quote;
+
+// A REST-ish file API over HTTP/1.1, one request per connection.
+//
+//   GET    /a/b          read a file (`?offset=&length=`), or list a directory
+//   GET    /a/b?stat     stat as JSON
+//   PUT    /a/b          write the body at `?offset=` (default 0), creating
+//                        the file if it does not exist
+//   POST   /a/b?mkdir    create a directory
+//   DELETE /a/b          remove a file or an empty directory
+//
+// Requests are made as the user named by the X-Remote-User header, which an
+// authenticating proxy in front is expected to set, or as "" without it,
+// see auth.rs.
+
+struct HttpRequest {
+  method: String,
+  path: Vec<u8>,
+  query: HashMap<String, String>,
+  body: Vec<u8>,
+  user: String,
+}
+
+struct HttpResponse {
+  status: u16,
+  content_type: &'static str,
+  body: Vec<u8>,
+}
+
+impl HttpResponse {
+  fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Self {
+    HttpResponse {
+      status,
+      content_type,
+      body,
+    }
+  }
+
+  fn json(status: u16, body: String) -> Self {
+    HttpResponse::new(status, "application/json", body.into_bytes())
+  }
+
+  fn error(e: Error) -> Self {
+    let status = match e {
+      Error::ReadOnly | Error::NoKey | Error::Denied => 403,
+      Error::NotFound | Error::Stale => 404,
+      Error::Exists | Error::NotDir | Error::IsDir | Error::NotEmpty => 409,
+      Error::NameTooLong | Error::Invalid => 400,
+      Error::NoSpace | Error::TooManyLinks => 507,
+      Error::Unsupported => 501,
+      Error::Corrupt | Error::Io => 500,
+    };
+    HttpResponse::json(status, format!("{{\"errno\":{}}}", e.errno()))
+  }
+}
+
+fn reason(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    201 => "Created",
+    204 => "No Content",
+    400 => "Bad Request",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    409 => "Conflict",
+    413 => "Payload Too Large",
+    501 => "Not Implemented",
+    507 => "Insufficient Storage",
+    _ => "Internal Server Error",
+  }
+}
+
+fn unhex(c: u8) -> Option<u8> {
+  match c {
+    b'0'..=b'9' => Some(c - b'0'),
+    b'a'..=b'f' => Some(c - b'a' + 10),
+    b'A'..=b'F' => Some(c - b'A' + 10),
+    _ => None,
+  }
+}
+
+fn percent_decode(s: &[u8]) -> Option<Vec<u8>> {
+  let mut result = vec![];
+  let mut i = 0;
+
+  while i < s.len() {
+    if s[i] == b'%' {
+      if i + 2 >= s.len() {
+        return None;
+      }
+      result.push(unhex(s[i + 1])? << 4 | unhex(s[i + 2])?);
+      i += 3;
+    } else {
+      result.push(s[i]);
+      i += 1;
+    }
+  }
+  Some(result)
+}
+
+fn json_stat(name: Option<&[u8]>, stat: &ops::Stat) -> String {
+  let file_type = match stat.file_type {
+    FileType::Directory => "directory",
+    FileType::Symlink => "symlink",
+    FileType::Device => "device",
+    _ => "file",
+  };
+  let name = match name {
+    Some(name) => {
+      format!("\"name\":{},", quote(&String::from_utf8_lossy(name)))
+    },
+    None => String::new(),
+  };
+
+  format!(
+    "{{{}\"inum\":{},\"type\":\"{}\",\"nlink\":{},\"size\":{}}}",
+    name,
+    stat.inum,
+    file_type,
+    stat.nlink,
+    stat.size
+  )
+}
+
+fn read_request(stream: &mut BufReader<TcpStream>) -> Option<HttpRequest> {
+  let mut line = String::new();
+  stream.read_line(&mut line).ok()?;
+
+  let mut parts = line.split_whitespace();
+  let method = parts.next()?.to_string();
+  let target = parts.next()?.as_bytes().to_vec();
+  let (path, query) = match target.iter().position(|c| *c == b'?') {
+    Some(i) => (target[..i].to_vec(), target[i + 1..].to_vec()),
+    None => (target, vec![]),
+  };
+
+  let mut content_length = 0;
+  let mut user = String::new();
+  loop {
+    let mut line = String::new();
+    stream.read_line(&mut line).ok()?;
+    let line = line.trim();
+    if line.is_empty() {
+      break;
+    }
+    if let Some(i) = line.find(':') {
+      if line[..i].eq_ignore_ascii_case("content-length") {
+        content_length = line[i + 1..].trim().parse().ok()?;
+      }
+      if line[..i].eq_ignore_ascii_case("x-remote-user") {
+        user = line[i + 1..].trim().to_string();
+      }
+    }
+  }
+  if content_length > MAXFILESIZE {
+    return None;
+  }
+  let mut body = vec![0; content_length];
+  stream.read_exact(&mut body).ok()?;
+
+  let mut params = HashMap::new();
+  for param in query.split(|c| *c == b'&').filter(|s| !s.is_empty()) {
+    let param = percent_decode(param)?;
+    let param = String::from_utf8(param).ok()?;
+    let (key, value) = match param.find('=') {
+      Some(i) => (param[..i].to_string(), param[i + 1..].to_string()),
+      None => (param, String::new()),
+    };
+    params.insert(key, value);
+  }
+
+  Some(HttpRequest {
+    method,
+    path: percent_decode(&path)?,
+    query: params,
+    body,
+    user,
+  })
+}
+
+fn param(req: &HttpRequest, key: &str, default: usize) -> Result<usize> {
+  match req.query.get(key) {
+    Some(value) => value.parse().map_err(|_| Error::Invalid),
+    None => Ok(default),
+  }
+}
+
+fn get(req: &HttpRequest) -> Result<HttpResponse> {
+  let txn = LOGGING.new_txn();
+  let inode = ops::resolve(&txn, &req.path)?;
+  let stat = ops::stat(&txn, &inode);
+
+  if req.query.contains_key("stat") {
+    return Ok(HttpResponse::json(200, json_stat(None, &stat)));
+  }
+  if stat.file_type == FileType::Directory {
+    let mut ents = vec![];
+
+    for (inode, name) in ops::readdir(&txn, &inode)? {
+      let name = ops::from_name(&name);
+      if name != b"." && name != b".." {
+        ents.push(json_stat(Some(name), &ops::stat(&txn, &inode)));
+      }
+    }
+    return Ok(HttpResponse::json(200, format!("[{}]", ents.join(","))));
+  }
+
+  let offset = param(req, "offset", 0)?;
+  let length = param(req, "length", stat.size as usize)?;
+  let data = ops::read(&txn, &inode, offset, length)?;
+  Ok(HttpResponse::new(200, "application/octet-stream", data))
+}
+
+fn put(
+  req: &HttpRequest,
+  auth: &dyn Authorizer,
+  who: &Identity,
+) -> Result<HttpResponse> {
+  let offset = param(req, "offset", 0)?;
+
+  auth::check(auth, who, Op::Write)?;
+
+  // Bodies larger than the log are written in several transactions, so
+  // they are not atomic with respect to crashes.
+  batch::run(|b| {
+    let mut status = 200;
+    match b.stat(&req.path) {
+      Ok(_) => (),
+      Err(Error::NotFound) => {
+        auth::check(auth, who, Op::Create)?;
+        b.create(&req.path)?;
+        b.own(&req.path, who)?;
+        status = 201;
+      },
+      Err(e) => return Err(e),
+    }
+    let written = b.write(&req.path, offset, &req.body)?;
+    Ok(HttpResponse::json(status, format!("{{\"written\":{}}}", written)))
+  })
+}
+
+fn post(
+  req: &HttpRequest,
+  auth: &dyn Authorizer,
+  who: &Identity,
+) -> Result<HttpResponse> {
+  if !req.query.contains_key("mkdir") {
+    return Err(Error::Unsupported);
+  }
+  auth::check(auth, who, Op::Mkdir)?;
+
+  let txn = LOGGING.new_txn();
+  let (dir, name) = ops::resolve_parent(&txn, &req.path)?;
+  let inode = ops::create(&txn, &dir, &name, FileType::Directory)?;
+  auth::own(&txn, &dir, &inode, who)?;
+  let stat = ops::stat(&txn, &inode);
+  Ok(HttpResponse::json(201, json_stat(None, &stat)))
+}
+
+fn delete(
+  req: &HttpRequest,
+  auth: &dyn Authorizer,
+  who: &Identity,
+) -> Result<HttpResponse> {
+  auth::check(auth, who, Op::Remove)?;
+
+  let txn = LOGGING.new_txn();
+  let (dir, name) = ops::resolve_parent(&txn, &req.path)?;
+  let file_type = ops::stat(&txn, &ops::lookup(&txn, &dir, &name)?).file_type;
+
+  match file_type {
+    FileType::Directory => ops::rmdir(&txn, &dir, &name)?,
+    _ => ops::unlink(&txn, &dir, &name)?,
+  }
+  Ok(HttpResponse::new(204, "text/plain", vec![]))
+}
+
+fn serve(stream: TcpStream, auth: Arc<dyn Authorizer>) {
+  let mut reader = BufReader::new(stream);
+  let resp = match read_request(&mut reader) {
+    None => HttpResponse::new(400, "text/plain", vec![]),
+    Some(req) => {
+      info!(
+        "[http] {} {:?}",
+        req.method,
+        String::from_utf8_lossy(&req.path)
+      );
+
+      let auth = &*auth;
+      let result = match (auth.identify(&req.user), req.method.as_str()) {
+        (None, _) => Err(Error::Denied),
+        (Some(_), "GET") => get(&req),
+        (Some(who), "PUT") => put(&req, auth, &who),
+        (Some(who), "POST") => post(&req, auth, &who),
+        (Some(who), "DELETE") => delete(&req, auth, &who),
+        _ => Ok(HttpResponse::new(405, "text/plain", vec![])),
+      };
+      result.unwrap_or_else(HttpResponse::error)
+    },
+  };
+
+  let mut stream = reader.into_inner();
+  let head = format!(
+    "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+     Connection: close\r\n\r\n",
+    resp.status,
+    reason(resp.status),
+    resp.content_type,
+    resp.body.len()
+  );
+  let _ = stream
+    .write_all(head.as_bytes())
+    .and_then(|_| stream.write_all(&resp.body));
+}
+
+fn main() {
+  env_logger::init();
+
+  let mut args = disk::parse_force(env::args().collect());
+  let auth = auth::from_args(&mut args).unwrap_or_else(|e| {
+    eprintln!("httpd: {}", e);
+    process::exit(1);
+  });
+  let fsimg = &args[1];
+  let addr = args.get(2).cloned().unwrap_or("127.0.0.1:8080".to_string());
+
+  DISK.mount(Disk::load(fsimg).unwrap_or_else(|e| {
+    eprintln!("httpd: {}: {}", fsimg, e);
+    process::exit(1);
+  }));
+  if let Err(e) = LOGGING.init() {
+    eprintln!("httpd: {}: {:?}", fsimg, e);
+    process::exit(1);
+  }
+  reclaim::start();
+  ICACHE.reclaim_orphans();
+  if let Some(interval) = disk::sync_interval() {
+    DISK.start_flusher(interval);
+  }
+
+  let listener = TcpListener::bind(&addr).unwrap();
+  info!("serving HTTP on {}", addr);
+
+  for stream in listener.incoming() {
+    match stream {
+      Ok(stream) => {
+        let auth = auth.clone();
+        thread::spawn(move || serve(stream, auth));
+      },
+      Err(e) => println!("{}", e),
+    }
+  }
+}
@@ -1,104 +1,147 @@
-#[macro_use]
 extern crate xv6fs;
 
 use std::env;
 use std::fs::File;
-use std::io::{Write, Seek, SeekFrom};
-use std::mem::{size_of, transmute};
-use xv6fs::disk::BSIZE;
-use xv6fs::fs::{SuperBlock, DiskInode, FileType, Dirent, IPB, BPB, LOGSIZE,
-                NDIRECT, DIRSIZE};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process;
+use xv6fs::disk::{self, BSIZE, Block, BlockDevice, DISK, Disk};
+use xv6fs::fs::LOGSIZE;
+use xv6fs::logging::LOGGING;
+use xv6fs::mkfs::{self, Options};
 
 const NBLOCKS: usize = 20000;
-const NINODES: usize = 1000;
 
-fn str2u8(s: &str) -> [u8; DIRSIZE] {
-  let s_bytes = s.as_bytes();
-  let mut result: [u8; DIRSIZE] = [0; DIRSIZE];
-  for i in 0..s_bytes.len() {
-    result[i] = s_bytes[i];
-  }
-  result
+// The image file being created, written in place.
+struct Image {
+  f: File,
+  nblocks: usize,
 }
 
-fn main() {
-  let mut f = File::create(env::args_os().nth(1).unwrap()).unwrap();
-
-  // Write NBLOCKS zeroed blocks into fs image.
-  for _ in 0..NBLOCKS {
-    f.write_all(&[0; BSIZE]).unwrap();
+impl BlockDevice for Image {
+  fn nblocks(&self) -> usize {
+    self.nblocks
   }
 
-  let ninodeblks = (NINODES / IPB + 1) as u32;
-  let nbitmapblks = (NBLOCKS / BPB + 1) as u32;
-  let nmeta = 2 + LOGSIZE as u32 + ninodeblks + nbitmapblks;
-
-  let sb = SuperBlock {
-    nblocks: NBLOCKS as u32,
-    unused: 0,
-    ninodes: NINODES as u32,
-    nlogs: LOGSIZE as u32,
-    log_start: 2,
-    inode_start: 2 + LOGSIZE as u32,
-    bmap_start: 2 + LOGSIZE as u32 + ninodeblks,
-  };
-
-  let mut nfree = nmeta;
-
-  // Write the super block.
-  f.seek(SeekFrom::Start(BSIZE as u64)).unwrap();
-  f.write_all(&to_block!(&sb, SuperBlock)).unwrap();
-
-  // Write the root inode and folder.
-  let mut iroot = DiskInode {
-    file_type: FileType::Directory,
-    unused1: 0,
-    unused2: 0,
-    nlink: 1,
-    size: size_of::<Dirent>() as u32 * 2, /* two files in root folder: `.`
-                                           * and `..`. */
-    addrs: [0; NDIRECT + 1],
-  };
-  let inode_blk0 = nfree;
-  iroot.addrs[0] = inode_blk0;
-  nfree += 1;
-
-  f.seek(SeekFrom::Start(
-    (sb.inode_start as usize * BSIZE +
-       size_of::<DiskInode>()) as u64,
-  )).unwrap();
-  f.write_all(unsafe {
-    &transmute::<_, [u8; size_of::<DiskInode>()]>(iroot)
-  }).unwrap();
-
-  let dirents: [Dirent; 2] = [
-    Dirent {
-      inum: 1,
-      name: str2u8("."),
-    },
-    Dirent {
-      inum: 1,
-      name: str2u8(".."),
-    },
-  ];
-  f.seek(SeekFrom::Start(inode_blk0 as u64 * BSIZE as u64))
-    .unwrap();
-  f.write_all(unsafe {
-    &transmute::<_, [u8; size_of::<Dirent>() * 2]>(dirents)
-  }).unwrap();
+  fn read(&mut self, blockno: usize) -> Block {
+    let mut block = [0; BSIZE];
+    self
+      .f
+      .read_exact_at(&mut block, (blockno * BSIZE) as u64)
+      .unwrap();
+    block
+  }
 
-  // Write bitmap.
+  fn write(&mut self, blockno: usize, data: &Block) {
+    self.f.write_all_at(data, (blockno * BSIZE) as u64).unwrap();
+  }
+}
 
-  // all used blocks should stay within one block in bitmap.
-  assert!(nfree <= BPB as u32);
+fn usage() -> ! {
+  eprintln!(
+    "usage: mkfs fs.img [--blocks <n>] [--inodes <n>] [--logsize <n>] \
+     [--label <s>] [--case-insensitive] [--integrity] [--manifest <file>] \
+     [--spares <n>] [--max-inodes <n>] [--log-segments <n>] [--force]"
+  );
+  process::exit(2);
+}
 
-  let mut bitmap: [u8; BSIZE] = [0; BSIZE];
-  for i in 0..nfree as usize {
-    bitmap[i / 8] |= 1 << (i % 8);
+// mkfs fs.img [--blocks <n>] [--inodes <n>] [--logsize <n>] [--label <s>]
+//      [--case-insensitive] [--integrity] [--manifest <file>]
+//      [--spares <n>] [--max-inodes <n>] [--log-segments <n>] [--force]
+//
+// The log size is in blocks, a multiple of LOGSIZE, as --log-segments
+// counts them.
+fn main() {
+  let args = disk::parse_force(env::args().collect());
+  let mut opts = Options::default();
+  let mut nblocks = NBLOCKS;
+  let mut manifest = None;
+  let mut i = 2;
+
+  if args.len() < 2 {
+    usage();
+  }
+  while i < args.len() {
+    match args[i].as_str() {
+      "--blocks" if i + 1 < args.len() => {
+        nblocks = args[i + 1].parse().unwrap_or_else(|_| usage());
+        i += 1;
+      },
+      "--inodes" if i + 1 < args.len() => {
+        opts.ninodes = args[i + 1].parse().unwrap_or_else(|_| usage());
+        i += 1;
+      },
+      "--logsize" if i + 1 < args.len() => {
+        opts.log_segments = match args[i + 1].parse::<usize>() {
+          Ok(n) if n % LOGSIZE == 0 => n / LOGSIZE,
+          _ => {
+            eprintln!("mkfs: log size not a multiple of {} blocks", LOGSIZE);
+            usage();
+          },
+        };
+        i += 1;
+      },
+      "--label" if i + 1 < args.len() => {
+        opts.label = args[i + 1].clone();
+        i += 1;
+      },
+      "--case-insensitive" => opts.case_insensitive = true,
+      "--integrity" => opts.integrity = true,
+      "--manifest" if i + 1 < args.len() => {
+        manifest = Some(args[i + 1].clone());
+        i += 1;
+      },
+      "--spares" if i + 1 < args.len() => {
+        opts.spares = args[i + 1].parse().unwrap_or_else(|_| usage());
+        i += 1;
+      },
+      "--log-segments" if i + 1 < args.len() => {
+        opts.log_segments = args[i + 1].parse().unwrap_or_else(|_| usage());
+        i += 1;
+      },
+      "--max-inodes" if i + 1 < args.len() => {
+        opts.max_inodes = args[i + 1].parse().unwrap_or_else(|_| usage());
+        i += 1;
+      },
+      _ => usage(),
+    }
+    i += 1;
+  }
+  let fsimg = &args[1];
+  // Not over an image in use.
+  let lock = disk::lock(fsimg, false).unwrap_or_else(|e| {
+    eprintln!("mkfs: {}: {}", fsimg, e);
+    process::exit(1);
+  });
+  let f = File::create(fsimg).unwrap();
+
+  // Sparse, the blocks that mkfs leaves zeroed are never written.
+  f.set_len((nblocks * BSIZE) as u64).unwrap();
+  let mut image = Image { f, nblocks };
+  if let Err(e) = xv6fs::mkfs(&mut image, &opts) {
+    eprintln!("mkfs: {}: {:?}, the options do not fit {} blocks", fsimg, e,
+              nblocks);
+    process::exit(1);
+  }
+  drop(lock);
+
+  if let Some(manifest) = manifest {
+    DISK.mount(Disk::load(fsimg).unwrap_or_else(|e| {
+      eprintln!("mkfs: {}: {}", fsimg, e);
+      process::exit(1);
+    }));
+    if let Err(e) = LOGGING.init() {
+      eprintln!("mkfs: {}: {:?}", fsimg, e);
+      process::exit(1);
+    }
+    let result = mkfs::populate(Path::new(&manifest));
+    // Written back to `fsimg`.
+    DISK.unmount();
+
+    if let Err(e) = result {
+      eprintln!("mkfs: {}: {}", manifest, e);
+      process::exit(1);
+    }
   }
-  f.seek(SeekFrom::Start(sb.bmap_start as u64 * BSIZE as u64))
-    .unwrap();
-  f.write_all(&bitmap).unwrap();
-
-  f.flush().unwrap();
 }
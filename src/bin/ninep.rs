@@ -0,0 +1,674 @@
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate xv6fs;
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::env;
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use xv6fs::auth::{self, Authorizer, Identity, Op};
+use xv6fs::disk::{BSIZE, DISK, Disk};
+use xv6fs::disk;
+use xv6fs::error::{Error, Result};
+use xv6fs::fs::{self, DIRSIZE, FileType};
+use xv6fs::inode::{ICACHE, UnlockedInode};
+use xv6fs::logging::{LOGGING, Transaction};
+use xv6fs::memory::{Account, Charge};
+use xv6fs::ops;
+use xv6fs::reclaim;
+
+// 9P2000.L message types, see
+// https://github.com/chaos/diod/blob/master/protocol.md. A reply is always
+// its request's type plus one.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TMKNOD: u8 = 18;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TXATTRWALK: u8 = 30;
+const TXATTRCREATE: u8 = 32;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TAUTH: u8 = 102;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const VERSION: &str = "9P2000.L";
+
+// Size of the header preceding the payload of Rread / Twrite.
+const IOHDRSZ: u32 = 4 + 1 + 2 + 4 + 8 + 4;
+
+// Largest message we accept, which keeps every Twrite within one
+// transaction.
+const MSIZE: u32 = ops::MAXWRITE as u32 + IOHDRSZ;
+
+const QTDIR: u8 = 0x80;
+const QTSYMLINK: u8 = 0x02;
+const QTFILE: u8 = 0x00;
+
+const DT_CHR: u8 = 2;
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+const O_TRUNC: u32 = 0o1000;
+
+// Return the dev_t of a Device, as glibc encodes it.
+fn rdev(stat: &ops::Stat) -> u64 {
+  if stat.file_type != FileType::Device {
+    return 0;
+  }
+  let (major, minor) = (stat.major as u64, stat.minor as u64);
+
+  (major & 0xfff) << 8 | (major & !0xfff) << 32 | (minor & 0xff)
+    | (minor & !0xff) << 12
+}
+const AT_REMOVEDIR: u32 = 0x200;
+
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_UID: u32 = 0x2;
+const SETATTR_GID: u32 = 0x4;
+const SETATTR_SIZE: u32 = 0x8;
+const SETATTR_ATIME: u32 = 0x10;
+const SETATTR_MTIME: u32 = 0x20;
+const SETATTR_ATIME_SET: u32 = 0x80;
+const SETATTR_MTIME_SET: u32 = 0x100;
+const GETATTR_BASIC: u64 = 0x7ff;
+
+// Inodes predating time stamps have them 0, use the same dummy value as the
+// FUSE daemon for them.
+const DEFAULT_TIME: u64 = 42;
+
+fn time((sec, nsec): (u32, u32)) -> (u64, u64) {
+  if sec == 0 {
+    (DEFAULT_TIME, 0)
+  } else {
+    (sec as u64, nsec as u64)
+  }
+}
+
+// The time of a Tsetattr in `valid` by `flag`, the time given if `set`, or
+// else now.
+fn set_time(
+  valid: u32,
+  flag: u32,
+  set: u32,
+  time: (u64, u64),
+) -> Option<(u32, u32)> {
+  if valid & flag == 0 {
+    None
+  } else if valid & set != 0 {
+    Some((time.0 as u32, time.1 as u32))
+  } else {
+    Some(fs::now())
+  }
+}
+
+struct Decoder<'a> {
+  buf: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+  fn new(buf: &'a [u8]) -> Self {
+    Decoder { buf, pos: 0 }
+  }
+
+  fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+    if self.pos + n > self.buf.len() {
+      return Err(Error::Invalid);
+    }
+    let result = &self.buf[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(result)
+  }
+
+  fn u8(&mut self) -> Result<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16> {
+    let b = self.bytes(2)?;
+    Ok(b[0] as u16 | (b[1] as u16) << 8)
+  }
+
+  fn u32(&mut self) -> Result<u32> {
+    let b = self.bytes(4)?;
+    Ok((0..4).fold(0, |x, i| x | (b[i] as u32) << (8 * i)))
+  }
+
+  fn u64(&mut self) -> Result<u64> {
+    let b = self.bytes(8)?;
+    Ok((0..8).fold(0, |x, i| x | (b[i] as u64) << (8 * i)))
+  }
+
+  fn str(&mut self) -> Result<&'a [u8]> {
+    let n = self.u16()? as usize;
+    self.bytes(n)
+  }
+
+  fn name(&mut self) -> Result<[u8; DIRSIZE]> {
+    ops::to_name(self.str()?)
+  }
+}
+
+struct Encoder {
+  buf: Vec<u8>,
+}
+
+impl Encoder {
+  fn new() -> Self {
+    Encoder { buf: vec![] }
+  }
+
+  fn u8(&mut self, x: u8) {
+    self.buf.push(x);
+  }
+
+  fn u16(&mut self, x: u16) {
+    for i in 0..2 {
+      self.buf.push((x >> (8 * i)) as u8);
+    }
+  }
+
+  fn u32(&mut self, x: u32) {
+    for i in 0..4 {
+      self.buf.push((x >> (8 * i)) as u8);
+    }
+  }
+
+  fn u64(&mut self, x: u64) {
+    for i in 0..8 {
+      self.buf.push((x >> (8 * i)) as u8);
+    }
+  }
+
+  fn str(&mut self, s: &[u8]) {
+    self.u16(s.len() as u16);
+    self.buf.extend_from_slice(s);
+  }
+
+  fn qid(&mut self, stat: &ops::Stat) {
+    self.u8(match stat.file_type {
+      FileType::Directory => QTDIR,
+      FileType::Symlink => QTSYMLINK,
+      _ => QTFILE,
+    });
+    self.u32(0); // version
+    // Unique even when the inode number is reused.
+    self.u64((stat.gen as u64) << 32 | stat.inum as u64);
+  }
+}
+
+struct Fid {
+  inode: UnlockedInode,
+  // Directory entry this fid was walked through, which Tremove and
+  // Trename operate on. None for the root.
+  parent: Option<(UnlockedInode, [u8; DIRSIZE])>,
+  // The user of the attach it was walked from.
+  who: Identity,
+  _charge: Charge,
+}
+
+impl Fid {
+  fn new(
+    inode: UnlockedInode,
+    parent: Option<(UnlockedInode, [u8; DIRSIZE])>,
+    who: Identity,
+  ) -> Self {
+    Fid {
+      inode,
+      parent,
+      who,
+      _charge: Charge::new(Account::Handles, size_of::<Fid>()),
+    }
+  }
+}
+
+struct Session {
+  fids: HashMap<u32, Fid>,
+  msize: u32,
+  auth: Arc<dyn Authorizer>,
+}
+
+impl Session {
+  fn new(auth: Arc<dyn Authorizer>) -> Self {
+    Session {
+      fids: HashMap::new(),
+      msize: MSIZE,
+      auth,
+    }
+  }
+
+  fn fid(&self, fid: u32) -> Result<&Fid> {
+    self.fids.get(&fid).ok_or(Error::Invalid)
+  }
+
+  // Like `fid`, if its user may do `op`.
+  fn allowed(&self, fid: u32, op: Op) -> Result<&Fid> {
+    let fid = self.fid(fid)?;
+
+    auth::check(&*self.auth, &fid.who, op)?;
+    Ok(fid)
+  }
+
+  fn handle<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    typ: u8,
+    req: &mut Decoder,
+    rep: &mut Encoder,
+  ) -> Result<()> {
+    match typ {
+      TVERSION => {
+        let msize = req.u32()?;
+        let version = req.str()?;
+
+        self.fids.clear();
+        self.msize = min(msize, MSIZE);
+        rep.u32(self.msize);
+        rep.str(if version == VERSION.as_bytes() {
+          VERSION.as_bytes()
+        } else {
+          b"unknown"
+        });
+      },
+      TATTACH => {
+        let fid = req.u32()?;
+        let _afid = req.u32()?;
+        let uname = String::from_utf8_lossy(req.str()?).into_owned();
+        let who = self.auth.identify(&uname).ok_or(Error::Denied)?;
+        let root = ops::root();
+
+        rep.qid(&ops::stat(txn, &root));
+        self.fids.insert(fid, Fid::new(root, None, who));
+      },
+      TFLUSH => {
+        // Requests of a session are served one at a time, so there is
+        // never anything in flight to cancel.
+      },
+      TWALK => {
+        let fid = req.u32()?;
+        let newfid = req.u32()?;
+        let nwname = req.u16()? as usize;
+        let mut inode = self.fid(fid)?.inode.clone();
+        let mut parent = self.fid(fid)?.parent.clone();
+        let who = self.fid(fid)?.who.clone();
+        let mut stats = vec![];
+
+        if newfid != fid && self.fids.contains_key(&newfid) {
+          return Err(Error::Invalid);
+        }
+        for i in 0..nwname {
+          let name = req.name()?;
+          let next = match ops::lookup(txn, &inode, &name) {
+            Ok(next) => next,
+            Err(e) => {
+              if i == 0 {
+                return Err(e);
+              }
+              break;
+            },
+          };
+
+          stats.push(ops::stat(txn, &next));
+          parent = Some((inode, name));
+          inode = next;
+        }
+
+        rep.u16(stats.len() as u16);
+        for stat in &stats {
+          rep.qid(stat);
+        }
+        if stats.len() == nwname {
+          self.fids.insert(newfid, Fid::new(inode, parent, who));
+        }
+      },
+      TCLUNK => {
+        let fid = req.u32()?;
+        self.fids.remove(&fid).ok_or(Error::Invalid)?;
+      },
+      TREMOVE => {
+        let fid = req.u32()?;
+        // Clunked even if refused.
+        let fid = self.fids.remove(&fid).ok_or(Error::Invalid)?;
+
+        auth::check(&*self.auth, &fid.who, Op::Remove)?;
+        let (dir, name) = fid.parent.ok_or(Error::Invalid)?;
+
+        match ops::stat(txn, &fid.inode).file_type {
+          FileType::Directory => ops::rmdir(txn, &dir, &name)?,
+          _ => ops::unlink(txn, &dir, &name)?,
+        }
+      },
+      TLOPEN => {
+        let fid = req.u32()?;
+        let flags = req.u32()?;
+
+        if flags & O_TRUNC != 0 {
+          ops::truncate(txn, &self.allowed(fid, Op::Write)?.inode, 0)?;
+        }
+        rep.qid(&ops::stat(txn, &self.fid(fid)?.inode));
+        rep.u32(self.msize - IOHDRSZ);
+      },
+      TLCREATE => {
+        let fid = req.u32()?;
+        let name = req.name()?;
+        let dir = self.allowed(fid, Op::Create)?.inode.clone();
+        let who = self.fid(fid)?.who.clone();
+        let inode = ops::create(txn, &dir, &name, FileType::File)?;
+
+        auth::own(txn, &dir, &inode, &who)?;
+        rep.qid(&ops::stat(txn, &inode));
+        rep.u32(self.msize - IOHDRSZ);
+        self.fids.insert(fid, Fid::new(inode, Some((dir, name)), who));
+      },
+      TMKDIR => {
+        let dfid = req.u32()?;
+        let name = req.name()?;
+        let fid = self.allowed(dfid, Op::Mkdir)?;
+        let inode = ops::create(txn, &fid.inode, &name, FileType::Directory)?;
+
+        auth::own(txn, &fid.inode, &inode, &fid.who)?;
+        rep.qid(&ops::stat(txn, &inode));
+      },
+      TUNLINKAT => {
+        let dfid = req.u32()?;
+        let name = req.name()?;
+        let flags = req.u32()?;
+        let dir = &self.allowed(dfid, Op::Remove)?.inode;
+
+        if flags & AT_REMOVEDIR != 0 {
+          ops::rmdir(txn, dir, &name)?;
+        } else {
+          ops::unlink(txn, dir, &name)?;
+        }
+      },
+      TRENAME => {
+        let fid = req.u32()?;
+        let dfid = req.u32()?;
+        let newname = req.name()?;
+        let parent = self.allowed(fid, Op::Rename)?.parent.clone();
+        let (dir, name) = parent.ok_or(Error::Invalid)?;
+        let newdir = self.fid(dfid)?.inode.clone();
+
+        ops::rename(txn, &dir, &name, &newdir, &newname)?;
+        self.fids.get_mut(&fid).unwrap().parent = Some((newdir, newname));
+      },
+      TRENAMEAT => {
+        let olddirfid = req.u32()?;
+        let oldname = req.name()?;
+        let newdirfid = req.u32()?;
+        let newname = req.name()?;
+
+        ops::rename(
+          txn,
+          &self.allowed(olddirfid, Op::Rename)?.inode,
+          &oldname,
+          &self.fid(newdirfid)?.inode,
+          &newname,
+        )?;
+      },
+      TGETATTR => {
+        let fid = req.u32()?;
+        let stat = ops::stat(txn, &self.fid(fid)?.inode);
+        let mode = match stat.file_type {
+          FileType::Directory => S_IFDIR,
+          FileType::Symlink => S_IFLNK,
+          FileType::Device => S_IFCHR,
+          _ => S_IFREG,
+        } | stat.mode as u32;
+
+        rep.u64(GETATTR_BASIC);
+        rep.qid(&stat);
+        rep.u32(mode);
+        rep.u32(stat.uid);
+        rep.u32(stat.gid);
+        rep.u64(stat.nlink as u64);
+        rep.u64(rdev(&stat));
+        rep.u64(stat.size as u64);
+        rep.u64(BSIZE as u64);
+        rep.u64((stat.size as u64 + 511) / 512);
+        for &t in &[stat.atime, stat.mtime, stat.ctime, (0, 0)] {
+          // atime, mtime, ctime and btime
+          let (sec, nsec) = time(t);
+
+          rep.u64(sec);
+          rep.u64(nsec);
+        }
+        rep.u64(0); // gen
+        rep.u64(0); // data_version
+      },
+      TSETATTR => {
+        let fid = req.u32()?;
+        let valid = req.u32()?;
+        let mode = req.u32()?;
+        let uid = req.u32()?;
+        let gid = req.u32()?;
+        let size = req.u64()?;
+        let atime = (req.u64()?, req.u64()?);
+        let mtime = (req.u64()?, req.u64()?);
+        let fid = self.allowed(fid, Op::Setattr)?;
+        let inode = &fid.inode;
+        let stat = ops::stat(txn, inode);
+
+        // As for FUSE, only root gives files away, and only the owner or
+        // root changes the mode or group.
+        let caller = fid.who.uid;
+        let owned = caller == 0 || caller == stat.uid;
+        let give = valid & SETATTR_UID != 0 && uid != stat.uid;
+        if valid & (SETATTR_MODE | SETATTR_GID) != 0 && !owned ||
+          give && caller != 0
+        {
+          return Err(Error::Denied);
+        }
+        if valid & SETATTR_SIZE != 0 && size != stat.size as u64 {
+          ops::truncate(txn, inode, size as usize)?;
+        }
+        if valid & SETATTR_MODE != 0 {
+          ops::chmod(txn, inode, mode as u16)?;
+        }
+        if valid & (SETATTR_UID | SETATTR_GID) != 0 {
+          let uid = if valid & SETATTR_UID != 0 { uid } else { stat.uid };
+          let gid = if valid & SETATTR_GID != 0 { gid } else { stat.gid };
+          ops::chown(txn, inode, uid, gid)?;
+        }
+        let atime = set_time(valid, SETATTR_ATIME, SETATTR_ATIME_SET, atime);
+        let mtime = set_time(valid, SETATTR_MTIME, SETATTR_MTIME_SET, mtime);
+        if atime.is_some() || mtime.is_some() {
+          ops::set_times(txn, inode, atime, mtime)?;
+        }
+      },
+      TREADDIR => {
+        let fid = req.u32()?;
+        let offset = req.u64()? as usize;
+        let count = req.u32()? as usize;
+        let ents = ops::readdir(txn, &self.fid(fid)?.inode)?;
+        let mut data = Encoder::new();
+
+        for (i, (inode, name)) in ents.iter().enumerate().skip(offset) {
+          let stat = ops::stat(txn, inode);
+          let name = ops::from_name(name);
+
+          if data.buf.len() + 13 + 8 + 1 + 2 + name.len() > count {
+            break;
+          }
+          data.qid(&stat);
+          data.u64(i as u64 + 1);
+          data.u8(match stat.file_type {
+            FileType::Directory => DT_DIR,
+            FileType::Symlink => DT_LNK,
+            FileType::Device => DT_CHR,
+            _ => DT_REG,
+          });
+          data.str(name);
+        }
+        rep.u32(data.buf.len() as u32);
+        rep.buf.extend_from_slice(&data.buf);
+      },
+      TREAD => {
+        let fid = req.u32()?;
+        let offset = req.u64()? as usize;
+        let count = req.u32()?;
+        let count = min(count, self.msize - IOHDRSZ) as usize;
+        let data = ops::read(txn, &self.fid(fid)?.inode, offset, count)?;
+
+        rep.u32(data.len() as u32);
+        rep.buf.extend_from_slice(&data);
+      },
+      TWRITE => {
+        let fid = req.u32()?;
+        let offset = req.u64()? as usize;
+        let count = req.u32()? as usize;
+        let data = req.bytes(count)?;
+        // Short writes are retried by the client.
+        let data = &data[..min(count, ops::MAXWRITE)];
+        let inode = &self.allowed(fid, Op::Write)?.inode;
+        let written = ops::write(txn, inode, offset, data)?;
+
+        rep.u32(written as u32);
+      },
+      TFSYNC => {
+        // Every request runs in its own transaction, which has been
+        // committed by the time the previous reply was sent.
+        self.fid(req.u32()?)?;
+      },
+      TAUTH | TSTATFS | TSYMLINK | TMKNOD | TREADLINK | TXATTRWALK |
+      TXATTRCREATE | TLOCK | TGETLOCK | TLINK => {
+        return Err(Error::Unsupported);
+      },
+      _ => {
+        warn!("unknown message type {}", typ);
+        return Err(Error::Unsupported);
+      },
+    }
+    Ok(())
+  }
+}
+
+fn read_message(stream: &mut TcpStream, msize: u32) -> Option<Vec<u8>> {
+  let mut size = [0; 4];
+  stream.read_exact(&mut size).ok()?;
+
+  let size = Decoder::new(&size).u32().unwrap();
+  if size < 7 || size > msize {
+    warn!("bad message size {}", size);
+    return None;
+  }
+  let mut buf = vec![0; size as usize - 4];
+  stream.read_exact(&mut buf).ok()?;
+  Some(buf)
+}
+
+fn serve(mut stream: TcpStream, auth: Arc<dyn Authorizer>) {
+  let mut session = Session::new(auth);
+
+  while let Some(msg) = read_message(&mut stream, session.msize) {
+    let mut req = Decoder::new(&msg);
+    let typ = req.u8().unwrap();
+    let tag = req.u16().unwrap();
+    let mut rep = Encoder::new();
+
+    debug!("[9p] type={} tag={}", typ, tag);
+
+    let result = {
+      let txn = LOGGING.new_txn();
+      let result = session.handle(&txn, typ, &mut req, &mut rep);
+      // Nothing a request failing halfway wrote is committed.
+      if result.is_err() {
+        txn.abort();
+      }
+      result
+    };
+
+    let mut msg = Encoder::new();
+    match result {
+      Ok(()) => {
+        msg.u32(7 + rep.buf.len() as u32);
+        msg.u8(typ + 1);
+        msg.u16(tag);
+        msg.buf.extend_from_slice(&rep.buf);
+      },
+      Err(e) => {
+        msg.u32(7 + 4);
+        msg.u8(RLERROR);
+        msg.u16(tag);
+        msg.u32(e.errno() as u32);
+      },
+    }
+    if stream.write_all(&msg.buf).is_err() {
+      break;
+    }
+  }
+
+  // Dropping inodes may free them, which needs an outer txn.
+  let _txn = LOGGING.new_txn();
+  session.fids.clear();
+}
+
+fn main() {
+  env_logger::init();
+
+  let mut args = disk::parse_force(env::args().collect());
+  let auth = auth::from_args(&mut args).unwrap_or_else(|e| {
+    eprintln!("ninep: {}", e);
+    process::exit(1);
+  });
+  let fsimg = &args[1];
+  let addr = args.get(2).cloned().unwrap_or("127.0.0.1:5640".to_string());
+
+  DISK.mount(Disk::load(fsimg).unwrap_or_else(|e| {
+    eprintln!("ninep: {}: {}", fsimg, e);
+    process::exit(1);
+  }));
+  if let Err(e) = LOGGING.init() {
+    eprintln!("ninep: {}: {:?}", fsimg, e);
+    process::exit(1);
+  }
+  reclaim::start();
+  ICACHE.reclaim_orphans();
+  if let Some(interval) = disk::sync_interval() {
+    DISK.start_flusher(interval);
+  }
+
+  let listener = TcpListener::bind(&addr).unwrap();
+  info!("serving 9P2000.L on {}", addr);
+
+  for stream in listener.incoming() {
+    match stream {
+      Ok(stream) => {
+        let auth = auth.clone();
+        thread::spawn(move || serve(stream, auth));
+      },
+      Err(e) => println!("{}", e),
+    }
+  }
+}
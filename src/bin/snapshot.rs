@@ -0,0 +1,57 @@
+extern crate env_logger;
+extern crate xv6fs;
+
+use std::env;
+use std::process;
+use xv6fs::disk::{self, DISK, Disk};
+use xv6fs::logging::LOGGING;
+use xv6fs::snapshot;
+
+// Manage the snapshots of an image, which must not be mounted meanwhile,
+// or given --force.
+//
+//   snapshot fs.img create <name> [--force]
+//   snapshot fs.img list [--force]
+//   snapshot fs.img delete <name> [--force]
+
+fn usage() -> ! {
+  eprintln!(
+    "usage: snapshot fs.img (create <name> | list | delete <name>) [--force]"
+  );
+  process::exit(2);
+}
+
+fn main() {
+  env_logger::init();
+
+  let args = disk::parse_force(env::args().collect());
+  if args.len() < 3 {
+    usage();
+  }
+  let fsimg = &args[1];
+
+  DISK.mount(Disk::load(fsimg).unwrap_or_else(|e| {
+    eprintln!("snapshot: {}: {}", fsimg, e);
+    process::exit(1);
+  }));
+  if let Err(e) = LOGGING.init() {
+    eprintln!("snapshot: {}: {:?}", fsimg, e);
+    process::exit(1);
+  }
+
+  let result = match (args[2].as_str(), args.get(3)) {
+    ("create", Some(name)) => snapshot::create(name.as_bytes()),
+    ("delete", Some(name)) => snapshot::delete(name.as_bytes()),
+    ("list", None) => snapshot::list().map(|names| for name in names {
+      println!("{}", String::from_utf8_lossy(&name));
+    }),
+    _ => usage(),
+  };
+  if let Err(e) = result {
+    eprintln!("snapshot: {:?}", e);
+    process::exit(1);
+  }
+
+  // Saved as it is flushed, see Disk::flush.
+  DISK.unmount();
+}
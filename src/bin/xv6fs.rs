@@ -0,0 +1,283 @@
+extern crate libc;
+extern crate xv6fs;
+
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::process;
+use xv6fs::badblock;
+use xv6fs::disk::{self, DISK, Disk};
+use xv6fs::error::{Error, Result};
+use xv6fs::fs::{self, FileType};
+use xv6fs::integrity;
+use xv6fs::legacy::Legacy;
+use xv6fs::logging::LOGGING;
+use xv6fs::ops;
+use xv6fs::overlay::{self, Overlay};
+
+// Operations on a mounted file system that the daemon runs by itself,
+// instead of going through FUSE one entry at a time, and inspection of an
+// image that is not mounted.
+//
+//   xv6fs rm -r <path>...
+//   xv6fs ls fs.img <dir>
+//   xv6fs overlay (create | commit) fs.img <delta>
+//   xv6fs overlay discard <delta>
+//   xv6fs scrub fs.img
+//   xv6fs convert legacy.img fs.img
+//   xv6fs clone <base> <new>
+//   xv6fs verify fs.img
+//   xv6fs fsck fs.img
+//
+// An image in use by the daemon or another tool is refused unless --force
+// is given, see `disk::lock`.
+
+const XATTR_RMTREE: &str = "user.xv6fs.rmtree";
+
+fn usage() -> ! {
+  eprintln!(
+    "usage: xv6fs (rm -r <path>... | ls fs.img <dir> | \
+     overlay (create | commit) fs.img <delta> | overlay discard <delta> | \
+     scrub fs.img | convert legacy.img fs.img | clone <base> <new> | \
+     verify fs.img | fsck fs.img) [--force]"
+  );
+  process::exit(2);
+}
+
+// Load the image `fsimg`, or exit if it is in use.
+fn load(fsimg: &str) -> Disk {
+  Disk::load(fsimg).unwrap_or_else(|e| {
+    eprintln!("xv6fs: {}: {}", fsimg, e);
+    process::exit(1);
+  })
+}
+
+// Ask the daemon to remove `path` and everything below it.
+fn remove_recursive(path: &Path) -> io::Result<()> {
+  let name = match path.file_name() {
+    Some(name) => name,
+    None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+  };
+  let dir = match path.parent() {
+    Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+    Some(dir) => dir,
+    None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+  };
+  let dir = CString::new(dir.as_os_str().as_bytes())?;
+  let attr = CString::new(XATTR_RMTREE).unwrap();
+  let value = name.as_bytes();
+
+  let ret = unsafe {
+    libc::setxattr(
+      dir.as_ptr(),
+      attr.as_ptr(),
+      value.as_ptr() as *const _,
+      value.len(),
+      0,
+    )
+  };
+  if ret != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+// Print the entries of `dir` of the image `fsimg`, one per line with their
+// type, inode number and size, and whiteouts as `w`.
+fn list(fsimg: &str, dir: &str) -> Result<()> {
+  DISK.mount(load(fsimg));
+  LOGGING.init()?;
+
+  let txn = LOGGING.new_txn();
+  let dir = ops::resolve(&txn, dir.as_bytes())?;
+
+  for (inode, name) in ops::readdir(&txn, &dir)? {
+    let stat = ops::stat(&txn, &inode);
+    let kind = match stat.file_type {
+      FileType::Directory => 'd',
+      FileType::Symlink => 'l',
+      FileType::Device => 'c',
+      _ => '-',
+    };
+    println!(
+      "{} {:5} {:8} {}",
+      kind,
+      stat.inum,
+      stat.size,
+      String::from_utf8_lossy(ops::from_name(&name))
+    );
+  }
+  for name in ops::whiteouts(&txn, &dir)? {
+    println!(
+      "w {:>5} {:>8} {}",
+      "-",
+      "-",
+      String::from_utf8_lossy(ops::from_name(&name))
+    );
+  }
+  Ok(())
+}
+
+// Read every block of the image `fsimg`, remap those that cannot be read
+// and print the bad-block table, see badblock.rs.
+fn scrub(fsimg: &str) {
+  let disk = load(fsimg);
+  let unreadable = disk.unreadable().to_vec();
+
+  DISK.mount(disk);
+  if let Err(e) = LOGGING.init() {
+    eprintln!("xv6fs: {}: {:?}", fsimg, e);
+    process::exit(1);
+  }
+  badblock::remap_all(&unreadable);
+  for entry in badblock::table() {
+    match entry.spare {
+      0 => println!("{} retired", entry.blockno),
+      spare => println!("{} remapped to {}", entry.blockno, spare),
+    }
+  }
+  // Written back to `fsimg`.
+  DISK.unmount();
+}
+
+// Convert the image `from` of upstream xv6 into a new image `to`, see
+// legacy.rs.
+fn convert(from: &str, to: &str) -> io::Result<()> {
+  let legacy = Legacy::open(from)?.ok_or(io::Error::new(
+    io::ErrorKind::InvalidData,
+    "not an image of upstream xv6",
+  ))?;
+  let errno = |e: Error| io::Error::from_raw_os_error(e.errno());
+
+  DISK.mount(legacy.format().map_err(errno)?);
+  LOGGING.init().map_err(errno)?;
+  let result = legacy.copy(false);
+  let mut disk = DISK.unmount();
+  result.map_err(errno)?;
+
+  // Sparse, see Disk::save.
+  let _lock = disk::lock(to, false)?;
+  let blocks = (0..disk.nblocks()).map(|i| disk.read(i)).collect();
+  Disk::from(blocks).save(to)
+}
+
+// Check the image `fsimg` against its hash tree, printing the blocks that
+// do not match, see integrity.rs. Return false if any does not.
+fn verify(fsimg: &str) -> Result<bool> {
+  DISK.mount(load(fsimg));
+  LOGGING.init()?;
+  let result = integrity::verify();
+  DISK.unmount();
+
+  let blocknos = result?;
+  for blockno in &blocknos {
+    println!("{} does not match its hash", blockno);
+  }
+  Ok(blocknos.is_empty())
+}
+
+// Run `xv6fs overlay`, see overlay.rs.
+fn overlay(args: &[String]) -> io::Result<()> {
+  match (args[0].as_str(), args.len()) {
+    ("create", 3) => Overlay::create(&args[1], &args[2]),
+    ("commit", 3) => {
+      let overlay = Overlay::open(&args[1], &args[2])?;
+      println!("{} blocks changed", overlay.changed());
+      overlay.commit()
+    },
+    ("discard", 2) => overlay::discard(&args[1]),
+    _ => usage(),
+  }
+}
+
+// Check the bitmap of the image `fsimg` against the blocks its inodes
+// refer to, printing what does not match, see fs::verify. Return false if
+// anything does not.
+fn fsck(fsimg: &str) -> Result<bool> {
+  DISK.mount(load(fsimg));
+  LOGGING.init()?;
+  let result = fs::verify(&LOGGING.new_txn());
+  DISK.unmount();
+
+  let report = result?;
+  for blockno in &report.leaked {
+    println!("{} is marked used, but nothing refers to it", blockno);
+  }
+  for blockno in &report.unmarked {
+    println!("{} is referred to, but marked free", blockno);
+  }
+  for blockno in &report.doubled {
+    println!("{} is referred to more often than it is counted", blockno);
+  }
+  Ok(report.is_ok())
+}
+
+fn main() {
+  let args = disk::parse_force(env::args().collect());
+  if args.len() >= 3 && args[1] == "overlay" {
+    if let Err(e) = overlay(&args[2..]) {
+      eprintln!("xv6fs: overlay {}: {}", args[2], e);
+      process::exit(1);
+    }
+    return;
+  }
+  if args.len() == 3 && args[1] == "scrub" {
+    scrub(&args[2]);
+    return;
+  }
+  if args.len() == 3 && args[1] == "verify" {
+    match verify(&args[2]) {
+      Ok(true) => return,
+      Ok(false) => process::exit(1),
+      Err(e) => {
+        eprintln!("xv6fs: cannot verify {}: {:?}", args[2], e);
+        process::exit(1);
+      },
+    }
+  }
+  if args.len() == 3 && args[1] == "fsck" {
+    match fsck(&args[2]) {
+      Ok(true) => return,
+      Ok(false) => process::exit(1),
+      Err(e) => {
+        eprintln!("xv6fs: cannot check {}: {:?}", args[2], e);
+        process::exit(1);
+      },
+    }
+  }
+  if args.len() == 4 && args[1] == "clone" {
+    if let Err(e) = Overlay::create_clone(&args[2], &args[3]) {
+      eprintln!("xv6fs: cannot clone {}: {}", args[2], e);
+      process::exit(1);
+    }
+    return;
+  }
+  if args.len() == 4 && args[1] == "convert" {
+    if let Err(e) = convert(&args[2], &args[3]) {
+      eprintln!("xv6fs: cannot convert {}: {}", args[2], e);
+      process::exit(1);
+    }
+    return;
+  }
+  if args.len() == 4 && args[1] == "ls" {
+    if let Err(e) = list(&args[2], &args[3]) {
+      eprintln!("xv6fs: cannot list {}: {:?}", args[3], e);
+      process::exit(1);
+    }
+    return;
+  }
+  if args.len() < 4 || args[1] != "rm" || args[2] != "-r" {
+    usage();
+  }
+
+  let mut status = 0;
+  for path in &args[3..] {
+    if let Err(e) = remove_recursive(Path::new(path)) {
+      eprintln!("xv6fs: cannot remove {}: {}", path, e);
+      status = 1;
+    }
+  }
+  process::exit(status);
+}
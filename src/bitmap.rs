@@ -1,10 +1,57 @@
-use buffer::BCACHE;
-use disk::BSIZE;
-use fs::BPB;
-use logging::Transaction;
+use buffer::{BCACHE, LockedBuf};
+use delalloc;
+use disk::{BSIZE, Block, DISK};
+use error::{Error, Result};
+use fs::{BPB, DiskInode, FileType, IPB, SuperBlock};
+use inode::ICACHE;
+use logging::{LOGGING, Transaction};
+use refcount;
+use std::cmp::{max, min};
+use std::mem::transmute;
+use std::sync::Mutex;
 
 pub struct Bitmap;
 
+// What `verify` found wrong with the bitmap, by block number.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+  // Marked used, but no inode refers to them.
+  pub leaked: Vec<usize>,
+  // Referred to, but marked free.
+  pub unmarked: Vec<usize>,
+  // Referred to more often than their reference count allows, see
+  // refcount.rs.
+  pub doubled: Vec<usize>,
+}
+
+impl Report {
+  pub fn is_ok(&self) -> bool {
+    self.leaked.is_empty() && self.unmarked.is_empty() &&
+      self.doubled.is_empty()
+  }
+}
+
+// The free data blocks, counted at mount and kept up to date since, and
+// the block `alloc` looks at first, past the one it last allocated, so
+// that it rarely scans the bitmap. Each group has such a cursor of its
+// own, for the inodes it is the goal of, so that writers of different
+// inodes take blocks of different bitmap blocks, see `goal`.
+struct Summary {
+  nfree: usize,
+  next: usize,
+  // By group, from that of the first data block.
+  cursors: Vec<usize>,
+}
+
+lazy_static! {
+  // None until `init`, when `alloc` scans from the start and `nfree`
+  // counts.
+  static ref SUMMARY: Mutex<Option<Summary>> = Mutex::new(None);
+  // The blocks freed since the last commit, or None unless they are then
+  // discarded, see `set_discard`.
+  static ref FREED: Mutex<Option<Vec<usize>>> = Mutex::new(None);
+}
+
 impl Bitmap {
   // Zero `blockno`.
   fn zero<'a>(txn: &Transaction<'a>, blockno: usize) {
@@ -14,29 +61,417 @@ impl Bitmap {
     txn.write(&mut block);
   }
 
-  // Allocate a new block and mark it used in block bitmap.
-  pub fn alloc<'a>(txn: &Transaction<'a>) -> usize {
+  // Forget the free blocks counted, and those freed, of a file system no
+  // longer mounted.
+  pub fn clear() {
+    *SUMMARY.lock().unwrap() = None;
+    if let Some(ref mut freed) = *FREED.lock().unwrap() {
+      freed.clear();
+    }
+  }
+
+  // Have the disk discard the blocks freed from now on, once the commit
+  // that freed them is written, see BlockDevice::discard, or no longer.
+  // Never with a hash tree, which would then find them tampered with, see
+  // integrity.rs.
+  pub fn set_discard(discard: bool) {
+    let discard = discard && BCACHE.sb().hashblk == 0;
+
+    *FREED.lock().unwrap() = if discard { Some(vec![]) } else { None };
+  }
+
+  // Discard the blocks freed, once the commit that freed them is written.
+  // Those used again are left, taken since, or freed by a transaction that
+  // was aborted.
+  pub fn discard_freed() {
+    let mut freed = match *FREED.lock().unwrap() {
+      Some(ref mut freed) => freed.split_off(0),
+      None => return,
+    };
+    let sb = BCACHE.sb();
+
+    freed.sort();
+    freed.dedup();
+    for blockno in freed {
+      let used = {
+        let block = BCACHE.read(sb.bblock(blockno)).unwrap();
+        let i = blockno % BPB;
+        block.data[i / 8] & (1 << (i % 8)) != 0
+      };
+      if !used {
+        DISK.discard(blockno);
+      }
+    }
+  }
+
+  // Count the free blocks of the mounted file system, once recovered, and
+  // start allocating from the hint its super block keeps, see `save`.
+  pub fn init<'a>(txn: &Transaction<'a>) {
+    Bitmap::clear();
+
+    let sb = BCACHE.sb();
+    let nfree = Bitmap::count_free(txn);
+    let next = sb.nextfree as usize;
+    let next = if next >= sb.data_start() && next < sb.data_end() {
+      next
+    } else {
+      sb.data_start()
+    };
+    let (start, end) = (sb.data_start(), sb.data_end());
+    let cursors = (start / BPB..(end - 1) / BPB + 1)
+      .map(|g| max(start, g * BPB))
+      .collect();
+    *SUMMARY.lock().unwrap() = Some(Summary {
+      nfree,
+      next,
+      cursors,
+    });
+  }
+
+  // Move the cursor of the group of `blockno` past it, just allocated,
+  // back to the start of the group from its end.
+  fn advance(blockno: usize) {
+    let sb = BCACHE.sb();
+    let (start, end) = (sb.data_start(), sb.data_end());
+    let g = blockno / BPB;
+    let next = if blockno + 1 < min(end, (g + 1) * BPB) {
+      blockno + 1
+    } else {
+      max(start, g * BPB)
+    };
+
+    if let Some(ref mut summary) = *SUMMARY.lock().unwrap() {
+      summary.cursors[g - start / BPB] = next;
+    }
+  }
+
+  // Keep the hint in the super block, for the next mount. It is saved at
+  // unmount only, so a crash leaves an older one, which is harmless.
+  pub fn save() {
+    if DISK.is_read_only() {
+      return;
+    }
+    let next = match *SUMMARY.lock().unwrap() {
+      Some(ref summary) => summary.next as u32,
+      None => return,
+    };
+    let txn = LOGGING.new_txn();
+    let mut buf = txn.read(1).unwrap();
+    let mut sb = from_block!(&buf.data, SuperBlock);
+
+    if sb.nextfree != next {
+      sb.nextfree = next;
+      buf.data = to_block!(&sb, SuperBlock);
+      txn.write(&mut buf);
+      BCACHE.set_sb(sb);
+    }
+  }
+
+  // Note that data block `blockno` was taken, or freed if not `used`.
+  fn note(blockno: usize, used: bool) {
+    let sb = BCACHE.sb();
+
+    if blockno < sb.data_start() || blockno >= sb.data_end() {
+      return;
+    }
+    if let Some(ref mut summary) = *SUMMARY.lock().unwrap() {
+      if used {
+        summary.nfree -= 1;
+      } else {
+        summary.nfree += 1;
+      }
+    }
+  }
+
+  // Note that bitmap block `blockno` went back from `data` to `old`, as a
+  // transaction is aborted.
+  pub fn revert(blockno: usize, data: &Block, old: &Block) {
+    let sb = BCACHE.sb();
+    let first = (blockno - sb.bmap_start as usize) * BPB;
+
+    for i in max(first, sb.data_start())..min(first + BPB, sb.data_end()) {
+      let j = i % BPB;
+      let mask = 1 << (j % 8);
+
+      if data[j / 8] & mask != old[j / 8] & mask {
+        Bitmap::note(i, old[j / 8] & mask != 0);
+      }
+    }
+  }
+
+  // Take the first free block of `from..to`, all in the group bitmap
+  // block `block` is for, and zero it.
+  fn take_first<'a, 'b>(
+    txn: &Transaction<'a>,
+    block: &mut LockedBuf<'b>,
+    from: usize,
+    to: usize,
+  ) -> Option<usize> {
+    for i in from..to {
+      let j = i % BPB;
+      let mask = 1 << (j % 8);
+
+      if (block.data[j / 8] & mask) == 0 {
+        block.data[j / 8] |= mask;
+        txn.write(block);
+        Bitmap::note(i, true);
+        Bitmap::zero(txn, i);
+        return Some(i);
+      }
+    }
+    None
+  }
+
+  // Allocate a new block and mark it used in block bitmap, the first free
+  // from the hint on, wrapping around. NoSpace if there is none.
+  pub fn alloc<'a>(txn: &Transaction<'a>) -> Result<usize> {
     let sb = BCACHE.sb();
     let nblocks = sb.nblocks as usize;
+    let nbmap = (nblocks + BPB - 1) / BPB;
+    if !Bitmap::has_room(1) {
+      return Err(Error::NoSpace);
+    }
+    let start = match *SUMMARY.lock().unwrap() {
+      Some(ref summary) => summary.next,
+      None => 0,
+    };
 
-    for b in 0..nblocks / BPB {
+    // The bitmap block of the hint comes twice, from it, then up to it.
+    for k in 0..nbmap + 1 {
+      let b = (start / BPB + k) % nbmap;
       let mut block = txn.read(sb.bblock(b * BPB)).unwrap();
+      let from = if k == 0 { start } else { b * BPB };
+      let to = min(nblocks, (b + 1) * BPB);
+
+      if let Some(i) = Bitmap::take_first(txn, &mut block, from, to) {
+        if let Some(ref mut summary) = *SUMMARY.lock().unwrap() {
+          summary.next = i + 1;
+        }
+        return Ok(i);
+      }
+    }
+    Err(Error::NoSpace)
+  }
+
+  // Allocate `n` blocks in a row, in one pass over the bitmap, and return
+  // the first, or None if no group has that many free in a row. They are
+  // in one group, so that one bitmap block is written for them all. The
+  // groups are tried from that of `goal` on, whose cursor goes past them,
+  // see `alloc_near`.
+  pub fn alloc_contiguous<'a>(
+    txn: &Transaction<'a>,
+    goal: usize,
+    n: usize,
+  ) -> Option<usize> {
+    let sb = BCACHE.sb();
+    let (start, end) = (sb.data_start(), sb.data_end());
+    let ngroups = (end - 1) / BPB - start / BPB + 1;
+    if !Bitmap::has_room(n) {
+      return None;
+    }
+    let goal = if goal >= start && goal < end { goal } else { start };
+
+    for k in 0..ngroups {
+      let g = start / BPB + (goal / BPB - start / BPB + k) % ngroups;
+      let mut block = txn.read(sb.bblock(g * BPB)).unwrap();
+      let mut run = 0;
+
+      for i in max(start, g * BPB)..min(end, (g + 1) * BPB) {
+        let j = i % BPB;
+        if block.data[j / 8] & 1 << (j % 8) != 0 {
+          run = 0;
+          continue;
+        }
+        run += 1;
+        if run < n {
+          continue;
+        }
+        let first = i + 1 - n;
+        for b in first..i + 1 {
+          let j = b % BPB;
+          block.data[j / 8] |= 1 << (j % 8);
+          Bitmap::note(b, true);
+        }
+        txn.write(&mut block);
+        for b in first..i + 1 {
+          Bitmap::zero(txn, b);
+        }
+        Bitmap::advance(i);
+        return Some(first);
+      }
+    }
+    None
+  }
+
+  // Like `alloc`, but the first free block from `goal` on, in its group,
+  // is taken if there is one, or else before it, so that the blocks of a
+  // file end up close together. A group is the blocks a bitmap block is
+  // for, whose cursor goes past the block taken.
+  pub fn alloc_near<'a>(
+    txn: &Transaction<'a>,
+    goal: usize,
+  ) -> Result<usize> {
+    let sb = BCACHE.sb();
+
+    if !Bitmap::has_room(1) {
+      return Err(Error::NoSpace);
+    }
+    if goal >= sb.data_start() && goal < sb.data_end() {
+      let mut block = txn.read(sb.bblock(goal)).unwrap();
+      let from = max(sb.data_start(), goal / BPB * BPB);
+      let to = min(sb.data_end(), (goal / BPB + 1) * BPB);
+      let i = Bitmap::take_first(txn, &mut block, goal, to)
+        .or_else(|| Bitmap::take_first(txn, &mut block, from, goal));
+
+      if let Some(i) = i {
+        Bitmap::advance(i);
+        return Ok(i);
+      }
+    }
+    Bitmap::alloc(txn)
+  }
+
+  // The block the data of inode `inum` goes near first, the cursor of a
+  // group, the groups shared out among the inodes in order, or its start
+  // without a summary.
+  pub fn goal(inum: usize) -> usize {
+    let sb = BCACHE.sb();
+    let (start, end) = (sb.data_start(), sb.data_end());
+    let ngroups = (end - 1) / BPB - start / BPB + 1;
+    let group = start / BPB + inum * ngroups / sb.ninodes as usize;
+
+    match *SUMMARY.lock().unwrap() {
+      Some(ref summary) => summary.cursors[group - start / BPB],
+      None => max(start, group * BPB),
+    }
+  }
+
+  // Overwrite the content of `blocknos` with zeros on disk, before they
+  // are freed. This bypasses the log, which is too small for the blocks of
+  // a whole file, and waits for the disk, so that the zeros are durable
+  // before any transaction freeing the blocks commits. A crash in between
+  // leaves the blocks zeroed but not freed.
+  pub fn shred(blocknos: &[usize]) {
+    if blocknos.is_empty() {
+      return;
+    }
+    for blockno in blocknos {
+      let mut block = BCACHE.read(*blockno).unwrap();
+
+      // A copy of the block pending in the log is taken from the cache at
+      // commit, so it is zeroed as well.
+      block.data = [0; BSIZE];
+      BCACHE.write(&mut block);
+    }
+    DISK.flush();
+  }
+
+  // Mark `blockno` used, if it is not already. Return whether it was free.
+  pub fn take<'a>(txn: &Transaction<'a>, blockno: usize) -> bool {
+    let sb = BCACHE.sb();
+    let mut block = txn.read(sb.bblock(blockno)).unwrap();
+    let i = blockno % BPB;
+    let mask = 1 << (i % 8);
+
+    if block.data[i / 8] & mask != 0 {
+      return false;
+    }
+    block.data[i / 8] |= mask;
+    txn.write(&mut block);
+    Bitmap::note(blockno, true);
+    true
+  }
+
+  // Return whether `n` more blocks may be allocated, past those reserved
+  // for the blocks whose allocation is delayed, see delalloc.rs. Always
+  // without a summary, which counts them.
+  pub fn has_room(n: usize) -> bool {
+    match *SUMMARY.lock().unwrap() {
+      Some(ref summary) => summary.nfree >= delalloc::reserved() + n,
+      None => true,
+    }
+  }
+
+  // Return the number of free data blocks, counted once at mount, less
+  // those reserved, see `has_room`.
+  pub fn count_free<'a>(txn: &Transaction<'a>) -> usize {
+    if let Some(ref summary) = *SUMMARY.lock().unwrap() {
+      return summary.nfree.saturating_sub(delalloc::reserved());
+    }
+    let sb = BCACHE.sb();
+    let (start, end) = (sb.data_start(), sb.data_end());
+    let mut n = 0;
 
-      for j in 0..BPB {
-        let i = b * BPB + j;
-        if i >= nblocks {
-          break;
+    for b in start / BPB..(end + BPB - 1) / BPB {
+      let block = txn.read(sb.bblock(b * BPB)).unwrap();
+
+      for i in max(start, b * BPB)..min(end, (b + 1) * BPB) {
+        let j = i % BPB;
+        if block.data[j / 8] & 1 << (j % 8) == 0 {
+          n += 1;
         }
-        let mask = 1 << (j % 8);
-        if (block.data[j / 8] & mask) == 0 {
-          block.data[j / 8] |= mask;
-          txn.write(&mut block);
-          Bitmap::zero(txn, i);
-          return i;
+      }
+    }
+    n
+  }
+
+  // Cross-check the data blocks marked used against those the inodes in
+  // use refer to, data and indirect blocks alike, for fsck and the crash
+  // tests. The spare blocks past them are left out, see badblock.rs. The
+  // caller must not have any inode locked. Corrupt if an inode refers to a
+  // block before the data blocks.
+  pub fn verify<'a>(txn: &Transaction<'a>) -> Result<Report> {
+    let sb = BCACHE.sb();
+    let (start, end) = (sb.data_start(), sb.data_end());
+    let mut refs = vec![0; sb.nblocks as usize];
+
+    for inum in 1..sb.ninodes as usize {
+      let free = {
+        let buf = txn.read(sb.iblock(inum)).unwrap();
+        let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
+        inodes[inum % IPB].file_type == FileType::None
+      };
+      if free {
+        continue;
+      }
+      let inode = ICACHE.get(inum).unwrap();
+      let dinode = ICACHE.lock(txn, &inode);
+
+      for blockno in dinode
+        .data_blocks(txn)?
+        .into_iter()
+        .chain(dinode.indirect_blocks(txn)?)
+      {
+        if blockno < start {
+          error!("inode {} refers to block {}", inum, blockno);
+          return Err(Error::Corrupt);
         }
+        refs[blockno] += 1;
       }
     }
-    panic!("no free block");
+
+    let mut report = Report::default();
+    for b in start / BPB..(end + BPB - 1) / BPB {
+      let block = txn.read(sb.bblock(b * BPB)).unwrap();
+
+      for i in max(start, b * BPB)..min(end, (b + 1) * BPB) {
+        let j = i % BPB;
+        let used = block.data[j / 8] & 1 << (j % 8) != 0;
+
+        if used && refs[i] == 0 {
+          report.leaked.push(i);
+        } else if !used && refs[i] > 0 {
+          report.unmarked.push(i);
+        }
+      }
+    }
+    // Counted after, as reading them locks the inode of the counts.
+    for i in start..end {
+      if refs[i] > 1 && refs[i] > 1 + refcount::get(txn, i) {
+        report.doubled.push(i);
+      }
+    }
+    Ok(report)
   }
 
   // Free a block.
@@ -50,31 +485,235 @@ impl Bitmap {
 
     block.data[i / 8] &= !mask;
     txn.write(&mut block);
+    Bitmap::note(blockno, false);
+    if let Some(ref mut freed) = *FREED.lock().unwrap() {
+      freed.push(blockno);
+    }
   }
 }
 
 #[cfg(test)]
 mod test {
+  use bitmap::Bitmap;
+  use buffer::BCACHE;
+  use disk::{BSIZE, DISK, Disk};
+  use fs::{BPB, FileType, IPB};
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use mkfs;
+  use ops;
+  use std::cmp::min;
+  use testfs;
+
   #[test]
   fn test() {
-    use bitmap::Bitmap;
-    use buffer::BCACHE;
-    use disk::DISK;
-    use logging::LOGGING;
-    use testfs;
-
-    #[test]
-    fn test() {
-      let (disk, nfree) = testfs::test::create();
-      DISK.mount(disk);
-      BCACHE.init();
+    let (disk, nfree) = testfs::test::create();
+    DISK.mount(disk);
+    BCACHE.init();
 
+    // Without a summary, the first free block is taken.
+    {
       let txn = LOGGING.new_txn();
       for i in 0..30 {
-        assert!(Bitmap::alloc(&txn) == nfree + i);
+        assert!(Bitmap::alloc(&txn) == Ok(nfree + i));
       }
       Bitmap::free(&txn, nfree + 10);
-      assert!(Bitmap::alloc(&txn) == nfree + 10);
+      assert!(Bitmap::alloc(&txn) == Ok(nfree + 10));
+    }
+
+    // With one, the blocks past the last one taken, and they are counted.
+    LOGGING.init().unwrap();
+    let txn = LOGGING.new_txn();
+    let free = Bitmap::count_free(&txn);
+    assert!(Bitmap::alloc(&txn) == Ok(nfree + 30));
+    Bitmap::free(&txn, nfree + 10);
+    assert!(Bitmap::alloc(&txn) == Ok(nfree + 31));
+    assert!(Bitmap::count_free(&txn) == free - 1);
+    drop(txn);
+
+    // Also after an abort, and a mount goes on from the hint saved, rather
+    // than from the block freed.
+    let txn = LOGGING.new_txn();
+    assert!(Bitmap::alloc(&txn) == Ok(nfree + 32));
+    assert!(txn.abort() && Bitmap::count_free(&LOGGING.new_txn()) == free - 1);
+    Bitmap::save();
+    LOGGING.checkpoint();
+    BCACHE.init();
+    LOGGING.init().unwrap();
+    let txn = LOGGING.new_txn();
+    assert!(Bitmap::count_free(&txn) == free - 1);
+    assert!(Bitmap::alloc(&txn) == Ok(nfree + 33));
+  }
+
+  #[test]
+  fn test_partial() {
+    // The last bitmap and inode blocks are only partly in use, and scanned
+    // all the same.
+    let mut disk = Disk::new(200);
+    let opts = mkfs::Options {
+      ninodes: 2 * IPB + 3,
+      ..mkfs::Options::default()
+    };
+    mkfs::mkfs(&mut disk, &opts).unwrap();
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+
+    let sb = BCACHE.sb();
+    let mut last = 0;
+    while let Ok(blockno) = Bitmap::alloc(&LOGGING.new_txn()) {
+      last = blockno;
+    }
+    assert!(sb.nblocks as usize % BPB != 0);
+    assert!(last == sb.data_end() - 1);
+
+    let mut inodes = vec![];
+    let txn = LOGGING.new_txn();
+    while let Some(inode) = ICACHE.alloc(&txn, FileType::File) {
+      inodes.push(inode);
+    }
+    assert!(sb.ninodes as usize % IPB != 0);
+    assert!(inodes.last().unwrap().no() == sb.ninodes as usize - 1);
+  }
+
+  #[test]
+  fn test_near() {
+    let nfree = testfs::test::create().1;
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let sb = BCACHE.sb();
+
+    // The inodes share out the groups in order, from their starts.
+    let last = sb.ninodes as usize - 1;
+    assert!(Bitmap::goal(1) == sb.data_start());
+    assert!(Bitmap::goal(last) >= Bitmap::goal(1));
+    assert!(Bitmap::goal(last) < sb.data_end());
+
+    // The goal itself, or the first free block past it in its group, or
+    // else before it. The cursor of the group goes past it.
+    assert!(Bitmap::alloc_near(&txn, nfree + 20) == Ok(nfree + 20));
+    assert!(Bitmap::alloc_near(&txn, nfree + 20) == Ok(nfree + 21));
+    assert!(Bitmap::goal(1) == nfree + 22);
+    let end = min(sb.data_end(), BPB);
+    assert!(Bitmap::alloc_near(&txn, end - 1) == Ok(end - 1));
+    assert!(Bitmap::alloc_near(&txn, end - 1) == Ok(nfree));
+    assert!(Bitmap::goal(1) == nfree + 1);
+    // Out of the data blocks, wherever `alloc` takes one.
+    assert!(Bitmap::alloc_near(&txn, 0) == Ok(nfree + 1));
+  }
+
+  #[test]
+  fn test_groups() {
+    // Three groups, one for each third of the inodes.
+    let mut disk = Disk::new(3 * BPB);
+    mkfs::mkfs(&mut disk, &mkfs::Options::default()).unwrap();
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+
+    let txn = LOGGING.new_txn();
+    let sb = BCACHE.sb();
+    let inums = [1, sb.ninodes as usize / 2, sb.ninodes as usize - 1];
+    let starts = [sb.data_start(), BPB, 2 * BPB];
+    assert!(sb.data_start() < BPB && sb.data_end() > 2 * BPB);
+    for i in 0..3 {
+      assert!(Bitmap::goal(inums[i]) == starts[i]);
     }
+
+    // Each cursor goes past the blocks taken in its own group only, and
+    // back to its start from its end.
+    let goals = || -> Vec<usize> {
+      inums.iter().map(|&inum| Bitmap::goal(inum)).collect()
+    };
+    let before = goals();
+    assert!(Bitmap::alloc_near(&txn, BPB + 10) == Ok(BPB + 10));
+    assert!(goals() == [before[0], BPB + 11, before[2]]);
+    assert!(Bitmap::alloc_near(&txn, 2 * BPB - 1) == Ok(2 * BPB - 1));
+    assert!(Bitmap::goal(inums[1]) == BPB);
+    let blockno = Bitmap::alloc_near(&txn, before[0]).unwrap();
+    assert!(blockno >= before[0] && blockno < BPB);
+    assert!(goals() == [blockno + 1, BPB, before[2]]);
+    assert!(Bitmap::verify(&txn).unwrap().leaked.len() == 3);
+  }
+
+  #[test]
+  fn test_contiguous() {
+    let nfree = testfs::test::create().1;
+    testfs::test::mount();
+
+    // The first run long enough in the group, and its cursor past it.
+    let txn = LOGGING.new_txn();
+    let free = Bitmap::count_free(&txn);
+    assert!(Bitmap::alloc_near(&txn, nfree + 2) == Ok(nfree + 2));
+    assert!(Bitmap::alloc_contiguous(&txn, nfree, 3) == Some(nfree + 3));
+    assert!(Bitmap::count_free(&txn) == free - 4);
+    assert!(Bitmap::goal(1) == nfree + 6);
+    assert!(Bitmap::alloc_contiguous(&txn, nfree, 2) == Some(nfree));
+    assert!(Bitmap::alloc_contiguous(&txn, nfree, free).is_none());
+  }
+
+  #[test]
+  fn test_discard() {
+    testfs::test::mount();
+    Bitmap::set_discard(true);
+
+    let block = |name: &[u8]| {
+      let txn = LOGGING.new_txn();
+      let name = ops::to_name(name).unwrap();
+      let file = ops::create(&txn, &ops::root(), &name, FileType::File);
+      let file = file.unwrap();
+      ops::write(&txn, &file, 0, &[1; BSIZE]).unwrap();
+      let blockno = ICACHE.lock(&txn, &file).addrs[0] as usize;
+      blockno
+    };
+    let (a, b) = (block(b"a"), block(b"b"));
+    assert!(DISK.read(a) == [1; BSIZE] && DISK.read(b) == [1; BSIZE]);
+
+    // Discarded once the commit that freed it is written, unless it was
+    // aborted.
+    {
+      let txn = LOGGING.new_txn();
+      Bitmap::free(&txn, b);
+      assert!(txn.abort());
+    }
+    {
+      let txn = LOGGING.new_txn();
+      ops::unlink(&txn, &ops::root(), &ops::to_name(b"a").unwrap()).unwrap();
+      assert!(DISK.read(a) == [1; BSIZE]);
+    }
+    assert!(DISK.read(a) == [0; BSIZE] && DISK.read(b) == [1; BSIZE]);
+
+    Bitmap::set_discard(false);
+  }
+
+  #[test]
+  fn test_verify() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let (a, b) = (ops::to_name(b"a").unwrap(), ops::to_name(b"b").unwrap());
+    let a = ops::create(&txn, &root, &a, FileType::File).unwrap();
+    let b = ops::create(&txn, &root, &b, FileType::File).unwrap();
+    ops::write(&txn, &a, 0, &[1; 2 * BSIZE]).unwrap();
+    ops::write(&txn, &b, 0, &[2; BSIZE]).unwrap();
+    assert!(Bitmap::verify(&txn).unwrap().is_ok());
+
+    // A block of `b` leaked, as `b` refers to one of `a` instead, and the
+    // other block of `a` freed.
+    let (first, second) = {
+      let a = ICACHE.lock(&txn, &a);
+      (a.addrs[0] as usize, a.addrs[1] as usize)
+    };
+    let old = {
+      let mut b = ICACHE.lock(&txn, &b);
+      let old = b.addrs[0] as usize;
+      b.set_nth_block(&txn, 0, first);
+      old
+    };
+    Bitmap::free(&txn, second);
+    let report = Bitmap::verify(&txn).unwrap();
+    assert!(report.leaked == [old]);
+    assert!(report.unmarked == [second]);
+    assert!(report.doubled == [first]);
   }
 }
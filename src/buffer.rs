@@ -1,37 +1,116 @@
 use disk::{BSIZE, Block, DISK};
 use fs::SuperBlock;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use integrity;
+use memory::{Account, MEMORY};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, mpsc};
+use std::thread;
+use std::time::Duration;
 use util::locked::{LockedItem, UnlockedItem};
 
 bitflags! {
   struct BufFlags: u32 {
     const VALID = 0b01;
+    // Written in the cache only, for the flusher, see `defer_write`.
     const DIRTY = 0b10;
+    // Read from the disk not matching its hash, see integrity.rs.
+    const CORRUPT = 0b100;
   }
 }
 
 pub struct Buf {
   pub data: Block,
   flags: BufFlags,
+  // Pinned in the cache, e.g. by the log until committed, see `pin`.
+  pins: usize,
 }
 
 pub type LockedBuf<'a> = LockedItem<'a, Buf, usize /* blockno */>;
 pub type UnlockedBuf = UnlockedItem<Buf, usize /* blockno */>;
 
+// How a full tier picks the buffer to evict, among the clean ones nobody
+// uses.
+enum Policy {
+  // The first one found, which is cheap and fine for the few metadata
+  // blocks.
+  Any,
+  // 2Q: buffers come in cold, and are evicted first in the order they came
+  // in, unless they were evicted recently already, and come in hot then.
+  // The hot ones, the working set, go in least recently used order once
+  // the cold ones are few, so that scanning a big file once does not evict
+  // the directory and indirect blocks read over and over.
+  TwoQ,
+}
+
+// Buffers, the tick of their last use, or of their coming in if cold, and
+// whether they are hot, see Policy::TwoQ.
+type Shard = Mutex<HashMap<usize, (UnlockedBuf, usize, bool)>>;
+
+struct Tier {
+  capacity: AtomicUsize,
+  policy: Policy,
+  // Sharded by block number so that the threads of the server do not all
+  // wait for one lock.
+  shards: Vec<Shard>,
+  tick: AtomicUsize,
+  // Buffers in all shards, and the cold ones among them.
+  len: AtomicUsize,
+  cold: AtomicUsize,
+  // The blocks last evicted cold, and again as a set.
+  ghosts: Mutex<(VecDeque<usize>, HashSet<usize>)>,
+}
+
+// Blocks are cached in two tiers, so that streaming a big file cannot evict
+// the super block, log, inode and bitmap blocks. Blocks from the data start
+// on, including directory and indirect blocks, go to the larger data tier,
+// which is scan resistant too, see Policy::TwoQ.
 pub struct Cache {
-  capacity: usize,
-  cache: Mutex<HashMap<usize, UnlockedBuf>>,
+  meta: Tier,
+  data: Tier,
+  // Most blocks read ahead at once.
+  readahead: AtomicUsize,
+  // Most bytes the buffers of both tiers take, none if 0.
+  limit: AtomicUsize,
+  // Blocks waiting for the flusher, in write-back mode, see `defer_write`.
+  pending: Mutex<BTreeSet<usize>>,
+  // How often the flusher writes them out, None to write through, and
+  // whether it runs.
+  writeback: Mutex<(Option<Duration>, bool)>,
+  flusher: Condvar,
 }
 
+// Capacity of the data tier.
+const DATA_CACHE_BYTES: usize = 1 << 20;
+
+// Shards of each tier.
+const NSHARDS: usize = 16;
+
 lazy_static! {
-  pub static ref BCACHE: Cache = Cache::new(256);
+  pub static ref BCACHE: Cache = Cache::new(256, DATA_CACHE_BYTES / BSIZE);
 
-  // Block 1 is immutable after file system is created, so we can safely
-  // store it here.
-  static ref SB: SuperBlock = from_block!(
+  // Block 1 only changes through `set_sb`, so we can safely store it here.
+  // Tests mounting another file system reload it in `init`.
+  static ref SB: RwLock<SuperBlock> = RwLock::new(from_block!(
     &DISK.read(1), SuperBlock
-  );
+  ));
+
+  // Blocks to be read in the background, see `readahead`.
+  static ref READAHEAD: Mutex<mpsc::Sender<Vec<usize>>> = {
+    let (send, recv) = mpsc::channel::<Vec<usize>>();
+
+    thread::spawn(move || for blocknos in recv {
+      for blockno in blocknos {
+        // Nothing is read if the cache is full.
+        if DISK.is_mounted() {
+          BCACHE.read(blockno);
+        }
+      }
+    });
+    Mutex::new(send)
+  };
 }
 
 impl Buf {
@@ -39,64 +118,327 @@ impl Buf {
     Buf {
       flags: BufFlags::empty(),
       data: [0; BSIZE],
+      pins: 0,
+    }
+  }
+
+  pub fn is_corrupt(&self) -> bool {
+    self.flags.contains(BufFlags::CORRUPT)
+  }
+
+  // Return true if it was written in the cache only, not to the disk yet.
+  pub fn is_dirty(&self) -> bool {
+    self.flags.contains(BufFlags::DIRTY)
+  }
+
+  // It must stay in the cache, dirty or not.
+  pub fn is_pinned(&self) -> bool {
+    self.pins > 0 || self.is_dirty()
+  }
+}
+
+impl Tier {
+  fn new(capacity: usize, policy: Policy) -> Self {
+    Tier {
+      capacity: AtomicUsize::new(capacity),
+      policy: policy,
+      shards: (0..NSHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+      tick: AtomicUsize::new(0),
+      len: AtomicUsize::new(0),
+      cold: AtomicUsize::new(0),
+      ghosts: Mutex::new((VecDeque::new(), HashSet::new())),
+    }
+  }
+
+  fn shard(&self, blockno: usize) -> &Shard {
+    &self.shards[blockno % NSHARDS]
+  }
+
+  // Return the order a buffer last used at `used` is evicted in, first the
+  // least.
+  fn rank(&self, used: usize, hot: bool) -> (bool, usize) {
+    match self.policy {
+      // The cold ones go first while they are more than a quarter.
+      Policy::TwoQ => {
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let cold_first = self.cold.load(Ordering::Relaxed) > capacity / 4;
+        (hot == cold_first, used)
+      },
+      _ => (false, used),
+    }
+  }
+
+  // Return a buffer of `bufs` to evict to make room for another one, and
+  // the order it is evicted in.
+  fn victim(
+    &self,
+    bufs: &HashMap<usize, (UnlockedBuf, usize, bool)>,
+  ) -> Option<(usize, (bool, usize))> {
+    let mut result: Option<(usize, (bool, usize))> = None;
+
+    for (blockno, &(ref buf, used, hot)) in bufs.iter() {
+      if buf.refcnt() != 0 || buf.acquire().is_pinned() {
+        continue;
+      }
+      let rank = self.rank(used, hot);
+      match self.policy {
+        Policy::Any => return Some((*blockno, rank)),
+        _ => {
+          if result.map_or(true, |(_, rank2)| rank < rank2) {
+            result = Some((*blockno, rank));
+          }
+        },
+      }
+    }
+    result
+  }
+
+  // Evict a buffer of any shard, locking one at a time. Return false if
+  // none can be.
+  fn evict(&self) -> bool {
+    loop {
+      let mut result: Option<(usize, (bool, usize))> = None;
+
+      for shard in &self.shards {
+        let victim = self.victim(&shard.lock().unwrap());
+        match (victim, &self.policy) {
+          (None, _) => (),
+          (Some(_), &Policy::Any) => {
+            result = victim;
+            break;
+          },
+          (Some((_, rank)), _) => {
+            if result.map_or(true, |(_, rank2)| rank < rank2) {
+              result = victim;
+            }
+          },
+        }
+      }
+      let (blockno, (_, used)) = match result {
+        Some(victim) => victim,
+        None => return false,
+      };
+      // Unless it was used meanwhile.
+      let mut bufs = self.shard(blockno).lock().unwrap();
+      let unused = match bufs.get(&blockno) {
+        Some(&(ref buf, used2, _)) => {
+          used == used2 && buf.refcnt() == 0 && !buf.acquire().is_pinned()
+        },
+        None => false,
+      };
+      if unused {
+        let (_, _, hot) = bufs.remove(&blockno).unwrap();
+        if !hot {
+          self.cold.fetch_sub(1, Ordering::Relaxed);
+          self.haunt(blockno);
+        }
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        MEMORY.release(Account::Blocks, size_of::<Buf>());
+        return true;
+      }
+    }
+  }
+
+  // Remember `blockno`, evicted cold, as a ghost, so that it comes in hot
+  // if it is used again soon, see Policy::TwoQ.
+  fn haunt(&self, blockno: usize) {
+    if let Policy::TwoQ = self.policy {
+      let mut ghosts = self.ghosts.lock().unwrap();
+      let (ref mut order, ref mut set) = *ghosts;
+
+      if set.insert(blockno) {
+        order.push_back(blockno);
+      }
+      while order.len() > self.capacity.load(Ordering::Relaxed) / 2 {
+        set.remove(&order.pop_front().unwrap());
+      }
+    }
+  }
+
+  // Return the buffer of `blockno`, evicting another one for it if the
+  // cache is `over_limit`, or None if it cannot.
+  fn get(&self, blockno: usize, over_limit: bool) -> Option<UnlockedBuf> {
+    let mut full = false;
+
+    loop {
+      {
+        let mut bufs = self.shard(blockno).lock().unwrap();
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if let Some(entry) = bufs.get_mut(&blockno) {
+          // Cold ones go in the order they came in.
+          let cold = match self.policy {
+            Policy::TwoQ => !entry.2,
+            _ => false,
+          };
+          if !cold {
+            entry.1 = tick;
+          }
+          return Some(entry.0.clone());
+        }
+        // Over the memory budget, the tier does not grow any more if it
+        // can. Shrunk, or over the limit of the cache, it evicts a buffer
+        // for every new one.
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let len = self.len.load(Ordering::Relaxed);
+        if full || (len < capacity && !MEMORY.over_budget() && !over_limit) {
+          let new_buf = Arc::new((Mutex::new(Buf::new()), blockno));
+          let hot = self.ghosts.lock().unwrap().1.remove(&blockno);
+
+          if !hot {
+            self.cold.fetch_add(1, Ordering::Relaxed);
+          }
+          bufs.insert(blockno, (UnlockedBuf::new(new_buf.clone()), tick, hot));
+          self.len.fetch_add(1, Ordering::Relaxed);
+          MEMORY.charge(Account::Blocks, size_of::<Buf>());
+          return Some(UnlockedBuf::new(new_buf));
+        }
+      }
+      // With no shard locked, to make room.
+      if !self.evict() &&
+        (over_limit ||
+           self.len.load(Ordering::Relaxed) >=
+             self.capacity.load(Ordering::Relaxed))
+      {
+        return None;
+      }
+      full = true;
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.len.load(Ordering::Relaxed)
+  }
+
+  #[cfg(test)]
+  fn clear(&self) {
+    for shard in &self.shards {
+      let mut bufs = shard.lock().unwrap();
+
+      MEMORY.release(Account::Blocks, bufs.len() * size_of::<Buf>());
+      self.len.fetch_sub(bufs.len(), Ordering::Relaxed);
+      bufs.clear();
     }
+    self.cold.store(0, Ordering::Relaxed);
+    *self.ghosts.lock().unwrap() = (VecDeque::new(), HashSet::new());
   }
 }
 
 impl Cache {
-  fn new(capacity: usize) -> Self {
+  fn new(meta_capacity: usize, data_capacity: usize) -> Self {
     Cache {
-      capacity: capacity,
-      cache: Mutex::new(HashMap::with_capacity(capacity)),
+      meta: Tier::new(meta_capacity, Policy::Any),
+      data: Tier::new(data_capacity, Policy::TwoQ),
+      readahead: AtomicUsize::new(256),
+      limit: AtomicUsize::new(0),
+      pending: Mutex::new(BTreeSet::new()),
+      writeback: Mutex::new((None, false)),
+      flusher: Condvar::new(),
+    }
+  }
+
+  // Return the capacities of the metadata and data tiers, in blocks.
+  pub fn capacities(&self) -> (usize, usize) {
+    (
+      self.meta.capacity.load(Ordering::Relaxed),
+      self.data.capacity.load(Ordering::Relaxed),
+    )
+  }
+
+  pub fn set_capacities(&self, meta: usize, data: usize) {
+    self.meta.capacity.store(meta, Ordering::Relaxed);
+    self.data.capacity.store(data, Ordering::Relaxed);
+  }
+
+  // Return the bytes the buffers take.
+  pub fn usage(&self) -> usize {
+    self.bytes(self.meta.len() + self.data.len())
+  }
+
+  // Return the bytes `n` buffers take.
+  pub fn bytes(&self, n: usize) -> usize {
+    n * size_of::<Buf>()
+  }
+
+  pub fn limit(&self) -> usize {
+    self.limit.load(Ordering::Relaxed)
+  }
+
+  // Let the buffers take at most `bytes`, with a buffer evicted for every
+  // new one past it, from either tier, none if 0. Unlike over the memory
+  // budget, a new buffer is refused past it if none can be.
+  pub fn set_limit(&self, bytes: usize) {
+    self.limit.store(bytes, Ordering::Relaxed);
+  }
+
+  fn over_limit(&self) -> bool {
+    let limit = self.limit();
+
+    limit != 0 && self.usage() + self.bytes(1) > limit
+  }
+
+  pub fn readahead_limit(&self) -> usize {
+    self.readahead.load(Ordering::Relaxed)
+  }
+
+  // Read ahead at most `limit` blocks at once, none if 0.
+  pub fn set_readahead_limit(&self, limit: usize) {
+    self.readahead.store(limit, Ordering::Relaxed);
+  }
+
+  // Return the tier caching `blockno`. A disk without a file system has no
+  // data start, all of its blocks are metadata.
+  fn tier(&self, blockno: usize) -> &Tier {
+    let sb = self.sb();
+
+    if sb.nblocks != 0 && blockno >= sb.data_start() {
+      &self.data
+    } else {
+      &self.meta
     }
   }
 
   #[cfg(test)]
   pub fn init(&self) {
-    self.cache.lock().unwrap().clear();
+    self.meta.clear();
+    self.data.clear();
+    self.pending.lock().unwrap().clear();
+    *SB.write().unwrap() = from_block!(&DISK.read(1), SuperBlock);
+    ::bitmap::Bitmap::clear();
   }
 
   #[cfg(test)]
   pub fn nitems(&self) -> usize {
-    self.cache.lock().unwrap().len()
+    self.meta.len() + self.data.len()
+  }
+
+  pub fn sb(&self) -> SuperBlock {
+    *SB.read().unwrap()
   }
 
-  pub fn sb(&self) -> &SuperBlock {
-    &SB
+  // Note that the super block in block 1 changed to `sb` in a transaction.
+  pub fn set_sb(&self, sb: SuperBlock) {
+    *SB.write().unwrap() = sb;
   }
 
   pub fn get(&self, blockno: usize) -> Option<UnlockedBuf> {
-    let mut buf: Option<UnlockedBuf>;
-    let mut cache = self.cache.lock().unwrap();
-
-    buf = cache.get_mut(&blockno).map(|buf| buf.clone());
-    if buf.is_none() {
-      if cache.len() >= self.capacity {
-        let mut free_nos = vec![];
-
-        for (blockno2, buf2) in cache.iter() {
-          if buf2.refcnt() == 0 {
-            if !buf2.acquire().flags.contains(BufFlags::DIRTY) {
-              free_nos.push(*blockno2);
-              if cache.len() - free_nos.len() < self.capacity {
-                break;
-              }
-            }
-          }
-        }
-        if free_nos.is_empty() {
-          return None;
-        }
-        for blockno2 in free_nos {
-          cache.remove(&blockno2);
-        }
+    let tier = self.tier(blockno);
+
+    tier.get(blockno, self.over_limit()).or_else(|| {
+      // Over the limit, the other tier may have a buffer to evict instead.
+      if self.over_limit() {
+        let other = if ptr::eq(tier, &self.meta) {
+          &self.data
+        } else {
+          &self.meta
+        };
+        other.evict();
       }
-
-      let new_buf = Arc::new((Mutex::new(Buf::new()), blockno));
-      buf = Some(UnlockedBuf::new(new_buf.clone()));
-      cache.insert(blockno, UnlockedBuf::new(new_buf.clone()));
-    }
-    buf
+      // Full of blocks waiting for the flusher, which it writes out now as
+      // far as nobody uses them.
+      self.write_pending(false);
+      tier.get(blockno, self.over_limit())
+    })
   }
 
   pub fn read<'a>(&self, blockno: usize) -> Option<LockedBuf<'a>> {
@@ -105,25 +447,147 @@ impl Cache {
     if !buf.flags.contains(BufFlags::VALID) {
       buf.data = DISK.read(blockno);
       buf.flags.insert(BufFlags::VALID);
+      if !integrity::check(blockno, &buf.data) {
+        error!("block {} does not match its hash", blockno);
+        buf.flags.insert(BufFlags::CORRUPT);
+      }
     }
     Some(buf)
   }
 
+  // Read `blocknos` into the cache in the background, as they are about to
+  // be read.
+  pub fn readahead(&self, mut blocknos: Vec<usize>) {
+    blocknos.truncate(self.readahead_limit());
+    if blocknos.is_empty() {
+      return;
+    }
+    // Only a hint, dropped if the thread is gone.
+    let _ = READAHEAD.lock().unwrap().send(blocknos);
+  }
+
   pub fn write<'a>(&self, buf: &mut LockedBuf<'a>) {
     DISK.write(buf.no(), &buf.data);
-    buf.flags.remove(BufFlags::DIRTY);
+    if buf.flags.contains(BufFlags::DIRTY) {
+      buf.flags.remove(BufFlags::DIRTY);
+      self.pending.lock().unwrap().remove(&buf.no());
+    }
   }
 
-  // Pins this buf in cache.
-  pub fn pin<'a>(&self, buf: &mut LockedBuf<'a>) {
+  // Like `write`, but only in the cache in write-back mode, leaving it to
+  // the flusher, or to `sync`. It must not be evicted until then.
+  pub fn defer_write<'a>(&self, buf: &mut LockedBuf<'a>) {
+    if !self.is_writeback() {
+      return self.write(buf);
+    }
     buf.flags.insert(BufFlags::DIRTY);
+    self.pending.lock().unwrap().insert(buf.no());
+  }
+
+  pub fn is_writeback(&self) -> bool {
+    self.writeback.lock().unwrap().0.is_some()
+  }
+
+  // Write the blocks written in the cache only out from now on, in block
+  // number order, every `interval` from a background thread, see
+  // `defer_write`.
+  pub fn start_writeback(&'static self, interval: Duration) {
+    let mut writeback = self.writeback.lock().unwrap();
+
+    writeback.0 = Some(interval);
+    if !writeback.1 {
+      writeback.1 = true;
+      thread::spawn(move || self.run_flusher());
+    }
+    self.flusher.notify_all();
+  }
+
+  // Write through again, once what is pending is written out, e.g. before
+  // unmounting. The blocks the log pins are left to it, see
+  // Logging::checkpoint.
+  pub fn stop_writeback(&self) {
+    self.writeback.lock().unwrap().0 = None;
+    self.flusher.notify_all();
+    self.sync();
+  }
+
+  fn run_flusher(&self) {
+    let mut writeback = self.writeback.lock().unwrap();
+
+    while let Some(interval) = writeback.0 {
+      writeback = self.flusher.wait_timeout(writeback, interval).unwrap().0;
+      if writeback.0.is_some() && DISK.is_mounted() {
+        drop(writeback);
+        self.write_pending(false);
+        writeback = self.writeback.lock().unwrap();
+      }
+    }
+    writeback.1 = false;
+  }
+
+  // Write out the blocks waiting for the flusher, in block number order,
+  // and return those left, pinned, e.g. by the log as their content is not
+  // committed yet, or locked by someone unless `wait`.
+  fn write_pending(&self, wait: bool) -> Vec<usize> {
+    let blocknos: Vec<usize> =
+      self.pending.lock().unwrap().iter().cloned().collect();
+    let mut left = vec![];
+
+    for blockno in blocknos {
+      // Never evicted while pending.
+      let buf = match self.tier(blockno).get(blockno, false) {
+        Some(buf) => buf,
+        None => continue,
+      };
+      let mut buf = if wait { Some(buf.acquire()) } else { buf.try_acquire() };
+      match buf {
+        Some(ref mut buf) if buf.pins == 0 => {
+          if buf.flags.contains(BufFlags::DIRTY) {
+            self.write(buf);
+          }
+        },
+        _ => left.push(blockno),
+      }
+    }
+    left
+  }
+
+  // Write out the blocks waiting for the flusher now, a barrier for the
+  // log, and return those it pins, see `write_pending`.
+  pub fn sync(&self) -> Vec<usize> {
+    self.write_pending(true)
+  }
+
+  // Drop what was written to this buf since it was last written to the
+  // disk, and unpin it, see `Transaction::abort`. It must not be dirty.
+  pub fn revert<'a>(&self, buf: &mut LockedBuf<'a>) {
+    assert!(!buf.is_dirty());
+    buf.data = DISK.read(buf.no());
+    self.unpin(buf);
+    if buf.no() == 1 {
+      self.set_sb(from_block!(&buf.data, SuperBlock));
+    }
+  }
+
+  // Pins this buf in cache, until unpinned as many times.
+  pub fn pin<'a>(&self, buf: &mut LockedBuf<'a>) {
+    buf.pins += 1;
+  }
+
+  pub fn unpin<'a>(&self, buf: &mut LockedBuf<'a>) {
+    assert!(buf.pins > 0, "block {} is not pinned", buf.no());
+    buf.pins -= 1;
   }
 }
 
 #[cfg(test)]
 mod test {
-  use buffer::{BCACHE, BufFlags};
-  use disk::{Disk, DISK};
+  use buffer::{BCACHE, BufFlags, Policy, Tier};
+  use disk::{BSIZE, Disk, DISK};
+  use fs::{LABELSIZE, SuperBlock};
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
 
   #[test]
   fn test1() {
@@ -150,7 +614,7 @@ mod test {
       let b = BCACHE.get(i);
       assert!(b.is_some());
       // Mark every newly-inserted cache entry as inevictable.
-      b.unwrap().acquire().flags.insert(BufFlags::DIRTY);
+      BCACHE.pin(&mut b.unwrap().acquire());
     }
     assert!(BCACHE.nitems() == 256);
     // Cache is full, we cannot insert any new entries.
@@ -208,4 +672,170 @@ mod test {
       assert!(b.acquire().data[0] == 0);
     }
   }
+  #[test]
+  fn test5() {
+    let disk = Disk::new(1024);
+    DISK.mount(disk);
+    let sb = SuperBlock {
+      nblocks: 1024,
+      refino: 0,
+      ninodes: 16,
+      nlogs: 4,
+      log_start: 2,
+      inode_start: 7,
+      bmap_start: 11,
+      flags: 0,
+      badblk: 0,
+      nspares: 0,
+      hashblk: 0,
+      root: [0; 32],
+      checksum: 0,
+      nextfree: 0,
+      label: [0; LABELSIZE],
+    };
+    DISK.write(1, &to_block!(&sb, SuperBlock));
+    BCACHE.init();
+
+    {
+      let mut b = BCACHE.read(11).unwrap();
+      b.data[0] = 42;
+    }
+    // Streaming every data block leaves the bitmap block cached.
+    for i in sb.data_start()..1024 {
+      BCACHE.read(i).unwrap();
+    }
+    assert!(BCACHE.get(11).unwrap().acquire().data[0] == 42);
+    assert!(BCACHE.nitems() == 1 + 1024 - sb.data_start());
+  }
+
+  #[test]
+  fn test6() {
+    let tier = Tier::new(8, Policy::TwoQ);
+
+    // Blocks used again once evicted come in hot.
+    tier.get(1, false).unwrap();
+    tier.get(2, false).unwrap();
+    for i in 100..108 {
+      tier.get(i, false).unwrap();
+    }
+    tier.get(1, false).unwrap().acquire().data[0] = 1;
+    tier.get(2, false).unwrap().acquire().data[0] = 2;
+
+    // Used twice before, block 3 is still cold, and a scan evicts it, but
+    // not the hot ones.
+    tier.get(3, false).unwrap().acquire().data[0] = 3;
+    tier.get(3, false).unwrap();
+    for i in 200..300 {
+      tier.get(i, false).unwrap();
+    }
+    assert!(tier.get(1, false).unwrap().acquire().data[0] == 1);
+    assert!(tier.get(2, false).unwrap().acquire().data[0] == 2);
+    assert!(tier.len() == 8);
+    assert!(!tier.shard(3).lock().unwrap().contains_key(&3));
+  }
+
+  #[test]
+  fn test7() {
+    let tier = Arc::new(Tier::new(64, Policy::TwoQ));
+
+    // Threads sharing a full tier each get their blocks.
+    let threads: Vec<_> = (0..4)
+      .map(|i| {
+        let tier = tier.clone();
+        thread::spawn(move || for j in 0..1000 {
+          let blockno = i * 1000 + j;
+          let buf = tier.get(blockno, false).unwrap();
+          buf.acquire().data[0] = j as u8;
+          let data = tier.get(blockno, false).unwrap().acquire().data;
+          assert!(data[0] == j as u8);
+        })
+      })
+      .collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+    assert!(tier.len() <= 64 + 4);
+  }
+
+  #[test]
+  fn test_limit() {
+    let disk = Disk::new(1024);
+    DISK.mount(disk);
+    BCACHE.init();
+
+    // Buffers are evicted past it, and refused if none can be.
+    BCACHE.set_limit(BCACHE.bytes(16));
+    for i in 0..100 {
+      BCACHE.get(i).unwrap();
+      assert!(BCACHE.usage() <= BCACHE.limit());
+    }
+    let pinned: Vec<_> = (0..16).map(|i| BCACHE.get(i).unwrap()).collect();
+    for b in &pinned {
+      BCACHE.pin(&mut b.acquire());
+    }
+    assert!(BCACHE.get(200).is_none());
+    for b in &pinned {
+      BCACHE.unpin(&mut b.acquire());
+    }
+    drop(pinned);
+    assert!(BCACHE.get(200).is_some());
+    BCACHE.set_limit(0);
+  }
+
+  #[test]
+  fn test_readahead() {
+    let disk = Disk::new(1024);
+    DISK.mount(disk);
+    DISK.write(500, &[42; BSIZE]);
+    BCACHE.init();
+
+    BCACHE.readahead(vec![500, 501]);
+    let b = BCACHE.get(500).unwrap();
+    while !b.acquire().flags.contains(BufFlags::VALID) {
+      thread::yield_now();
+    }
+    assert!(b.acquire().data[0] == 42);
+  }
+
+  #[test]
+  fn test_writeback() {
+    let disk = Disk::new(1024);
+    DISK.mount(disk);
+    BCACHE.init();
+
+    let write = |blockno: usize, byte: u8| {
+      let mut b = BCACHE.read(blockno).unwrap();
+      b.data[0] = byte;
+      BCACHE.defer_write(&mut b);
+    };
+
+    // Written in the cache only, until synced.
+    BCACHE.start_writeback(Duration::from_secs(60));
+    write(600, 1);
+    write(500, 2);
+    assert!(DISK.read(500)[0] == 0 && DISK.read(600)[0] == 0);
+    {
+      let mut b = BCACHE.read(500).unwrap();
+      assert!(b.is_dirty());
+      // Pinned by the log meanwhile, it is not written out.
+      BCACHE.pin(&mut b);
+    }
+    assert!(BCACHE.sync() == vec![500]);
+    assert!(DISK.read(500)[0] == 0 && DISK.read(600)[0] == 1);
+    BCACHE.unpin(&mut BCACHE.read(500).unwrap());
+    assert!(BCACHE.sync().is_empty() && DISK.read(500)[0] == 2);
+
+    // Or by the flusher.
+    BCACHE.start_writeback(Duration::from_millis(1));
+    write(700, 3);
+    while DISK.read(700)[0] != 3 {
+      thread::yield_now();
+    }
+    assert!(!BCACHE.read(700).unwrap().is_dirty());
+
+    // And written through once stopped.
+    BCACHE.stop_writeback();
+    write(800, 4);
+    assert!(DISK.read(800)[0] == 4);
+  }
 }
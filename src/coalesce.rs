@@ -0,0 +1,224 @@
+// Coalescing of small writes, so that appending a few bytes at a time does
+// not journal the same block in transaction after transaction.
+//
+// A write smaller than a block is held back per handle, and the writes that
+// follow it through the same handle are appended to it, until it is taken
+// for writing out: as soon as it fills up to a block boundary, which writes
+// the whole blocks and holds back the rest, when a write through the handle
+// does not follow it, when a write through another handle or an operation
+// other than a write concerns the same inode, when the handle is flushed or
+// released, and once it is older than the window. The server writes the
+// held back writes it is handed in order, and reports an error writing one
+// out on the next write or flush of its handle.
+
+use disk::BSIZE;
+use error::Error;
+use std::collections::HashMap;
+use std::mem::replace;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// A write held back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Pending {
+  // The handle it was made through, and its inode number.
+  pub fh: u64,
+  pub inum: usize,
+  pub offset: usize,
+  pub data: Vec<u8>,
+}
+
+impl Pending {
+  fn end(&self) -> usize {
+    self.offset + self.data.len()
+  }
+}
+
+pub struct Coalescer {
+  window: Duration,
+  // By handle, with the time of their first write.
+  pending: Mutex<HashMap<u64, (Pending, Instant)>>,
+  // Of the handles whose writes failed to be written out.
+  errors: Mutex<HashMap<u64, Error>>,
+}
+
+impl Coalescer {
+  pub fn new(window: Duration) -> Self {
+    Coalescer {
+      window,
+      pending: Mutex::new(HashMap::new()),
+      errors: Mutex::new(HashMap::new()),
+    }
+  }
+
+  pub fn window(&self) -> Duration {
+    self.window
+  }
+
+  // Return true if no write is held back.
+  pub fn is_empty(&self) -> bool {
+    self.pending.lock().unwrap().is_empty()
+  }
+
+  // Hold back `write`, smaller than a block, and return the writes to
+  // write out before it is acknowledged, in order.
+  pub fn write(&self, write: Pending) -> Vec<Pending> {
+    assert!(write.data.len() < BSIZE);
+    let mut pending = self.pending.lock().unwrap();
+    let mut result = vec![];
+    let others: Vec<u64> = pending
+      .iter()
+      .filter(|&(fh, p)| *fh != write.fh && p.0.inum == write.inum)
+      .map(|(fh, _)| *fh)
+      .collect();
+
+    for fh in others {
+      result.push(pending.remove(&fh).unwrap().0);
+    }
+    let fh = write.fh;
+    let follows = match pending.get(&fh) {
+      Some(&(ref p, _)) => p.inum == write.inum && p.end() == write.offset,
+      None => false,
+    };
+    if follows {
+      pending.get_mut(&fh).unwrap().0.data.extend(write.data);
+    } else {
+      result.extend(pending.remove(&fh).map(|p| p.0));
+      pending.insert(fh, (write, Instant::now()));
+    }
+
+    // Whole blocks are written out at once.
+    let full = {
+      let p = &mut pending.get_mut(&fh).unwrap().0;
+      let boundary = p.end() / BSIZE * BSIZE;
+
+      if boundary > p.offset {
+        let rest = p.data.split_off(boundary - p.offset);
+        let offset = replace(&mut p.offset, boundary);
+        Some(Pending {
+          fh,
+          inum: p.inum,
+          offset,
+          data: replace(&mut p.data, rest),
+        })
+      } else {
+        None
+      }
+    };
+    if let Some(full) = full {
+      if pending[&fh].0.data.is_empty() {
+        pending.remove(&fh);
+      }
+      result.push(full);
+    }
+    result
+  }
+
+  // Return the write held back of handle `fh`.
+  pub fn take(&self, fh: u64) -> Vec<Pending> {
+    let mut pending = self.pending.lock().unwrap();
+    pending.remove(&fh).map(|p| p.0).into_iter().collect()
+  }
+
+  // Return the writes held back to inode `inum`.
+  pub fn take_inode(&self, inum: usize) -> Vec<Pending> {
+    self.take_if(|p, _| p.inum == inum)
+  }
+
+  // Return the writes held back for longer than the window.
+  pub fn take_expired(&self) -> Vec<Pending> {
+    let window = self.window;
+    self.take_if(|_, since| since.elapsed() >= window)
+  }
+
+  pub fn take_all(&self) -> Vec<Pending> {
+    self.take_if(|_, _| true)
+  }
+
+  fn take_if<F>(&self, f: F) -> Vec<Pending>
+  where
+    F: Fn(&Pending, &Instant) -> bool,
+  {
+    let mut pending = self.pending.lock().unwrap();
+    let fhs: Vec<u64> = pending
+      .iter()
+      .filter(|&(_, &(ref p, ref since))| f(p, since))
+      .map(|(fh, _)| *fh)
+      .collect();
+
+    fhs
+      .into_iter()
+      .map(|fh| pending.remove(&fh).unwrap().0)
+      .collect()
+  }
+
+  // Record that a write held back of handle `fh` failed with `e`.
+  pub fn fail(&self, fh: u64, e: Error) {
+    self.errors.lock().unwrap().entry(fh).or_insert(e);
+  }
+
+  // Return the error of handle `fh` since the last call, if any.
+  pub fn error(&self, fh: u64) -> Option<Error> {
+    self.errors.lock().unwrap().remove(&fh)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use coalesce::{Coalescer, Pending};
+  use disk::BSIZE;
+  use error::Error;
+  use std::thread;
+  use std::time::Duration;
+
+  fn write(fh: u64, inum: usize, offset: usize, data: &[u8]) -> Pending {
+    Pending {
+      fh,
+      inum,
+      offset,
+      data: data.to_vec(),
+    }
+  }
+
+  #[test]
+  fn test() {
+    let coalescer = Coalescer::new(Duration::from_secs(60));
+
+    // Appends are held back until they fill a block.
+    for i in 0..BSIZE - 11 {
+      assert!(coalescer.write(write(1, 5, 10 + i, &[1])).is_empty());
+    }
+    let written = coalescer.write(write(1, 5, BSIZE - 1, &[2; 20]));
+    assert!(written.len() == 1);
+    assert!(written[0].offset == 10 && written[0].data.len() == BSIZE - 10);
+    assert!(written[0].data[BSIZE - 12..] == [1, 2]);
+    assert!(coalescer.take(1) == vec![write(1, 5, BSIZE, &[2; 19])]);
+    assert!(coalescer.take(1).is_empty());
+
+    // A write elsewhere, or through another handle, writes out the rest.
+    coalescer.write(write(1, 5, 0, b"ab"));
+    let written = coalescer.write(write(1, 5, 7, b"c"));
+    assert!(written == vec![write(1, 5, 0, b"ab")]);
+    let written = coalescer.write(write(2, 5, 8, b"d"));
+    assert!(written == vec![write(1, 5, 7, b"c")]);
+    coalescer.write(write(3, 6, 0, b"e"));
+    assert!(coalescer.take_inode(5) == vec![write(2, 5, 8, b"d")]);
+    assert!(coalescer.take_expired().is_empty());
+    assert!(coalescer.take_all() == vec![write(3, 6, 0, b"e")]);
+
+    // Only the first error is reported, once.
+    coalescer.fail(1, Error::Io);
+    coalescer.fail(1, Error::NoSpace);
+    assert!(coalescer.error(1) == Some(Error::Io));
+    assert!(coalescer.error(1) == None);
+  }
+
+  #[test]
+  fn test_window() {
+    let coalescer = Coalescer::new(Duration::from_millis(10));
+
+    coalescer.write(write(1, 5, 0, b"a"));
+    thread::sleep(Duration::from_millis(20));
+    assert!(coalescer.take_expired() == vec![write(1, 5, 0, b"a")]);
+  }
+}
@@ -0,0 +1,221 @@
+// Crash-recovery simulation, to check the log end to end.
+//
+// A workload runs against an in-memory disk that records every write it
+// is given, a step per transaction. The disk as a crash would leave it
+// after any of those writes, mid-commit and mid-install included, is then
+// rebuilt from the initial image, mounted and recovered, and the file
+// system must be just as it was after one of the steps: the last whose
+// commit point made it to the disk. Crashes during that recovery are
+// simulated the same way.
+
+#[cfg(test)]
+pub mod test {
+  use bitmap::Bitmap;
+  use buffer::BCACHE;
+  use disk::{BSIZE, Block, BlockDevice, DISK, Disk};
+  use fs::{FileType, LogHeader};
+  use inode::{ICACHE, UnlockedInode};
+  use logging::{LOGGING, Transaction};
+  use ops;
+  use std::sync::{Arc, Mutex};
+  use testfs;
+
+  type Writes = Arc<Mutex<Vec<(usize, Block)>>>;
+
+  // A disk recording the writes it is given.
+  struct Recorder {
+    disk: Disk,
+    writes: Writes,
+  }
+
+  impl BlockDevice for Recorder {
+    fn nblocks(&self) -> usize {
+      self.disk.nblocks()
+    }
+
+    fn read(&mut self, blockno: usize) -> Block {
+      self.disk.read(blockno)
+    }
+
+    fn write(&mut self, blockno: usize, data: &Block) {
+      self.disk.write(blockno, data);
+      self.writes.lock().unwrap().push((blockno, *data));
+    }
+  }
+
+  // What the file system holds, as far as a crash is concerned: the path
+  // and content of every file and directory, and how many blocks and
+  // inodes are free.
+  #[derive(PartialEq, Eq, Debug)]
+  pub struct State {
+    files: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    free_blocks: usize,
+    free_inodes: usize,
+  }
+
+  // Add what is under `dir`, at `path`, to `files`. Every entry must refer
+  // to an inode in use.
+  fn walk<'a>(
+    txn: &Transaction<'a>,
+    dir: &UnlockedInode,
+    path: &[u8],
+    files: &mut Vec<(Vec<u8>, Option<Vec<u8>>)>,
+  ) {
+    for (inode, name) in ops::readdir(txn, dir).unwrap() {
+      let name = ops::from_name(&name);
+      if name == b"." || name == b".." {
+        continue;
+      }
+      let mut child = path.to_vec();
+      child.push(b'/');
+      child.extend_from_slice(name);
+
+      let stat = ops::stat(txn, &inode);
+      assert!(stat.file_type != FileType::None);
+      if stat.file_type == FileType::Directory {
+        files.push((child.clone(), None));
+        walk(txn, &inode, &child, files);
+      } else {
+        let data = ops::read(txn, &inode, 0, stat.size as usize).unwrap();
+        files.push((child, Some(data)));
+      }
+    }
+  }
+
+  // The state of the file system, whose bitmap must match the blocks in
+  // use, see Bitmap::verify.
+  pub fn state() -> State {
+    let txn = LOGGING.new_txn();
+    let mut files = vec![];
+
+    let report = Bitmap::verify(&txn).unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    walk(&txn, &ops::root(), b"", &mut files);
+    files.sort();
+    State {
+      files,
+      free_blocks: Bitmap::count_free(&txn),
+      free_inodes: ICACHE.count_free(&txn),
+    }
+  }
+
+  fn copy(disk: &mut Disk) -> Vec<Block> {
+    (0..disk.nblocks()).map(|blockno| disk.read(blockno)).collect()
+  }
+
+  // Rebuild the disk as a crash after the first `n` of `writes` to `base`
+  // leaves it.
+  fn crashed(base: &[Block], writes: &[(usize, Block)], n: usize) -> Disk {
+    let mut disk = Disk::from(base.to_vec());
+
+    for &(blockno, ref data) in &writes[..n] {
+      disk.write(blockno, data);
+    }
+    disk
+  }
+
+  // Mount `disk`, recording the writes, with empty caches.
+  fn mount(disk: Disk) -> Writes {
+    let writes = Arc::new(Mutex::new(vec![]));
+
+    DISK.mount(Recorder {
+      disk,
+      writes: writes.clone(),
+    });
+    BCACHE.init();
+    {
+      let _txn = LOGGING.new_txn();
+      ICACHE.init();
+    }
+    writes
+  }
+
+  // Return true if `blockno`, written with `data`, is the commit point of
+  // a transaction, the first log header counting some blocks.
+  fn is_commit(blockno: usize, data: &Block) -> bool {
+    blockno == BCACHE.sb().log_start as usize &&
+      from_block!(data, LogHeader).n > 0
+  }
+
+  // Run `steps` on a fresh file system, each in a transaction of its own,
+  // then crash after every write they made in turn, and check that the
+  // file system recovers to what it was after the last step committed.
+  // Return how many crashes were simulated.
+  pub fn check(steps: &[&dyn Fn(&Transaction)]) -> usize {
+    let base = copy(&mut testfs::test::create().0);
+    let writes = mount(Disk::from(base.clone()));
+    LOGGING.init().unwrap();
+
+    // The state after each step that committed, and the write that did.
+    let mut states = vec![state()];
+    let mut commits = vec![];
+    for step in steps {
+      let before = writes.lock().unwrap().len();
+      step(&LOGGING.new_txn());
+      let writes = writes.lock().unwrap();
+      let commit = writes[before..]
+        .iter()
+        .rposition(|&(blockno, ref data)| is_commit(blockno, data));
+      if let Some(commit) = commit {
+        commits.push(before + commit);
+        states.push(state());
+      }
+    }
+    let writes = writes.lock().unwrap().clone();
+
+    let mut n = 0;
+    for i in 0..writes.len() + 1 {
+      // Committed once the header is written.
+      let done = commits.iter().filter(|&&commit| commit < i).count();
+      let recovery = mount(crashed(&base, &writes, i));
+
+      LOGGING.init().unwrap();
+      assert!(state() == states[done], "crash after write {}", i);
+      n += 1;
+
+      // And crashing while recovering recovers just the same.
+      let recovery = recovery.lock().unwrap().clone();
+      let before = copy(&mut crashed(&base, &writes, i));
+      for j in 0..recovery.len() {
+        mount(crashed(&before, &recovery, j));
+        LOGGING.init().unwrap();
+        assert!(state() == states[done], "crash in recovery {}/{}", i, j);
+        n += 1;
+      }
+    }
+    n
+  }
+
+  #[test]
+  fn test() {
+    let file = |path: &'static [u8], data: Vec<u8>| {
+      move |txn: &Transaction| {
+        let (dir, name) = ops::resolve_parent(txn, path).unwrap();
+        let inode = ops::create(txn, &dir, &name, FileType::File).unwrap();
+        ops::write(txn, &inode, 0, &data).unwrap();
+      }
+    };
+    let step1 = file(b"/a", vec![1; 3000]);
+    let step2 = |txn: &Transaction| {
+      let name = ops::to_name(b"d").unwrap();
+      ops::create(txn, &ops::root(), &name, FileType::Directory).unwrap();
+    };
+    let step3 = file(b"/d/b", vec![2; 2 * BSIZE + 1]);
+    let step4 = |txn: &Transaction| {
+      let inode = ops::resolve(txn, b"/a").unwrap();
+      ops::write(txn, &inode, 3000, &[3; 4000]).unwrap();
+    };
+    let step5 = |txn: &Transaction| {
+      let name = ops::to_name(b"a").unwrap();
+      ops::unlink(txn, &ops::root(), &name).unwrap();
+    };
+    let step6 = |txn: &Transaction| {
+      let (d, b) = (ops::resolve(txn, b"/d").unwrap(), ops::to_name(b"b"));
+      let c = ops::to_name(b"c").unwrap();
+      ops::rename(txn, &d, &b.unwrap(), &ops::root(), &c).unwrap();
+    };
+
+    let n = check(&[&step1, &step2, &step3, &step4, &step5, &step6]);
+    assert!(n > 100);
+  }
+}
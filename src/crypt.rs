@@ -0,0 +1,150 @@
+// Per-directory encryption of file contents, in the spirit of fscrypt.
+//
+// An empty directory is marked IENCRYPT together with the id of a key, and
+// everything created below it inherits both. The content of such a file is
+// xored with a ChaCha20 keystream of the key and the file's nonce at the
+// same offset, so that it can be read and written at any offset and its
+// size is unchanged. Names are left alone, as the 14 bytes of a dirent
+// leave no room for a ciphertext.
+//
+// Keys are only kept in memory and provided at runtime, without the key of
+// a file its content can be neither read nor written. The nonce of a file
+// is its inode number when it is created, and inode numbers are recycled,
+// so this protects an image at rest rather than against an attacker who
+// sees several versions of it.
+
+use error::{Error, Result};
+use fs::{DiskInode, FileType, IENCRYPT};
+use inode::{ICACHE, UnlockedInode};
+use logging::Transaction;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use util::chacha20;
+use util::sha256::sha256;
+
+pub type Key = chacha20::Key;
+
+lazy_static! {
+  static ref KEYS: Mutex<HashMap<u8, Key>> = Mutex::new(HashMap::new());
+}
+
+pub fn key_id(key: &Key) -> u8 {
+  sha256(key)[0]
+}
+
+// Make `key` available, and return its id.
+pub fn add_key(key: Key) -> Result<u8> {
+  let id = key_id(&key);
+  let mut keys = KEYS.lock().unwrap();
+
+  match keys.get(&id) {
+    Some(other) if *other != key => return Err(Error::Exists),
+    _ => (),
+  }
+  keys.insert(id, key);
+  Ok(id)
+}
+
+// Forget the key with id `id`, return false if there was none.
+pub fn remove_key(id: u8) -> bool {
+  KEYS.lock().unwrap().remove(&id).is_some()
+}
+
+// Return true if the content of `inode` is accessible.
+pub fn has_key(inode: &DiskInode) -> bool {
+  !inode.is_encrypted() || KEYS.lock().unwrap().contains_key(&inode.key_id())
+}
+
+// Let `inode` numbered `inum`, which is just created in directory `parent`,
+// follow the encryption policy of `parent`.
+pub fn inherit(parent: &DiskInode, inode: &mut DiskInode, inum: usize) {
+  if parent.is_encrypted() {
+    inode.flags |= IENCRYPT | (parent.key_id() as u16) << 8;
+    inode.nonce = inum as u16;
+  }
+}
+
+// Mark the empty directory `dir` to be encrypted with `key`.
+pub fn encrypt_dir<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  key: Key,
+) -> Result<()> {
+  let mut dinode = ICACHE.lock(txn, dir);
+
+  if dinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  if dinode.is_encrypted() {
+    return Err(Error::Exists);
+  }
+  if !dinode.as_directory().is_empty(txn)? {
+    return Err(Error::NotEmpty);
+  }
+
+  let id = add_key(key)?;
+  dinode.flags |= IENCRYPT | (id as u16) << 8;
+  dinode.update(txn);
+  Ok(())
+}
+
+// En- or decrypt `data` at `offset` of `inode`, if it is an encrypted file.
+// Return false if its key is missing.
+pub fn apply(inode: &DiskInode, offset: usize, data: &mut [u8]) -> bool {
+  if !inode.is_encrypted() || inode.file_type != FileType::File {
+    return true;
+  }
+  let key = match KEYS.lock().unwrap().get(&inode.key_id()) {
+    Some(key) => *key,
+    None => return false,
+  };
+  let mut nonce = [0; 12];
+
+  nonce[0] = inode.nonce as u8;
+  nonce[1] = (inode.nonce >> 8) as u8;
+  chacha20::xor(&key, &nonce, offset, data);
+  true
+}
+
+#[cfg(test)]
+mod test {
+  use crypt;
+  use error::Error;
+  use fs::FileType;
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use ops;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+
+    let key = [7; 32];
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let d = ops::to_name(b"d").unwrap();
+    let f = ops::to_name(b"f").unwrap();
+
+    let dir = ops::create(&txn, &root, &d, FileType::Directory).unwrap();
+    crypt::encrypt_dir(&txn, &dir, key).unwrap();
+    assert!(crypt::encrypt_dir(&txn, &dir, key).err() ==
+      Some(Error::Exists));
+    let file = ops::create(&txn, &dir, &f, FileType::File).unwrap();
+    ops::write(&txn, &file, 0, b"secret").unwrap();
+    assert!(ops::read(&txn, &file, 2, 4).unwrap() == b"cret");
+
+    // The disk only holds ciphertext.
+    let blockno = ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap()[0];
+    assert!(&txn.read(blockno).unwrap().data[..6] != b"secret");
+
+    crypt::remove_key(crypt::key_id(&key));
+    assert!(ops::read(&txn, &file, 0, 6).err() == Some(Error::NoKey));
+    assert!(ops::write(&txn, &file, 0, b"x").err() == Some(Error::NoKey));
+    crypt::add_key(key).unwrap();
+    assert!(ops::read(&txn, &file, 0, 6).unwrap() == b"secret");
+  }
+}
@@ -0,0 +1,176 @@
+// Offline deduplication of file data blocks.
+//
+// Every data block of every file is hashed, and a block with the same
+// content as one seen before is replaced by a reference to that one, using
+// the block reference counts of refcount.rs, so that writes copy it again.
+// Like snapshots, this takes many transactions and the tree must not be
+// modified meanwhile.
+
+use bitmap::Bitmap;
+use buffer::BCACHE;
+use disk::BSIZE;
+use error::{Error, Result};
+use fs::{DiskInode, FileType, IPB, MAXBLOCKS};
+use inode::ICACHE;
+use logging::LOGGING;
+use refcount;
+use std::collections::HashMap;
+use std::mem::transmute;
+use util::sha256::{Digest, sha256};
+
+pub struct Report {
+  // Number of data blocks looked at.
+  pub scanned: usize,
+  // Number of references redirected to an identical block.
+  pub shared: usize,
+  // Number of blocks freed as a result.
+  pub freed: usize,
+}
+
+// Return the inode numbers of all files.
+fn files() -> Vec<usize> {
+  let txn = LOGGING.new_txn();
+  let sb = BCACHE.sb();
+  let mut result = vec![];
+
+  for inum in 1..sb.ninodes as usize {
+    let buf = txn.read(sb.iblock(inum)).unwrap();
+    let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
+
+    if inodes[inum % IPB].file_type == FileType::File &&
+      inum != sb.refino as usize
+    {
+      result.push(inum);
+    }
+  }
+  result
+}
+
+pub fn run() -> Result<Report> {
+  if !refcount::supported() {
+    return Err(Error::Unsupported);
+  }
+
+  let mut seen: HashMap<Digest, usize> = HashMap::new();
+  let mut report = Report {
+    scanned: 0,
+    shared: 0,
+    freed: 0,
+  };
+
+  for inum in files() {
+    for n in 0..MAXBLOCKS {
+      // One transaction per block, as sharing a block touches the inode,
+      // its indirect block, reference counts and the bitmap.
+      let txn = LOGGING.new_txn();
+      let inode = ICACHE.get(inum).unwrap();
+      let mut dinode = ICACHE.lock(&txn, &inode);
+
+      // No block is left past the end, see Inode::truncate.
+      if n * BSIZE >= dinode.size as usize {
+        break;
+      }
+      let blockno = match dinode.mapped_block(&txn, n)? {
+        Some(blockno) => blockno,
+        None => continue,
+      };
+      let data = txn.read(blockno).unwrap().data;
+
+      report.scanned += 1;
+      let digest = sha256(&data);
+      let first = *seen.entry(digest).or_insert(blockno);
+      if first == blockno || txn.read(first).unwrap().data[..] != data[..] {
+        continue;
+      }
+      // Its count is full, the next copies share this one instead.
+      if refcount::get(&txn, first) == refcount::MAXREFS {
+        seen.insert(digest, blockno);
+        continue;
+      }
+
+      refcount::inc(&txn, first)?;
+      dinode.set_nth_block(&txn, n, first);
+      if refcount::release(&txn, blockno) {
+        Bitmap::free(&txn, blockno);
+        report.freed += 1;
+      }
+      report.shared += 1;
+    }
+  }
+  Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+  use dedup;
+  use disk::BSIZE;
+  use error::Error;
+  use fs::FileType;
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use ops;
+  use refcount;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+
+    {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let template = [42; 1024];
+
+      for name in &[b"a", b"b", b"c"] {
+        let name = ops::to_name(*name).unwrap();
+        let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+        ops::write(&txn, &file, 0, &template).unwrap();
+      }
+    }
+
+    let report = dedup::run().unwrap();
+    assert!(report.scanned == 6);
+    assert!(report.shared == 5);
+    assert!(report.freed == 5);
+    assert!(dedup::run().unwrap().shared == 0);
+
+    let txn = LOGGING.new_txn();
+    let a = ops::resolve(&txn, b"/a").unwrap();
+    let b = ops::resolve(&txn, b"/b").unwrap();
+    ops::write(&txn, &a, 600, b"x").unwrap();
+    assert!(ops::read(&txn, &a, 599, 3).unwrap() == [42, b'x', 42]);
+    assert!(ops::read(&txn, &b, 599, 3).unwrap() == [42, 42, 42]);
+  }
+
+  #[test]
+  fn test_full() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    for name in &[b"a", b"b", b"c"] {
+      let name = ops::to_name(*name).unwrap();
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+      ops::write(&txn, &file, 0, &[42; BSIZE]).unwrap();
+    }
+
+    // The count of the block of a goes up to the limit, and no further.
+    let a = ops::resolve(&txn, b"/a").unwrap();
+    let first = ICACHE.lock(&txn, &a).mapped_block(&txn, 0).unwrap().unwrap();
+    for _ in 0..refcount::MAXREFS {
+      refcount::inc(&txn, first).unwrap();
+    }
+    assert!(refcount::get(&txn, first) == refcount::MAXREFS);
+    assert!(refcount::inc(&txn, first).err() == Some(Error::TooManyLinks));
+    drop(txn);
+
+    // Then the block of b is shared in its stead.
+    let report = dedup::run().unwrap();
+    assert!(report.scanned == 3 && report.shared == 1);
+    let txn = LOGGING.new_txn();
+    let b = ops::resolve(&txn, b"/b").unwrap();
+    let c = ops::resolve(&txn, b"/c").unwrap();
+    let block = |file| ICACHE.lock(&txn, file).mapped_block(&txn, 0).unwrap();
+    assert!(block(&b) == block(&c) && block(&b) != Some(first));
+  }
+}
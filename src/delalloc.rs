@@ -0,0 +1,357 @@
+// Delayed allocation of the blocks written to files, along with a
+// write-back cache, see mount.rs.
+//
+// Once started, the new direct blocks of regular files are kept in memory
+// as they are written, rather than allocated, and only allocated as the
+// transactions that wrote them commit, the adjacent blocks of a file in a
+// row, see Bitmap::alloc_contiguous. A file removed or truncated before
+// then never touches the bitmap. The blocks are reserved meanwhile, so that
+// a write rather than the commit fails once the file system is full, see
+// Bitmap::has_room.
+//
+// A commit allocates as many as the log has room for, and leaves the rest
+// to the next one, and `sync` all of them, e.g. for fsync. A crash in
+// between leaves a hole where the rest are, in files as large as their
+// last write, as the size goes with the transaction.
+
+use bitmap::Bitmap;
+use disk::{BSIZE, Block};
+use inode::{ICACHE, UnlockedInode};
+use logging::{LOGGING, MAXOPBLOCKS, Transaction};
+use memory::{Account, MEMORY};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Blocks kept at most, past which they are allocated as they are written,
+// as a commit takes about as many along in the log.
+const MAXPENDING: usize = 64;
+
+struct State {
+  // Blocks are delayed, see `start`.
+  enabled: bool,
+  // The delayed blocks of each inode, by their number in the file.
+  inodes: BTreeMap<usize, BTreeMap<usize, Block>>,
+}
+
+lazy_static! {
+  static ref STATE: Mutex<State> = Mutex::new(State {
+    enabled: false,
+    inodes: BTreeMap::new(),
+  });
+}
+
+// The blocks delayed, so many reserved, read without the lock of the state
+// as blocks are allocated.
+static RESERVED: AtomicUsize = AtomicUsize::new(0);
+
+// Delay the allocation of blocks written from now on.
+pub fn start() {
+  STATE.lock().unwrap().enabled = true;
+}
+
+// Allocate blocks as they are written again, and those delayed now, e.g.
+// before unmounting.
+pub fn stop() {
+  STATE.lock().unwrap().enabled = false;
+  sync();
+}
+
+pub fn is_started() -> bool {
+  STATE.lock().unwrap().enabled
+}
+
+// Forget the blocks delayed, of a file system no longer mounted.
+pub fn clear() {
+  let mut state = STATE.lock().unwrap();
+
+  MEMORY.release(Account::Log, RESERVED.swap(0, Ordering::SeqCst) * BSIZE);
+  state.inodes.clear();
+}
+
+// Return how many blocks are delayed, and reserved.
+pub fn reserved() -> usize {
+  RESERVED.load(Ordering::SeqCst)
+}
+
+// Write `data` at `from` into block `bn` of inode `inum`, delayed unless it
+// is already allocated. Return false, with nothing written, if there is no
+// room for it, which is then allocated at once.
+pub fn write(inum: usize, bn: usize, from: usize, data: &[u8]) -> bool {
+  let mut state = STATE.lock().unwrap();
+
+  if !state.inodes.get(&inum).map_or(false, |blocks| blocks.contains_key(&bn))
+  {
+    if !state.enabled || reserved() >= MAXPENDING || !Bitmap::has_room(1) {
+      return false;
+    }
+    RESERVED.fetch_add(1, Ordering::SeqCst);
+    MEMORY.charge(Account::Log, BSIZE);
+  }
+  // The rest reads as zeros, as a hole does.
+  let block = state
+    .inodes
+    .entry(inum)
+    .or_insert_with(BTreeMap::new)
+    .entry(bn)
+    .or_insert([0; BSIZE]);
+  block[from..from + data.len()].copy_from_slice(data);
+  true
+}
+
+// Return block `bn` of inode `inum`, if it is delayed.
+pub fn read(inum: usize, bn: usize) -> Option<Block> {
+  let state = STATE.lock().unwrap();
+
+  state.inodes.get(&inum).and_then(|blocks| blocks.get(&bn).cloned())
+}
+
+pub fn has(inum: usize) -> bool {
+  STATE.lock().unwrap().inodes.contains_key(&inum)
+}
+
+// Drop the delayed blocks of inode `inum` from the `first`th on, as it is
+// truncated or freed.
+pub fn discard(inum: usize, first: usize) {
+  let mut state = STATE.lock().unwrap();
+  let n = match state.inodes.get_mut(&inum) {
+    Some(blocks) => blocks.split_off(&first).len(),
+    None => return,
+  };
+
+  if state.inodes[&inum].is_empty() {
+    state.inodes.remove(&inum);
+  }
+  RESERVED.fetch_sub(n, Ordering::SeqCst);
+  MEMORY.release(Account::Log, n * BSIZE);
+}
+
+// Drop the delayed block `bn` of inode `inum`, if any, as something else
+// takes its place in the file, e.g. a shared block, see Inode::share_block.
+pub fn discard_block(inum: usize, bn: usize) {
+  let mut state = STATE.lock().unwrap();
+  let removed = match state.inodes.get_mut(&inum) {
+    Some(blocks) => blocks.remove(&bn).is_some(),
+    None => return,
+  };
+
+  if state.inodes[&inum].is_empty() {
+    state.inodes.remove(&inum);
+  }
+  if removed {
+    RESERVED.fetch_sub(1, Ordering::SeqCst);
+    MEMORY.release(Account::Log, BSIZE);
+  }
+}
+
+// Take at most `max` of the delayed blocks of inode `inum`, the first ones,
+// to be allocated, their reservation with them.
+pub fn take(inum: usize, max: usize) -> Vec<(usize, Block)> {
+  let mut state = STATE.lock().unwrap();
+  let blocks = match state.inodes.get_mut(&inum) {
+    Some(blocks) => blocks,
+    None => return vec![],
+  };
+  let bns: Vec<usize> = blocks.keys().take(max).cloned().collect();
+  let taken: Vec<_> = bns
+    .into_iter()
+    .map(|bn| (bn, blocks.remove(&bn).unwrap()))
+    .collect();
+
+  if blocks.is_empty() {
+    state.inodes.remove(&inum);
+  }
+  RESERVED.fetch_sub(taken.len(), Ordering::SeqCst);
+  MEMORY.release(Account::Log, taken.len() * BSIZE);
+  taken
+}
+
+// Delay `blocks` of inode `inum` again, taken but not allocated for lack of
+// room, maybe past MAXPENDING, as they cannot be dropped.
+pub fn restore(inum: usize, blocks: Vec<(usize, Block)>) {
+  let mut state = STATE.lock().unwrap();
+
+  RESERVED.fetch_add(blocks.len(), Ordering::SeqCst);
+  MEMORY.charge(Account::Log, blocks.len() * BSIZE);
+  state
+    .inodes
+    .entry(inum)
+    .or_insert_with(BTreeMap::new)
+    .extend(blocks);
+}
+
+// Allocate the delayed blocks, of as many inodes as `room` blocks written in
+// `txn` allow. Those locked meanwhile are left, e.g. by the thread that
+// commits. Return the inodes, to be dropped once the transaction is over,
+// see Logging::commit.
+pub fn flush<'a>(txn: &Transaction<'a>, mut room: usize) -> Vec<UnlockedInode> {
+  let inums: Vec<usize> = {
+    let state = STATE.lock().unwrap();
+    state.inodes.keys().cloned().collect()
+  };
+  let mut inodes = vec![];
+
+  for inum in inums {
+    // Each block with its bitmap block, and the inode block.
+    if room < 3 {
+      break;
+    }
+    let inode = match ICACHE.get(inum) {
+      Some(inode) => inode,
+      None => break,
+    };
+    let n = ICACHE.alloc_delayed(txn, &inode, (room - 1) / 2);
+
+    room -= 2 * n + 1;
+    inodes.push(inode);
+  }
+  inodes
+}
+
+// Allocate all the delayed blocks, in transactions of their own. It must
+// not run within a transaction.
+pub fn sync() {
+  while reserved() > 0 {
+    let before = reserved();
+    {
+      let txn = LOGGING.new_txn();
+      let _inodes = flush(&txn, MAXOPBLOCKS);
+    }
+    // Those left have no room, or their inodes are held locked.
+    if reserved() == before {
+      break;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use bitmap::Bitmap;
+  use delalloc;
+  use disk::BSIZE;
+  use error::Error;
+  use fs::{self, FileType};
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use ops;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+    delalloc::start();
+
+    // A file removed before the commit never touches the bitmap, but its
+    // blocks are reserved meanwhile.
+    let free = fs::usage(&LOGGING.new_txn()).free_blocks;
+    let name = ops::to_name(b"f").unwrap();
+    {
+      let txn = LOGGING.new_txn();
+      let file = ops::create(&txn, &ops::root(), &name, FileType::File);
+      let file = file.unwrap();
+      let data: Vec<u8> = (0..3 * BSIZE).map(|i| i as u8).collect();
+
+      assert!(ops::write(&txn, &file, 0, &data) == Ok(data.len()));
+      assert!(ops::read(&txn, &file, 0, data.len()).unwrap() == data);
+      assert!(ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap().is_empty());
+      assert!(fs::usage(&txn).free_blocks == free - 3);
+      assert!(Bitmap::verify(&txn).unwrap().is_ok());
+      ops::unlink(&txn, &ops::root(), &name).unwrap();
+    }
+    assert!(delalloc::reserved() == 0);
+    assert!(fs::usage(&LOGGING.new_txn()).free_blocks == free);
+
+    // One kept is allocated in a row as the transaction commits.
+    let file = {
+      let txn = LOGGING.new_txn();
+      let file = ops::create(&txn, &ops::root(), &name, FileType::File);
+      let file = file.unwrap();
+
+      ops::write(&txn, &file, 0, &[1; 2 * BSIZE]).unwrap();
+      ops::write(&txn, &file, 2 * BSIZE, &[2; BSIZE + 1]).unwrap();
+      // Truncated, the block past the end goes, and the rest of the last.
+      ops::truncate(&txn, &file, 2 * BSIZE + 10).unwrap();
+      assert!(delalloc::reserved() == 3);
+      file
+    };
+    assert!(delalloc::reserved() == 0);
+    let txn = LOGGING.new_txn();
+    let blocks = ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap();
+    assert!(blocks.len() == 3 && blocks[2] == blocks[0] + 2);
+    assert!(fs::usage(&txn).free_blocks == free - 3);
+    assert!(Bitmap::verify(&txn).unwrap().is_ok());
+    let data = ops::read(&txn, &file, 2 * BSIZE - 1, 11).unwrap();
+    assert!(data[0] == 1 && data[1..] == [2; 10]);
+    ops::truncate(&txn, &file, 3 * BSIZE).unwrap();
+    assert!(ops::read(&txn, &file, 2 * BSIZE + 10, 1).unwrap() == [0]);
+    drop(txn);
+
+    delalloc::stop();
+  }
+
+  #[test]
+  fn test_copy_range() {
+    testfs::test::mount();
+    delalloc::start();
+
+    // A block shared over one delayed takes its place, at commit too.
+    let create = |name: &[u8], data: &[u8]| {
+      let txn = LOGGING.new_txn();
+      let name = ops::to_name(name).unwrap();
+      let file = ops::create(&txn, &ops::root(), &name, FileType::File);
+      let file = file.unwrap();
+      ops::write(&txn, &file, 0, data).unwrap();
+      file
+    };
+    let src = create(b"a", &[1; BSIZE]);
+    {
+      let txn = LOGGING.new_txn();
+      let name = ops::to_name(b"b").unwrap();
+      let dst = ops::create(&txn, &ops::root(), &name, FileType::File);
+      let dst = dst.unwrap();
+      ops::write(&txn, &dst, 0, &[2; BSIZE]).unwrap();
+      assert!(ops::copy_range(&txn, &src, 0, &dst, 0, BSIZE) == Ok(BSIZE));
+      assert!(ops::read(&txn, &dst, 0, BSIZE).unwrap() == [1; BSIZE]);
+      assert!(delalloc::reserved() == 0);
+    }
+    let txn = LOGGING.new_txn();
+    let dst = ops::resolve(&txn, b"/b").unwrap();
+    assert!(ops::read(&txn, &dst, 0, BSIZE).unwrap() == [1; BSIZE]);
+    let shared = |file| ICACHE.lock(&txn, file).addrs[0];
+    assert!(shared(&src) == shared(&dst));
+    assert!(Bitmap::verify(&txn).unwrap().is_ok());
+    drop(txn);
+
+    delalloc::stop();
+  }
+
+  #[test]
+  fn test_no_space() {
+    testfs::test::mount();
+    delalloc::start();
+
+    // Blocks reserved are not allocated to others, and writes fail rather
+    // than the commit.
+    let name = ops::to_name(b"f").unwrap();
+    let file = {
+      let txn = LOGGING.new_txn();
+      ops::create(&txn, &ops::root(), &name, FileType::File).unwrap()
+    };
+    while fs::usage(&LOGGING.new_txn()).free_blocks > 5 {
+      Bitmap::alloc(&LOGGING.new_txn()).unwrap();
+    }
+    {
+      let txn = LOGGING.new_txn();
+      let data = [1; 6 * BSIZE];
+      assert!(ops::write(&txn, &file, 0, &data) == Ok(5 * BSIZE));
+      assert!(Bitmap::alloc(&txn) == Err(Error::NoSpace));
+    }
+    let txn = LOGGING.new_txn();
+    assert!(delalloc::reserved() == 0);
+    assert!(ops::read(&txn, &file, 0, 6 * BSIZE).unwrap() == [1; 5 * BSIZE]);
+    assert!(fs::usage(&txn).free_blocks == 0);
+    drop(txn);
+
+    delalloc::stop();
+  }
+}
@@ -1,41 +1,246 @@
-use std::path::Path;
-use std::sync::{Mutex, mpsc};
-use std::fs::File;
-use std::io::Read;
+use libc::{ENXIO, EWOULDBLOCK, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE,
+           LOCK_EX, LOCK_NB, LOCK_SH, SEEK_DATA, SEEK_HOLE, fallocate, flock,
+           lseek, off_t};
+use std::cmp::{max, min};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, RwLock, mpsc};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
 use std::thread;
+use std::time::Duration;
 
 // Size of each block.
 pub const BSIZE: usize = 512;
 
 pub type Block = [u8; BSIZE];
 
+// A device of `nblocks` blocks that `DiskService` can serve.
+pub trait BlockDevice: Send + Sync {
+  fn nblocks(&self) -> usize;
+  fn read(&mut self, blockno: usize) -> Block;
+  fn write(&mut self, blockno: usize, data: &Block);
+
+  // Read `blockno` alongside other such reads, if the device can do so
+  // without changing, or None to have it read by `read` alone.
+  fn read_shared(&self, _blockno: usize) -> Option<Block> {
+    None
+  }
+
+  // Make every write so far durable.
+  fn flush(&mut self) {}
+
+  // Drop `blockno`, no longer in use, so that the device can reclaim its
+  // space, as TRIM does. It reads as zeros since, or as it was if the
+  // device cannot, and only once the writes before are durable, as they
+  // freed it.
+  fn discard(&mut self, _blockno: usize) {}
+}
+
+// In-memory disk. One loaded from an image file is saved back to it when
+// flushed.
 pub struct Disk {
   blocks: Vec<Block>,
+  path: Option<PathBuf>,
+  // Written to since it was loaded or last saved.
+  dirty: bool,
+  // Blocks that could not be read from the image file, zeroed.
+  unreadable: Vec<usize>,
+  // Held on the image file, see `lock`.
+  lock: Option<File>,
+}
+
+// Disk served from its image file rather than from memory, so that it
+// needs not fit there, read and written a block at a time. Writes may be
+// held back in a small cache, and written out in the order they came in,
+// consecutive blocks together, so that a crash of the process leaves the
+// image as if it stopped after any of them, as the log expects.
+pub struct FileDisk {
+  file: File,
+  nblocks: usize,
+  // The writes held back, at most `capacity`, a block once at most.
+  cache: Vec<(usize, Block)>,
+  capacity: usize,
+  // Nothing written since the last flush, see `discard`.
+  synced: bool,
+  // Held on the image file, see `lock`.
+  _lock: Option<File>,
 }
 
+// How many threads serve the requests to a mounted disk.
+const NWORKERS: usize = 4;
+
+// What a request returns, or how it panicked, for the caller to panic the
+// same way.
+type Reply = thread::Result<Option<Block>>;
+
 enum Request {
   Read {
-    reply: mpsc::Sender<Block>,
+    reply: mpsc::Sender<Reply>,
     blockno: usize,
   },
   Write {
-    reply: mpsc::Sender<()>,
+    reply: mpsc::Sender<Reply>,
     blockno: usize,
     data: Block,
   },
-  Exit { reply: mpsc::Sender<Disk> },
+  Flush { reply: mpsc::Sender<Reply> },
+  Discard {
+    reply: mpsc::Sender<Reply>,
+    blockno: usize,
+  },
+}
+
+// A mounted disk, shared by its workers. Reads that the disk can do
+// shared proceed in parallel, but a write, or a flush, has it alone, so
+// that it is done when the caller returns and every request after sees it,
+// and the log writes out a commit in the order it meant to.
+struct Service {
+  requests: mpsc::Sender<Request>,
+  workers: Vec<thread::JoinHandle<()>>,
+  disk: Arc<RwLock<Box<dyn BlockDevice>>>,
+}
+
+struct Flusher {
+  interval: Option<Duration>,
+  // A thread flushes every `interval`.
+  running: bool,
 }
 
 pub struct DiskService {
-  channel: Mutex<Option<mpsc::Sender<Request>>>,
+  service: Mutex<Option<Service>>,
+  flusher: Mutex<Flusher>,
+  // Notified when the interval of the flusher changes.
+  retuned: Condvar,
+  // Nothing is written to the disk mounted, see `set_read_only`.
+  read_only: AtomicBool,
 }
 
 lazy_static! {
   pub static ref DISK: DiskService = DiskService {
-    channel: Mutex::new(None)
+    service: Mutex::new(None),
+    flusher: Mutex::new(Flusher {
+      interval: None,
+      running: false,
+    }),
+    retuned: Condvar::new(),
+    read_only: AtomicBool::new(false),
   };
 }
 
+thread_local! {
+  // Where the workers reply to the requests of this thread, rather than to
+  // a channel made for each.
+  static REPLIES: (mpsc::Sender<Reply>, mpsc::Receiver<Reply>) =
+    mpsc::channel();
+}
+
+// Take images in use anyway, see `lock`.
+static FORCE: AtomicBool = AtomicBool::new(false);
+
+// Interval for `start_flusher` of the servers, XV6FS_SYNC_INTERVAL seconds
+// and 5 by default, or None if that is 0.
+pub fn sync_interval() -> Option<Duration> {
+  let secs = env::var("XV6FS_SYNC_INTERVAL")
+    .ok()
+    .and_then(|s| s.parse().ok())
+    .unwrap_or(5);
+
+  if secs == 0 {
+    None
+  } else {
+    Some(Duration::from_secs(secs))
+  }
+}
+
+// Make `lock` go ahead on images in use, when a tool is run with --force.
+pub fn set_force(force: bool) {
+  FORCE.store(force, Ordering::Relaxed);
+}
+
+// Remove --force from the arguments of a tool, and `set_force` if it was
+// there.
+pub fn parse_force(args: Vec<String>) -> Vec<String> {
+  let (force, args): (Vec<String>, Vec<String>) =
+    args.into_iter().partition(|arg| arg == "--force");
+
+  set_force(!force.is_empty());
+  args
+}
+
+// Take the advisory lock of the image at `path`, shared if `shared`, for as
+// long as the file returned is open, so that tools do not write an image
+// the daemon or another tool is using, nor read one being written. The
+// lock is taken on `<path>.lock`, as images are replaced when saved. Fail
+// with WouldBlock if it is held the other way, unless forced. An image
+// whose lock file cannot be created, e.g. in a read-only directory, goes
+// unlocked.
+pub fn lock<P: AsRef<Path>>(path: P, shared: bool) -> io::Result<Option<File>> {
+  let mut lock_path = path.as_ref().as_os_str().to_os_string();
+
+  lock_path.push(".lock");
+  let f = match OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .open(&lock_path)
+  {
+    Ok(f) => f,
+    Err(e) => {
+      warn!("cannot lock {}: {}", path.as_ref().display(), e);
+      return Ok(None);
+    },
+  };
+  let how = if shared { LOCK_SH } else { LOCK_EX };
+
+  if unsafe { flock(f.as_raw_fd(), how | LOCK_NB) } == 0 {
+    return Ok(Some(f));
+  }
+  let e = io::Error::last_os_error();
+  if e.raw_os_error() != Some(EWOULDBLOCK) {
+    return Err(e);
+  }
+  if FORCE.load(Ordering::Relaxed) {
+    warn!("{} is in use, going ahead", path.as_ref().display());
+    return Ok(None);
+  }
+  Err(io::Error::new(
+    io::ErrorKind::WouldBlock,
+    "image in use, e.g. mounted (--force to go ahead)",
+  ))
+}
+
+// The ranges of bytes of `f`, `size` long, that may hold data, the others
+// being holes of a sparse file, which read as zeros. All of them if the
+// file system does not tell.
+fn data_ranges(f: &File, size: usize) -> Vec<(usize, usize)> {
+  let fd = f.as_raw_fd();
+  let mut ranges = vec![];
+  let mut offset = 0;
+
+  while offset < size {
+    let start = unsafe { lseek(fd, offset as off_t, SEEK_DATA) };
+    if start < 0 {
+      // None past `offset`.
+      if io::Error::last_os_error().raw_os_error() == Some(ENXIO) {
+        break;
+      }
+      return vec![(0, size)];
+    }
+    let end = unsafe { lseek(fd, start, SEEK_HOLE) };
+    if end < 0 {
+      return vec![(0, size)];
+    }
+    ranges.push((start as usize, end as usize));
+    offset = end as usize;
+  }
+  ranges
+}
+
 impl Disk {
   pub fn new(nblocks: usize) -> Self {
     let mut blocks = Vec::with_capacity(nblocks);
@@ -43,139 +248,562 @@ impl Disk {
     for _ in 0..nblocks {
       blocks.push([0; BSIZE]);
     }
-    Disk { blocks }
+    Disk::from(blocks)
   }
 
   pub fn from(blocks: Vec<Block>) -> Self {
-    Disk { blocks }
+    Disk {
+      blocks,
+      path: None,
+      dirty: false,
+      unreadable: vec![],
+      lock: None,
+    }
   }
 
-  pub fn load<P: AsRef<Path>>(path: P) -> Option<Self> {
-    let mut f = File::open(path).unwrap();
-    let size = f.metadata().unwrap().len() as usize;
+  // Load the image at `path`, locked for writing, see `lock`.
+  pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    Disk::open(path, false)
+  }
+
+  // Load the image at `path` only to read it, which others may do as well.
+  pub fn load_shared<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    Disk::open(path, true)
+  }
+
+  fn open<P: AsRef<Path>>(path: P, shared: bool) -> io::Result<Self> {
+    let lock = lock(&path, shared)?;
+    let f = File::open(&path)?;
+    let size = f.metadata()?.len() as usize;
 
     if size % BSIZE != 0 {
-      return None;
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "not a multiple of the block size",
+      ));
     }
 
     let nblocks = size / BSIZE;
-    let mut blocks = Vec::with_capacity(nblocks);
-    for _ in 0..nblocks {
-      let mut buf: [u8; BSIZE] = [0; BSIZE];
-      f.read(&mut buf).unwrap();
-      blocks.push(buf);
+    let mut blocks = vec![[0; BSIZE]; nblocks];
+    let mut unreadable = vec![];
+    let mut next = 0;
+    // Holes are left zeroed.
+    for (start, end) in data_ranges(&f, size) {
+      let end = min((end + BSIZE - 1) / BSIZE, nblocks);
+
+      for i in max(start / BSIZE, next)..end {
+        // A block of flaky storage failing to read is zeroed, the others
+        // are still worth reading.
+        if let Err(e) = f.read_exact_at(&mut blocks[i], (i * BSIZE) as u64) {
+          warn!("cannot read block {}: {}", i, e);
+          blocks[i] = [0; BSIZE];
+          unreadable.push(i);
+        }
+      }
+      next = max(next, end);
+    }
+
+    Ok(Disk {
+      blocks,
+      path: Some(path.as_ref().to_path_buf()),
+      dirty: false,
+      unreadable,
+      lock,
+    })
+  }
+
+  // Take the lock of an image loaded shared for writing as well. The shared
+  // lock may be lost if that fails.
+  pub fn lock_exclusive(&mut self) -> io::Result<()> {
+    let fd = match self.lock {
+      Some(ref f) => f.as_raw_fd(),
+      None => return Ok(()),
+    };
+
+    if unsafe { flock(fd, LOCK_EX | LOCK_NB) } == 0 {
+      return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(EWOULDBLOCK) &&
+      FORCE.load(Ordering::Relaxed)
+    {
+      return Ok(());
+    }
+    Err(e)
+  }
+
+  // Return the blocks that could not be read when it was loaded, see
+  // badblock.rs.
+  pub fn unreadable(&self) -> &[usize] {
+    &self.unreadable
+  }
+
+  // Return the image file it was loaded from, if any.
+  pub fn path(&self) -> Option<&Path> {
+    self.path.as_ref().map(|path| path.as_path())
+  }
+
+  // Write the image to `path`, through a temporary file renamed over it, so
+  // that the file is either the old image or the new one.
+  pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_os_string();
+
+    tmp.push(".tmp");
+    {
+      let f = File::create(&tmp)?;
+      // Zeroed blocks are left as holes, see `data_ranges`.
+      f.set_len((self.blocks.len() * BSIZE) as u64)?;
+      for (i, block) in self.blocks.iter().enumerate() {
+        if block.iter().any(|&x| x != 0) {
+          f.write_all_at(block, (i * BSIZE) as u64)?;
+        }
+      }
+      f.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+  }
+}
+
+impl BlockDevice for Disk {
+  fn nblocks(&self) -> usize {
+    self.blocks.len()
+  }
+
+  fn read(&mut self, blockno: usize) -> Block {
+    self.blocks[blockno]
+  }
+
+  fn read_shared(&self, blockno: usize) -> Option<Block> {
+    Some(self.blocks[blockno])
+  }
+
+  fn write(&mut self, blockno: usize, data: &Block) {
+    self.blocks[blockno] = *data;
+    self.dirty = true;
+  }
+
+  // Left as a hole when saved, see `save`.
+  fn discard(&mut self, blockno: usize) {
+    self.write(blockno, &[0; BSIZE]);
+  }
+
+  fn flush(&mut self) {
+    if !self.dirty {
+      return;
+    }
+    if let Some(ref path) = self.path {
+      if let Err(e) = self.save(path) {
+        warn!("cannot save {}: {}", path.display(), e);
+        return;
+      }
+    }
+    self.dirty = false;
+  }
+}
+
+impl FileDisk {
+  // Open the image at `path`, locked for writing, see `lock`, holding back
+  // at most `capacity` writes, none if 0.
+  pub fn open<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<Self> {
+    let lock = lock(&path, false)?;
+    let file = OpenOptions::new().read(true).write(true).open(&path)?;
+    let size = file.metadata()?.len() as usize;
+
+    if size % BSIZE != 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "not a multiple of the block size",
+      ));
+    }
+    Ok(FileDisk {
+      file,
+      nblocks: size / BSIZE,
+      cache: Vec::with_capacity(capacity),
+      capacity,
+      synced: true,
+      _lock: lock,
+    })
+  }
+
+  // Deallocate `len` bytes of the image from `offset`, to read as zeros.
+  fn punch(&self, offset: usize, len: usize) -> io::Result<()> {
+    let mode = FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE;
+    let fd = self.file.as_raw_fd();
+
+    if unsafe { fallocate(fd, mode, offset as off_t, len as off_t) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  // Write out the writes held back.
+  fn write_out(&mut self) {
+    let mut i = 0;
+
+    while i < self.cache.len() {
+      let first = self.cache[i].0;
+      let mut run = vec![];
+
+      while i < self.cache.len() && self.cache[i].0 == first + run.len() / BSIZE
+      {
+        run.extend_from_slice(&self.cache[i].1);
+        i += 1;
+      }
+      if let Err(e) = self.file.write_all_at(&run, (first * BSIZE) as u64) {
+        error!("cannot write block {}: {}", first, e);
+      }
+    }
+    self.cache.clear();
+  }
+}
+
+impl BlockDevice for FileDisk {
+  fn nblocks(&self) -> usize {
+    self.nblocks
+  }
+
+  fn read(&mut self, blockno: usize) -> Block {
+    self.read_shared(blockno).unwrap()
+  }
+
+  fn read_shared(&self, blockno: usize) -> Option<Block> {
+    let mut data = [0; BSIZE];
+
+    if let Some(&(_, ref cached)) =
+      self.cache.iter().rev().find(|&&(b, _)| b == blockno)
+    {
+      return Some(*cached);
+    }
+    // Zeroed, as by Disk::load.
+    if let Err(e) = self.file.read_exact_at(&mut data, (blockno * BSIZE) as u64)
+    {
+      warn!("cannot read block {}: {}", blockno, e);
+      data = [0; BSIZE];
+    }
+    Some(data)
+  }
+
+  fn write(&mut self, blockno: usize, data: &Block) {
+    assert!(blockno < self.nblocks);
+    self.synced = false;
+    // Written again, it must not go out before those written meanwhile.
+    if self.cache.len() >= self.capacity ||
+      self.cache.iter().any(|&(b, _)| b == blockno)
+    {
+      self.write_out();
+    }
+    if self.capacity == 0 {
+      if let Err(e) = self.file.write_all_at(data, (blockno * BSIZE) as u64) {
+        error!("cannot write block {}: {}", blockno, e);
+      }
+    } else {
+      self.cache.push((blockno, *data));
+    }
+  }
+
+  fn flush(&mut self) {
+    self.write_out();
+    if let Err(e) = self.file.sync_data() {
+      warn!("cannot sync the image: {}", e);
+    }
+    self.synced = true;
+  }
+
+  // Punch a hole in the image where it was, and across the block of the
+  // file system under the image around it once that reads as zeros, as a
+  // smaller hole only zeros what it covers.
+  fn discard(&mut self, blockno: usize) {
+    assert!(blockno < self.nblocks);
+    if !self.synced {
+      self.flush();
     }
+    if let Err(e) = self.punch(blockno * BSIZE, BSIZE) {
+      warn!("cannot discard block {}: {}", blockno, e);
+      return;
+    }
+    let size = match self.file.metadata() {
+      Ok(metadata) => max(metadata.blksize() as usize, BSIZE),
+      Err(_) => return,
+    };
+    let start = blockno * BSIZE / size * size;
+    let len = min(size, self.nblocks * BSIZE - start);
+    let mut data = vec![0; len];
+
+    if self.file.read_exact_at(&mut data, start as u64).is_ok() &&
+      data.iter().all(|&x| x == 0)
+    {
+      let _ = self.punch(start, len);
+    }
+  }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Box<D> {
+  fn nblocks(&self) -> usize {
+    (**self).nblocks()
+  }
+
+  fn read(&mut self, blockno: usize) -> Block {
+    (**self).read(blockno)
+  }
+
+  fn read_shared(&self, blockno: usize) -> Option<Block> {
+    (**self).read_shared(blockno)
+  }
 
-    Some(Disk { blocks })
+  fn write(&mut self, blockno: usize, data: &Block) {
+    (**self).write(blockno, data)
   }
 
-  pub fn save<P: AsRef<Path>>(_path: P) {
-    // TODO: save xv6fs disk image into host's disk.
-    unimplemented!();
+  fn flush(&mut self) {
+    (**self).flush()
   }
 
-  fn read(&self, blockno: usize) -> &Block {
-    &self.blocks[blockno]
+  fn discard(&mut self, blockno: usize) {
+    (**self).discard(blockno)
   }
+}
+
+// Serve `requests` to `disk` until the disk is unmounted.
+fn serve(
+  requests: &Mutex<mpsc::Receiver<Request>>,
+  disk: &RwLock<Box<dyn BlockDevice>>,
+) {
+  let read = |blockno| {
+    if let Some(data) = disk.read().unwrap().read_shared(blockno) {
+      return data;
+    }
+    disk.write().unwrap().read(blockno)
+  };
 
-  fn write(&mut self, blockno: usize, data: Block) {
-    self.blocks[blockno] = data;
+  loop {
+    let request = match requests.lock().unwrap().recv() {
+      Ok(request) => request,
+      Err(_) => break,
+    };
+    let (reply, result) = match request {
+      Request::Read { reply, blockno } => (
+        reply,
+        panic::catch_unwind(AssertUnwindSafe(|| Some(read(blockno)))),
+      ),
+      Request::Write {
+        reply,
+        blockno,
+        data,
+      } => (
+        reply,
+        panic::catch_unwind(AssertUnwindSafe(|| {
+          disk.write().unwrap().write(blockno, &data);
+          None
+        })),
+      ),
+      Request::Flush { reply } => (
+        reply,
+        panic::catch_unwind(AssertUnwindSafe(|| {
+          disk.write().unwrap().flush();
+          None
+        })),
+      ),
+      Request::Discard { reply, blockno } => (
+        reply,
+        panic::catch_unwind(AssertUnwindSafe(|| {
+          disk.write().unwrap().discard(blockno);
+          None
+        })),
+      ),
+    };
+    let _ = reply.send(result);
   }
 }
 
+// Send the request `f` makes to `requests` and wait for the reply.
+fn call<F>(requests: &mpsc::Sender<Request>, f: F) -> Option<Block>
+where
+  F: FnOnce(mpsc::Sender<Reply>) -> Request,
+{
+  REPLIES.with(|&(ref send, ref recv)| {
+    requests.send(f(send.clone())).unwrap();
+    match recv.recv().unwrap() {
+      Ok(result) => result,
+      Err(e) => panic::resume_unwind(e),
+    }
+  })
+}
+
 impl DiskService {
-  pub fn mount(&self, mut disk: Disk) {
-    let mut channel = self.channel.lock().unwrap();
-    if channel.is_some() {
-      drop(channel);
+  pub fn mount<D: BlockDevice + 'static>(&self, disk: D) {
+    let disk: Box<dyn BlockDevice> = Box::new(disk);
+    let mut service = self.service.lock().unwrap();
+    if service.is_some() {
+      drop(service);
       self.unmount();
       return self.mount(disk);
     }
 
+    let disk = Arc::new(RwLock::new(disk));
     let (send, recv) = mpsc::channel();
-    *channel = Some(send.clone());
-    thread::spawn(move || loop {
-      let m = recv.recv();
+    let recv = Arc::new(Mutex::new(recv));
+    let workers = (0..NWORKERS)
+      .map(|_| {
+        let (recv, disk) = (recv.clone(), disk.clone());
+        thread::spawn(move || serve(&recv, &disk))
+      })
+      .collect();
+    *service = Some(Service {
+      requests: send,
+      workers,
+      disk,
+    });
+  }
 
-      if m.is_err() {
-        println!("{}", m.err().unwrap());
-        break;
+  // Flush the mounted disk every `interval` from a background thread, so
+  // that a crash of the process loses at most that much work. The thread
+  // exits when it finds no disk mounted.
+  pub fn start_flusher(&'static self, interval: Duration) {
+    self.set_flush_interval(Some(interval));
+  }
+
+  pub fn flush_interval(&self) -> Option<Duration> {
+    self.flusher.lock().unwrap().interval
+  }
+
+  // Change the interval of the flusher, counted from now, starting it if
+  // need be. None stops it.
+  pub fn set_flush_interval(&'static self, interval: Option<Duration>) {
+    let mut flusher = self.flusher.lock().unwrap();
+
+    flusher.interval = interval;
+    self.retuned.notify_all();
+    if interval.is_some() && !flusher.running {
+      flusher.running = true;
+      thread::spawn(move || self.flush_periodically());
+    }
+  }
+
+  fn flush_periodically(&self) {
+    let mut flusher = self.flusher.lock().unwrap();
+
+    while let Some(interval) = flusher.interval {
+      let (guard, wait) = self.retuned.wait_timeout(flusher, interval).unwrap();
+      if !wait.timed_out() {
+        flusher = guard;
+        continue;
       }
-      match m.unwrap() {
-        Request::Read { reply, blockno } => {
-          reply.send(*disk.read(blockno)).unwrap();
-        },
-        Request::Write {
-          reply,
-          blockno,
-          data,
-        } => {
-          disk.write(blockno, data);
-          reply.send(()).unwrap();
-        },
-        Request::Exit { reply } => {
-          reply.send(disk).unwrap();
-          break;
-        },
+      drop(guard);
+
+      let requests = self.requests();
+      let mounted = requests.is_some();
+      if let Some(requests) = requests {
+        call(&requests, |reply| Request::Flush { reply });
       }
-    });
+      flusher = self.flusher.lock().unwrap();
+      if !mounted {
+        break;
+      }
+    }
+    flusher.running = false;
   }
 
-  pub fn unmount(&self) -> Disk {
-    let mut channel = self.channel.lock().unwrap();
-    assert!(channel.is_some());
+  // Where to send requests, if a disk is mounted. The workers serve them
+  // as long as it is held, unmounted or not.
+  fn requests(&self) -> Option<mpsc::Sender<Request>> {
+    self
+      .service
+      .lock()
+      .unwrap()
+      .as_ref()
+      .map(|service| service.requests.clone())
+  }
 
-    let (send, recv) = mpsc::channel();
+  fn call<F>(&self, f: F) -> Option<Block>
+  where
+    F: FnOnce(mpsc::Sender<Reply>) -> Request,
+  {
+    let requests = self.requests();
+    assert!(requests.is_some());
 
-    channel
-      .as_ref()
-      .unwrap()
-      .send(Request::Exit { reply: send })
-      .unwrap();
-    let disk = recv.recv().unwrap();
-    *channel = None;
-    disk
+    call(&requests.unwrap(), f)
   }
 
-  pub fn read(&self, blockno: usize) -> Block {
-    let channel = self.channel.lock().unwrap();
-    assert!(channel.is_some());
+  pub fn is_mounted(&self) -> bool {
+    self.service.lock().unwrap().is_some()
+  }
 
-    let (send, recv) = mpsc::channel();
+  // Leave the disk mounted as it is until it is unmounted, for the file
+  // system to be inspected without changing it. The file system refuses
+  // changes then, see ICACHE, and the log recovers in the cache only, see
+  // Logging::init. Writes that get here anyway are dropped.
+  pub fn set_read_only(&self, read_only: bool) {
+    self.read_only.store(read_only, Ordering::Relaxed);
+  }
 
-    channel
-      .as_ref()
-      .unwrap()
-      .send(Request::Read {
-        reply: send,
-        blockno: blockno,
-      })
-      .unwrap();
-    recv.recv().unwrap()
+  pub fn is_read_only(&self) -> bool {
+    self.read_only.load(Ordering::Relaxed)
   }
 
-  pub fn write(&self, blockno: usize, data: &Block) {
-    let channel = self.channel.lock().unwrap();
-    assert!(channel.is_some());
+  // Unmount the disk once the requests under way are served, flushing it.
+  pub fn unmount(&self) -> Box<dyn BlockDevice> {
+    let mut service = self.service.lock().unwrap();
+    assert!(service.is_some());
+
+    let Service {
+      requests,
+      workers,
+      disk,
+    } = service.take().unwrap();
+    self.set_read_only(false);
+    drop(requests);
+    for worker in workers {
+      worker.join().unwrap();
+    }
+    let mut disk = match Arc::try_unwrap(disk) {
+      Ok(disk) => disk.into_inner().unwrap(),
+      Err(_) => unreachable!(),
+    };
+    disk.flush();
+    disk
+  }
 
-    let (send, recv) = mpsc::channel();
+  pub fn flush(&self) {
+    self.call(|reply| Request::Flush { reply });
+  }
 
-    channel
-      .as_ref()
-      .unwrap()
-      .send(Request::Write {
-        reply: send,
-        blockno: blockno,
-        data: *data,
-      })
-      .unwrap();
-    recv.recv().unwrap()
+  pub fn read(&self, blockno: usize) -> Block {
+    self.call(|reply| Request::Read { reply, blockno }).unwrap()
+  }
+
+  pub fn write(&self, blockno: usize, data: &Block) {
+    if self.is_read_only() {
+      error!("dropping a write to block {} of a read-only disk", blockno);
+      return;
+    }
+    self.call(|reply| Request::Write {
+      reply,
+      blockno,
+      data: *data,
+    });
+  }
+
+  // See BlockDevice::discard.
+  pub fn discard(&self, blockno: usize) {
+    if self.is_read_only() {
+      return;
+    }
+    self.call(|reply| Request::Discard { reply, blockno });
   }
 }
 
 #[cfg(test)]
 mod test {
-  use disk::{Disk, Block, DISK, BSIZE};
+  use disk::{self, BlockDevice, Disk, Block, DISK, BSIZE, FileDisk};
+  use std::env;
+  use std::fs;
+  use std::io;
+  use std::os::unix::fs::{FileExt, MetadataExt};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
 
   #[test]
   fn test() {
@@ -189,4 +817,191 @@ mod test {
     assert!(DISK.read(0)[0] == 0);
     assert!(DISK.read(1)[0] == 42);
   }
+  #[test]
+  fn test_flusher() {
+    let path = env::temp_dir().join("xv6fs-test-flusher.img");
+    fs::write(&path, &[0; 2 * BSIZE][..]).unwrap();
+
+    DISK.mount(Disk::load(&path).unwrap());
+    DISK.write(1, &[42; BSIZE]);
+    DISK.start_flusher(Duration::from_millis(10));
+    thread::sleep(Duration::from_millis(100));
+    assert!(fs::read(&path).unwrap()[BSIZE] == 42);
+
+    DISK.unmount();
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(path.with_extension("img.lock")).unwrap();
+  }
+
+  // A disk whose shared reads take a while, counting how many run at once.
+  struct Slow {
+    readers: AtomicUsize,
+    most: Arc<AtomicUsize>,
+  }
+
+  impl BlockDevice for Slow {
+    fn nblocks(&self) -> usize {
+      1
+    }
+
+    fn read(&mut self, _blockno: usize) -> Block {
+      unreachable!()
+    }
+
+    fn write(&mut self, _blockno: usize, _data: &Block) {}
+
+    fn read_shared(&self, blockno: usize) -> Option<Block> {
+      assert!(blockno < 1);
+      let n = self.readers.fetch_add(1, Ordering::SeqCst) + 1;
+      self.most.fetch_max(n, Ordering::SeqCst);
+      thread::sleep(Duration::from_millis(50));
+      self.readers.fetch_sub(1, Ordering::SeqCst);
+      Some([0; BSIZE])
+    }
+  }
+
+  #[test]
+  fn test_workers() {
+    let most = Arc::new(AtomicUsize::new(0));
+
+    DISK.mount(Slow {
+      readers: AtomicUsize::new(0),
+      most: most.clone(),
+    });
+    let readers: Vec<_> =
+      (0..4).map(|_| thread::spawn(|| DISK.read(0))).collect();
+    for reader in readers {
+      reader.join().unwrap();
+    }
+    assert!(most.load(Ordering::SeqCst) > 1);
+
+    // A request that panics panics the caller.
+    assert!(thread::spawn(|| DISK.write(1, &[0; BSIZE])).join().is_ok());
+    assert!(thread::spawn(|| DISK.read(1)).join().is_err());
+    DISK.unmount();
+  }
+
+  #[test]
+  fn test_save() {
+    let path = env::temp_dir().join("xv6fs-test-save.img");
+    fs::write(&path, &[0; 2 * BSIZE][..]).unwrap();
+
+    // Unmounting saves what was written to the image.
+    DISK.mount(Disk::load(&path).unwrap());
+    DISK.write(1, &[42; BSIZE]);
+    assert!(fs::read(&path).unwrap()[BSIZE] == 0);
+    DISK.unmount();
+    assert!(fs::read(&path).unwrap()[BSIZE] == 42);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(path.with_extension("img.lock")).unwrap();
+  }
+
+  #[test]
+  fn test_sparse() {
+    let path = env::temp_dir().join("xv6fs-test-sparse.img");
+    let f = fs::File::create(&path).unwrap();
+    f.set_len((1024 * BSIZE) as u64).unwrap();
+    f.write_all_at(&[42; BSIZE], (700 * BSIZE) as u64).unwrap();
+    drop(f);
+
+    // Holes load as zeros, and are left as holes when saved.
+    let mut disk = Disk::load(&path).unwrap();
+    assert!(disk.read(700)[0] == 42 && disk.read(699)[0] == 0);
+    disk.write(3, &[43; BSIZE]);
+    disk.flush();
+    let image = fs::read(&path).unwrap();
+    assert!(image.len() == 1024 * BSIZE);
+    assert!(image[3 * BSIZE] == 43 && image[700 * BSIZE] == 42);
+    assert!(fs::metadata(&path).unwrap().blocks() < 1024);
+    drop(disk);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(path.with_extension("img.lock")).unwrap();
+  }
+
+  #[test]
+  fn test_file_disk() {
+    let path = env::temp_dir().join("xv6fs-test-file-disk.img");
+    fs::write(&path, &[0; 8 * BSIZE][..]).unwrap();
+
+    // Written through.
+    let mut disk = FileDisk::open(&path, 0).unwrap();
+    assert!(disk.nblocks() == 8);
+    disk.write(1, &[42; BSIZE]);
+    assert!(fs::read(&path).unwrap()[BSIZE] == 42);
+    drop(disk);
+
+    // Or held back, but read back, until flushed, or a block is written
+    // again.
+    let mut disk = FileDisk::open(&path, 4).unwrap();
+    disk.write(3, &[1; BSIZE]);
+    disk.write(4, &[2; BSIZE]);
+    assert!(disk.read(4)[0] == 2 && fs::read(&path).unwrap()[4 * BSIZE] == 0);
+    disk.write(3, &[3; BSIZE]);
+    assert!(fs::read(&path).unwrap()[4 * BSIZE] == 2);
+    assert!(fs::read(&path).unwrap()[3 * BSIZE] == 1);
+    disk.flush();
+    assert!(fs::read(&path).unwrap()[3 * BSIZE] == 3);
+    assert!(disk.read(1)[0] == 42);
+    drop(disk);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(path.with_extension("img.lock")).unwrap();
+  }
+
+  #[test]
+  fn test_discard() {
+    let path = env::temp_dir().join("xv6fs-test-discard.img");
+    fs::write(&path, &[42; 8 * BSIZE][..]).unwrap();
+
+    // Writes held back go out first, and the blocks discarded read as
+    // zeros, holes in the image.
+    let mut disk = FileDisk::open(&path, 4).unwrap();
+    disk.write(0, &[1; BSIZE]);
+    for blockno in 1..8 {
+      disk.discard(blockno);
+    }
+    disk.discard(0);
+    assert!((0..8).all(|blockno| disk.read(blockno) == [0; BSIZE]));
+    assert!(fs::read(&path).unwrap() == vec![0; 8 * BSIZE]);
+    assert!(fs::metadata(&path).unwrap().blocks() == 0);
+    drop(disk);
+
+    let mut disk = Disk::new(2);
+    disk.write(1, &[42; BSIZE]);
+    disk.discard(1);
+    assert!(disk.read(1) == [0; BSIZE]);
+
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(path.with_extension("img.lock")).unwrap();
+  }
+
+  #[test]
+  fn test_lock() {
+    let path = env::temp_dir().join("xv6fs-test-lock.img");
+    fs::write(&path, &[0; 2 * BSIZE][..]).unwrap();
+
+    let mut disk = Disk::load_shared(&path).unwrap();
+    let shared = Disk::load_shared(&path).unwrap();
+    let in_use = |e: io::Error| e.kind() == io::ErrorKind::WouldBlock;
+    assert!(Disk::load(&path).err().map_or(false, in_use));
+    assert!(disk.lock_exclusive().is_err());
+    drop(shared);
+    disk.lock_exclusive().unwrap();
+    assert!(Disk::load_shared(&path).err().map_or(false, in_use));
+
+    disk::set_force(true);
+    Disk::load(&path).unwrap();
+    disk::set_force(false);
+    drop(disk);
+    Disk::load(&path).unwrap();
+
+    let args = vec!["fsck", "--force", "fs.img"];
+    let args = disk::parse_force(args.iter().map(|s| s.to_string()).collect());
+    assert!(args == ["fsck", "fs.img"]);
+    disk::set_force(false);
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(path.with_extension("img.lock")).unwrap();
+  }
 }
@@ -0,0 +1,56 @@
+use libc::{c_int, EACCES, EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT,
+           EMLINK, ENOSPC, ENOTDIR, ENOTEMPTY, EOPNOTSUPP, EROFS, ESTALE};
+use std::result;
+
+// Not in our libc yet.
+const ENOKEY: c_int = 126;
+const EUCLEAN: c_int = 117;
+
+// Errors returned by the high-level file system operations. Every
+// frontend (FUSE, 9P, ...) speaks errno in the end, see `errno`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+  NotFound,
+  Exists,
+  NotDir,
+  IsDir,
+  NotEmpty,
+  NameTooLong,
+  NoSpace,
+  Invalid,
+  Unsupported,
+  ReadOnly,
+  NoKey,
+  Stale,
+  Denied,
+  // A block has as many references as its count can hold.
+  TooManyLinks,
+  // The image is inconsistent, e.g. a block number is out of range.
+  Corrupt,
+  Io,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+  pub fn errno(self) -> c_int {
+    match self {
+      Error::NotFound => ENOENT,
+      Error::Exists => EEXIST,
+      Error::NotDir => ENOTDIR,
+      Error::IsDir => EISDIR,
+      Error::NotEmpty => ENOTEMPTY,
+      Error::NameTooLong => ENAMETOOLONG,
+      Error::NoSpace => ENOSPC,
+      Error::Invalid => EINVAL,
+      Error::Unsupported => EOPNOTSUPP,
+      Error::ReadOnly => EROFS,
+      Error::NoKey => ENOKEY,
+      Error::Stale => ESTALE,
+      Error::Denied => EACCES,
+      Error::TooManyLinks => EMLINK,
+      Error::Corrupt => EUCLEAN,
+      Error::Io => EIO,
+    }
+  }
+}
@@ -0,0 +1,260 @@
+// Fault injection, to test the file system on a disk that misbehaves.
+//
+// A `FaultyDisk` wraps any device, and on a schedule drawn from a seed,
+// so that a failure replays, fails reads and writes, delays them, and
+// tears writes, leaving only part of the block written. It may also hold
+// writes back, to reach the device in any order, as a disk with a volatile
+// cache does until it is flushed, and `crash` leaves the device as a power
+// cut would then. A failed read is zeroed and a failed write lost, as the
+// devices do when the host fails them.
+
+#[cfg(test)]
+pub mod test {
+  use buffer::BCACHE;
+  use crash;
+  use disk::{BSIZE, Block, BlockDevice, DISK, Disk};
+  use fs::FileType;
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use ops;
+  use std::collections::HashSet;
+  use std::thread;
+  use std::time::Duration;
+  use testfs;
+
+  // A xorshift generator, the same for the same seed.
+  pub struct Rng(u64);
+
+  impl Rng {
+    pub fn new(seed: u64) -> Self {
+      Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    pub fn next(&mut self) -> u64 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      self.0
+    }
+
+    // A number under `n`.
+    pub fn below(&mut self, n: usize) -> usize {
+      (self.next() % n as u64) as usize
+    }
+
+    // True one time in `n`, never if 0.
+    pub fn one_in(&mut self, n: usize) -> bool {
+      n > 0 && self.below(n) == 0
+    }
+  }
+
+  // How often each fault happens, one access in so many, never if 0.
+  #[derive(Clone, Copy, Default)]
+  pub struct Faults {
+    pub read_error: usize,
+    pub write_error: usize,
+    pub torn_write: usize,
+    pub delay: usize,
+    // The longest delay.
+    pub max_delay: Duration,
+    // How many writes may be held back.
+    pub reorder: usize,
+  }
+
+  pub struct FaultyDisk<D: BlockDevice> {
+    disk: D,
+    faults: Faults,
+    rng: Rng,
+    // Blocks that always fail to read, or to be written.
+    bad_reads: HashSet<usize>,
+    bad_writes: HashSet<usize>,
+    // The writes held back, a block once at most.
+    pending: Vec<(usize, Block)>,
+  }
+
+  impl<D: BlockDevice> FaultyDisk<D> {
+    pub fn new(disk: D, faults: Faults, seed: u64) -> Self {
+      FaultyDisk {
+        disk,
+        faults,
+        rng: Rng::new(seed),
+        bad_reads: HashSet::new(),
+        bad_writes: HashSet::new(),
+        pending: vec![],
+      }
+    }
+
+    pub fn fail_read(&mut self, blockno: usize) {
+      self.bad_reads.insert(blockno);
+    }
+
+    pub fn fail_write(&mut self, blockno: usize) {
+      self.bad_writes.insert(blockno);
+    }
+
+    fn delay(&mut self) {
+      let ms = self.faults.max_delay.as_millis() as usize;
+
+      if ms > 0 && self.rng.one_in(self.faults.delay) {
+        thread::sleep(Duration::from_millis(self.rng.below(ms + 1) as u64));
+      }
+    }
+
+    // Write `data` to the device itself, unless it fails.
+    fn put(&mut self, blockno: usize, data: &Block) {
+      if self.bad_writes.contains(&blockno) ||
+        self.rng.one_in(self.faults.write_error)
+      {
+        return;
+      }
+      if self.rng.one_in(self.faults.torn_write) {
+        let mut torn = self.disk.read(blockno);
+        let n = self.rng.below(BSIZE);
+
+        torn[..n].copy_from_slice(&data[..n]);
+        return self.disk.write(blockno, &torn);
+      }
+      self.disk.write(blockno, data);
+    }
+
+    // Write out one of the writes held back, any.
+    fn put_any(&mut self) {
+      let i = self.rng.below(self.pending.len());
+      let (blockno, data) = self.pending.swap_remove(i);
+
+      self.put(blockno, &data);
+    }
+
+    // The device as a power cut now leaves it, with any of the writes held
+    // back written out, the others lost.
+    pub fn crash(mut self) -> D {
+      for (blockno, data) in self.pending.split_off(0) {
+        if self.rng.one_in(2) {
+          self.put(blockno, &data);
+        }
+      }
+      self.disk
+    }
+  }
+
+  impl<D: BlockDevice> BlockDevice for FaultyDisk<D> {
+    fn nblocks(&self) -> usize {
+      self.disk.nblocks()
+    }
+
+    fn read(&mut self, blockno: usize) -> Block {
+      self.delay();
+      if self.bad_reads.contains(&blockno) ||
+        self.rng.one_in(self.faults.read_error)
+      {
+        return [0; BSIZE];
+      }
+      match self.pending.iter().find(|&&(b, _)| b == blockno) {
+        Some(&(_, ref data)) => *data,
+        None => self.disk.read(blockno),
+      }
+    }
+
+    fn write(&mut self, blockno: usize, data: &Block) {
+      self.delay();
+      if self.faults.reorder == 0 {
+        return self.put(blockno, data);
+      }
+      if let Some(i) = self.pending.iter().position(|&(b, _)| b == blockno) {
+        self.pending[i].1 = *data;
+        return;
+      }
+      if self.pending.len() == self.faults.reorder {
+        self.put_any();
+      }
+      self.pending.push((blockno, *data));
+    }
+
+    fn flush(&mut self) {
+      while !self.pending.is_empty() {
+        self.put_any();
+      }
+      self.disk.flush();
+    }
+  }
+
+  #[test]
+  fn test() {
+    let faults = Faults {
+      reorder: 4,
+      ..Faults::default()
+    };
+    let mut disk = FaultyDisk::new(Disk::new(16), faults, 1);
+
+    disk.fail_read(1);
+    disk.fail_write(2);
+    disk.write(1, &[1; BSIZE]);
+    disk.write(2, &[2; BSIZE]);
+    disk.flush();
+    assert!(disk.read(1)[0] == 0 && disk.disk.read(1)[0] == 1);
+    assert!(disk.read(2)[0] == 0);
+
+    // Writes held back are read back, until a crash loses some.
+    for blockno in 3..7 {
+      disk.write(blockno, &[blockno as u8; BSIZE]);
+    }
+    assert!((3..7).all(|blockno| disk.read(blockno)[0] == blockno as u8));
+    let mut disk = disk.crash();
+    let lost = (3..7).filter(|&blockno| disk.read(blockno)[0] == 0).count();
+    assert!(lost > 0 && lost < 4);
+
+    // Torn writes write a prefix, the same for the same seed.
+    let faults = Faults {
+      torn_write: 1,
+      ..Faults::default()
+    };
+    let torn = |seed| {
+      let mut disk = FaultyDisk::new(Disk::new(1), faults, seed);
+      disk.write(0, &[42; BSIZE]);
+      disk.read(0).iter().take_while(|&&x| x == 42).count()
+    };
+    assert!(torn(7) == torn(7) && torn(7) < BSIZE);
+    assert!(torn(7) + torn(8) + torn(9) > 0);
+  }
+
+  // Mount `disk` with empty caches, and recover it.
+  fn mount<D: BlockDevice + 'static>(disk: D) {
+    DISK.mount(disk);
+    BCACHE.init();
+    {
+      let _txn = LOGGING.new_txn();
+      ICACHE.init();
+    }
+    LOGGING.init().unwrap();
+  }
+
+  #[test]
+  fn test_slow() {
+    let faults = Faults {
+      delay: 4,
+      max_delay: Duration::from_millis(2),
+      reorder: 8,
+      ..Faults::default()
+    };
+    let disk = testfs::test::create().0;
+
+    // Writes in any order, and late, are as good as flushed.
+    mount(FaultyDisk::new(disk, faults, 42));
+    let writers: Vec<_> = (0..4)
+      .map(|i| {
+        thread::spawn(move || {
+          let txn = LOGGING.new_txn();
+          let name = ops::to_name(format!("f{}", i).as_bytes()).unwrap();
+          let file = ops::create(&txn, &ops::root(), &name, FileType::File);
+          ops::write(&txn, &file.unwrap(), 0, &[i as u8; 3 * BSIZE]).unwrap();
+        })
+      })
+      .collect();
+    for writer in writers {
+      writer.join().unwrap();
+    }
+    let state = crash::test::state();
+    mount(DISK.unmount());
+    assert!(crash::test::state() == state);
+  }
+}
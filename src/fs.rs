@@ -1,33 +1,183 @@
-use disk::BSIZE;
-use std::mem::size_of;
+use bitmap::Bitmap;
+use buffer::BCACHE;
+use disk::{BSIZE, Block};
+use error::{Error, Result};
+use inode::ICACHE;
+use logging::Transaction;
+use std::cmp::min;
+use std::mem::{size_of, transmute};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use util::crc32::crc32;
+
+pub use bitmap::Report;
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct SuperBlock {
   pub nblocks: u32, // Number of blocks (size of file system image)
-  pub unused: u32,
+  pub refino: u32, // Inode holding block reference counts, or 0
   pub ninodes: u32, // Number of inodes (not inode blocks!)
   pub nlogs: u32, // Number of log blocks
   pub log_start: u32, // Block number of first log block
   pub inode_start: u32, // Block number of first inode block
   pub bmap_start: u32, // Block number of first free map block
+  pub flags: u32, // Super block flags, 0 for images predating them
+  pub badblk: u32, // Block of the bad-block table, or 0
+  pub nspares: u32, // Number of spare blocks following it
+  pub hashblk: u32, // First block of data hashes, or 0, see integrity.rs
+  pub root: [u8; 32], // Root of the hash tree over them
+  pub checksum: u32, // Of the fields above, with CHECKSUMS
+  pub nextfree: u32, // Where to look for a free block first, or 0
+  pub label: [u8; LABELSIZE], // Name of the file system, zero-padded
 }
 
+// Longest label of a file system.
+pub const LABELSIZE: usize = 16;
+
+// Super block flags.
+pub const CASEFOLD: u32 = 0x1; // Names are looked up ignoring ASCII case
+// The super block, the log header and every inode carry a CRC32 of their
+// fields, checked as they are read.
+pub const CHECKSUMS: u32 = 0x2;
+
 // Number of bitmap bits per block.
 pub const BPB: usize = BSIZE * 8;
 
+// Number of free map blocks of a file system of `nblocks` blocks, one too
+// many if a multiple of BPB, as images have always been laid out.
+pub fn nbitmapblks(nblocks: usize) -> usize {
+  nblocks / BPB + 1
+}
+
 // Number of inodes per block.
 pub const IPB: usize = BSIZE / size_of::<DiskInode>();
 
 impl SuperBlock {
+  fn compute_checksum(&self) -> u32 {
+    let mut copy = *self;
+
+    copy.checksum = 0;
+    // Not the hint and label past it, so that images predating them still
+    // verify. An image labeled checks its label too.
+    let n = size_of::<SuperBlock>() - size_of::<u32>() - LABELSIZE;
+    let mut data = to_block!(&copy, SuperBlock)[..n].to_vec();
+    if self.label.iter().any(|&c| c != 0) {
+      data.extend_from_slice(&self.label);
+    }
+    crc32(&data)
+  }
+
+  // Set the checksum, after changing any field.
+  pub fn seal(&mut self) {
+    self.checksum = self.compute_checksum();
+  }
+
+  // Return false if this super block has checksums and fails its own.
+  pub fn verify(&self) -> bool {
+    self.flags & CHECKSUMS == 0 || self.checksum == self.compute_checksum()
+  }
+
   // Block of free map containing bit for block `blockno`.
   pub fn bblock(&self, blockno: usize) -> usize {
     self.bmap_start as usize + blockno / BPB
   }
 
+  // First block after the free map, where data, directory and indirect
+  // blocks live.
+  pub fn data_start(&self) -> usize {
+    self.bmap_start as usize + nbitmapblks(self.nblocks as usize)
+  }
+
+  // The label, without its padding.
+  pub fn label(&self) -> &[u8] {
+    let n = self.label.iter().position(|&c| c == 0).unwrap_or(LABELSIZE);
+
+    &self.label[..n]
+  }
+
+  // First block after the data blocks, where the reserved blocks at the
+  // end of the device start.
+  pub fn data_end(&self) -> usize {
+    if self.hashblk != 0 {
+      self.hashblk as usize
+    } else if self.badblk != 0 {
+      self.badblk as usize
+    } else {
+      self.nblocks as usize
+    }
+  }
+
   // Block containing inode `inodeno`.
   pub fn iblock(&self, inodeno: usize) -> usize {
     self.inode_start as usize + inodeno / IPB
   }
+
+  // Number of inodes the inode blocks have room for, which the table may
+  // grow to, see `grow_inodes`, as far as dirents can refer to them.
+  pub fn max_inodes(&self) -> usize {
+    let room = (self.bmap_start - self.inode_start) as usize * IPB;
+
+    min(room, WHITEOUT as usize)
+  }
+
+  // Return true if lookups treat `a` and `b` as the same name.
+  pub fn name_eq(&self, a: &[u8; DIRSIZE], b: &[u8; DIRSIZE]) -> bool {
+    if self.flags & CASEFOLD != 0 {
+      a.eq_ignore_ascii_case(b)
+    } else {
+      a == b
+    }
+  }
+}
+
+// How much of the file system is in use, in data blocks and inodes.
+pub struct Usage {
+  pub blocks: usize,
+  pub free_blocks: usize,
+  pub inodes: usize,
+  pub free_inodes: usize,
+}
+
+// Return the usage of the mounted file system, for statfs and the tools.
+// The free blocks are counted at mount, the free inodes as it is called.
+pub fn usage<'a>(txn: &Transaction<'a>) -> Usage {
+  let sb = BCACHE.sb();
+
+  Usage {
+    blocks: sb.data_end() - sb.data_start(),
+    free_blocks: Bitmap::count_free(txn),
+    inodes: sb.ninodes as usize - 1,
+    free_inodes: ICACHE.count_free(txn),
+  }
+}
+
+// Cross-check the bitmap of the mounted file system against the blocks its
+// inodes refer to, for fsck, see Bitmap::verify. It must run with no inode
+// locked.
+pub fn verify<'a>(txn: &Transaction<'a>) -> Result<Report> {
+  Bitmap::verify(txn)
+}
+
+// Grow the inode table of the mounted file system by `n` inodes, into the
+// room mkfs left in the inode blocks, see mkfs::Options::max_inodes, and
+// return how many it has then. NoSpace if there is not that much room. The
+// growth goes back if the transaction aborts.
+pub fn grow_inodes<'a>(txn: &Transaction<'a>, n: usize) -> Result<usize> {
+  let mut buf = txn.read(1).unwrap();
+  let mut sb = from_block!(&buf.data, SuperBlock);
+  let ninodes = sb.ninodes as usize + n;
+
+  // The slots past the table are zeroed, as never used.
+  if ninodes > sb.max_inodes() {
+    return Err(Error::NoSpace);
+  }
+  sb.ninodes = ninodes as u32;
+  sb.seal();
+  buf.data = to_block!(&sb, SuperBlock);
+  txn.write(&mut buf);
+  BCACHE.set_sb(sb);
+  ICACHE.grow(n);
+  Ok(ninodes)
 }
 
 // Number of direct blocks of an inode.
@@ -36,8 +186,12 @@ pub const NDIRECT: usize = 12;
 // Number of indirect blocks of an inode.
 pub const NINDIRECT: usize = BSIZE / size_of::<u32>();
 
-// Maximum file size.
-pub const MAXFILESIZE: usize = (NDIRECT + NINDIRECT) * BSIZE;
+// Number of doubly indirect blocks of an inode.
+pub const NDINDIRECT: usize = NINDIRECT * NINDIRECT;
+
+// Maximum number of data blocks of a file, and its maximum size.
+pub const MAXBLOCKS: usize = NDIRECT + NINDIRECT + NDINDIRECT;
+pub const MAXFILESIZE: usize = MAXBLOCKS * BSIZE;
 
 // Inode index of root folder.
 pub const ROOTINO: usize = 1;
@@ -48,39 +202,279 @@ pub enum FileType {
   None,
   Directory,
   File,
+  // Its content is the path it points to.
+  Symlink,
+  // A character device, by its major and minor numbers.
+  Device,
 }
 
+// Inode flags. The high byte holds the key id of an IENCRYPT inode.
+pub const IREADONLY: u16 = 0x1; // Part of a snapshot, never modified
+pub const IENCRYPT: u16 = 0x2; // Content encrypted, see crypt.rs
+pub const IORPHAN: u16 = 0x4; // Unnamed file, freed at mount, see ops::tmpfile
+pub const IDEFAULTS: u16 = 0x8; // Directory with defaults for new children
+pub const ISHRED: u16 = 0x10; // Freed blocks are zeroed, see Bitmap::shred
+
+// Owner of inodes that are not given one, which is what every file was
+// reported to belong to before owners were stored.
+pub const DEFAULT_UID: u32 = 1000;
+pub const DEFAULT_GID: u32 = 1000;
+
+// Bits of an access mask, as for access(2).
+pub const R_OK: u32 = 4;
+pub const W_OK: u32 = 2;
+pub const X_OK: u32 = 1;
+
 #[repr(C)]
 #[derive(Clone)]
 pub struct DiskInode {
   pub file_type: FileType,
-  pub unused1: u16,
-  pub unused2: u16,
+  pub flags: u16,
+  pub nonce: u16, // Keystream nonce of an IENCRYPT file
   pub nlink: u16,
   pub size: u32,
   pub addrs: [u32; NDIRECT + 1],
+  pub mode: u16, // Permission bits
+  pub dmode: u16, // Default mode of an IDEFAULTS directory
+  pub uid: u32,
+  pub gid: u32,
+  pub duid: u32, // Default owner of an IDEFAULTS directory
+  pub dgid: u32,
+  pub gen: u32, // Bumped whenever the slot is allocated again
+  pub major: u16, // Of a Device
+  pub minor: u16,
+  // Seconds and nanoseconds since the epoch, 0 for inodes predating them.
+  pub atime: u32,
+  pub atime_nsec: u32,
+  pub mtime: u32,
+  pub mtime_nsec: u32,
+  pub ctime: u32,
+  pub ctime_nsec: u32,
+  // The doubly indirect block, which follows `addrs` in spirit, but not in
+  // place, as older images have the fields above there.
+  pub dindirect: u32,
+  pub checksum: u32, // Of the fields above, see `verify`
+  pub unused: u32, // Pads the inode to 128 bytes
+}
+
+// Return the time now as stored in inodes.
+pub fn now() -> (u32, u32) {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or(Duration::from_secs(0));
+
+  (now.as_secs() as u32, now.subsec_nanos())
 }
 
 impl DiskInode {
   pub fn init(&mut self, file_type: FileType) {
     self.file_type = file_type;
-    self.unused1 = 0;
-    self.unused2 = 0;
+    self.flags = 0;
+    self.nonce = 0;
     self.nlink = 0;
     self.size = 0;
     for i in 0..(NDIRECT + 1) {
       self.addrs[i] = 0;
     }
+    self.mode = match file_type {
+      FileType::Directory => 0o755,
+      FileType::Symlink => 0o777,
+      _ => 0o644,
+    };
+    self.dmode = 0;
+    self.uid = DEFAULT_UID;
+    self.gid = DEFAULT_GID;
+    self.duid = 0;
+    self.dgid = 0;
+    self.gen = self.gen.wrapping_add(1);
+    self.major = 0;
+    self.minor = 0;
+    let (sec, nsec) = now();
+    self.atime = sec;
+    self.atime_nsec = nsec;
+    self.mtime = sec;
+    self.mtime_nsec = nsec;
+    self.ctime = sec;
+    self.ctime_nsec = nsec;
+    self.dindirect = 0;
+    self.unused = 0;
+  }
+
+  fn compute_checksum(&self) -> u32 {
+    let mut copy = self.clone();
+
+    copy.checksum = 0;
+    crc32(&unsafe { transmute::<_, [u8; size_of::<DiskInode>()]>(copy) })
+  }
+
+  // Set the checksum, after changing any field.
+  pub fn seal(&mut self) {
+    self.checksum = self.compute_checksum();
+  }
+
+  // Return false if this inode, of an image with checksums, fails its
+  // checksum or has no valid type. Slots never used are all zeros.
+  pub fn verify(&self) -> bool {
+    let ty = unsafe { *(&self.file_type as *const FileType as *const u16) };
+
+    if ty > FileType::Device as u16 {
+      return false;
+    }
+    self.checksum == self.compute_checksum() ||
+      self.file_type == FileType::None && self.checksum == 0
+  }
+
+  // Note that the content of this inode changed.
+  pub fn touch(&mut self) {
+    let (sec, nsec) = now();
+    self.mtime = sec;
+    self.mtime_nsec = nsec;
+  }
+
+  // Let this inode, which is just created in directory `parent`, take the
+  // defaults of `parent`, if any. Files do not get the execute bits, and a
+  // directory gets the defaults themselves too, like setgid directories
+  // pass on their group.
+  pub fn inherit_defaults(&mut self, parent: &DiskInode) {
+    if parent.flags & IDEFAULTS == 0 {
+      return;
+    }
+    self.uid = parent.duid;
+    self.gid = parent.dgid;
+    if self.file_type == FileType::Directory {
+      self.mode = parent.dmode;
+      self.flags |= IDEFAULTS;
+      self.dmode = parent.dmode;
+      self.duid = parent.duid;
+      self.dgid = parent.dgid;
+    } else {
+      self.mode = parent.dmode & !0o111;
+    }
+  }
+
+  // Let this inode, which is just created in directory `parent`, be
+  // shredded too if `parent` is.
+  pub fn inherit_shred(&mut self, parent: &DiskInode) {
+    self.flags |= parent.flags & ISHRED;
+  }
+
+  // Return true if its mode grants user `uid` of group `gid` every access
+  // of `mask`. Root is granted anything but executing a file nobody may.
+  pub fn allows(&self, uid: u32, gid: u32, mask: u32) -> bool {
+    let mode = self.mode as u32;
+    let granted = if uid == 0 {
+      if self.file_type == FileType::Directory || mode & 0o111 != 0 {
+        R_OK | W_OK | X_OK
+      } else {
+        R_OK | W_OK
+      }
+    } else if uid == self.uid {
+      mode >> 6 & 7
+    } else if gid == self.gid {
+      mode >> 3 & 7
+    } else {
+      mode & 7
+    };
+
+    mask & !granted == 0
+  }
+
+  pub fn is_read_only(&self) -> bool {
+    self.flags & IREADONLY != 0
+  }
+
+  pub fn is_encrypted(&self) -> bool {
+    self.flags & IENCRYPT != 0
+  }
+
+  pub fn is_orphan(&self) -> bool {
+    self.flags & IORPHAN != 0
+  }
+
+  pub fn is_shredded(&self) -> bool {
+    self.flags & ISHRED != 0
+  }
+
+  pub fn key_id(&self) -> u8 {
+    assert!(self.is_encrypted());
+    (self.flags >> 8) as u8
   }
 }
 
-// Maximum number of log entries.
+// Number of blocks of a log segment, its header included, and of the log
+// of images predating segments.
 pub const LOGSIZE: usize = 64;
 
+// The header of a log segment, see logging.rs.
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct LogHeader {
   pub n: u32,
-  pub blocks: [u32; LOGSIZE], // blocks[i] <-> sb.log_start + i + 1
+  pub blocks: [u32; LOGSIZE], // blocks[i] <-> header block + i + 1
+  pub checksum: u32, // Of the blocks committed, with CHECKSUMS
+  pub more: u32, // Segments after this one in the commit, see logging.rs
+  pub payload: u32, // Of what the blocks logged hold, or 0
+}
+
+impl LogHeader {
+  fn compute_checksum(&self) -> u32 {
+    let n = min(self.n as usize, LOGSIZE);
+    let mut data = self.n.to_le_bytes().to_vec();
+
+    for blockno in &self.blocks[..n] {
+      data.extend_from_slice(&blockno.to_le_bytes());
+    }
+    // Headers of single segments check as they did before, and so do
+    // those predating payloads.
+    if self.more != 0 {
+      data.extend_from_slice(&self.more.to_le_bytes());
+    }
+    if self.payload != 0 {
+      data.extend_from_slice(&self.payload.to_le_bytes());
+    }
+    crc32(&data)
+  }
+
+  // Set the checksum of `payload`, what the blocks logged hold in order,
+  // before sealing the header, see `verify_payload`.
+  pub fn set_payload(&mut self, payload: &[Block]) {
+    self.payload = crc32(&payload.concat());
+  }
+
+  // Return false if `payload`, read back from the log, is not what the
+  // header was written for, as the commit was torn by a crash.
+  pub fn verify_payload(&self, payload: &[Block]) -> bool {
+    self.payload == 0 || self.payload == crc32(&payload.concat())
+  }
+
+  pub fn seal(&mut self) {
+    self.checksum = self.compute_checksum();
+  }
+
+  // Return false if this header, of an image with checksums, fails its
+  // checksum. One with no transaction committed stands as it is.
+  pub fn verify(&self) -> bool {
+    self.n == 0 || self.checksum == self.compute_checksum()
+  }
+}
+
+// Maximum number of bad blocks recorded.
+pub const NBADBLOCKS: usize = (BSIZE - 4) / 8;
+
+// A block found unreadable, and the spare it was remapped to, or 0 if it
+// was free and merely retired.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BadBlock {
+  pub blockno: u32,
+  pub spare: u32,
+}
+
+// Block `sb.badblk`, see badblock.rs.
+#[repr(C)]
+pub struct BadBlockTable {
+  pub n: u32,
+  pub entries: [BadBlock; NBADBLOCKS],
 }
 
 // Maximum length of directory name.
@@ -92,5 +486,9 @@ pub struct Dirent {
   pub name: [u8; DIRSIZE],
 }
 
+// Inode number of a whiteout, a dirent marking its name as definitely
+// absent, e.g. to hide a file of a lower overlay layer.
+pub const WHITEOUT: u16 = 0xffff;
+
 // Number of directories per block.
 pub const DPB: usize = BSIZE / size_of::<Dirent>();
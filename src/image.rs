@@ -0,0 +1,251 @@
+// Read-only access to an image apart from the mounted disk, e.g. for the
+// submounts of the daemon.
+//
+// An `Image` reads blocks straight from its own copy of the image, without
+// the caches and the log, which are those of the mounted disk only. The
+// transaction left in the log, if any, is installed in the copy when it is
+// opened, so the image reads as it would once mounted. Nothing is ever
+// written back.
+
+use disk::{BSIZE, Block, BlockDevice, Disk};
+use error::{Error, Result};
+use fs::{CHECKSUMS, DIRSIZE, Dirent, DiskInode, FileType, IENCRYPT, IPB,
+         LOGSIZE, LogHeader, MAXBLOCKS, NDIRECT, NINDIRECT, SuperBlock,
+         WHITEOUT};
+use std::cmp::min;
+use std::io;
+use std::mem::{size_of, transmute};
+use std::path::Path;
+
+pub struct Image {
+  disk: Disk,
+  sb: SuperBlock,
+}
+
+impl Image {
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad image");
+
+    if !path.as_ref().is_file() {
+      return Err(io::Error::new(io::ErrorKind::NotFound, "no such image"));
+    }
+    let mut disk = Disk::load_shared(path)?;
+    if disk.nblocks() < 2 {
+      return Err(invalid());
+    }
+    let sb = from_block!(&disk.read(1), SuperBlock);
+    if !sb.verify() || sb.nblocks as usize != disk.nblocks() ||
+      sb.log_start as usize + sb.nlogs as usize >= disk.nblocks()
+    {
+      return Err(invalid());
+    }
+
+    // Recover like `Logging::init` does, a torn commit being dropped.
+    let nlogs = sb.nlogs as usize;
+    let first = from_block!(&disk.read(sb.log_start as usize), LogHeader);
+    let nsegs = if first.n > 0 { first.more as usize + 1 } else { 0 };
+    let mut logged = vec![];
+    for i in 0..nsegs {
+      let start = sb.log_start as usize + i * LOGSIZE;
+      if i * LOGSIZE >= nlogs {
+        return Err(invalid());
+      }
+      let lh = if i == 0 {
+        first
+      } else {
+        from_block!(&disk.read(start), LogHeader)
+      };
+      if sb.flags & CHECKSUMS != 0 && !lh.verify() {
+        logged.clear();
+        break;
+      }
+      let n = min(lh.n as usize, min(LOGSIZE, nlogs - i * LOGSIZE) - 1);
+      let payload: Vec<Block> =
+        (0..n).map(|j| disk.read(start + j + 1)).collect();
+      if lh.blocks[..n].iter().any(|&b| b as usize >= disk.nblocks()) {
+        return Err(invalid());
+      }
+      if !lh.verify_payload(&payload) {
+        logged.clear();
+        break;
+      }
+      logged.extend(lh.blocks[..n].iter().cloned().zip(payload));
+    }
+    for (blockno, block) in logged {
+      disk.write(blockno as usize, &block);
+    }
+    Ok(Image { disk, sb })
+  }
+
+  fn block(&mut self, blockno: usize) -> Result<Block> {
+    if blockno == 0 || blockno >= self.disk.nblocks() {
+      return Err(Error::Corrupt);
+    }
+    Ok(self.disk.read(blockno))
+  }
+
+  // Return inode `inum`, NotFound if it is free.
+  pub fn inode(&mut self, inum: usize) -> Result<DiskInode> {
+    if inum == 0 || inum >= self.sb.ninodes as usize {
+      return Err(Error::NotFound);
+    }
+    let blockno = self.sb.iblock(inum);
+    let block = self.block(blockno)?;
+    let inodes: &[DiskInode; IPB] = unsafe { transmute(&block) };
+
+    if self.sb.flags & CHECKSUMS != 0 && !inodes[inum % IPB].verify() {
+      return Err(Error::Corrupt);
+    }
+    let dinode = inodes[inum % IPB].clone();
+    if dinode.file_type == FileType::None {
+      return Err(Error::NotFound);
+    }
+    Ok(dinode)
+  }
+
+  // Return the block holding byte `n * BSIZE` of `dinode`, 0 for a hole.
+  fn nth_block(&mut self, dinode: &DiskInode, n: usize) -> Result<usize> {
+    if n < NDIRECT {
+      return Ok(dinode.addrs[n] as usize);
+    }
+    if n < NDIRECT + NINDIRECT {
+      return self.entry(dinode.addrs[NDIRECT], n - NDIRECT);
+    }
+    if n >= MAXBLOCKS {
+      return Ok(0);
+    }
+    let n = n - NDIRECT - NINDIRECT;
+    let indirect = self.entry(dinode.dindirect, n / NINDIRECT)?;
+    self.entry(indirect as u32, n % NINDIRECT)
+  }
+
+  // Return the `i`th block in indirect block `indirect`, 0 for none.
+  fn entry(&mut self, indirect: u32, i: usize) -> Result<usize> {
+    if indirect == 0 {
+      return Ok(0);
+    }
+    let block = self.block(indirect as usize)?;
+    let a: &[u32; NINDIRECT] = unsafe { transmute(&block) };
+    Ok(a[i] as usize)
+  }
+
+  // Read up to `len` bytes at `offset` of file `inum`.
+  pub fn read(
+    &mut self,
+    inum: usize,
+    offset: usize,
+    len: usize,
+  ) -> Result<Vec<u8>> {
+    let dinode = self.inode(inum)?;
+    let size = dinode.size as usize;
+    let mut data = vec![];
+
+    if dinode.flags & IENCRYPT != 0 {
+      return Err(Error::NoKey);
+    }
+    let mut cur = offset;
+    while cur < min(offset + len, size) {
+      let n = min(BSIZE - cur % BSIZE, min(offset + len, size) - cur);
+      let blockno = self.nth_block(&dinode, cur / BSIZE)?;
+
+      if blockno == 0 {
+        data.extend_from_slice(&[0; BSIZE][..n]);
+      } else {
+        let block = self.block(blockno)?;
+        data.extend_from_slice(&block[cur % BSIZE..cur % BSIZE + n]);
+      }
+      cur += n;
+    }
+    Ok(data)
+  }
+
+  // Return the inode numbers and names of the entries of directory `inum`,
+  // including `.` and `..`.
+  pub fn readdir(
+    &mut self,
+    inum: usize,
+  ) -> Result<Vec<(usize, [u8; DIRSIZE])>> {
+    let dinode = self.inode(inum)?;
+    let mut entries = vec![];
+
+    if dinode.file_type != FileType::Directory {
+      return Err(Error::NotDir);
+    }
+    let data = self.read(inum, 0, dinode.size as usize)?;
+    for chunk in data.chunks(size_of::<Dirent>()) {
+      if chunk.len() < size_of::<Dirent>() {
+        break;
+      }
+      let dirent = from_block!(chunk, Dirent);
+      if dirent.inum != 0 && dirent.inum != WHITEOUT {
+        entries.push((dirent.inum as usize, dirent.name));
+      }
+    }
+    Ok(entries)
+  }
+
+  // Return the inode number of entry `name` of directory `dir`.
+  pub fn lookup(&mut self, dir: usize, name: &[u8; DIRSIZE]) -> Result<usize> {
+    let sb = self.sb;
+
+    self
+      .readdir(dir)?
+      .iter()
+      .find(|&&(_, ref entry)| sb.name_eq(entry, name))
+      .map(|&(inum, _)| inum)
+      .ok_or(Error::NotFound)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use disk::{BSIZE, DISK};
+  use error::Error;
+  use fs::{FileType, ROOTINO};
+  use image::Image;
+  use logging::LOGGING;
+  use ops;
+  use std::env;
+  use std::fs;
+  use std::process;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+    {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let d = ops::to_name(b"d").unwrap();
+      let f = ops::to_name(b"f").unwrap();
+
+      let dir = ops::create(&txn, &root, &d, FileType::Directory).unwrap();
+      let file = ops::create(&txn, &dir, &f, FileType::File).unwrap();
+      ops::write(&txn, &file, 0, &vec![7; 2 * BSIZE + 1]).unwrap();
+    }
+
+    // Read the image back apart from the mounted disk.
+    let path = env::temp_dir().join(format!("xv6fs-image-{}", process::id()));
+    let mut disk = DISK.unmount();
+    let mut data = vec![];
+    for i in 0..disk.nblocks() {
+      data.extend_from_slice(&disk.read(i));
+    }
+    fs::write(&path, data).unwrap();
+    testfs::test::mount();
+    let mut image = Image::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(path.with_extension("lock")).unwrap();
+
+    let dir = image.lookup(ROOTINO, &ops::to_name(b"d").unwrap()).unwrap();
+    let file = image.lookup(dir, &ops::to_name(b"f").unwrap()).unwrap();
+    assert!(image.readdir(dir).unwrap().len() == 3);
+    assert!(image.inode(dir).unwrap().file_type == FileType::Directory);
+    assert!(image.read(file, BSIZE, 2 * BSIZE).unwrap() == vec![7; BSIZE + 1]);
+    assert!(
+      image.lookup(ROOTINO, &ops::to_name(b"x").unwrap()).err() ==
+        Some(Error::NotFound)
+    );
+    assert!(image.readdir(file).err() == Some(Error::NotDir));
+  }
+}
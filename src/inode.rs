@@ -1,19 +1,31 @@
 use bitmap::Bitmap;
 use buffer::BCACHE;
-use disk::BSIZE;
-use fs::{DiskInode, FileType, IPB, ROOTINO, NDIRECT, NINDIRECT, MAXFILESIZE,
-         Dirent, DIRSIZE};
-use logging::{LOGGING, Transaction};
+use crypt;
+use delalloc;
+use disk::{BSIZE, Block, DISK};
+use error::{Error, Result};
+use fs::{CHECKSUMS, DiskInode, FileType, IORPHAN, IPB, IREADONLY, ROOTINO,
+         NDIRECT, NINDIRECT, NDINDIRECT, MAXBLOCKS, MAXFILESIZE, Dirent,
+         DIRSIZE, WHITEOUT, now};
+use logging::{self, LOGGING, Transaction};
+use memory::{Account, MEMORY};
+use readahead::Window;
+use reclaim;
+use refcount;
+use std::cell::RefCell;
 use std::cmp::min;
 use std::collections::HashMap;
+use std::io::{IoSlice, IoSliceMut};
 use std::mem::{transmute, size_of};
 use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use util::locked::{LockedItem, UnlockedItem, UnlockedDrop};
 
 pub struct Inode {
   inode: Option<DiskInode>,
   no: usize,
+  window: Window,
 }
 
 impl Deref for Inode {
@@ -37,21 +49,146 @@ pub type LockedInode<'a> = LockedItem<'a, Inode, usize /* inodeno */>;
 pub type UnlockedInode = UnlockedItem<Inode, usize /* inodeno */>;
 
 pub struct Cache {
-  capacity: usize,
+  capacity: AtomicUsize,
   cache: Mutex<HashMap<usize, UnlockedInode>>,
+  // None until `init_free`, when `alloc` scans from the start and
+  // `count_free` counts.
+  free: Mutex<Option<Free>>,
+}
+
+// The free inodes, counted at mount and kept up to date since, and the
+// inode `alloc` looks at first, past the one it last allocated or the
+// lowest freed since, so that it rarely scans the inode table.
+struct Free {
+  nfree: usize,
+  next: usize,
 }
 
 lazy_static! {
   pub static ref ICACHE: Cache = Cache::new(256);
 }
 
+thread_local! {
+  // Inodes whose last reference was dropped in a read transaction, see
+  // `Logging::new_read_txn`.
+  static DEFERRED: RefCell<Vec<UnlockedInode>> = RefCell::new(vec![]);
+}
+
+// Return block number `blockno`, as found on the disk, unless it is past
+// the end of the file system, which only a corrupt image has.
+fn checked(blockno: u32) -> Result<usize> {
+  if blockno >= BCACHE.sb().nblocks {
+    error!("block number {} out of range", blockno);
+    return Err(Error::Corrupt);
+  }
+  Ok(blockno as usize)
+}
+
+// Return the block numbers in `indirect`, an indirect block, all 0 if there
+// is none.
+fn entries<'a>(
+  txn: &Transaction<'a>,
+  indirect: u32,
+) -> Result<[u32; NINDIRECT]> {
+  if indirect == 0 {
+    return Ok([0; NINDIRECT]);
+  }
+  let buf = txn.read(checked(indirect)?).unwrap();
+  Ok(unsafe { transmute(buf.data) })
+}
+
+fn entry<'a>(txn: &Transaction<'a>, indirect: u32, i: usize) -> Result<u32> {
+  Ok(entries(txn, indirect)?[i])
+}
+
+// Like `entry`, but the block `alloc` returns is put there if there is none.
+fn alloc_entry<'a, F>(
+  txn: &Transaction<'a>,
+  indirect: u32,
+  i: usize,
+  alloc: F,
+) -> Result<usize>
+where
+  F: FnOnce() -> Result<usize>,
+{
+  let mut buf = txn.read(checked(indirect)?).unwrap();
+  let a: &mut [u32; NINDIRECT] = unsafe { transmute(&mut buf.data) };
+
+  if a[i] == 0 {
+    a[i] = alloc()? as u32;
+    txn.write(&mut buf);
+  }
+  checked(a[i])
+}
+
+fn set_entry<'a>(
+  txn: &Transaction<'a>,
+  indirect: u32,
+  i: usize,
+  blockno: usize,
+) {
+  let mut buf = txn.read(indirect as usize).unwrap();
+  let a: &mut [u32; NINDIRECT] = unsafe { transmute(&mut buf.data) };
+
+  a[i] = blockno as u32;
+  txn.write(&mut buf);
+}
+
+// Return true if no block is left in `indirect`, a valid indirect block.
+fn is_empty<'a>(txn: &Transaction<'a>, indirect: u32) -> bool {
+  match checked(indirect) {
+    Ok(indirect) => {
+      let buf = txn.read(indirect).unwrap();
+      let a: &[u32; NINDIRECT] = unsafe { transmute(&buf.data) };
+      a.iter().all(|&blockno| blockno == 0)
+    },
+    Err(_) => false,
+  }
+}
+
+// Drop the data blocks in `indirect`, an indirect block, or those in the
+// indirect blocks in it if `double`, into `freed` like `free_blocks`, and
+// free the indirect blocks themselves.
+fn free_indirect<'a>(
+  txn: &Transaction<'a>,
+  indirect: u32,
+  double: bool,
+  freed: &mut Vec<usize>,
+) {
+  if indirect == 0 {
+    return;
+  }
+  let indirect = match checked(indirect) {
+    Ok(indirect) => indirect,
+    Err(_) => return,
+  };
+  let buf = txn.read(indirect).unwrap();
+  let a: &[u32; NINDIRECT] = unsafe { transmute(&buf.data) };
+
+  for &blockno in a.iter().filter(|&&blockno| blockno != 0) {
+    if double {
+      free_indirect(txn, blockno, false, freed);
+    } else if let Ok(blockno) = checked(blockno) {
+      if refcount::release(txn, blockno) {
+        freed.push(blockno);
+      }
+    }
+  }
+  Bitmap::free(txn, indirect);
+}
+
 impl Inode {
   fn new(no: usize) -> Self {
-    Inode { inode: None, no }
+    Inode {
+      inode: None,
+      no,
+      window: Window::default(),
+    }
   }
 
   fn clear(&mut self) {
     self.inode = None;
+    self.window = Window::default();
   }
 
   pub fn as_directory<'a>(&'a mut self) -> Directory<'a> {
@@ -62,109 +199,620 @@ impl Inode {
     Directory { inode: self }
   }
 
-  // Update the disk copy of this inode.
-  pub fn update<'a>(&self, txn: &Transaction<'a>) {
+  // Update the disk copy of this inode, which changed now.
+  pub fn update<'a>(&mut self, txn: &Transaction<'a>) {
     assert!(self.inode.is_some());
+    let inode = self.inode.as_mut().unwrap();
+
+    let (sec, nsec) = now();
+    inode.ctime = sec;
+    inode.ctime_nsec = nsec;
+    self.store(txn);
+  }
+
+  // Like `update`, but without changing its ctime, as it did not.
+  fn store<'a>(&mut self, txn: &Transaction<'a>) {
     let sb = BCACHE.sb();
     let mut buf = txn.read(sb.iblock(self.no)).unwrap();
     let inodes: &mut [DiskInode; IPB] = unsafe { transmute(&mut buf.data) };
+    let inode = self.inode.as_mut().unwrap();
 
-    inodes[self.no % IPB] = self.inode.as_ref().unwrap().clone();
+    inode.seal();
+    inodes[self.no % IPB] = inode.clone();
     txn.write(&mut buf);
   }
 
-  // Return the blockno of this inode's nth block.
+  // Return the blockno of this inode's nth block, or None if it is past
+  // the largest file. Corrupt if a block number on the disk is invalid.
   pub fn nth_block<'a>(
     &mut self,
     txn: &Transaction<'a>,
     n: usize,
-  ) -> Option<usize> {
+  ) -> Result<Option<usize>> {
     assert!(self.inode.is_some());
+    if let Some(blockno) = self.mapped_block(txn, n)? {
+      return Ok(Some(blockno));
+    }
+    let goal = self.goal(txn, n)?;
+
+    self.map_block(txn, n, goal, None)
+  }
+
+  // Allocate this inode's nth block, which is not yet, near `goal`, or
+  // point it to `fresh` if some, along with the indirect blocks on the way.
+  fn map_block<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    n: usize,
+    goal: usize,
+    fresh: Option<usize>,
+  ) -> Result<Option<usize>> {
     let inode = self.inode.as_mut().unwrap();
+    let near = || Bitmap::alloc_near(txn, goal);
+    let data = || fresh.map_or_else(near, Ok);
 
     if n < NDIRECT {
-      if inode.addrs[n] == 0 {
-        inode.addrs[n] = Bitmap::alloc(txn) as u32;
-      }
-      return Some(inode.addrs[n] as usize);
+      inode.addrs[n] = data()? as u32;
+      return checked(inode.addrs[n]).map(Some);
     }
     let n = n - NDIRECT;
     if n < NINDIRECT {
       if inode.addrs[NDIRECT] == 0 {
-        inode.addrs[NDIRECT] = Bitmap::alloc(txn) as u32;
+        inode.addrs[NDIRECT] = near()? as u32;
       }
-      let mut buf = txn.read(inode.addrs[NDIRECT] as usize).unwrap();
-      let a: &mut [u32; NINDIRECT] = unsafe { transmute(&mut buf.data) };
-      if a[n] == 0 {
-        a[n] = Bitmap::alloc(txn) as u32;
+      return alloc_entry(txn, inode.addrs[NDIRECT], n, data).map(Some);
+    }
+    let n = n - NINDIRECT;
+    if n < NDINDIRECT {
+      if inode.dindirect == 0 {
+        inode.dindirect = near()? as u32;
+      }
+      let indirect = alloc_entry(txn, inode.dindirect, n / NINDIRECT, near)?;
+      return alloc_entry(txn, indirect as u32, n % NINDIRECT, data).map(Some);
+    }
+    Ok(None)
+  }
+
+  // Allocate the blocks of bytes `offset..offset + n` that are not yet, in
+  // a row, if there are a few and a group has room for them in a row, see
+  // Bitmap::alloc_contiguous. Return which of this inode's blocks they are.
+  fn alloc_run<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    offset: usize,
+    n: usize,
+  ) -> Result<Vec<usize>> {
+    let mut missing = vec![];
+
+    for bn in offset / BSIZE..(offset + n + BSIZE - 1) / BSIZE {
+      if self.mapped_block(txn, bn)?.is_none() && !self.delays(bn) {
+        missing.push(bn);
+      }
+    }
+    if missing.len() < 2 {
+      return Ok(vec![]);
+    }
+    let goal = self.goal(txn, missing[0])?;
+    let first = match Bitmap::alloc_contiguous(txn, goal, missing.len()) {
+      Some(first) => first,
+      None => return Ok(vec![]),
+    };
+    for i in 0..missing.len() {
+      let blockno = first + i;
+
+      // Short of space for an indirect block, the rest of the run goes
+      // back, and those mapped are filled, see `write`.
+      match self.map_block(txn, missing[i], blockno, Some(blockno)) {
+        Ok(_) => (),
+        Err(Error::NoSpace) => {
+          for blockno in blockno..first + missing.len() {
+            Bitmap::free(txn, blockno);
+          }
+          missing.truncate(i);
+          break;
+        },
+        Err(e) => return Err(e),
       }
+    }
+    Ok(missing)
+  }
+
+  // Whether writing the `n`th block of this inode, not allocated yet,
+  // leaves its allocation to the commit, see delalloc.rs. Only direct
+  // blocks are, which take no indirect block along.
+  fn delays(&self, n: usize) -> bool {
+    n < NDIRECT && self.addrs[n] == 0 && self.file_type == FileType::File &&
+      !self.is_encrypted() && delalloc::is_started()
+  }
+
+  // Allocate at most `max` of the delayed blocks of this inode, in a row
+  // if a group has room for them, and write them. Those that find no room
+  // are delayed again, and those whose slot was mapped meanwhile, which
+  // reads hid already, are dropped. Return how many were allocated.
+  pub fn alloc_delayed<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    max: usize,
+  ) -> usize {
+    let mut blocks = delalloc::take(self.no, max);
+    blocks.retain(|&(bn, _)| self.addrs[bn] == 0);
+    let first = if blocks.len() > 1 {
+      let goal = self.goal(txn, blocks[0].0).ok();
+      goal.and_then(|goal| Bitmap::alloc_contiguous(txn, goal, blocks.len()))
+    } else {
+      None
+    };
+    let mut n = 0;
+
+    while n < blocks.len() {
+      let (bn, ref data) = blocks[n];
+      let blockno = match first {
+        Some(first) => first + n,
+        None => {
+          let goal = self.goal(txn, bn);
+          match goal.and_then(|goal| Bitmap::alloc_near(txn, goal)) {
+            Ok(blockno) => blockno,
+            Err(_) => break,
+          }
+        },
+      };
+      let mut buf = txn.read(blockno).unwrap();
+
+      buf.data = *data;
       txn.write(&mut buf);
+      self.addrs[bn] = blockno as u32;
+      n += 1;
     }
-    None
+    if n > 0 {
+      self.store(txn);
+    }
+    delalloc::restore(self.no, blocks.split_off(n));
+    n
   }
 
-  // Free all blocks of this inode.
+  // Where to allocate the `n`th block of this inode: just past the block
+  // before it, or else in the group of the inode, see Bitmap::goal.
+  fn goal<'a>(&self, txn: &Transaction<'a>, n: usize) -> Result<usize> {
+    let prev = if n > 0 {
+      self.mapped_block(txn, n - 1)?
+    } else {
+      None
+    };
+
+    Ok(prev.map_or_else(|| Bitmap::goal(self.no), |blockno| blockno + 1))
+  }
+
+  // Return the blockno of this inode's nth block, or None if it is not
+  // allocated yet. Corrupt if a block number on the disk is invalid.
+  pub fn mapped_block<'a>(
+    &self,
+    txn: &Transaction<'a>,
+    n: usize,
+  ) -> Result<Option<usize>> {
+    assert!(self.inode.is_some());
+    let inode = self.inode.as_ref().unwrap();
+    let blockno = if n < NDIRECT {
+      inode.addrs[n]
+    } else if n < NDIRECT + NINDIRECT {
+      entry(txn, inode.addrs[NDIRECT], n - NDIRECT)?
+    } else if n < MAXBLOCKS {
+      let n = n - NDIRECT - NINDIRECT;
+      let indirect = entry(txn, inode.dindirect, n / NINDIRECT)?;
+      entry(txn, indirect, n % NINDIRECT)?
+    } else {
+      0
+    };
+
+    if blockno == 0 {
+      Ok(None)
+    } else {
+      checked(blockno).map(Some)
+    }
+  }
+
+  // Return the blocknos of all allocated data blocks of this inode, not
+  // including the indirect blocks.
+  pub fn data_blocks<'a>(&self, txn: &Transaction<'a>) -> Result<Vec<usize>> {
+    let mut result = vec![];
+
+    for n in 0..NDIRECT + NINDIRECT {
+      result.extend(self.mapped_block(txn, n)?);
+    }
+    // Those under the doubly indirect block an indirect block at a time.
+    let dindirect = self.inode.as_ref().unwrap().dindirect;
+    for &indirect in entries(txn, dindirect)?.iter() {
+      for &blockno in entries(txn, indirect)?.iter() {
+        if blockno != 0 {
+          result.push(checked(blockno)?);
+        }
+      }
+    }
+    Ok(result)
+  }
+
+  // Return the blocknos of the indirect blocks of this inode, the doubly
+  // indirect one included.
+  pub fn indirect_blocks<'a>(
+    &self,
+    txn: &Transaction<'a>,
+  ) -> Result<Vec<usize>> {
+    let inode = self.inode.as_ref().unwrap();
+    let mut result = vec![];
+
+    for &blockno in &[inode.addrs[NDIRECT], inode.dindirect] {
+      if blockno != 0 {
+        result.push(checked(blockno)?);
+      }
+    }
+    for &indirect in entries(txn, inode.dindirect)?.iter() {
+      if indirect != 0 {
+        result.push(checked(indirect)?);
+      }
+    }
+    Ok(result)
+  }
+
+  // Like `nth_block`, but the block is about to be written, so a block
+  // shared with other inodes is replaced by a private copy first.
+  fn nth_block_cow<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    n: usize,
+  ) -> Result<Option<usize>> {
+    let blockno = match self.nth_block(txn, n)? {
+      Some(blockno) => blockno,
+      None => return Ok(None),
+    };
+
+    if refcount::get(txn, blockno) == 0 {
+      return Ok(Some(blockno));
+    }
+
+    let copy = Bitmap::alloc_near(txn, self.goal(txn, n)?)?;
+    let data = txn.read(blockno).unwrap().data;
+    let mut buf = txn.read(copy).unwrap();
+
+    buf.data = data;
+    txn.write(&mut buf);
+    refcount::release(txn, blockno);
+    self.set_nth_block(txn, n, copy);
+    Ok(Some(copy))
+  }
+
+  // Point this inode's nth block, which must be allocated, to `blockno`.
+  // The caller takes care of the old block.
+  pub fn set_nth_block<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    n: usize,
+    blockno: usize,
+  ) {
+    assert!(self.mapped_block(txn, n).unwrap_or(None).is_some());
+    let inode = self.inode.as_mut().unwrap();
+
+    if n < NDIRECT {
+      inode.addrs[n] = blockno as u32;
+      self.update(txn);
+    } else if n < NDIRECT + NINDIRECT {
+      set_entry(txn, inode.addrs[NDIRECT], n - NDIRECT, blockno);
+    } else {
+      let n = n - NDIRECT - NINDIRECT;
+      let indirect = entry(txn, inode.dindirect, n / NINDIRECT);
+
+      set_entry(txn, indirect.unwrap(), n % NINDIRECT, blockno);
+    }
+  }
+
+  // Point the indirect blocks of this inode at `blockno` to `spare`, a copy
+  // of it, like `set_nth_block` does the data blocks.
+  pub fn remap_indirect<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    blockno: usize,
+    spare: usize,
+  ) -> Result<()> {
+    let inode = self.inode.as_mut().unwrap();
+    let mut changed = false;
+
+    if inode.addrs[NDIRECT] as usize == blockno {
+      inode.addrs[NDIRECT] = spare as u32;
+      changed = true;
+    }
+    if inode.dindirect as usize == blockno {
+      inode.dindirect = spare as u32;
+      changed = true;
+    }
+    let dindirect = inode.dindirect;
+    for (i, &indirect) in entries(txn, dindirect)?.iter().enumerate() {
+      if indirect as usize == blockno {
+        set_entry(txn, dindirect, i, spare);
+      }
+    }
+    if changed {
+      self.update(txn);
+    }
+    Ok(())
+  }
+
+  // Point this inode's nth block to `blockno`, a data block of another
+  // inode, which is shared from then on. The block it had is dropped.
+  pub fn share_block<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    n: usize,
+    blockno: usize,
+  ) -> Result<()> {
+    let old = self.mapped_block(txn, n)?;
+
+    if old == Some(blockno) {
+      return Ok(());
+    }
+    refcount::inc(txn, blockno)?;
+    match old {
+      Some(old) => {
+        self.set_nth_block(txn, n, blockno);
+        if refcount::release(txn, old) {
+          if self.inode.as_ref().unwrap().is_shredded() {
+            Bitmap::shred(&[old]);
+          }
+          Bitmap::free(txn, old);
+        }
+      },
+      // The slot is allocated like any other first, along with the
+      // indirect block if need be. A block written there but delayed is
+      // overwritten.
+      None => {
+        delalloc::discard_block(self.no, n);
+        let fresh = self.nth_block(txn, n)?.unwrap();
+        self.set_nth_block(txn, n, blockno);
+        Bitmap::free(txn, fresh);
+      },
+    }
+    self.update(txn);
+    Ok(())
+  }
+
+  // Free all blocks of this inode. Shared data blocks just lose a
+  // reference. The data blocks of an ISHRED inode are zeroed first.
   pub fn free_blocks<'a>(&mut self, txn: &Transaction<'a>) {
     assert!(self.inode.is_some());
     let inode = self.inode.as_mut().unwrap();
+    let mut freed = vec![];
 
+    delalloc::discard(self.no, 0);
+    // Invalid block numbers are dropped, their blocks are nobody's.
     for i in 0..NDIRECT {
       if inode.addrs[i] != 0 {
-        Bitmap::free(txn, inode.addrs[i] as usize);
+        if let Ok(blockno) = checked(inode.addrs[i]) {
+          if refcount::release(txn, blockno) {
+            freed.push(blockno);
+          }
+        }
         inode.addrs[i] = 0;
       }
     }
-    if inode.addrs[NDIRECT] != 0 {
-      let mut buf = txn.read(inode.addrs[NDIRECT] as usize).unwrap();
+    free_indirect(txn, inode.addrs[NDIRECT], false, &mut freed);
+    inode.addrs[NDIRECT] = 0;
+    free_indirect(txn, inode.dindirect, true, &mut freed);
+    inode.dindirect = 0;
+
+    if inode.is_shredded() {
+      Bitmap::shred(&freed);
+    }
+    for blockno in freed {
+      Bitmap::free(txn, blockno);
+    }
+  }
+
+  // Free at most `max` of the data blocks of this inode from the `first`th
+  // on, the last ones first, like `free_blocks`, and the indirect blocks
+  // once none is left under them. Return how many were freed.
+  pub fn free_last_blocks<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    first: usize,
+    max: usize,
+  ) -> usize {
+    let mut freed = vec![];
+    let mut count = self.free_last_dindirect(txn, first, max, &mut freed);
+    let mut n = NDIRECT + NINDIRECT;
+
+    while n > first && count < max {
+      n -= 1;
+      if let Ok(Some(blockno)) = self.mapped_block(txn, n) {
+        self.set_nth_block(txn, n, 0);
+        if refcount::release(txn, blockno) {
+          freed.push(blockno);
+        }
+        count += 1;
+      }
+    }
+    let indirect = self.inode.as_ref().unwrap().addrs[NDIRECT];
+    if indirect != 0 && is_empty(txn, indirect) {
+      freed.push(indirect as usize);
+      self.inode.as_mut().unwrap().addrs[NDIRECT] = 0;
+      self.update(txn);
+    }
+
+    if self.inode.as_ref().unwrap().is_shredded() {
+      Bitmap::shred(&freed);
+    }
+    for blockno in freed {
+      Bitmap::free(txn, blockno);
+    }
+    count
+  }
+
+  // Drop at most `max` of the data blocks under the doubly indirect block
+  // from the `first`th on into `freed`, like `free_last_blocks`. Indirect
+  // blocks left empty go without being written, so that truncating a large
+  // file does not write every one of them in the transaction.
+  fn free_last_dindirect<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    first: usize,
+    max: usize,
+    freed: &mut Vec<usize>,
+  ) -> usize {
+    let dindirect = self.inode.as_ref().unwrap().dindirect;
+    if dindirect == 0 {
+      return 0;
+    }
+    let dindirect = match checked(dindirect) {
+      Ok(dindirect) => dindirect,
+      Err(_) => return 0,
+    };
+    let mut dbuf = txn.read(dindirect).unwrap();
+    let d: &mut [u32; NINDIRECT] = unsafe { transmute(&mut dbuf.data) };
+    let mut count = 0;
+    let mut i = NINDIRECT;
+
+    while i > 0 && count < max {
+      i -= 1;
+      let base = NDIRECT + NINDIRECT + i * NINDIRECT;
+      if base + NINDIRECT <= first {
+        break;
+      }
+      if d[i] == 0 {
+        continue;
+      }
+      let indirect = match checked(d[i]) {
+        Ok(indirect) => indirect,
+        Err(_) => continue,
+      };
+      let mut buf = txn.read(indirect).unwrap();
       let a: &mut [u32; NINDIRECT] = unsafe { transmute(&mut buf.data) };
-      for i in 0..NINDIRECT {
-        if a[i] != 0 {
-          Bitmap::free(txn, a[i] as usize);
-          a[i] = 0;
+      let mut j = NINDIRECT;
+
+      while j > 0 && base + j > first && count < max {
+        j -= 1;
+        if a[j] == 0 {
+          continue;
+        }
+        if let Ok(blockno) = checked(a[j]) {
+          a[j] = 0;
+          if refcount::release(txn, blockno) {
+            freed.push(blockno);
+          }
+          count += 1;
         }
       }
-      Bitmap::free(txn, inode.addrs[NDIRECT] as usize);
-      inode.addrs[NDIRECT] = 0;
+      if a.iter().all(|&blockno| blockno == 0) {
+        freed.push(indirect);
+        d[i] = 0;
+      } else {
+        txn.write(&mut buf);
+      }
+    }
+    if d.iter().all(|&blockno| blockno == 0) {
+      freed.push(dindirect);
+      self.inode.as_mut().unwrap().dindirect = 0;
+      self.update(txn);
+    } else {
+      txn.write(&mut dbuf);
     }
+    count
   }
 
   pub fn read<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    offset: usize,
+    n: usize,
+  ) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(n);
+
+    self.read_with(txn, offset, n, |chunk| result.extend_from_slice(chunk))?;
+    Ok(result)
+  }
+
+  // Like `read`, into `buf` rather than a new Vec, as much of it as the
+  // file fills from `offset`. Return how many bytes were read.
+  pub fn read_into<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    offset: usize,
+    buf: &mut [u8],
+  ) -> Result<usize> {
+    let mut done = 0;
+
+    self.read_with(txn, offset, buf.len(), |chunk| {
+      buf[done..done + chunk.len()].copy_from_slice(chunk);
+      done += chunk.len();
+    })
+  }
+
+  // Like `read`, but call `f` with each piece of the content in turn, the
+  // cached block itself unless it is encrypted, rather than gathering them
+  // in a new Vec. Return how many bytes `f` was given. On an error, it may
+  // have been given some already.
+  pub fn read_with<'a, F>(
     &mut self,
     txn: &Transaction<'a>,
     offset: usize,
     mut n: usize,
-  ) -> Option<Vec<u8>> {
+    mut f: F,
+  ) -> Result<usize>
+  where
+    F: FnMut(&[u8]),
+  {
     assert!(self.inode.is_some());
     let inode_size = self.inode.as_ref().unwrap().size;
 
     if offset > inode_size as usize || offset.saturating_add(n) != offset + n ||
       offset + n > MAXFILESIZE
     {
-      return None;
+      return Err(Error::Invalid);
     }
     if offset + n > inode_size as usize {
       n = inode_size as usize - offset;
     }
+    if !crypt::apply(self, offset, &mut []) {
+      return Err(Error::NoKey);
+    }
 
-    let mut result = Vec::with_capacity(n);
     let mut cur_offset = offset;
     let mut got = 0;
 
+    // Holes read as zeros, without allocating, so that reads never write,
+    // even those of encrypted files.
     while got < n {
-      let buf = txn
-        .read(self.nth_block(txn, cur_offset / BSIZE).unwrap())
-        .unwrap()
-        .data;
       let from = cur_offset % BSIZE;
       let m = min(n - got, BSIZE - from);
-
-      for i in from..(from + m) {
-        result.push(buf[i]);
+      match self.mapped_block(txn, cur_offset / BSIZE)? {
+        Some(blockno) => {
+          let buf = txn.read(blockno).unwrap();
+          if buf.is_corrupt() {
+            return Err(Error::Io);
+          }
+          if self.is_encrypted() {
+            let mut data = buf.data;
+            crypt::apply(self, cur_offset, &mut data[from..from + m]);
+            f(&data[from..from + m]);
+          } else {
+            f(&buf.data[from..from + m]);
+          }
+        },
+        // Unless it is delayed, see delalloc.rs.
+        None => match delalloc::read(self.no, cur_offset / BSIZE) {
+          Some(block) => f(&block[from..from + m]),
+          None => f(&[0; BSIZE][..m]),
+        },
       }
       got += m;
       cur_offset += m;
     }
-    Some(result)
+    // Read on sequentially, have the following blocks on their way.
+    let nblocks = (inode_size as usize + BSIZE - 1) / BSIZE;
+    let blocknos = self
+      .window
+      .access(offset, n, BCACHE.readahead_limit())
+      .into_iter()
+      .filter(|&i| i < nblocks)
+      .filter_map(|i| self.mapped_block(txn, i).unwrap_or(None))
+      .collect();
+    BCACHE.readahead(blocknos);
+    Ok(n)
   }
 
   pub fn write<'a>(
@@ -172,27 +820,60 @@ impl Inode {
     txn: &Transaction<'a>,
     offset: usize,
     data: &[u8],
-  ) -> Option<usize> {
+  ) -> Result<usize> {
     assert!(self.inode.is_some());
     let inode_size = self.inode.as_ref().unwrap().size as usize;
     let n = data.len();
 
-    if offset > inode_size || offset.saturating_add(n) != offset + n ||
-      offset + n > MAXFILESIZE
-    {
-      return None;
+    // Past the end is fine, the blocks in between left as holes. The rest
+    // of the block the file ended in reads as zeros already, see
+    // `truncate`.
+    if offset.saturating_add(n) != offset + n || offset + n > MAXFILESIZE {
+      return Err(Error::Invalid);
     }
 
+    let mut encrypted;
+    let data = if self.is_encrypted() {
+      encrypted = data.to_vec();
+      if !crypt::apply(self, offset, &mut encrypted) {
+        return Err(Error::NoKey);
+      }
+      &encrypted[..]
+    } else {
+      data
+    };
+
+    let fresh = self.alloc_run(txn, offset, n)?;
     let mut cur_offset = offset;
     let mut written = 0;
 
     while written < n {
-      let mut buf = txn
-        .read(self.nth_block(txn, cur_offset / BSIZE).unwrap())
-        .unwrap();
+      let bn = cur_offset / BSIZE;
       let from = cur_offset % BSIZE;
       let m = min(n - written, BSIZE - from);
 
+      if self.delays(bn) &&
+        delalloc::write(self.no, bn, from, &data[written..written + m])
+      {
+        written += m;
+        cur_offset += m;
+        continue;
+      }
+      let hole = self.is_encrypted() &&
+        (fresh.contains(&bn) || self.mapped_block(txn, bn)?.is_none());
+      let blockno = match self.nth_block_cow(txn, bn) {
+        Ok(blockno) => blockno.unwrap(),
+        // Short of space, as much as fits is written.
+        Err(Error::NoSpace) if written > 0 => break,
+        Err(e) => return Err(e),
+      };
+      let mut buf = txn.read(blockno).unwrap();
+
+      // The rest of a hole filled still reads as zeros, see `read`.
+      if hole {
+        crypt::apply(self, bn * BSIZE, &mut buf.data);
+      }
+
       for i in from..(from + m) {
         buf.data[i] = data[i - from + written];
       }
@@ -201,11 +882,88 @@ impl Inode {
       cur_offset += m;
     }
 
-    if written > 0 && cur_offset > inode_size as usize {
-      self.inode.as_mut().unwrap().size = cur_offset as u32;
+    if written > 0 {
+      let inode = self.inode.as_mut().unwrap();
+
+      if cur_offset > inode_size {
+        inode.size = cur_offset as u32;
+      }
+      inode.touch();
       self.update(txn);
     }
-    Some(written)
+    Ok(written)
+  }
+
+  // Like `read_into`, filling `bufs` one after the other, as readv(2) does,
+  // under one lock of the inode. Return how many bytes were read in all.
+  pub fn read_vectored<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    offset: usize,
+    bufs: &mut [IoSliceMut],
+  ) -> Result<usize> {
+    let mut done = 0;
+
+    for buf in bufs.iter_mut() {
+      let n = self.read_into(txn, offset + done, buf)?;
+      done += n;
+      if n < buf.len() {
+        break;
+      }
+    }
+    Ok(done)
+  }
+
+  // Like `write`, writing `bufs` one after the other, as writev(2) does, in
+  // the same transaction. On an error, some may be written already.
+  pub fn write_vectored<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    offset: usize,
+    bufs: &[IoSlice],
+  ) -> Result<usize> {
+    let mut done = 0;
+
+    for buf in bufs {
+      let n = self.write(txn, offset + done, buf)?;
+      done += n;
+      if n < buf.len() {
+        break;
+      }
+    }
+    Ok(done)
+  }
+
+  // Set the size of this inode to `size`, freeing the blocks past it, or
+  // leaving a hole up to it.
+  pub fn truncate<'a>(
+    &mut self,
+    txn: &Transaction<'a>,
+    size: usize,
+  ) -> Result<()> {
+    assert!(self.inode.is_some());
+    if size > MAXFILESIZE {
+      return Err(Error::Invalid);
+    }
+    let end = min(size, self.inode.as_ref().unwrap().size as usize);
+
+    // The rest of the block the file ends in is zeroed as well, so that it
+    // reads as zeros once the file grows again.
+    if end % BSIZE != 0 &&
+      (self.mapped_block(txn, end / BSIZE)?.is_some() ||
+         delalloc::read(self.no, end / BSIZE).is_some())
+    {
+      self.write(txn, end, &[0; BSIZE][end % BSIZE..])?;
+    }
+    let first = (size + BSIZE - 1) / BSIZE;
+    self.free_last_blocks(txn, first, MAXBLOCKS);
+    delalloc::discard(self.no, first);
+    let inode = self.inode.as_mut().unwrap();
+
+    inode.size = size as u32;
+    inode.touch();
+    self.update(txn);
+    Ok(())
   }
 }
 
@@ -214,21 +972,47 @@ impl<'a> Directory<'a> {
     self.inode.inode.as_ref().unwrap()
   }
 
-  // Enumerate all entries of this folder. Return inode and file name.
-  pub fn enumerate<'b>(
+  // Call `f` with the offset of every dirent of this folder in turn, until
+  // it returns true. Return that offset. Corrupt if a dirent refers to an
+  // inode past the last one.
+  fn visit<'b, F>(
     &mut self,
     txn: &Transaction<'b>,
-  ) -> Vec<(UnlockedInode, [u8; DIRSIZE])> {
-    let nentries = self.inode().size as usize / size_of::<Dirent>();
-    let mut result = vec![];
-    let mut cur_index = 0;
+    f: F,
+  ) -> Result<Option<usize>>
+  where
+    F: FnMut(usize, &Dirent) -> bool,
+  {
+    self.visit_from(txn, 0, f)
+  }
 
+  // Likewise from the dirent at byte offset `from`.
+  fn visit_from<'b, F>(
+    &mut self,
+    txn: &Transaction<'b>,
+    from: usize,
+    mut f: F,
+  ) -> Result<Option<usize>>
+  where
+    F: FnMut(usize, &Dirent) -> bool,
+  {
+    let ninodes = BCACHE.sb().ninodes;
+    let nentries = self.inode().size as usize / size_of::<Dirent>();
+    let nblocks = (self.inode().size as usize + BSIZE - 1) / BSIZE;
+    let mut cur_index = (from + size_of::<Dirent>() - 1) / size_of::<Dirent>();
+
+    // The blocks are read one after the other, have the following ones on
+    // their way meanwhile.
+    if nblocks > 1 {
+      BCACHE.readahead(
+        (cur_index * size_of::<Dirent>() / BSIZE + 1..nblocks)
+          .filter_map(|n| self.inode.mapped_block(txn, n).unwrap_or(None))
+          .collect(),
+      );
+    }
     while cur_index < nentries {
       let m = min((nentries - cur_index) * size_of::<Dirent>(), BSIZE);
-      let buf = self
-        .inode
-        .read(txn, cur_index * size_of::<Dirent>(), m)
-        .unwrap();
+      let buf = self.inode.read(txn, cur_index * size_of::<Dirent>(), m)?;
 
       assert!(buf.len() == m);
       assert!(m % size_of::<Dirent>() == 0);
@@ -236,123 +1020,387 @@ impl<'a> Directory<'a> {
       for i in 0..(m / size_of::<Dirent>()) {
         let ent: &Dirent =
           unsafe { &*(buf.as_slice().as_ptr() as *const Dirent).add(i) };
+        let offset = (cur_index + i) * size_of::<Dirent>();
 
-        if ent.inum != 0 {
-          result.push((ICACHE.get(ent.inum as usize).unwrap(), ent.name));
+        if ent.inum != WHITEOUT && ent.inum as u32 >= ninodes {
+          error!("dirent of inode {} out of range", ent.inum);
+          return Err(Error::Corrupt);
+        }
+        if f(offset, ent) {
+          return Ok(Some(offset));
         }
       }
       cur_index += m / size_of::<Dirent>();
     }
-    result
+    Ok(None)
   }
 
-  // Return true if this directory is empty regardless `.` and `..`.
-  pub fn is_empty<'b>(&mut self, txn: &Transaction<'b>) -> bool {
-    self.enumerate(txn).len() == 2
+  // Enumerate all entries of this folder. Return inode and file name.
+  pub fn enumerate<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+  ) -> Result<Vec<(UnlockedInode, [u8; DIRSIZE])>> {
+    let mut found = vec![];
+
+    self.visit(txn, |_, ent| {
+      if ent.inum != 0 && ent.inum != WHITEOUT {
+        found.push((ent.inum as usize, ent.name));
+      }
+      false
+    })?;
+    let mut result = vec![];
+    for (inum, name) in found {
+      ICACHE.verify(txn, inum)?;
+      result.push((ICACHE.get(inum).unwrap(), name));
+    }
+    Ok(result)
   }
 
-  pub fn lookup<'b>(
+  // Return the entries of this folder from byte offset `from` on, each with
+  // its inode number and the offset of the dirent after it, where to go on
+  // from. Unlike `enumerate`, no inode is referenced.
+  pub fn entries<'b>(
     &mut self,
     txn: &Transaction<'b>,
-    name: &[u8; DIRSIZE],
-  ) -> Option<(UnlockedInode, usize)> {
-    let nentries = self.inode().size as usize / size_of::<Dirent>();
-    let mut cur_index = 0;
-
-    while cur_index < nentries {
-      let m = min((nentries - cur_index) * size_of::<Dirent>(), BSIZE);
-      let buf = self.inode.read(txn, cur_index * size_of::<Dirent>(), m)?;
+    from: usize,
+  ) -> Result<Vec<(usize, usize, [u8; DIRSIZE])>> {
+    let mut result = vec![];
 
-      assert!(buf.len() == m);
-      assert!(m % size_of::<Dirent>() == 0);
+    self.visit_from(txn, from, |offset, ent| {
+      if ent.inum != 0 && ent.inum != WHITEOUT {
+        let next = offset + size_of::<Dirent>();
+        result.push((next, ent.inum as usize, ent.name));
+      }
+      false
+    })?;
+    for &(_, inum, _) in &result {
+      ICACHE.verify(txn, inum)?;
+    }
+    Ok(result)
+  }
 
-      for i in 0..(m / size_of::<Dirent>()) {
-        let ent: &Dirent =
-          unsafe { &*(buf.as_slice().as_ptr() as *const Dirent).add(i) };
+  // Return the names of all whiteouts of this folder.
+  pub fn whiteouts<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+  ) -> Result<Vec<[u8; DIRSIZE]>> {
+    let mut result = vec![];
 
-        if ent.inum != 0 && ent.name == *name {
-          return Some((
-            ICACHE.get(ent.inum as usize).unwrap(),
-            (cur_index + i) * size_of::<Dirent>(),
-          ));
-        }
+    self.visit(txn, |_, ent| {
+      if ent.inum == WHITEOUT {
+        result.push(ent.name);
       }
-      cur_index += m / size_of::<Dirent>();
+      false
+    })?;
+    Ok(result)
+  }
+
+  // Return true if this directory is empty regardless `.` and `..`, and
+  // whiteouts.
+  pub fn is_empty<'b>(&mut self, txn: &Transaction<'b>) -> Result<bool> {
+    Ok(self.enumerate(txn)?.len() == 2)
+  }
+
+  pub fn lookup<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    name: &[u8; DIRSIZE],
+  ) -> Result<Option<(UnlockedInode, usize)>> {
+    let sb = BCACHE.sb();
+    let mut inum = 0;
+    let offset = self.visit(txn, |_, ent| {
+      inum = ent.inum;
+      ent.inum != 0 && ent.inum != WHITEOUT && sb.name_eq(&ent.name, name)
+    })?;
+
+    match offset {
+      Some(offset) => {
+        ICACHE.verify(txn, inum as usize)?;
+        Ok(Some((ICACHE.get(inum as usize).unwrap(), offset)))
+      },
+      None => Ok(None),
     }
-    None
   }
 
-  // Link the file with inode number `inum` in this directory.
+  // Return the offset of the whiteout of `name`, if any.
+  pub fn find_whiteout<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    name: &[u8; DIRSIZE],
+  ) -> Result<Option<usize>> {
+    let sb = BCACHE.sb();
+
+    self.visit(txn, |_, ent| {
+      ent.inum == WHITEOUT && sb.name_eq(&ent.name, name)
+    })
+  }
+
+  // Link the file with inode number `inum` in this directory, replacing a
+  // whiteout of the same name. With WHITEOUT as `inum`, add a whiteout.
+  // Exists if the name is taken, or has a whiteout already.
   pub fn link<'b>(
     &mut self,
     txn: &Transaction<'b>,
     name: &[u8; DIRSIZE],
     inum: u16,
-  ) -> bool {
+  ) -> Result<()> {
     assert!(inum > 0);
 
-    if self.lookup(txn, name).is_some() {
-      return false;
+    if self.lookup(txn, name)?.is_some() {
+      return Err(Error::Exists);
     }
+    let offset = match self.find_whiteout(txn, name)? {
+      Some(_) if inum == WHITEOUT => return Err(Error::Exists),
+      Some(offset) => offset,
+      None => {
+        let size = self.inode().size as usize;
+        self.visit(txn, |_, ent| ent.inum == 0)?.unwrap_or(size)
+      },
+    };
 
-    let nentries = self.inode().size as usize / size_of::<Dirent>();
-    let mut cur_index = 0;
+    let ent_bytes: [u8; size_of::<Dirent>()] = unsafe {
+      transmute(Dirent {
+        name: *name,
+        inum: inum,
+      })
+    };
+    assert!(self.inode.write(txn, offset, &ent_bytes)? == ent_bytes.len());
+    Ok(())
+  }
 
-    while cur_index < nentries {
-      let m = min((nentries - cur_index) * size_of::<Dirent>(), BSIZE);
-      let buf = self
-        .inode
-        .read(txn, cur_index * size_of::<Dirent>(), m)
-        .unwrap();
+  // Remove the entry `name`, not a directory, and count the link gone. The
+  // file is freed once it is no longer held, see Cache::put. Return it.
+  pub fn unlink<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    name: &[u8; DIRSIZE],
+  ) -> Result<UnlockedInode> {
+    self.remove(txn, name, false)
+  }
 
-      assert!(buf.len() == m);
-      assert!(m % size_of::<Dirent>() == 0);
+  // Likewise for the empty directory `name`, whose `..` links this one.
+  pub fn rmdir<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    name: &[u8; DIRSIZE],
+  ) -> Result<UnlockedInode> {
+    self.remove(txn, name, true)
+  }
 
-      let mut found = false;
-      for i in 0..(m / size_of::<Dirent>()) {
-        let ent: &Dirent =
-          unsafe { &*(buf.as_slice().as_ptr() as *const Dirent).add(i) };
+  fn remove<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    name: &[u8; DIRSIZE],
+    is_dir: bool,
+  ) -> Result<UnlockedInode> {
+    let (inode, offset) = self.lookup(txn, name)?.ok_or(Error::NotFound)?;
+    {
+      let mut dinode = ICACHE.lock(txn, &inode);
 
-        if ent.inum == 0 {
-          cur_index += i;
-          found = true;
-          break;
-        }
+      if self.inode.is_read_only() || dinode.is_read_only() {
+        return Err(Error::ReadOnly);
       }
-      if found {
-        break;
-      } else {
-        cur_index += m / size_of::<Dirent>();
+      match (is_dir, dinode.file_type == FileType::Directory) {
+        (false, true) => return Err(Error::IsDir),
+        (true, false) => return Err(Error::NotDir),
+        _ => (),
       }
+      if is_dir {
+        if !dinode.as_directory().is_empty(txn)? {
+          return Err(Error::NotEmpty);
+        }
+        self.inode.nlink -= 1; // for `..`
+        self.inode.update(txn);
+      }
+      dinode.nlink -= 1;
+      dinode.update(txn);
     }
+    self.clear(txn, offset)?;
+    Ok(inode)
+  }
 
+  // Clear the dirent at `offset`. The last one cleared, the directory
+  // shrinks to the last one in use, whiteouts included, so that it is not
+  // scanned past it forever once it held many entries. The others are not
+  // moved, as readdir goes on from their offsets.
+  pub fn clear<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    offset: usize,
+  ) -> Result<()> {
+    let size = self.inode().size as usize;
     let ent_bytes: [u8; size_of::<Dirent>()] = unsafe {
       transmute(Dirent {
-        name: *name,
-        inum: inum,
+        name: [0; DIRSIZE],
+        inum: 0,
       })
     };
-    self
-      .inode
-      .write(txn, cur_index * size_of::<Dirent>(), &ent_bytes)
-      .unwrap() == ent_bytes.len()
+
+    assert!(self.inode.write(txn, offset, &ent_bytes)? == ent_bytes.len());
+    if offset + size_of::<Dirent>() < size {
+      return Ok(());
+    }
+    let mut end = 0;
+    self.visit(txn, |offset, ent| {
+      if ent.inum != 0 {
+        end = offset + size_of::<Dirent>();
+      }
+      false
+    })?;
+    self.inode.truncate(txn, end)
+  }
+
+  // Link `inode`, an existing file, in this directory as `name` as well, and
+  // count the link. Directories cannot be linked, nor files already removed.
+  pub fn link_existing<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    name: &[u8; DIRSIZE],
+    inode: &mut Inode,
+  ) -> Result<()> {
+    if inode.file_type == FileType::Directory {
+      return Err(Error::IsDir);
+    }
+    if inode.nlink == 0 {
+      return Err(Error::NotFound);
+    }
+    if inode.nlink == u16::max_value() {
+      return Err(Error::NoSpace);
+    }
+    // Its content stays encrypted as it was, see ops::link_tmpfile.
+    if inode.is_encrypted() != self.inode().is_encrypted() ||
+      inode.is_encrypted() && inode.key_id() != self.inode().key_id()
+    {
+      return Err(Error::Invalid);
+    }
+    self.link(txn, name, inode.no as u16)?;
+    inode.nlink += 1;
+    inode.update(txn);
+    Ok(())
+  }
+
+  // Create a new file or directory named `name` in this directory, as it
+  // is locked from the lookup to the link, so that concurrent creates of
+  // the same name find each other. It inherits what a child does.
+  pub fn create_entry<'b>(
+    &mut self,
+    txn: &Transaction<'b>,
+    name: &[u8; DIRSIZE],
+    file_type: FileType,
+  ) -> Result<UnlockedInode> {
+    assert!(file_type != FileType::None);
+
+    if self.lookup(txn, name)?.is_some() {
+      return Err(Error::Exists);
+    }
+    let inode = ICACHE.alloc(txn, file_type).ok_or(Error::NoSpace)?;
+    let inodeno = inode.no();
+    {
+      let mut dinode = ICACHE.lock(txn, &inode);
+
+      dinode.nlink = 1;
+      dinode.inherit_defaults(self.inode());
+      dinode.inherit_shred(self.inode());
+      crypt::inherit(self.inode(), &mut dinode, inodeno);
+      dinode.update(txn);
+
+      if file_type == FileType::Directory {
+        let mut dot = [0; DIRSIZE];
+        let mut dotdot = [0; DIRSIZE];
+
+        dot[0] = b'.';
+        dotdot[..2].copy_from_slice(b"..");
+        dinode.as_directory().link(txn, &dot, inodeno as u16)?;
+        dinode.as_directory().link(txn, &dotdot, self.inode.no as u16)?;
+        self.inode.nlink += 1; // for `..`
+        self.inode.update(txn);
+      }
+    }
+    self.link(txn, name, inodeno as u16)?;
+    Ok(inode)
   }
 }
 
 impl Cache {
   fn new(capacity: usize) -> Self {
     Cache {
-      capacity: capacity,
+      capacity: AtomicUsize::new(capacity),
       cache: Mutex::new(HashMap::with_capacity(capacity)),
+      free: Mutex::new(None),
     }
   }
 
   pub fn init(&self) {
-    self.cache.lock().unwrap().clear();
+    let mut cache = self.cache.lock().unwrap();
+
+    MEMORY.release(Account::Inodes, cache.len() * size_of::<Inode>());
+    cache.clear();
+    *self.free.lock().unwrap() = None;
+  }
+
+  // Count the free inodes of the mounted file system, once recovered.
+  pub fn init_free<'a>(&self, txn: &Transaction<'a>) {
+    *self.free.lock().unwrap() = None;
+    let nfree = self.count_free(txn);
+
+    *self.free.lock().unwrap() = Some(Free {
+      nfree,
+      next: ROOTINO + 1,
+    });
+  }
+
+  // Note that inode `inodeno` was freed, or allocated if `used`.
+  fn note(&self, inodeno: usize, used: bool) {
+    if let Some(ref mut free) = *self.free.lock().unwrap() {
+      if used {
+        free.nfree -= 1;
+        free.next = inodeno + 1;
+      } else {
+        free.nfree += 1;
+        free.next = min(free.next, inodeno);
+      }
+    }
+  }
+
+  // Note that inode block `blockno` went back from `data` to `old`, as a
+  // transaction is aborted.
+  pub fn revert(&self, blockno: usize, data: &Block, old: &Block) {
+    let first = (blockno - BCACHE.sb().inode_start as usize) * IPB;
+    let new: &[DiskInode; IPB] = unsafe { transmute(data) };
+    let old: &[DiskInode; IPB] = unsafe { transmute(old) };
+
+    for j in 0..IPB {
+      let (was, is) = (new[j].file_type, old[j].file_type);
+
+      if was == FileType::None && is != FileType::None {
+        self.note(first + j, true);
+      } else if was != FileType::None && is == FileType::None {
+        self.note(first + j, false);
+      }
+    }
+  }
+
+  // Note that the inode table grew by `n` inodes, free, see fs::grow_inodes.
+  pub fn grow(&self, n: usize) {
+    if let Some(ref mut free) = *self.free.lock().unwrap() {
+      free.nfree += n;
+    }
+  }
+
+  // Undo `grow`, its transaction aborted.
+  pub fn shrink(&self, n: usize) {
+    if let Some(ref mut free) = *self.free.lock().unwrap() {
+      free.nfree -= n;
+    }
   }
 
   pub fn capacity(&self) -> usize {
-    self.capacity
+    self.capacity.load(Ordering::Relaxed)
+  }
+
+  // Shrunk, the cache evicts its unused inodes on the next miss.
+  pub fn set_capacity(&self, capacity: usize) {
+    self.capacity.store(capacity, Ordering::Relaxed);
   }
 
   pub fn nitems(&self) -> usize {
@@ -366,22 +1414,32 @@ impl Cache {
   ) -> Option<UnlockedInode> {
     let sb = BCACHE.sb();
     let ninodes = sb.ninodes as usize;
+    let nblocks = (ninodes + IPB - 1) / IPB;
+    let start = match *self.free.lock().unwrap() {
+      Some(ref free) if free.nfree == 0 => return None,
+      Some(ref free) => min(free.next, ninodes),
+      None => 0,
+    };
 
-    for b in 0..ninodes / IPB {
+    // The inode block of the hint comes twice, from it, then up to it.
+    for k in 0..nblocks + 1 {
+      let b = (start / IPB + k) % nblocks;
       let mut buf = txn.read(sb.iblock(b * IPB)).unwrap();
       let inodes: &mut [DiskInode; IPB] = unsafe { transmute(&mut buf.data) };
+      let from = if k == 0 { start } else { b * IPB };
+      let to = min(ninodes, (b + 1) * IPB);
 
-      for j in 0..IPB {
-        let i = b * IPB + j;
+      for i in from..to {
+        let j = i % IPB;
         if i <= ROOTINO {
           continue;
-        } else if i >= ninodes {
-          break;
         }
         if inodes[j].file_type == FileType::None {
           inodes[j].init(file_type);
+          inodes[j].seal();
           txn.write(&mut buf);
           drop(buf);
+          self.note(i, true);
           return self.get(i);
         }
       }
@@ -389,13 +1447,129 @@ impl Cache {
     None
   }
 
+  // Return the number of free inodes, counted once at mount.
+  pub fn count_free<'a>(&self, txn: &Transaction<'a>) -> usize {
+    if let Some(ref free) = *self.free.lock().unwrap() {
+      return free.nfree;
+    }
+    let sb = BCACHE.sb();
+    let ninodes = sb.ninodes as usize;
+    let mut n = 0;
+
+    for b in 0..(ninodes + IPB - 1) / IPB {
+      let buf = txn.read(sb.iblock(b * IPB)).unwrap();
+      let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
+
+      // Inode 0 is never used.
+      n += (0..IPB)
+        .filter(|j| b * IPB + j > 0 && b * IPB + j < ninodes)
+        .filter(|j| inodes[*j].file_type == FileType::None)
+        .count();
+    }
+    n
+  }
+
+  // Free the unnamed files that a crash left behind, see ops::tmpfile, and
+  // return how many. It must run at mount, before any of them is in use.
+  // Large ones are left to the worker if it is started, see reclaim.rs.
+  pub fn reclaim_orphans(&self) -> usize {
+    let sb = BCACHE.sb();
+    let ninodes = sb.ninodes as usize;
+    let mut n = 0;
+
+    for b in 0..(ninodes + IPB - 1) / IPB {
+      let orphans: Vec<usize> = {
+        let txn = LOGGING.new_txn();
+        let buf = txn.read(sb.iblock(b * IPB)).unwrap();
+        let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
+
+        (0..IPB)
+          .filter(|j| {
+            inodes[*j].file_type != FileType::None && inodes[*j].is_orphan()
+          })
+          .map(|j| b * IPB + j)
+          .collect()
+      };
+
+      for inodeno in orphans {
+        info!("[orphan] reclaiming inode {}", inodeno);
+        let txn = LOGGING.new_txn();
+        let inode = self.get(inodeno).unwrap();
+        // Freed as the last reference is dropped.
+        self.lock(&txn, &inode).nlink = 0;
+        n += 1;
+      }
+    }
+    n
+  }
+
+  // Check inode `inodeno` on disk before it is referenced, as locking it
+  // trusts it. Corrupt if it is out of range, or fails its checksum.
+  pub fn verify<'a>(
+    &self,
+    txn: &Transaction<'a>,
+    inodeno: usize,
+  ) -> Result<()> {
+    let sb = BCACHE.sb();
+
+    if inodeno == 0 || inodeno >= sb.ninodes as usize {
+      error!("inode {} out of range", inodeno);
+      return Err(Error::Corrupt);
+    }
+    if sb.flags & CHECKSUMS == 0 {
+      return Ok(());
+    }
+    let buf = txn.read(sb.iblock(inodeno)).unwrap();
+    let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
+
+    if !inodes[inodeno % IPB].verify() {
+      error!("inode {} fails its checksum", inodeno);
+      return Err(Error::Corrupt);
+    }
+    Ok(())
+  }
+
+  // Return inode `inodeno` if it is in use and of generation `gen`, for
+  // handles that may outlive their file, whose slot is then reused.
+  pub fn get_live<'a>(
+    &self,
+    txn: &Transaction<'a>,
+    inodeno: usize,
+    gen: u32,
+  ) -> Option<UnlockedInode> {
+    let sb = BCACHE.sb();
+
+    if inodeno == 0 || inodeno >= sb.ninodes as usize {
+      return None;
+    }
+    // Looked up on disk, as getting a freed inode would free it again once
+    // dropped. The inode block is up to date with the cached inodes.
+    let live = {
+      let buf = txn.read(sb.iblock(inodeno)).unwrap();
+      let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
+      let dinode = &inodes[inodeno % IPB];
+
+      dinode.file_type != FileType::None && dinode.gen == gen
+    };
+    // Removed, only its blocks are still to be reclaimed.
+    if reclaim::is_pending(inodeno) {
+      return None;
+    }
+    if !live {
+      return None;
+    }
+    self.get(inodeno)
+  }
+
   pub fn get(&self, inodeno: usize) -> Option<UnlockedInode> {
     let mut inode: Option<UnlockedInode>;
     let mut cache = self.cache.lock().unwrap();
 
     inode = cache.get_mut(&inodeno).map(|inode| inode.clone());
     if inode.is_none() {
-      if cache.len() >= self.capacity {
+      // Over the memory budget, unused inodes are evicted as if full.
+      let capacity = self.capacity();
+      if cache.len() >= capacity || MEMORY.over_budget() {
         let mut free_nos = vec![];
 
         for (inodeno2, inode2) in cache.iter() {
@@ -403,9 +1577,10 @@ impl Cache {
             free_nos.push(*inodeno2);
           }
         }
-        if free_nos.is_empty() {
+        if free_nos.is_empty() && cache.len() >= capacity {
           return None;
         }
+        MEMORY.release(Account::Inodes, free_nos.len() * size_of::<Inode>());
         for inodeno2 in free_nos {
           cache.remove(&inodeno2);
         }
@@ -414,6 +1589,7 @@ impl Cache {
       let new_inode = Arc::new((Mutex::new(Inode::new(inodeno)), inodeno));
       inode = Some(UnlockedInode::new(new_inode.clone()));
       cache.insert(inodeno, UnlockedInode::new(new_inode.clone()));
+      MEMORY.charge(Account::Inodes, size_of::<Inode>());
     }
     inode
   }
@@ -424,6 +1600,15 @@ impl Cache {
     }
     let mut inode = self.lock(txn, inode); // acquiring lock here is expensive?
     if inode.nlink == 0 {
+      // Large files are left to the worker, see reclaim.rs.
+      let nblocks = inode.data_blocks(txn).map_or(0, |blocks| blocks.len());
+      if reclaim::defer(inode.no(), nblocks) {
+        if !inode.is_orphan() {
+          inode.flags |= IORPHAN;
+          inode.update(txn);
+        }
+        return;
+      }
       info!("[garbage] cleaning inode {}", inode.no());
       // Issue: potential garbage may be left here if crash happens before
       // put, which results in the following code unexecuted even in
@@ -432,6 +1617,53 @@ impl Cache {
       inode.file_type = FileType::None;
       inode.update(txn);
       inode.clear();
+      self.note(inode.no(), false);
+    }
+  }
+
+  // Reset the cached copies of `inodes`, inode numbers and their copies on
+  // the disk, to the latter, as a transaction that changed them aborts,
+  // see `Transaction::abort`. Unused ones are dropped from the cache. Do
+  // nothing and return None if one is locked, or false if one is held but
+  // free on the disk.
+  pub fn try_revert(&self, inodes: &[(usize, DiskInode)]) -> Option<bool> {
+    let mut cache = self.cache.lock().unwrap();
+    let mut unused = vec![];
+    let mut locked = vec![];
+
+    for &(inodeno, ref dinode) in inodes {
+      let inode = match cache.get(&inodeno) {
+        Some(inode) => inode,
+        None => continue,
+      };
+      // Nobody holds it, nor has it locked then.
+      if inode.refcnt() == 0 {
+        unused.push(inodeno);
+        continue;
+      }
+      if dinode.file_type == FileType::None {
+        return Some(false);
+      }
+      locked.push((inode.try_acquire()?, dinode));
+    }
+    for (mut inode, dinode) in locked {
+      inode.inode = Some(dinode.clone());
+    }
+    MEMORY.release(Account::Inodes, unused.len() * size_of::<Inode>());
+    for inodeno in unused {
+      cache.remove(&inodeno);
+    }
+    Some(true)
+  }
+
+  // Put the inodes dropped in the read transactions of this thread, now
+  // that they are over.
+  pub fn put_deferred(&self) {
+    let inodes = DEFERRED.with(|deferred| deferred.replace(vec![]));
+
+    if !inodes.is_empty() {
+      let _txn = LOGGING.new_txn();
+      drop(inodes);
     }
   }
 
@@ -440,7 +1672,35 @@ impl Cache {
     txn: &Transaction<'a>,
     inode: &UnlockedInode,
   ) -> LockedInode<'b> {
-    let mut inode = inode.acquire();
+    self.load(txn, inode.acquire())
+  }
+
+  // Allocate at most `max` of the delayed blocks of `inode`, unless it is
+  // locked, see Inode::alloc_delayed. Return how many were.
+  pub fn alloc_delayed<'a>(
+    &self,
+    txn: &Transaction<'a>,
+    inode: &UnlockedInode,
+    max: usize,
+  ) -> usize {
+    let inode = match inode.try_acquire() {
+      Some(inode) => inode,
+      None => return 0,
+    };
+
+    // Only those in use have any, see Inode::free_blocks.
+    if !delalloc::has(inode.no) {
+      return 0;
+    }
+    self.load(txn, inode).alloc_delayed(txn, max)
+  }
+
+  // Read the disk copy of `inode`, locked, unless it is cached.
+  fn load<'a, 'b>(
+    &self,
+    txn: &Transaction<'a>,
+    mut inode: LockedInode<'b>,
+  ) -> LockedInode<'b> {
     let sb = BCACHE.sb();
 
     if inode.inode.is_some() {
@@ -449,15 +1709,32 @@ impl Cache {
     let buf = txn.read(sb.iblock(inode.no)).unwrap();
     let inodes: &[DiskInode; IPB] = unsafe { transmute(&buf.data) };
 
-    assert!(inodes[inode.no % IPB].file_type != FileType::None);
+    // Those from the disk are verified as they are referenced, see `verify`.
+    let dinode = &inodes[inode.no % IPB];
+    if sb.flags & CHECKSUMS != 0 && !dinode.verify() {
+      panic!("inode {} fails its checksum", inode.no);
+    }
+    assert!(dinode.file_type != FileType::None);
 
-    inode.inode = Some(inodes[inode.no % IPB].clone());
+    inode.inode = Some(dinode.clone());
+    // Every inode of a read-only disk is, so that changes are refused.
+    if DISK.is_read_only() {
+      inode.flags |= IREADONLY;
+    }
     inode
   }
 }
 
 impl UnlockedDrop for UnlockedInode {
   fn drop(&mut self) {
+    // Only dropping the last reference does anything, see `put`.
+    if self.refcnt() != 1 {
+      return;
+    }
+    if logging::in_read_txn() {
+      DEFERRED.with(|deferred| deferred.borrow_mut().push(self.clone()));
+      return;
+    }
     let txn = LOGGING.new_nested_txn();
     ICACHE.put(&txn, self);
   }
@@ -0,0 +1,297 @@
+// Integrity of the data of an image, e.g. one handed out read-only, kept
+// by a hash tree.
+//
+// mkfs with `integrity` reserves the hash blocks at `sb.hashblk`, before
+// the bad-block table, holding the SHA-256 of every block by block number,
+// HPB to a block. Only the data blocks are hashed, the other entries are
+// zero. The hash blocks are the leaves of a binary tree whose root is
+// `sb.root`, where a node is the SHA-256 of its two children, or of its
+// only one. A commit brings the hashes of the data blocks it writes, their
+// hash blocks and the root up to date in the same transaction, so that
+// they stay consistent through crashes.
+//
+// The tree is kept in memory from the time the log is recovered, 32 bytes
+// per block. A data block read from the disk that does not match its hash
+// is flagged, so that reading a file through it fails, and `verify` checks
+// every data block in use against the root, which the daemon does at
+// mount. Blocks are never written around the log, which is why shredding
+// is not supported.
+
+use buffer::{BCACHE, LockedBuf};
+use disk::{BSIZE, Block};
+use error::{Error, Result};
+use fs::{BPB, SuperBlock};
+use std::cmp::min;
+use std::sync::Mutex;
+use util::sha256::{Digest, sha256};
+
+// Number of hashes per block.
+pub const HPB: usize = BSIZE / 32;
+
+struct Tree {
+  // The hash of every block, by block number.
+  hashes: Vec<Digest>,
+  // The hashes of the hash blocks, then those of pairs of the nodes of the
+  // level below, up to the root.
+  levels: Vec<Vec<Digest>>,
+}
+
+lazy_static! {
+  // The tree of the mounted file system, see `init`.
+  static ref TREE: Mutex<Option<Tree>> = Mutex::new(None);
+}
+
+impl Tree {
+  fn new(hashes: Vec<Digest>) -> Self {
+    let mut tree = Tree {
+      hashes,
+      levels: vec![],
+    };
+    let leaves = (0..nhashblks(tree.hashes.len()))
+      .map(|i| sha256(&tree.block(i)))
+      .collect();
+
+    tree.levels.push(leaves);
+    while tree.levels.last().unwrap().len() > 1 {
+      let parents = tree
+        .levels
+        .last()
+        .unwrap()
+        .chunks(2)
+        .map(|pair| sha256(&pair.concat()))
+        .collect();
+      tree.levels.push(parents);
+    }
+    tree
+  }
+
+  // Return hash block `i`.
+  fn block(&self, i: usize) -> Block {
+    let mut block = [0; BSIZE];
+
+    for (j, hash) in self.hashes[i * HPB..].iter().take(HPB).enumerate() {
+      block[j * 32..(j + 1) * 32].copy_from_slice(hash);
+    }
+    block
+  }
+
+  // Set the hash of hash block `i`, and those above it.
+  fn set(&mut self, mut i: usize, hash: Digest) {
+    self.levels[0][i] = hash;
+    for level in 1..self.levels.len() {
+      let below = &self.levels[level - 1];
+      let pair = below[i & !1..min((i & !1) + 2, below.len())].concat();
+
+      i /= 2;
+      self.levels[level][i] = sha256(&pair);
+    }
+  }
+
+  fn root(&self) -> Digest {
+    self.levels.last().unwrap()[0]
+  }
+}
+
+// Return the number of hash blocks of a file system of `nblocks` blocks.
+pub fn nhashblks(nblocks: usize) -> usize {
+  (nblocks + HPB - 1) / HPB
+}
+
+// Return the hash blocks and root of a file system whose blocks have
+// `hashes`, zero for those that are not data blocks.
+pub fn tree(hashes: Vec<Digest>) -> (Vec<Block>, Digest) {
+  let tree = Tree::new(hashes);
+  let blocks = (0..tree.levels[0].len()).map(|i| tree.block(i)).collect();
+
+  (blocks, tree.root())
+}
+
+// Return true if `blockno` is a data block of a file system with a tree.
+fn is_hashed(sb: &SuperBlock, blockno: usize) -> bool {
+  sb.hashblk != 0 && blockno >= sb.data_start() && blockno < sb.data_end()
+}
+
+// Load the tree of the mounted file system, once its log is recovered.
+pub fn init() {
+  let sb = BCACHE.sb();
+  let mut tree = TREE.lock().unwrap();
+
+  *tree = None;
+  if sb.hashblk == 0 {
+    return;
+  }
+  let mut hashes = Vec::with_capacity(sb.nblocks as usize);
+  for i in 0..nhashblks(sb.nblocks as usize) {
+    let buf = BCACHE.read(sb.hashblk as usize + i).unwrap();
+
+    for hash in buf.data.chunks(32) {
+      let mut digest = [0; 32];
+      digest.copy_from_slice(hash);
+      hashes.push(digest);
+    }
+  }
+  hashes.truncate(sb.nblocks as usize);
+  *tree = Some(Tree::new(hashes));
+}
+
+// Return false if `data`, just read from the disk at `blockno`, does not
+// match its hash.
+pub fn check(blockno: usize, data: &Block) -> bool {
+  if !is_hashed(&BCACHE.sb(), blockno) {
+    return true;
+  }
+  match *TREE.lock().unwrap() {
+    Some(ref tree) => tree.hashes[blockno] == sha256(data),
+    None => true,
+  }
+}
+
+// Bring the tree up to date with the cached content of `blocknos`, which
+// are about to be committed. Return the hash blocks and super block that
+// changed as well, locked, to be committed along.
+pub fn update<'a>(blocknos: &[u32]) -> Vec<LockedBuf<'a>> {
+  let sb = BCACHE.sb();
+  let mut tree = TREE.lock().unwrap();
+  let tree = match *tree {
+    Some(ref mut tree) if sb.hashblk != 0 => tree,
+    _ => return vec![],
+  };
+  let mut changed = vec![];
+
+  for &blockno in blocknos {
+    let blockno = blockno as usize;
+    if !is_hashed(&sb, blockno) {
+      continue;
+    }
+    let hash = sha256(&BCACHE.read(blockno).unwrap().data);
+
+    if tree.hashes[blockno] != hash {
+      tree.hashes[blockno] = hash;
+      if !changed.contains(&(blockno / HPB)) {
+        changed.push(blockno / HPB);
+      }
+    }
+  }
+  if changed.is_empty() {
+    return vec![];
+  }
+
+  let mut result = vec![];
+  for i in changed {
+    let mut buf = BCACHE.read(sb.hashblk as usize + i).unwrap();
+
+    buf.data = tree.block(i);
+    tree.set(i, sha256(&buf.data));
+    result.push(buf);
+  }
+  let mut buf = BCACHE.read(1).unwrap();
+  let mut disk_sb = from_block!(&buf.data, SuperBlock);
+
+  disk_sb.root = tree.root();
+  disk_sb.seal();
+  buf.data = to_block!(&disk_sb, SuperBlock);
+  result.push(buf);
+  result
+}
+
+// Check the tree of the mounted file system against its root, and then
+// every data block in use against its hash. Return the blocks that do not
+// match, Io if the tree itself does not.
+pub fn verify() -> Result<Vec<usize>> {
+  let sb = BCACHE.sb();
+  // Copied, as reading blocks checks them against the tree.
+  let (hashes, root) = match *TREE.lock().unwrap() {
+    Some(ref tree) if sb.hashblk != 0 => (tree.hashes.clone(), tree.root()),
+    _ => return Err(Error::Unsupported),
+  };
+
+  if from_block!(&BCACHE.read(1).unwrap().data, SuperBlock).root != root {
+    return Err(Error::Io);
+  }
+  let mut result = vec![];
+  for blockno in sb.data_start()..sb.data_end() {
+    let bitmap = BCACHE.read(sb.bblock(blockno)).unwrap();
+    let i = blockno % BPB;
+
+    if bitmap.data[i / 8] & 1 << (i % 8) == 0 {
+      continue;
+    }
+    drop(bitmap);
+    if sha256(&BCACHE.read(blockno).unwrap().data) != hashes[blockno] {
+      result.push(blockno);
+    }
+  }
+  Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+  use buffer::BCACHE;
+  use disk::{BSIZE, Block, DISK, Disk};
+  use error::Error;
+  use fs::FileType;
+  use integrity;
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use mkfs::{self, Options};
+  use ops;
+  use testfs;
+
+  fn mount(disk: Disk) {
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+  }
+
+  // Flip a bit of `blockno` behind the back of the log, and mount again.
+  fn tamper(blockno: usize) {
+    let mut disk = DISK.unmount();
+    let mut blocks: Vec<Block> =
+      (0..disk.nblocks()).map(|i| disk.read(i)).collect();
+
+    blocks[blockno][0] ^= 1;
+    mount(Disk::from(blocks));
+  }
+
+  #[test]
+  fn test() {
+    let mut disk = Disk::new(500);
+    let opts = Options {
+      ninodes: 20,
+      integrity: true,
+      ..Options::default()
+    };
+    mkfs::mkfs(&mut disk, &opts).unwrap();
+    mount(disk);
+    assert!(integrity::verify().unwrap().is_empty());
+
+    let blockno = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let name = ops::to_name(b"f").unwrap();
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+
+      ops::write(&txn, &file, 0, &[7; 2 * BSIZE]).unwrap();
+      let shred = ops::set_shred(&txn, &file, true);
+      assert!(shred.err() == Some(Error::Unsupported));
+      let dinode = ICACHE.lock(&txn, &file);
+      dinode.addrs[1] as usize
+    };
+    assert!(integrity::verify().unwrap().is_empty());
+
+    tamper(blockno);
+    assert!(integrity::verify().unwrap() == vec![blockno]);
+    {
+      let txn = LOGGING.new_txn();
+      let file = ops::resolve(&txn, b"/f").unwrap();
+      assert!(ops::read(&txn, &file, 0, 10).unwrap() == [7; 10]);
+      assert!(ops::read(&txn, &file, BSIZE, 10).err() == Some(Error::Io));
+    }
+
+    // The root no longer matches once a hash is forged as well.
+    tamper(BCACHE.sb().hashblk as usize + blockno / integrity::HPB);
+    assert!(integrity::verify().err() == Some(Error::Io));
+
+    testfs::test::mount();
+    assert!(integrity::verify().err() == Some(Error::Unsupported));
+  }
+}
@@ -0,0 +1,534 @@
+// Images made by the mkfs of upstream xv6, in C, which lays the file system
+// out in one of three ways over its history:
+//
+//   Rev7   512-byte blocks, a super block of size, nblocks, ninodes and
+//          nlog, the inodes from block 2 and the log at the end.
+//   Rev9   512-byte blocks, and the super block places the log, inodes and
+//          bitmap after it, as it does here.
+//   Rev11  1024-byte blocks, and the super block starts with FSMAGIC, like
+//          that of xv6-riscv.
+//
+// All of them have 64-byte inodes, without modes or owners, and numbers in
+// the byte order of the host that built the image, either of which is
+// recognized. A `Legacy` reads such an image from its own copy, once the
+// transaction left in its log is installed, and copies its tree into the
+// mounted file system, which is how the daemon serves one and how `xv6fs
+// convert` converts one for good.
+
+use disk::{BSIZE, Disk};
+use error::{Error, Result};
+use fs::{BPB, DIRSIZE, FileType, IPB, IREADONLY, LOGSIZE, NDIRECT, NINDIRECT,
+         ROOTINO};
+use inode::ICACHE;
+use logging::LOGGING;
+use mkfs::{self, Options};
+use ops::{self, MAXWRITE};
+use std::cmp::min;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const FSMAGIC: u32 = 0x10203040;
+
+// Sizes of the inodes and directory entries of every layout.
+const DINODE_SIZE: usize = 64;
+const DIRENT_SIZE: usize = 2 + DIRSIZE;
+
+// Inode types.
+const T_DIR: u16 = 1;
+const T_FILE: u16 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Layout {
+  Rev7,
+  Rev9,
+  Rev11,
+}
+
+// The fields of an inode that have a counterpart here.
+struct Dinode {
+  file_type: u16,
+  size: usize,
+  addrs: [usize; NDIRECT + 1],
+}
+
+pub struct Legacy {
+  data: Vec<u8>,
+  layout: Layout,
+  big_endian: bool,
+  bsize: usize,
+  nblocks: usize,
+  ninodes: usize,
+  inode_start: usize,
+}
+
+impl Legacy {
+  // Open the image at `path`, None if it is not one of upstream xv6.
+  pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Option<Self>> {
+    Ok(Legacy::parse(fs::read(path)?))
+  }
+
+  // Recognize the image `data`, None if it is not one of upstream xv6.
+  pub fn parse(data: Vec<u8>) -> Option<Self> {
+    let mut legacy = Legacy {
+      data,
+      layout: Layout::Rev11,
+      big_endian: false,
+      bsize: 0,
+      nblocks: 0,
+      ninodes: 0,
+      inode_start: 0,
+    };
+
+    for &big_endian in &[false, true] {
+      legacy.big_endian = big_endian;
+      if legacy.detect() {
+        return Some(legacy);
+      }
+    }
+    None
+  }
+
+  fn u32_at(&self, offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+
+    if offset + 4 <= self.data.len() {
+      bytes.copy_from_slice(&self.data[offset..offset + 4]);
+    }
+    if self.big_endian {
+      u32::from_be_bytes(bytes)
+    } else {
+      u32::from_le_bytes(bytes)
+    }
+  }
+
+  fn u16_at(&self, offset: usize) -> u16 {
+    let mut bytes = [0; 2];
+
+    if offset + 2 <= self.data.len() {
+      bytes.copy_from_slice(&self.data[offset..offset + 2]);
+    }
+    if self.big_endian {
+      u16::from_be_bytes(bytes)
+    } else {
+      u16::from_le_bytes(bytes)
+    }
+  }
+
+  // Find the layout of the image in the byte order of `big_endian`, and
+  // install its log. Return false if it has none of them.
+  fn detect(&mut self) -> bool {
+    let sb = |legacy: &Legacy, bsize: usize, i: usize| {
+      legacy.u32_at(bsize + 4 * i) as usize
+    };
+    let (layout, bsize, nlog, log_start);
+
+    if self.u32_at(1024) == FSMAGIC {
+      layout = Layout::Rev11;
+      bsize = 1024;
+      self.nblocks = sb(self, bsize, 1);
+      self.ninodes = sb(self, bsize, 3);
+      nlog = sb(self, bsize, 4);
+      log_start = sb(self, bsize, 5);
+      self.inode_start = sb(self, bsize, 6);
+    } else {
+      bsize = 512;
+      self.nblocks = sb(self, bsize, 0);
+      self.ninodes = sb(self, bsize, 2);
+      nlog = sb(self, bsize, 3);
+      if sb(self, bsize, 4) == 0 {
+        layout = Layout::Rev7;
+        log_start = self.nblocks.wrapping_sub(nlog);
+        self.inode_start = 2;
+      } else {
+        layout = Layout::Rev9;
+        log_start = sb(self, bsize, 4);
+        self.inode_start = sb(self, bsize, 5);
+      }
+    }
+    self.layout = layout;
+    self.bsize = bsize;
+
+    let ninodeblks = self.ninodes / (bsize / DINODE_SIZE) + 1;
+    if self.nblocks < 2 || self.nblocks * bsize > self.data.len() ||
+      self.ninodes <= ROOTINO || nlog == 0 ||
+      log_start.saturating_add(nlog) > self.nblocks ||
+      self.inode_start.saturating_add(ninodeblks) > self.nblocks
+    {
+      return false;
+    }
+
+    // Recover like `Logging::init` does.
+    let n = self.u32_at(log_start * bsize) as usize;
+    if n >= nlog {
+      return false;
+    }
+    for i in 0..n {
+      let to = self.u32_at(log_start * bsize + 4 + 4 * i) as usize;
+      let from = (log_start + 1 + i) * bsize;
+
+      if to == 0 || to >= self.nblocks {
+        return false;
+      }
+      let block = self.data[from..from + bsize].to_vec();
+      self.data[to * bsize..(to + 1) * bsize].copy_from_slice(&block);
+    }
+
+    // The root is a directory starting with `.`, which rules out the
+    // images made here, whose inodes are twice as large.
+    match self.inode(ROOTINO) {
+      Ok(ref root) if root.file_type == T_DIR && root.size > 0 => {
+        match self.block(root.addrs[0]) {
+          Ok(block) => {
+            self.u16_at(block) == ROOTINO as u16 &&
+              &self.data[block + 2..block + 4] == b".\0"
+          },
+          Err(_) => false,
+        }
+      },
+      _ => false,
+    }
+  }
+
+  pub fn layout(&self) -> Layout {
+    self.layout
+  }
+
+  pub fn is_big_endian(&self) -> bool {
+    self.big_endian
+  }
+
+  // Return the offset of block `blockno` in the image.
+  fn block(&self, blockno: usize) -> Result<usize> {
+    if blockno == 0 || blockno >= self.nblocks {
+      return Err(Error::Corrupt);
+    }
+    Ok(blockno * self.bsize)
+  }
+
+  fn inode(&self, inum: usize) -> Result<Dinode> {
+    if inum == 0 || inum >= self.ninodes {
+      return Err(Error::NotFound);
+    }
+    let ipb = self.bsize / DINODE_SIZE;
+    let offset =
+      self.block(self.inode_start + inum / ipb)? + inum % ipb * DINODE_SIZE;
+    let mut addrs = [0; NDIRECT + 1];
+
+    for i in 0..NDIRECT + 1 {
+      addrs[i] = self.u32_at(offset + 12 + 4 * i) as usize;
+    }
+    Ok(Dinode {
+      file_type: self.u16_at(offset),
+      size: self.u32_at(offset + 8) as usize,
+      addrs,
+    })
+  }
+
+  // Return the block holding byte `n * bsize` of `dinode`, 0 for a hole.
+  fn nth_block(&self, dinode: &Dinode, n: usize) -> Result<usize> {
+    if n < NDIRECT {
+      return Ok(dinode.addrs[n]);
+    }
+    if n - NDIRECT >= self.bsize / 4 || dinode.addrs[NDIRECT] == 0 {
+      return Ok(0);
+    }
+    let offset = self.block(dinode.addrs[NDIRECT])?;
+    Ok(self.u32_at(offset + 4 * (n - NDIRECT)) as usize)
+  }
+
+  // Return the content of file or directory `inum`.
+  pub fn read(&self, inum: usize) -> Result<Vec<u8>> {
+    let dinode = self.inode(inum)?;
+    let size = min(dinode.size, (NDIRECT + self.bsize / 4) * self.bsize);
+    let mut data = Vec::with_capacity(size);
+
+    for n in 0..(size + self.bsize - 1) / self.bsize {
+      let len = min(self.bsize, size - n * self.bsize);
+
+      match self.nth_block(&dinode, n)? {
+        0 => data.extend_from_slice(&vec![0; len]),
+        blockno => {
+          let offset = self.block(blockno)?;
+          data.extend_from_slice(&self.data[offset..offset + len]);
+        },
+      }
+    }
+    Ok(data)
+  }
+
+  // Return the inode numbers and names of the entries of directory `inum`,
+  // without `.` and `..`.
+  pub fn readdir(&self, inum: usize) -> Result<Vec<(usize, [u8; DIRSIZE])>> {
+    if self.inode(inum)?.file_type != T_DIR {
+      return Err(Error::NotDir);
+    }
+    let data = self.read(inum)?;
+    let mut entries = vec![];
+
+    for chunk in data.chunks(DIRENT_SIZE) {
+      if chunk.len() < DIRENT_SIZE {
+        break;
+      }
+      let inum = if self.big_endian {
+        (chunk[0] as usize) << 8 | chunk[1] as usize
+      } else {
+        (chunk[1] as usize) << 8 | chunk[0] as usize
+      };
+      let mut name = [0; DIRSIZE];
+
+      name.copy_from_slice(&chunk[2..]);
+      if inum != 0 && &name[..2] != b".\0" && &name[..3] != b"..\0" {
+        entries.push((inum, name));
+      }
+    }
+    Ok(entries)
+  }
+
+  // Return the options of a file system here that has room for the tree
+  // of the image, and its size in blocks.
+  pub fn native_size(&self) -> (Options, usize) {
+    let opts = Options {
+      ninodes: self.ninodes + 2,
+      ..Options::default()
+    };
+    // Each file may take an indirect block more than it did, the doubly
+    // indirect block and one under it for every NINDIRECT blocks.
+    let data = self.nblocks * self.bsize / BSIZE;
+    let data = data + data / NINDIRECT + 3 * self.ninodes;
+    let meta = 2 + LOGSIZE + opts.ninodes / IPB + 1 + opts.spares + 1;
+
+    (opts, meta + data + data / BPB + 1)
+  }
+
+  // Format an in-memory disk with room for the tree of the image.
+  pub fn format(&self) -> Result<Disk> {
+    let (opts, nblocks) = self.native_size();
+    let mut disk = Disk::new(nblocks);
+
+    mkfs::mkfs(&mut disk, &opts)?;
+    Ok(disk)
+  }
+
+  // Copy the tree of the image into the root of the mounted file system,
+  // making every inode IREADONLY if `read_only`. Hard links are copied
+  // apart, and device files, which have no counterpart, are left out.
+  pub fn copy(&self, read_only: bool) -> Result<()> {
+    let mut visited = HashSet::new();
+
+    self.copy_dir(ROOTINO, b"", read_only, &mut visited)?;
+    if read_only {
+      set_read_only(b"/")?;
+    }
+    Ok(())
+  }
+
+  fn copy_dir(
+    &self,
+    inum: usize,
+    dir: &[u8],
+    read_only: bool,
+    visited: &mut HashSet<usize>,
+  ) -> Result<()> {
+    // Directories cannot be linked twice, but the image may be corrupt.
+    if !visited.insert(inum) {
+      return Err(Error::Invalid);
+    }
+    for (child, name) in self.readdir(inum)? {
+      let mut path = dir.to_vec();
+      path.push(b'/');
+      path.extend_from_slice(ops::from_name(&name));
+
+      let file_type = match self.inode(child)?.file_type {
+        T_DIR => FileType::Directory,
+        T_FILE => FileType::File,
+        _ => {
+          warn!("{} skipped", String::from_utf8_lossy(&path));
+          continue;
+        },
+      };
+      {
+        let txn = LOGGING.new_txn();
+        let dir = ops::resolve(&txn, dir)?;
+        let name = ops::to_name(ops::from_name(&name))?;
+        ops::create(&txn, &dir, &name, file_type)?;
+      }
+      if file_type == FileType::Directory {
+        self.copy_dir(child, &path, read_only, visited)?;
+      } else {
+        let data = self.read(child)?;
+
+        for (n, chunk) in data.chunks(MAXWRITE).enumerate() {
+          let txn = LOGGING.new_txn();
+          let inode = ops::resolve(&txn, &path)?;
+          ops::write(&txn, &inode, n * MAXWRITE, chunk)?;
+        }
+      }
+      if read_only {
+        set_read_only(&path)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+fn set_read_only(path: &[u8]) -> Result<()> {
+  let txn = LOGGING.new_txn();
+  let inode = ops::resolve(&txn, path)?;
+  let mut dinode = ICACHE.lock(&txn, &inode);
+
+  dinode.flags |= IREADONLY;
+  dinode.update(&txn);
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use disk::DISK;
+  use error::Error;
+  use fs::{FileType, ROOTINO};
+  use legacy::{Layout, Legacy};
+  use logging::LOGGING;
+  use ops;
+  use testfs;
+
+  // Put `value` at `offset` of `image`, in 4 or 2 bytes.
+  fn put(image: &mut [u8], offset: usize, value: u32, big_endian: bool) {
+    let bytes = if big_endian {
+      value.to_be_bytes()
+    } else {
+      value.to_le_bytes()
+    };
+    image[offset..offset + 4].copy_from_slice(&bytes);
+  }
+
+  fn put16(image: &mut [u8], offset: usize, value: u16, big_endian: bool) {
+    let bytes = if big_endian {
+      value.to_be_bytes()
+    } else {
+      value.to_le_bytes()
+    };
+    image[offset..offset + 2].copy_from_slice(&bytes);
+  }
+
+  // Build an image like upstream mkfs does, holding /d/f and a device
+  // /console.
+  fn build(layout: Layout, big_endian: bool) -> Vec<u8> {
+    let (bsize, nblocks, ninodes, nlog) = match layout {
+      Layout::Rev11 => (1024, 100, 32, 10),
+      _ => (512, 200, 32, 10),
+    };
+    let (log_start, inode_start) = match layout {
+      Layout::Rev7 => (nblocks - nlog, 2),
+      _ => (2, 2 + nlog),
+    };
+    let data_start = inode_start + ninodes * 64 / bsize + 2;
+    let mut image = vec![0; nblocks * bsize];
+    let sb: Vec<usize> = match layout {
+      Layout::Rev7 => vec![nblocks, 0, ninodes, nlog],
+      Layout::Rev9 => {
+        vec![nblocks, 0, ninodes, nlog, log_start, inode_start, 0]
+      },
+      Layout::Rev11 => {
+        vec![0x10203040, nblocks, 0, ninodes, nlog, log_start, inode_start, 0]
+      },
+    };
+    for (i, field) in sb.iter().enumerate() {
+      put(&mut image, bsize + 4 * i, *field as u32, big_endian);
+    }
+
+    // Inodes 1 to 4: the root, d, f and console, with blocks from
+    // `data_start` on.
+    let inodes = [(1, 4 * 16), (1, 3 * 16), (2, 20 * bsize + 5), (3, 0)];
+    let (mut blockno, mut indirect) = (data_start, 0);
+    for (i, &(file_type, size)) in inodes.iter().enumerate() {
+      let offset = inode_start * bsize + (i + 1) * 64;
+
+      put16(&mut image, offset, file_type, big_endian);
+      put(&mut image, offset + 8, size as u32, big_endian);
+      for n in 0..(size + bsize - 1) / bsize {
+        let at = if n < 12 {
+          offset + 12 + 4 * n
+        } else {
+          if n == 12 {
+            indirect = blockno;
+            put(&mut image, offset + 60, indirect as u32, big_endian);
+            blockno += 1;
+          }
+          indirect * bsize + 4 * (n - 12)
+        };
+        put(&mut image, at, blockno as u32, big_endian);
+        for byte in 0..bsize {
+          image[blockno * bsize + byte] = n as u8;
+        }
+        blockno += 1;
+      }
+    }
+    let dirents = [
+      (data_start, vec![(1, "."), (1, ".."), (2, "d"), (4, "console")]),
+      (data_start + 1, vec![(2, "."), (1, ".."), (3, "f")]),
+    ];
+    for &(blockno, ref entries) in &dirents {
+      let block = &mut image[blockno * bsize..(blockno + 1) * bsize];
+
+      for (i, &(inum, name)) in entries.iter().enumerate() {
+        put16(block, 16 * i, inum, big_endian);
+        block[16 * i + 2..16 * i + 2 + name.len()]
+          .copy_from_slice(name.as_bytes());
+      }
+    }
+    image
+  }
+
+  #[test]
+  fn test() {
+    for &layout in &[Layout::Rev7, Layout::Rev9, Layout::Rev11] {
+      for &big_endian in &[false, true] {
+        let legacy = Legacy::parse(build(layout, big_endian)).unwrap();
+        assert!(legacy.layout() == layout);
+        assert!(legacy.is_big_endian() == big_endian);
+
+        let root = legacy.readdir(ROOTINO).unwrap();
+        assert!(root.len() == 2 && ops::from_name(&root[0].1) == b"d");
+        let f = legacy.read(3).unwrap();
+        assert!(f.len() == 20 * legacy.bsize + 5);
+        assert!(f[13 * legacy.bsize] == 13 && f[20 * legacy.bsize] == 20);
+        assert!(legacy.readdir(3).err() == Some(Error::NotDir));
+      }
+    }
+
+    // Images made here are not taken for legacy ones.
+    testfs::test::mount();
+    let mut disk = DISK.unmount();
+    let mut data = vec![];
+    for i in 0..disk.nblocks() {
+      data.extend_from_slice(&disk.read(i));
+    }
+    testfs::test::mount();
+    assert!(Legacy::parse(data).is_none());
+    assert!(Legacy::parse(vec![0; 4096]).is_none());
+  }
+
+  #[test]
+  fn test_copy() {
+    let legacy = Legacy::parse(build(Layout::Rev11, true)).unwrap();
+    testfs::test::mount_disk(legacy.format().unwrap());
+    LOGGING.init().unwrap();
+    legacy.copy(true).unwrap();
+
+    let txn = LOGGING.new_txn();
+    let d = ops::resolve(&txn, b"/d").unwrap();
+    let f = ops::resolve(&txn, b"/d/f").unwrap();
+    assert!(ops::stat(&txn, &d).file_type == FileType::Directory);
+    let data = ops::read(&txn, &f, 0, 1 << 16).unwrap();
+    assert!(data == legacy.read(3).unwrap());
+    assert!(ops::resolve(&txn, b"/console").err() == Some(Error::NotFound));
+    assert!(ops::write(&txn, &f, 0, b"x").err() == Some(Error::ReadOnly));
+    let name = ops::to_name(b"g").unwrap();
+    assert!(
+      ops::create(&txn, &ops::root(), &name, FileType::File).err() ==
+        Some(Error::ReadOnly)
+    );
+  }
+}
@@ -9,13 +9,47 @@ extern crate bitflags;
 #[macro_use]
 extern crate log;
 
+extern crate fuse;
+extern crate libc;
+extern crate threadpool;
+extern crate time;
+
 #[macro_use]
 pub mod util;
+pub mod auth;
+pub mod badblock;
+pub mod batch;
+pub mod coalesce;
+pub mod crypt;
+pub mod dedup;
 pub mod disk;
+pub mod error;
 pub mod fs;
+pub mod image;
 pub mod inode;
+pub mod integrity;
+pub mod legacy;
+pub mod lock;
 pub mod logging;
+pub mod memory;
+pub mod mkfs;
+pub mod mount;
+pub mod objstore;
+pub mod ops;
+pub mod overlay;
+pub mod qos;
+pub mod readahead;
+pub mod reclaim;
+pub mod snapshot;
+pub mod tune;
 
 mod buffer;
 mod bitmap;
+mod crash;
+mod delalloc;
+mod faulty;
+mod refcount;
 mod testfs;
+
+pub use mkfs::mkfs;
+pub use mount::{MountHandle, spawn_mount};
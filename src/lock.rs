@@ -0,0 +1,192 @@
+// Advisory locks of the open files, the byte-range locks of fcntl(2),
+// which the kernel also emulates flock(2) with, kept in memory only.
+//
+// A lock belongs to the owner the kernel names, a process or an open file,
+// and covers the bytes from its start to its end, both included. Locks of
+// different owners conflict where they overlap and one of them is a write
+// lock. Locking a range again replaces the locks its owner had there, and
+// unlocking a part of a lock leaves the rest of it.
+
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+  Read,
+  Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Lock {
+  pub owner: u64,
+  // Of the process that took it, as reported to others.
+  pub pid: u32,
+  pub start: u64,
+  pub end: u64,
+  pub kind: Kind,
+}
+
+impl Lock {
+  fn overlaps(&self, start: u64, end: u64) -> bool {
+    self.start <= end && start <= self.end
+  }
+
+  fn conflicts(&self, other: &Lock) -> bool {
+    self.owner != other.owner && self.overlaps(other.start, other.end)
+      && (self.kind == Kind::Write || other.kind == Kind::Write)
+  }
+}
+
+pub struct Locks {
+  // By inode number.
+  locks: Mutex<HashMap<usize, Vec<Lock>>>,
+  unlocked: Condvar,
+}
+
+impl Locks {
+  pub fn new() -> Self {
+    Locks {
+      locks: Mutex::new(HashMap::new()),
+      unlocked: Condvar::new(),
+    }
+  }
+
+  // Return a lock on inode `inum` that conflicts with `lock`, if any.
+  pub fn test(&self, inum: usize, lock: &Lock) -> Option<Lock> {
+    let locks = self.locks.lock().unwrap();
+
+    locks
+      .get(&inum)
+      .and_then(|locks| locks.iter().find(|l| l.conflicts(lock)).cloned())
+  }
+
+  // Take `lock` on inode `inum`. Return false if it conflicts with another
+  // one, unless `wait`, to wait for that one to go instead.
+  pub fn set(&self, inum: usize, lock: Lock, wait: bool) -> bool {
+    let mut locks = self.locks.lock().unwrap();
+
+    loop {
+      let conflict = match locks.get(&inum) {
+        Some(locks) => locks.iter().any(|l| l.conflicts(&lock)),
+        None => false,
+      };
+      if !conflict {
+        break;
+      }
+      if !wait {
+        return false;
+      }
+      locks = self.unlocked.wait(locks).unwrap();
+    }
+    let ranges = locks.entry(inum).or_insert_with(Vec::new);
+
+    remove(ranges, lock.owner, lock.start, lock.end);
+    ranges.push(lock);
+    true
+  }
+
+  // Drop the locks of `owner` on inode `inum` from `start` to `end`.
+  pub fn unlock(&self, inum: usize, owner: u64, start: u64, end: u64) {
+    let mut locks = self.locks.lock().unwrap();
+
+    if let Some(ranges) = locks.get_mut(&inum) {
+      remove(ranges, owner, start, end);
+    }
+    if locks.get(&inum).map_or(false, |ranges| ranges.is_empty()) {
+      locks.remove(&inum);
+    }
+    self.unlocked.notify_all();
+  }
+
+  // Drop every lock of `owner` on inode `inum`, as it closes the file.
+  pub fn release(&self, inum: usize, owner: u64) {
+    self.unlock(inum, owner, 0, u64::max_value());
+  }
+}
+
+// Cut `start` to `end` out of the locks of `owner` in `ranges`.
+fn remove(ranges: &mut Vec<Lock>, owner: u64, start: u64, end: u64) {
+  let mut rest = vec![];
+
+  for lock in ranges.drain(..) {
+    if lock.owner != owner || !lock.overlaps(start, end) {
+      rest.push(lock);
+      continue;
+    }
+    if lock.start < start {
+      rest.push(Lock {
+        end: start - 1,
+        ..lock
+      });
+    }
+    if lock.end > end {
+      rest.push(Lock {
+        start: end + 1,
+        ..lock
+      });
+    }
+  }
+  *ranges = rest;
+}
+
+#[cfg(test)]
+mod test {
+  use lock::{Kind, Lock, Locks};
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+
+  fn lock(owner: u64, start: u64, end: u64, kind: Kind) -> Lock {
+    Lock {
+      owner,
+      pid: owner as u32,
+      start,
+      end,
+      kind,
+    }
+  }
+
+  #[test]
+  fn test() {
+    let locks = Locks::new();
+
+    // Read locks are shared, write locks are not.
+    assert!(locks.set(5, lock(1, 0, 99, Kind::Read), false));
+    assert!(locks.set(5, lock(2, 50, 149, Kind::Read), false));
+    assert!(!locks.set(5, lock(3, 120, 120, Kind::Write), false));
+    assert!(locks.set(5, lock(3, 150, 199, Kind::Write), false));
+    assert!(locks.set(6, lock(3, 0, 10, Kind::Write), false));
+    let conflict = locks.test(5, &lock(4, 0, 0, Kind::Write));
+    assert!(conflict == Some(lock(1, 0, 99, Kind::Read)));
+    assert!(locks.test(5, &lock(4, 0, 0, Kind::Read)).is_none());
+
+    // Unlocking the middle of a lock leaves both ends.
+    locks.unlock(5, 1, 10, 19);
+    assert!(locks.test(5, &lock(4, 10, 19, Kind::Write)).is_none());
+    assert!(locks.test(5, &lock(4, 9, 9, Kind::Write)).is_some());
+    assert!(locks.test(5, &lock(4, 20, 20, Kind::Write)).is_some());
+
+    // Owners may lock over their own locks.
+    assert!(locks.set(5, lock(3, 100, 199, Kind::Read), false));
+    assert!(!locks.set(5, lock(2, 100, 100, Kind::Write), false));
+    locks.release(5, 2);
+    locks.release(5, 3);
+    assert!(locks.set(5, lock(2, 100, 199, Kind::Write), false));
+  }
+
+  #[test]
+  fn test_wait() {
+    let locks = Arc::new(Locks::new());
+
+    assert!(locks.set(5, lock(1, 0, u64::max_value(), Kind::Write), false));
+    let waiter = {
+      let locks = locks.clone();
+      thread::spawn(move || locks.set(5, lock(2, 0, 0, Kind::Read), true))
+    };
+    thread::sleep(Duration::from_millis(10));
+    assert!(locks.test(5, &lock(3, 0, 0, Kind::Read)).is_some());
+    locks.release(5, 1);
+    assert!(waiter.join().unwrap());
+    assert!(locks.test(5, &lock(3, 0, 0, Kind::Write)).is_some());
+  }
+}
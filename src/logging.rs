@@ -1,175 +1,865 @@
+use bitmap::Bitmap;
 use buffer::{BCACHE, LockedBuf};
-use disk::BSIZE;
-use fs::{LOGSIZE, LogHeader};
-use std::mem::size_of;
-use std::sync::{Mutex, Condvar};
+use delalloc;
+use disk::DISK;
+use disk::{BSIZE, Block};
+use error::{Error, Result};
+use fs::{CHECKSUMS, DiskInode, IPB, LOGSIZE, LogHeader, ROOTINO, SuperBlock};
+use inode::ICACHE;
+use integrity;
+use memory::{Account, MEMORY};
+use std::cell::Cell;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::mem::{size_of, transmute};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Condvar, Once};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
 // TODO: failpoint testing.
 // https://github.com/pingcap/fail-rs
 
-// We define LOGSIZE as 64 in fs.rs, thus allow maximum 4
-// concurrent txns.
-const MAXOPBLOCKS: usize = 16;
+// The log is made of segments of LOGSIZE blocks, as many as mkfs gives it,
+// each a header and the blocks it logs. A commit logs its blocks in as many
+// segments as it takes, and the header of the first one, which counts the
+// others, is written last as the commit point. Installing them, the
+// checkpoint, clears it again. With a write-back cache, they are installed
+// in the cache only, and the header is cleared once they are written out,
+// before the log is written again, see `checkpoint`.
+//
+// We define LOGSIZE as 64 in fs.rs, thus allow maximum 3 concurrent txns
+// per segment.
+pub const MAXOPBLOCKS: usize = 16;
 
 struct LogState {
   committing: bool,
   outstanding: usize,
+  // No transaction may start, see `freeze`.
+  frozen: bool,
+  // Nor while someone waits for a commit, see `force_commit`.
+  forcing: usize,
+  // Nor by any thread but this one, see `exclusive`.
+  owner: Option<ThreadId>,
+  // Commits since the log was recovered.
+  commits: usize,
+  // Operations of the transactions that ended since the last commit, left
+  // to the committer, and when the first of them ended.
+  deferred: usize,
+  since: Option<Instant>,
+  // Transactions waiting for room in the log.
+  waiting: usize,
+  // How long the committer may wait, see `start_committer`, None to commit
+  // as the last transaction ends instead.
+  window: Option<Duration>,
+  // And how many blocks it lets be written meanwhile, 0 for half the log.
+  threshold: usize,
+}
+
+impl LogState {
+  fn new() -> Self {
+    LogState {
+      committing: false,
+      outstanding: 0,
+      frozen: false,
+      forcing: 0,
+      owner: None,
+      commits: 0,
+      deferred: 0,
+      since: None,
+      waiting: 0,
+      window: None,
+      threshold: 0,
+    }
+  }
+
+  // Return true if another thread has the log to itself.
+  fn excludes(&self) -> bool {
+    self.owner.map_or(false, |owner| owner != thread::current().id())
+  }
 }
 
 pub struct Logging {
-  start: usize,
-  size: usize,
   state: Mutex<LogState>,
   condvar: Condvar,
-  lh: Mutex<LogHeader>,
+  // Blocks written since the last commit, to be logged at the next.
+  blocks: Mutex<Vec<u32>>,
+  // The transactions that wrote each of them, see `Transaction::abort`.
+  // Only locked with `blocks`.
+  writers: Mutex<HashMap<u32, Vec<usize>>>,
+  // The headers of the last commit until its checkpoint.
+  installing: Mutex<Vec<(usize, LogHeader)>>,
 }
 
 pub struct Transaction<'a> {
   logging: &'a Logging,
-  // A transaction can be nested if it happens within another
-  // transaction, where the number of outstanding transactions
-  // will not be increased, so a commit will not happen when this
-  // transaction is terminated.
+  // A transaction is nested if it happens within another one of the same
+  // thread, see `new_nested_txn`. It joins the enclosing transaction,
+  // where the number of outstanding transactions will not be increased,
+  // so a commit will not happen when this transaction is terminated.
   nested: bool,
+  // Number of operations the transaction reserves log space for.
+  nops: usize,
+  // A read transaction, see `new_read_txn`.
+  read_only: bool,
+  // Shared by a nested transaction with the enclosing one.
+  id: usize,
+  // The commit's own, see `commit`, which neither begins nor ends.
+  in_commit: bool,
 }
 
 lazy_static! {
   pub static ref LOGGING: Logging = Logging::new();
 }
 
+static COMMITTER: Once = Once::new();
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+  // Read transactions running on this thread.
+  static READERS: Cell<usize> = Cell::new(0);
+  // Other transactions running on this thread, nested ones included.
+  static WRITERS: Cell<usize> = Cell::new(0);
+  // The id of the outermost of them.
+  static CURRENT: Cell<usize> = Cell::new(0);
+}
+
+// Return true if a read transaction runs on this thread.
+pub fn in_read_txn() -> bool {
+  READERS.with(|readers| readers.get() > 0)
+}
+
 impl Logging {
   fn new() -> Self {
-    let sb = BCACHE.sb();
-
     assert!(size_of::<LogHeader>() <= BSIZE);
-    assert!(sb.nlogs as usize <= LOGSIZE);
 
     Logging {
-      start: sb.log_start as usize,
-      size: sb.nlogs as usize,
-      state: Mutex::new(LogState {
-        committing: false,
-        outstanding: 0,
-      }),
+      state: Mutex::new(LogState::new()),
       condvar: Condvar::new(),
-      lh: Mutex::new(LogHeader {
-        n: 0,
+      blocks: Mutex::new(vec![]),
+      writers: Mutex::new(HashMap::new()),
+      installing: Mutex::new(vec![]),
+    }
+  }
+
+  // First block and number of blocks of the log of the mounted file system.
+  fn start(&self) -> usize {
+    BCACHE.sb().log_start as usize
+  }
+
+  fn size(&self) -> usize {
+    BCACHE.sb().nlogs as usize
+  }
+
+  // Return the header block of each segment, and how many blocks it logs
+  // at most.
+  fn segments(&self) -> Vec<(usize, usize)> {
+    let (start, size) = (self.start(), self.size());
+
+    (0..(size + LOGSIZE - 1) / LOGSIZE)
+      .map(|i| (start + i * LOGSIZE, min(LOGSIZE, size - i * LOGSIZE) - 1))
+      .collect()
+  }
+
+  // Number of blocks a commit logs at most, all but the headers.
+  fn capacity(&self) -> usize {
+    let size = self.size();
+
+    size - (size + LOGSIZE - 1) / LOGSIZE
+  }
+
+  // Reset the log of the mounted file system, and recover it, in the cache
+  // only if the disk is read-only. Corrupt if the committed transaction
+  // refers to blocks past the end, which is then left in the log, installed
+  // nowhere, or if the log header, the super block or the root inode fails
+  // its checksum.
+  pub fn init(&self) -> Result<()> {
+    *self.state.lock().unwrap() = LogState::new();
+    // The blocks logged are pinned in the cache until they are committed.
+    let (meta, data) = BCACHE.capacities();
+    let least = 2 * self.size();
+    BCACHE.set_capacities(max(meta, least), max(data, least));
+    {
+      let mut blocks = self.blocks.lock().unwrap();
+
+      MEMORY.release(Account::Log, blocks.len() * BSIZE);
+      blocks.clear();
+      self.writers.lock().unwrap().clear();
+    }
+    self.installing.lock().unwrap().clear();
+    self.recover()?;
+    // The super block as recovered, not as cached before.
+    let sb = from_block!(&BCACHE.read(1).unwrap().data, SuperBlock);
+    if !sb.verify() {
+      error!("super block fails its checksum");
+      return Err(Error::Corrupt);
+    }
+    ICACHE.verify(&self.new_read_txn(), ROOTINO)?;
+    delalloc::clear();
+    Bitmap::init(&self.new_read_txn());
+    ICACHE.init_free(&self.new_read_txn());
+    integrity::init();
+    Ok(())
+  }
+
+  // Return the log blocks each operation reserves, and those held back
+  // for the super block. With a hash tree, each block written may take a
+  // hash block along at commit, see integrity.rs.
+  fn reserve(&self) -> (usize, usize) {
+    if BCACHE.sb().hashblk != 0 {
+      (2 * MAXOPBLOCKS, 2)
+    } else {
+      (MAXOPBLOCKS, 0)
+    }
+  }
+
+  // Add `buf` to the blocks of the transactions, pinned in the cache until
+  // committed, unless it is there already.
+  fn absorb<'b>(&self, blocks: &mut Vec<u32>, buf: &mut LockedBuf<'b>) {
+    if blocks.contains(&(buf.no() as u32)) {
+      return;
+    }
+    if blocks.len() >= self.capacity() {
+      panic!("too big transaction");
+    }
+    blocks.push(buf.no() as u32);
+    BCACHE.pin(buf);
+    MEMORY.charge(Account::Log, BSIZE);
+  }
+
+  // Revert the blocks transaction `id` wrote, see `Transaction::abort`.
+  // Return whether it could, or None to try again if one of them, or of
+  // the inodes in them, is locked meanwhile.
+  fn rollback(&self, id: usize) -> Option<bool> {
+    let mut blocks = self.blocks.lock().unwrap();
+    let mut writers = self.writers.lock().unwrap();
+    let mut bufs = vec![];
+    let mut inodes = vec![];
+    let sb = BCACHE.sb();
+
+    for (&blockno, ids) in writers.iter() {
+      if !ids.contains(&id) {
+        continue;
+      }
+      if ids.len() > 1 {
+        warn!("block {} is shared, not aborting", blockno);
+        return Some(false);
+      }
+      let blockno = blockno as usize;
+      let buf = BCACHE.get(blockno).and_then(|buf| buf.try_acquire())?;
+      // Its content on the disk is older than the last commit.
+      if buf.is_dirty() {
+        warn!("block {} is not written out, not aborting", blockno);
+        return Some(false);
+      }
+
+      // The inodes changed, whose cached copies go back too.
+      let (start, end) = (sb.inode_start as usize, sb.bmap_start as usize);
+      if blockno >= start && blockno < end {
+        let disk = DISK.read(blockno);
+        let old: &[DiskInode; IPB] = unsafe { transmute(&disk) };
+        let size = size_of::<DiskInode>();
+
+        for j in 0..IPB {
+          let (k, l) = (j * size, (j + 1) * size);
+
+          if buf.data[k..l] != disk[k..l] {
+            inodes.push(((blockno - start) * IPB + j, old[j].clone()));
+          }
+        }
+      }
+      bufs.push(buf);
+    }
+    if !ICACHE.try_revert(&inodes)? {
+      return Some(false);
+    }
+    let mut grown = None;
+    for mut buf in bufs {
+      let blockno = buf.no() as u32;
+      let data = buf.data;
+      let bmap_start = sb.bmap_start as usize;
+
+      BCACHE.revert(&mut buf);
+      if buf.no() >= bmap_start && buf.no() < sb.data_start() {
+        Bitmap::revert(buf.no(), &data, &buf.data);
+      }
+      if buf.no() >= sb.inode_start as usize && buf.no() < bmap_start {
+        ICACHE.revert(buf.no(), &data, &buf.data);
+      }
+      if buf.no() == 1 {
+        grown = Some(from_block!(&buf.data, SuperBlock));
+      }
+      blocks.retain(|&b| b != blockno);
+      writers.remove(&blockno);
+      MEMORY.release(Account::Log, BSIZE);
+    }
+    // The inode table it grew, once the inodes it took in there are freed.
+    if let Some(old) = grown {
+      ICACHE.shrink((sb.ninodes - old.ninodes) as usize);
+      BCACHE.set_sb(old);
+    }
+    Some(true)
+  }
+
+  fn read_head(&self, blockno: usize) -> LogHeader {
+    let buf = BCACHE.read(blockno).unwrap();
+
+    from_block!(&buf.data, LogHeader)
+  }
+
+  fn write_head(&self, blockno: usize, lh: &mut LogHeader) {
+    let mut buf = BCACHE.read(blockno).unwrap();
+
+    lh.seal();
+    buf.data = to_block!(lh, LogHeader);
+    BCACHE.write(&mut buf);
+  }
+
+  // Return the headers of the segments `blocks` are logged in, with their
+  // blocks, the first counting the others.
+  fn headers(&self, blocks: &[u32]) -> Vec<(usize, LogHeader)> {
+    let mut headers = vec![];
+    let mut rest = blocks;
+
+    for (blockno, n) in self.segments() {
+      if rest.is_empty() {
+        break;
+      }
+      let mut lh = LogHeader {
+        n: min(n, rest.len()) as u32,
         blocks: [0; LOGSIZE],
-      }),
+        checksum: 0,
+        more: 0,
+        payload: 0,
+      };
+
+      lh.blocks[..lh.n as usize].copy_from_slice(&rest[..lh.n as usize]);
+      rest = &rest[lh.n as usize..];
+      headers.push((blockno, lh));
     }
+    headers[0].1.more = headers.len() as u32 - 1;
+    headers
   }
 
-  pub fn init(&self) {
-    *self.state.lock().unwrap() = LogState {
-      committing: false,
-      outstanding: 0,
-    };
-    *self.lh.lock().unwrap() = LogHeader {
+  // Log the blocks of `headers`, and set the checksum of what they hold in
+  // each, so that a commit torn by a crash is not installed.
+  fn write_log(&self, headers: &mut [(usize, LogHeader)]) {
+    for &mut (start, ref mut lh) in headers {
+      let mut payload = vec![];
+
+      for i in 0..(lh.n as usize) {
+        let src_blockno = lh.blocks[i] as usize;
+        let dst_blockno = start + i + 1;
+
+        let src_buf = BCACHE.read(src_blockno).unwrap();
+        let mut dst_buf = BCACHE.read(dst_blockno).unwrap();
+
+        dst_buf.data = src_buf.data;
+        BCACHE.write(&mut dst_buf);
+        payload.push(dst_buf.data);
+      }
+      lh.set_payload(&payload);
+    }
+  }
+
+  // Return what the blocks logged in the segment of `lh` at `start` hold.
+  fn read_payload(&self, start: usize, lh: &LogHeader) -> Vec<Block> {
+    (0..lh.n as usize)
+      .map(|i| BCACHE.read(start + i + 1).unwrap().data)
+      .collect()
+  }
+
+  // Install the blocks logged to where they belong, in the cache only in
+  // write-back mode.
+  fn install_txn(&self, headers: &[(usize, LogHeader)]) {
+    for &(start, ref lh) in headers {
+      for i in 0..(lh.n as usize) {
+        let src_blockno = start + i + 1;
+        let dst_blockno = lh.blocks[i] as usize;
+
+        let src_buf = BCACHE.read(src_blockno).unwrap();
+        let mut dst_buf = BCACHE.read(dst_blockno).unwrap();
+
+        dst_buf.data = src_buf.data;
+        BCACHE.defer_write(&mut dst_buf);
+      }
+    }
+  }
+
+  // Write out what the last commit installed, and clear the header of the
+  // first segment, the checkpoint. The blocks written again since, which
+  // are not committed yet, are written out as they were logged instead. It
+  // must run before the log is written again, or the file system is frozen
+  // or unmounted.
+  pub fn checkpoint(&self) {
+    let mut installing = self.installing.lock().unwrap();
+
+    if installing.is_empty() {
+      return;
+    }
+    for blockno in BCACHE.sync() {
+      for &(start, ref lh) in installing.iter() {
+        let blocks = &lh.blocks[..lh.n as usize];
+
+        if let Some(i) = blocks.iter().position(|&b| b as usize == blockno) {
+          DISK.write(blockno, &BCACHE.read(start + i + 1).unwrap().data);
+        }
+      }
+    }
+    self.clear_head();
+    installing.clear();
+  }
+
+  // Clear the header of the first segment, once the checkpoint is done.
+  // A read-only disk keeps its log.
+  fn clear_head(&self) {
+    if DISK.is_read_only() {
+      return;
+    }
+    let mut lh = LogHeader {
       n: 0,
       blocks: [0; LOGSIZE],
+      checksum: 0,
+      more: 0,
+      payload: 0,
     };
-    self.recover();
+
+    self.write_head(self.start(), &mut lh);
   }
 
-  fn read_head(&self, lh: &mut LogHeader) {
-    let buf = BCACHE.read(self.start).unwrap();
+  // Install the committed transaction, if any. One whose headers or blocks
+  // fail their checksums was torn by a crash as it was being committed, and
+  // is dropped as if it never was.
+  fn recover(&self) -> Result<()> {
+    let segments = self.segments();
+    let first = self.read_head(self.start());
+    let mut headers = vec![];
+
+    if first.n == 0 {
+      return Ok(());
+    }
+    // The headers come from the disk, check them before installing anything.
+    let sb = BCACHE.sb();
+    if first.more as usize >= segments.len() {
+      error!("log header refers to segments out of range");
+      return Err(Error::Corrupt);
+    }
+    for &(blockno, n) in &segments[..first.more as usize + 1] {
+      let lh = if headers.is_empty() {
+        first
+      } else {
+        self.read_head(blockno)
+      };
+
+      if sb.flags & CHECKSUMS != 0 && !lh.verify() {
+        warn!("log header fails its checksum, dropping a torn commit");
+        self.clear_head();
+        return Ok(());
+      }
+      if lh.n as usize > n ||
+        lh.blocks[..lh.n as usize].iter().any(|&b| b >= sb.nblocks)
+      {
+        error!("log header refers to blocks out of range");
+        return Err(Error::Corrupt);
+      }
+      if !lh.verify_payload(&self.read_payload(blockno, &lh)) {
+        warn!("log blocks fail their checksum, dropping a torn commit");
+        self.clear_head();
+        return Ok(());
+      }
+      headers.push((blockno, lh));
+    }
+    // Read-only, in the cache only, where the blocks stay pinned.
+    if DISK.is_read_only() {
+      for &(start, ref lh) in &headers {
+        for i in 0..(lh.n as usize) {
+          let mut buf = BCACHE.read(lh.blocks[i] as usize).unwrap();
 
-    *lh = from_block!(&buf.data, LogHeader);
+          buf.data = BCACHE.read(start + i + 1).unwrap().data;
+          BCACHE.pin(&mut buf);
+        }
+      }
+      return Ok(());
+    }
+    self.install_txn(&headers);
+    BCACHE.sync();
+    self.clear_head();
+    Ok(())
   }
 
-  fn write_head(&self, lh: &LogHeader) {
-    let mut buf = BCACHE.read(self.start).unwrap();
+  // Return how many more blocks a commit may log, with the hash blocks
+  // they take along, see `reserve`.
+  fn room(&self) -> usize {
+    let (per_op, held) = self.reserve();
+    let k = per_op / MAXOPBLOCKS;
+    let n = self.blocks.lock().unwrap().len();
 
-    buf.data = to_block!(lh, LogHeader);
-    BCACHE.write(&mut buf);
+    (self.capacity() - held).saturating_sub(n * k) / k
   }
 
-  fn write_log(&self, lh: &LogHeader) {
-    for i in 0..(lh.n as usize) {
-      let src_blockno = lh.blocks[i] as usize;
-      let dst_blockno = (self.start as usize) + i + 1;
+  // Commit what the transactions that have ended wrote, once `committing`
+  // is set with none running.
+  fn commit(&self) {
+    // The blocks whose allocation was delayed are allocated along, as many
+    // as the log has room for, see delalloc.rs.
+    let inodes = if delalloc::reserved() > 0 {
+      let mut txn = Transaction::new(self, false, 0);
+      txn.in_commit = true;
+      delalloc::flush(&txn, self.room())
+    } else {
+      vec![]
+    };
+    {
+      let mut blocks = self.blocks.lock().unwrap();
 
-      let src_buf = BCACHE.read(src_blockno).unwrap();
-      let mut dst_buf = BCACHE.read(dst_blockno).unwrap();
+      if !blocks.is_empty() {
+        let blocknos = blocks.clone();
+        for mut buf in integrity::update(&blocknos) {
+          self.absorb(&mut blocks, &mut buf);
+        }
+        info!("committing {} blocks", blocks.len());
+        self.checkpoint();
 
-      dst_buf.data = src_buf.data;
-      BCACHE.write(&mut dst_buf);
+        let mut headers = self.headers(&blocks);
+        self.write_log(&mut headers);
+        // The first header last, the commit point.
+        for &mut (blockno, ref mut lh) in headers.iter_mut().rev() {
+          self.write_head(blockno, lh);
+        }
+        self.install_txn(&headers);
+        for &blockno in blocks.iter() {
+          BCACHE.unpin(&mut BCACHE.read(blockno as usize).unwrap());
+        }
+        MEMORY.release(Account::Log, blocks.len() * BSIZE);
+        blocks.clear();
+        self.writers.lock().unwrap().clear();
+        *self.installing.lock().unwrap() = headers;
+        if !BCACHE.is_writeback() {
+          self.checkpoint();
+        }
+        Bitmap::discard_freed();
+      }
     }
-  }
+    let mut state = self.state.lock().unwrap();
 
-  fn install_txn(&self, lh: &LogHeader) {
-    for i in 0..(lh.n as usize) {
-      let src_blockno = (self.start as usize) + i + 1;
-      let dst_blockno = lh.blocks[i] as usize;
+    state.committing = false;
+    state.commits += 1;
+    state.deferred = 0;
+    state.since = None;
+    self.condvar.notify_all();
+    drop(state);
+    // Putting them may take a transaction, see inode.rs.
+    drop(inodes);
+  }
 
-      let src_buf = BCACHE.read(src_blockno).unwrap();
-      let mut dst_buf = BCACHE.read(dst_blockno).unwrap();
+  // Leave the commits to a background thread from now on, rather than to
+  // the last transaction to end, which then returns at once. It commits
+  // what the transactions that end within `window` of the first of them
+  // wrote together, and sooner once they wrote `threshold` blocks, half
+  // the log if 0, someone waits for room in it, or `force_commit` is
+  // called, e.g. for fsync. A crash loses the transactions of the window
+  // that ended uncommitted. The blocks they wrote stay pinned in the cache
+  // meanwhile, which has room for the whole log, see `init`.
+  pub fn start_committer(&self, window: Duration, threshold: usize) {
+    {
+      let mut state = self.state.lock().unwrap();
 
-      dst_buf.data = src_buf.data;
-      BCACHE.write(&mut dst_buf);
+      state.window = Some(window);
+      state.threshold = threshold;
     }
+    self.condvar.notify_all();
+    COMMITTER.call_once(|| {
+      thread::spawn(|| LOGGING.run_committer());
+    });
   }
 
-  fn recover(&self) {
-    let lh = &mut *self.lh.lock().unwrap();
+  // Commit as the last transaction ends again, and what is left at once,
+  // e.g. before unmounting.
+  pub fn stop_committer(&self) {
+    self.state.lock().unwrap().window = None;
+    self.force_commit();
+  }
+
+  fn run_committer(&self) {
+    let mut state = self.state.lock().unwrap();
 
-    self.read_head(lh);
-    self.install_txn(lh);
-    lh.n = 0;
-    self.write_head(lh);
+    loop {
+      if state.deferred == 0 || state.committing {
+        state = self.condvar.wait(state).unwrap();
+        continue;
+      }
+      let zero = Duration::from_secs(0);
+      let elapsed = state.since.map_or(zero, |since| since.elapsed());
+      let left = state
+        .window
+        .map_or(zero, |window| window.checked_sub(elapsed).unwrap_or(zero));
+      let due = left == zero || state.forcing > 0 || state.frozen ||
+        state.waiting > 0 || MEMORY.over_budget() ||
+        self.blocks.lock().unwrap().len() >= self.threshold(&state);
+
+      if !due {
+        state = self.condvar.wait_timeout(state, left).unwrap().0;
+        continue;
+      }
+      // New transactions wait meanwhile, as for `force_commit`.
+      if state.outstanding > 0 {
+        state.forcing += 1;
+        while state.outstanding > 0 {
+          state = self.condvar.wait(state).unwrap();
+        }
+        state.forcing -= 1;
+        self.condvar.notify_all();
+        continue;
+      }
+      state.committing = true;
+      drop(state);
+      self.commit();
+      state = self.state.lock().unwrap();
+    }
+  }
+
+  // Return how many blocks the committer lets be written before it commits.
+  fn threshold(&self, state: &LogState) -> usize {
+    match state.threshold {
+      0 => self.capacity() / 2,
+      threshold => min(threshold, self.capacity()),
+    }
   }
 
   pub fn new_txn<'a>(&'a self) -> Transaction<'a> {
-    let txn = Transaction::new(self, false);
+    let txn = Transaction::new(self, false, 1);
     txn.begin_txn();
     txn
   }
 
+  // Start a transaction that joins the one this thread runs, if any, e.g.
+  // to put an inode wherever its last reference is dropped. It takes no
+  // room in the log of its own, and cannot wait for a commit that the
+  // enclosing transaction holds back. Without one, it is a full one.
   pub fn new_nested_txn<'a>(&'a self) -> Transaction<'a> {
-    let txn = Transaction::new(self, true);
+    let nested = WRITERS.with(|writers| writers.get() > 0);
+    let txn = Transaction::new(self, nested, 1);
+    txn.begin_txn();
+    txn
+  }
+
+  // Start a transaction that only reads, e.g. for getattr, which neither
+  // takes room in the log nor the lock of its state. Writers update the
+  // cached blocks in place under their own locks, and a commit merely
+  // copies them to the log and back, so a reader sees the same as in a
+  // full transaction, without waiting for the commit. Inodes whose last
+  // reference it drops are put once it ends, in a full transaction, as
+  // that may free them. It must not run within another transaction.
+  pub fn new_read_txn<'a>(&'a self) -> Transaction<'a> {
+    let mut txn = Transaction::new(self, false, 0);
+    txn.read_only = true;
     txn.begin_txn();
     txn
   }
+
+  // Maximum number of operations a single transaction can hold.
+  pub fn max_ops(&self) -> usize {
+    let (per_op, held) = self.reserve();
+    (self.capacity() - held) / per_op
+  }
+
+  // Start a transaction large enough for `nops` operations, which waits
+  // for the log to have room for all of them at once.
+  pub fn new_batch_txn<'a>(&'a self, nops: usize) -> Transaction<'a> {
+    assert!(nops > 0 && nops <= self.max_ops());
+
+    let txn = Transaction::new(self, false, nops);
+    txn.begin_txn();
+    txn
+  }
+
+  // Quiesce the file system for an external backup: keep new transactions
+  // from starting until `thaw`, wait for the running ones to commit, and
+  // checkpoint and flush the disk, whose content is then consistent. Return
+  // false if it is frozen already.
+  pub fn freeze(&self) -> bool {
+    if self.state.lock().unwrap().frozen {
+      return false;
+    }
+    // Those delayed are part of the backup as well, see delalloc.rs.
+    delalloc::sync();
+    let mut state = self.state.lock().unwrap();
+
+    if state.frozen {
+      return false;
+    }
+    state.frozen = true;
+    self.condvar.notify_all();
+    while state.committing || state.outstanding > 0 || state.deferred > 0 {
+      state = self.condvar.wait(state).unwrap();
+    }
+    drop(state);
+
+    self.checkpoint();
+    DISK.flush();
+    true
+  }
+
+  // Commit what the transactions that have ended wrote, and wait for it to
+  // be on the disk, e.g. for fsync. Rather than for the running ones to let
+  // the log drain by chance, new transactions wait meanwhile. The blocks
+  // whose allocation is delayed are allocated first, see delalloc.rs. It
+  // must not run within a transaction.
+  pub fn force_commit(&self) {
+    delalloc::sync();
+    let mut state = self.state.lock().unwrap();
+
+    // The log is empty otherwise, as the last transaction to end commits,
+    // or the committer with the transactions deferred to it.
+    if state.committing || state.outstanding > 0 || state.deferred > 0 {
+      let commit = state.commits + 1;
+
+      state.forcing += 1;
+      self.condvar.notify_all();
+      while state.commits < commit {
+        state = self.condvar.wait(state).unwrap();
+      }
+      state.forcing -= 1;
+      self.condvar.notify_all();
+    }
+    drop(state);
+
+    DISK.flush();
+  }
+
+  // Keep the transactions of other threads from starting until what is
+  // returned is dropped, and wait for the running ones to end, so that
+  // nothing but this thread changes the file system meanwhile, e.g. for a
+  // snapshot, which takes many transactions. It must not run within a
+  // transaction.
+  pub fn exclusive(&self) -> Exclusive<'_> {
+    let mut state = self.state.lock().unwrap();
+
+    while state.owner.is_some() || state.frozen {
+      state = self.condvar.wait(state).unwrap();
+    }
+    state.owner = Some(thread::current().id());
+    while state.committing || state.outstanding > 0 {
+      state = self.condvar.wait(state).unwrap();
+    }
+    Exclusive { logging: self }
+  }
+
+  // Let transactions start again, return false if it is not frozen.
+  pub fn thaw(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+
+    if !state.frozen {
+      return false;
+    }
+    state.frozen = false;
+    self.condvar.notify_all();
+    true
+  }
+}
+
+// The log to a thread of its own, see Logging::exclusive, until dropped.
+pub struct Exclusive<'a> {
+  logging: &'a Logging,
+}
+
+impl<'a> Drop for Exclusive<'a> {
+  fn drop(&mut self) {
+    self.logging.state.lock().unwrap().owner = None;
+    self.logging.condvar.notify_all();
+  }
 }
 
 // RAII transaction, which acts as a proxy for block cache read and
 // write.
 impl<'a> Transaction<'a> {
-  fn new(logging: &'a Logging, nested: bool) -> Self {
-    Transaction { logging, nested }
+  fn new(logging: &'a Logging, nested: bool, nops: usize) -> Self {
+    let id = if nested {
+      CURRENT.with(|current| current.get())
+    } else {
+      NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    };
+
+    Transaction {
+      logging,
+      nested,
+      nops,
+      read_only: false,
+      id,
+      in_commit: false,
+    }
   }
 
   fn begin_txn(&self) {
+    if self.read_only {
+      READERS.with(|readers| readers.set(readers.get() + 1));
+      return;
+    }
+    let (per_op, held) = self.logging.reserve();
     let mut state = self.logging.state.lock().unwrap();
 
+    WRITERS.with(|writers| writers.set(writers.get() + 1));
     if self.nested {
       assert!(!state.committing);
       return;
     }
+    CURRENT.with(|current| current.set(self.id));
     loop {
-      if state.committing {
+      if state.committing || state.frozen || state.forcing > 0 ||
+        state.excludes()
+      {
         state = self.logging.condvar.wait(state).unwrap();
-      } else if (state.outstanding + 1) * MAXOPBLOCKS > self.logging.size {
+      } else if state.outstanding > 0 && MEMORY.over_budget() {
+        // Let the outstanding transactions commit and release what they
+        // pinned first.
         state = self.logging.condvar.wait(state).unwrap();
+      } else if (state.outstanding + state.deferred + self.nops) * per_op +
+                 held > self.logging.capacity()
+      {
+        // The committer commits at once for us.
+        state.waiting += 1;
+        self.logging.condvar.notify_all();
+        state = self.logging.condvar.wait(state).unwrap();
+        state.waiting -= 1;
       } else {
-        state.outstanding += 1;
+        state.outstanding += self.nops;
         break;
       }
     }
   }
 
   fn end_txn(&self) {
+    if self.in_commit {
+      return;
+    }
+    if self.read_only {
+      let readers = READERS.with(|readers| {
+        readers.set(readers.get() - 1);
+        readers.get()
+      });
+      if readers == 0 {
+        ICACHE.put_deferred();
+      }
+      return;
+    }
     let mut state = self.logging.state.lock().unwrap();
     let mut do_commit = false;
 
+    WRITERS.with(|writers| writers.set(writers.get() - 1));
     assert!(state.outstanding > 0);
     assert!(!state.committing);
 
     if !self.nested {
-      state.outstanding -= 1;
+      state.outstanding -= self.nops;
+      // Left to the committer, see `start_committer`.
+      if state.window.is_some() {
+        state.deferred += self.nops;
+        state.since.get_or_insert_with(Instant::now);
+      }
     }
 
-    if state.outstanding == 0 {
+    if state.outstanding == 0 && state.window.is_none() {
       state.committing = true;
       do_commit = true;
     } else {
@@ -179,23 +869,7 @@ impl<'a> Transaction<'a> {
     drop(state);
 
     if do_commit {
-      self.commit();
-      self.logging.state.lock().unwrap().committing = false;
-      self.logging.condvar.notify_all();
-    }
-  }
-
-  fn commit(&self) {
-    let mut lh = self.logging.lh.lock().unwrap();
-
-    if lh.n > 0 {
-      info!("committing {} blocks", lh.n);
-
-      self.logging.write_log(&lh);
-      self.logging.write_head(&lh); // commit point
-      self.logging.install_txn(&lh);
-      lh.n = 0;
-      self.logging.write_head(&lh);
+      self.logging.commit();
     }
   }
 
@@ -204,27 +878,39 @@ impl<'a> Transaction<'a> {
   }
 
   pub fn write<'b>(&self, buf: &mut LockedBuf<'b>) {
-    let mut lh = self.logging.lh.lock().unwrap();
+    assert!(!self.read_only, "write in a read transaction");
+    let mut blocks = self.logging.blocks.lock().unwrap();
 
-    if lh.n as usize >= self.logging.size - 1 {
-      panic!("too big transaction");
+    // Pin this buffer in cache to avoid being evicted.
+    self.logging.absorb(&mut blocks, buf);
+    let mut writers = self.logging.writers.lock().unwrap();
+    let writers = writers.entry(buf.no() as u32).or_insert_with(Vec::new);
+    if !writers.contains(&self.id) {
+      writers.push(self.id);
     }
+  }
 
-    let mut lh_index = None;
-    for i in 0..(lh.n as usize) {
-      if lh.blocks[i] as usize == buf.no() {
-        lh_index = Some(i);
-        break;
-      }
+  // End the transaction, discarding what it wrote rather than having it
+  // committed, e.g. as an operation fails halfway. The blocks go back to
+  // their content on the disk, and the cached inodes in them with them.
+  // Only the blocks no other transaction wrote since the last commit can,
+  // else nothing is discarded and false returned, as is for a nested
+  // transaction. The caller must not have any inode or block locked, nor
+  // hold an inode the transaction allocated.
+  pub fn abort(self) -> bool {
+    if self.read_only {
+      return true;
     }
-    if lh_index.is_none() {
-      lh_index = Some(lh.n as usize);
-      lh.n += 1;
+    if self.nested {
+      return false;
+    }
+    loop {
+      match self.logging.rollback(self.id) {
+        Some(reverted) => return reverted,
+        // Some of them are locked, by whoever is about to be done with it.
+        None => thread::yield_now(),
+      }
     }
-    lh.blocks[lh_index.unwrap()] = buf.no() as u32;
-
-    // Pin this buffer in cache to avoid being evicted.
-    BCACHE.pin(buf);
   }
 }
 
@@ -237,8 +923,17 @@ impl<'a> Drop for Transaction<'a> {
 #[cfg(test)]
 mod test {
   use buffer::BCACHE;
-  use disk::DISK;
-  use logging::LOGGING;
+  use disk::{BSIZE, DISK, Disk};
+  use error::Error;
+  use fs::{FileType, LOGSIZE, LogHeader, SuperBlock};
+  use inode::ICACHE;
+  use logging::{self, LOGGING};
+  use mkfs::{self, Options};
+  use ops;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::thread;
+  use std::time::Duration;
   use testfs;
 
   #[test]
@@ -260,13 +955,16 @@ mod test {
 
       assert!(BCACHE.nitems() == 2);
       assert!(LOGGING.state.lock().unwrap().outstanding == 1);
-      assert!(LOGGING.lh.lock().unwrap().n == 2);
+      assert!(LOGGING.blocks.lock().unwrap().len() == 2);
+      assert!(buf1.is_pinned() && buf2.is_pinned());
     }
+    // Until committed.
+    assert!(!BCACHE.read(nfree).unwrap().is_pinned());
 
     BCACHE.init();
     assert!(BCACHE.nitems() == 0);
     assert!(LOGGING.state.lock().unwrap().outstanding == 0);
-    assert!(LOGGING.lh.lock().unwrap().n == 0);
+    assert!(LOGGING.blocks.lock().unwrap().is_empty());
 
     {
       let txn = LOGGING.new_txn();
@@ -278,4 +976,482 @@ mod test {
       assert!(buf2.data[0] == 100);
     }
   }
+
+  #[test]
+  fn test_corrupt() {
+    testfs::test::mount();
+
+    // A committed transaction to a block past the end is not installed.
+    let sb = BCACHE.sb();
+    let mut lh = LogHeader {
+      n: 1,
+      blocks: [0; LOGSIZE],
+      checksum: 0,
+      more: 0,
+      payload: 0,
+    };
+    lh.blocks[0] = sb.nblocks + 1;
+    lh.seal();
+    DISK.write(sb.log_start as usize, &to_block!(&lh, LogHeader));
+    BCACHE.init();
+    assert!(LOGGING.init().err() == Some(Error::Corrupt));
+    assert!(from_block!(&DISK.read(sb.log_start as usize), LogHeader).n == 1);
+
+    // One that fails its checksum was torn, and is dropped.
+    let blockno = sb.nblocks as usize - 1;
+    lh.blocks[0] = blockno as u32;
+    lh.set_payload(&[[42; BSIZE]]);
+    lh.seal();
+    lh.checksum ^= 1;
+    DISK.write(sb.log_start as usize, &to_block!(&lh, LogHeader));
+    DISK.write(sb.log_start as usize + 1, &[42; BSIZE]);
+    BCACHE.init();
+    LOGGING.init().unwrap();
+    assert!(from_block!(&DISK.read(sb.log_start as usize), LogHeader).n == 0);
+    assert!(DISK.read(blockno)[0] == 0);
+
+    // So is one whose blocks do not match it.
+    lh.seal();
+    DISK.write(sb.log_start as usize, &to_block!(&lh, LogHeader));
+    DISK.write(sb.log_start as usize + 1, &[43; BSIZE]);
+    BCACHE.init();
+    LOGGING.init().unwrap();
+    assert!(DISK.read(blockno)[0] == 0);
+
+    // Only the one that does is installed.
+    DISK.write(sb.log_start as usize, &to_block!(&lh, LogHeader));
+    DISK.write(sb.log_start as usize + 1, &[42; BSIZE]);
+    BCACHE.init();
+    LOGGING.init().unwrap();
+    assert!(DISK.read(blockno)[0] == 42);
+
+    // A super block that fails its checksum is corrupt.
+    let mut disk_sb = sb;
+    disk_sb.ninodes -= 1;
+    DISK.write(1, &to_block!(&disk_sb, SuperBlock));
+    BCACHE.init();
+    assert!(LOGGING.init().err() == Some(Error::Corrupt));
+  }
+
+  #[test]
+  fn test_read_only() {
+    testfs::test::mount();
+
+    // A committed transaction is installed in the cache only.
+    let sb = BCACHE.sb();
+    let blockno = sb.nblocks as usize - 1;
+    let mut lh = LogHeader {
+      n: 1,
+      blocks: [0; LOGSIZE],
+      checksum: 0,
+      more: 0,
+      payload: 0,
+    };
+    lh.blocks[0] = blockno as u32;
+    lh.set_payload(&[[42; BSIZE]]);
+    lh.seal();
+    DISK.write(sb.log_start as usize, &to_block!(&lh, LogHeader));
+    DISK.write(sb.log_start as usize + 1, &[42; BSIZE]);
+    DISK.set_read_only(true);
+    BCACHE.init();
+    {
+      let _txn = LOGGING.new_txn();
+      ICACHE.init();
+    }
+    LOGGING.init().unwrap();
+    assert!(BCACHE.read(blockno).unwrap().data[0] == 42);
+    assert!(DISK.read(blockno)[0] == 0);
+    assert!(from_block!(&DISK.read(sb.log_start as usize), LogHeader).n == 1);
+
+    // And changes are refused.
+    let txn = LOGGING.new_txn();
+    let name = ops::to_name(b"a").unwrap();
+    let result = ops::create(&txn, &ops::root(), &name, FileType::File);
+    assert!(result.err() == Some(Error::ReadOnly));
+    DISK.write(blockno, &[43; BSIZE]);
+    assert!(DISK.read(blockno)[0] == 0);
+    drop(txn);
+
+    DISK.unmount();
+    assert!(!DISK.is_read_only());
+  }
+
+  #[test]
+  fn test_freeze() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let started = Arc::new(AtomicBool::new(false));
+    let freezer = thread::spawn(|| assert!(LOGGING.freeze()));
+    let writer = {
+      let started = started.clone();
+      thread::spawn(move || {
+        // Waits for the freezer to take effect first.
+        while !LOGGING.state.lock().unwrap().frozen {
+          thread::yield_now();
+        }
+        let _txn = LOGGING.new_txn();
+        started.store(true, Ordering::SeqCst);
+      })
+    };
+
+    // The freezer waits for `txn` to finish.
+    thread::sleep(Duration::from_millis(50));
+    assert!(!freezer.is_finished());
+    drop(txn);
+    freezer.join().unwrap();
+    assert!(!LOGGING.freeze());
+
+    thread::sleep(Duration::from_millis(50));
+    assert!(!started.load(Ordering::SeqCst));
+    assert!(LOGGING.thaw());
+    writer.join().unwrap();
+    assert!(started.load(Ordering::SeqCst));
+    assert!(!LOGGING.thaw());
+  }
+
+  #[test]
+  fn test_exclusive() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let started = Arc::new(AtomicBool::new(false));
+    let owner = thread::spawn(|| {
+      let exclusive = LOGGING.exclusive();
+      // Its own transactions start, nested ones too.
+      {
+        let _txn = LOGGING.new_txn();
+        let _nested = LOGGING.new_nested_txn();
+      }
+      thread::sleep(Duration::from_millis(50));
+      drop(exclusive);
+    });
+    let writer = {
+      let started = started.clone();
+      thread::spawn(move || {
+        while LOGGING.state.lock().unwrap().owner.is_none() {
+          thread::yield_now();
+        }
+        let _txn = LOGGING.new_txn();
+        started.store(true, Ordering::SeqCst);
+      })
+    };
+
+    // The owner waits for `txn` to end, and the writer for the owner.
+    thread::sleep(Duration::from_millis(50));
+    assert!(!owner.is_finished());
+    drop(txn);
+    thread::sleep(Duration::from_millis(20));
+    assert!(!started.load(Ordering::SeqCst));
+    owner.join().unwrap();
+    writer.join().unwrap();
+    assert!(started.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn test_force_commit() {
+    let (disk, nfree) = testfs::test::create();
+    DISK.mount(disk);
+    BCACHE.init();
+
+    let txn = LOGGING.new_txn();
+    let writer = thread::spawn(move || {
+      let txn = LOGGING.new_txn();
+      let mut buf = txn.read(nfree).unwrap();
+
+      buf.data[0] = 42;
+      txn.write(&mut buf);
+    });
+
+    // What the writer wrote is committed once `txn` ends, which the forcer
+    // waits for, and no transaction starts meanwhile.
+    writer.join().unwrap();
+    assert!(DISK.read(nfree)[0] == 0);
+    let forcer = thread::spawn(|| LOGGING.force_commit());
+    while LOGGING.state.lock().unwrap().forcing == 0 {
+      thread::yield_now();
+    }
+    let later = thread::spawn(|| drop(LOGGING.new_txn()));
+    thread::sleep(Duration::from_millis(50));
+    assert!(!forcer.is_finished() && !later.is_finished());
+    drop(txn);
+    forcer.join().unwrap();
+    assert!(DISK.read(nfree)[0] == 42);
+    later.join().unwrap();
+
+    // Nothing to wait for with the log empty.
+    LOGGING.force_commit();
+  }
+
+  #[test]
+  fn test_read() {
+    testfs::test::mount();
+
+    let name = ops::to_name(b"f").unwrap();
+    let (inum, gen, file) = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+
+      ops::write(&txn, &file, 0, b"hello").unwrap();
+      ops::unlink(&txn, &root, &name).unwrap();
+      let gen = ICACHE.lock(&txn, &file).gen;
+      (file.no(), gen, file)
+    };
+
+    // Reading goes on while frozen, without taking room in the log.
+    assert!(LOGGING.freeze());
+    {
+      let txn = LOGGING.new_read_txn();
+      assert!(LOGGING.state.lock().unwrap().outstanding == 0);
+      assert!(ops::read(&txn, &file, 0, 5).unwrap() == b"hello");
+    }
+    assert!(LOGGING.thaw());
+    assert!(ops::get(&LOGGING.new_txn(), inum, gen).is_ok());
+
+    // The unlinked file is freed once the read transaction is over.
+    {
+      let _txn = LOGGING.new_read_txn();
+      drop(file);
+      assert!(logging::in_read_txn());
+      assert!(ICACHE.get(inum).unwrap().refcnt() == 2);
+    }
+    assert!(!logging::in_read_txn());
+    let txn = LOGGING.new_txn();
+    assert!(ops::get(&txn, inum, gen).err() == Some(Error::Stale));
+  }
+
+  #[test]
+  fn test_nested() {
+    let (disk, nfree) = testfs::test::create();
+    DISK.mount(disk);
+    BCACHE.init();
+
+    let outstanding = || LOGGING.state.lock().unwrap().outstanding;
+
+    // A nested transaction joins the one of its thread, and only that.
+    let txn = LOGGING.new_txn();
+    {
+      let nested = LOGGING.new_nested_txn();
+      let mut buf = nested.read(nfree).unwrap();
+
+      buf.data[0] = 42;
+      nested.write(&mut buf);
+      assert!(outstanding() == 1);
+    }
+    assert!(DISK.read(nfree)[0] == 0);
+    let other = thread::spawn(move || {
+      let _nested = LOGGING.new_nested_txn();
+      assert!(LOGGING.state.lock().unwrap().outstanding == 2);
+    });
+    other.join().unwrap();
+    drop(txn);
+    assert!(DISK.read(nfree)[0] == 42);
+
+    // Without an enclosing one, it commits on its own.
+    {
+      let nested = LOGGING.new_nested_txn();
+      let mut buf = nested.read(nfree).unwrap();
+
+      buf.data[0] = 43;
+      nested.write(&mut buf);
+    }
+    assert!(outstanding() == 0 && DISK.read(nfree)[0] == 43);
+  }
+
+  #[test]
+  fn test_abort() {
+    testfs::test::mount();
+
+    let name = ops::to_name(b"f").unwrap();
+    let nfree = ICACHE.count_free(&LOGGING.new_read_txn());
+
+    // Nothing of an aborted create is left, neither on the disk nor in the
+    // caches.
+    {
+      let txn = LOGGING.new_txn();
+      let file = ops::create(&txn, &ops::root(), &name, FileType::File);
+      drop(file.unwrap());
+      assert!(txn.abort());
+    }
+    assert!(LOGGING.blocks.lock().unwrap().is_empty());
+    {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      assert!(ops::lookup(&txn, &root, &name).err() == Some(Error::NotFound));
+      assert!(ICACHE.count_free(&txn) == nfree);
+      ops::create(&txn, &root, &name, FileType::File).unwrap();
+    }
+
+    // Not if another transaction wrote the same blocks.
+    let txn = LOGGING.new_txn();
+    let other = thread::spawn(|| {
+      let txn = LOGGING.new_txn();
+      ops::unlink(&txn, &ops::root(), &ops::to_name(b"f").unwrap()).unwrap();
+    });
+    while LOGGING.blocks.lock().unwrap().is_empty() {
+      thread::yield_now();
+    }
+    let name = ops::to_name(b"g").unwrap();
+    drop(ops::create(&txn, &ops::root(), &name, FileType::File).unwrap());
+    assert!(!txn.abort());
+    other.join().unwrap();
+    let txn = LOGGING.new_txn();
+    assert!(ops::lookup(&txn, &ops::root(), &name).is_ok());
+  }
+
+  #[test]
+  fn test_committer() {
+    let (disk, nfree) = testfs::test::create();
+    DISK.mount(disk);
+    BCACHE.init();
+
+    let write = |blockno: usize, byte: u8| {
+      let txn = LOGGING.new_txn();
+      let mut buf = txn.read(blockno).unwrap();
+
+      buf.data[0] = byte;
+      txn.write(&mut buf);
+    };
+    let commits = || LOGGING.state.lock().unwrap().commits;
+
+    // Transactions that end are left to the committer, until fsync.
+    LOGGING.start_committer(Duration::from_secs(60), 0);
+    write(nfree, 42);
+    assert!(DISK.read(nfree)[0] == 0);
+    LOGGING.force_commit();
+    assert!(DISK.read(nfree)[0] == 42);
+
+    // Or until the log has no room for more.
+    let before = commits();
+    for i in 0..2 * LOGGING.max_ops() {
+      write(nfree + i, 43);
+    }
+    assert!(commits() > before && DISK.read(nfree)[0] == 43);
+
+    // Or once they wrote as many blocks as it lets them.
+    LOGGING.start_committer(Duration::from_secs(60), 3);
+    write(nfree, 50);
+    write(nfree + 1, 50);
+    assert!(DISK.read(nfree)[0] == 43);
+    write(nfree + 2, 50);
+    while DISK.read(nfree)[0] != 50 {
+      thread::sleep(Duration::from_millis(1));
+    }
+
+    // Or once the window is over.
+    LOGGING.start_committer(Duration::from_millis(10), 0);
+    write(nfree, 44);
+    while DISK.read(nfree)[0] != 44 {
+      thread::sleep(Duration::from_millis(1));
+    }
+
+    // Stopping it commits what is left.
+    LOGGING.start_committer(Duration::from_secs(60), 0);
+    write(nfree, 45);
+    LOGGING.stop_committer();
+    assert!(DISK.read(nfree)[0] == 45);
+    write(nfree, 46);
+    assert!(DISK.read(nfree)[0] == 46);
+  }
+
+  #[test]
+  fn test_writeback() {
+    let (disk, nfree) = testfs::test::create();
+    DISK.mount(disk);
+    BCACHE.init();
+    LOGGING.init().unwrap();
+
+    let write = |blockno: usize, byte: u8| {
+      let txn = LOGGING.new_txn();
+      let mut buf = txn.read(blockno).unwrap();
+
+      buf.data[0] = byte;
+      txn.write(&mut buf);
+    };
+    let log_start = BCACHE.sb().log_start as usize;
+    let head = || from_block!(&DISK.read(log_start), LogHeader);
+
+    // Committed, installed in the cache only, and left in the log.
+    BCACHE.start_writeback(Duration::from_secs(60));
+    write(nfree, 42);
+    assert!(DISK.read(nfree)[0] == 0 && head().n == 1);
+    assert!(LOGGING.new_read_txn().read(nfree).unwrap().data[0] == 42);
+
+    // Which it is recovered from after a crash.
+    BCACHE.init();
+    LOGGING.init().unwrap();
+    assert!(DISK.read(nfree)[0] == 42 && head().n == 0);
+
+    // Until the checkpoint before the next commit, which writes out a block
+    // written again as it was logged.
+    write(nfree, 43);
+    assert!(DISK.read(nfree)[0] == 42);
+    write(nfree, 44);
+    assert!(DISK.read(nfree)[0] == 43 && head().n == 1);
+
+    // Freezing checkpoints too.
+    assert!(LOGGING.freeze());
+    assert!(DISK.read(nfree)[0] == 44 && head().n == 0);
+    assert!(LOGGING.thaw());
+    BCACHE.stop_writeback();
+  }
+
+  #[test]
+  fn test_segments() {
+    let mut disk = Disk::new(1000);
+    let opts = Options {
+      log_segments: 4,
+      ..Options::default()
+    };
+    mkfs::mkfs(&mut disk, &opts).unwrap();
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+    assert!(LOGGING.max_ops() == 4 * (LOGSIZE - 1) / 16);
+
+    // A transaction spans as many segments as it takes.
+    let sb = BCACHE.sb();
+    let base = sb.data_start() + 100;
+    {
+      let txn = LOGGING.new_batch_txn(8);
+
+      for i in 0..100 {
+        let mut buf = txn.read(base + i).unwrap();
+
+        buf.data[0] = i as u8 + 1;
+        txn.write(&mut buf);
+      }
+    }
+    assert!((0..100).all(|i| DISK.read(base + i)[0] == i as u8 + 1));
+
+    // And is recovered in full, the first header counting the others.
+    let log_start = sb.log_start as usize;
+    let mut lh = LogHeader {
+      n: 1,
+      blocks: [0; LOGSIZE],
+      checksum: 0,
+      more: 0,
+      payload: 0,
+    };
+    lh.blocks[0] = base as u32;
+    lh.seal();
+    DISK.write(log_start + LOGSIZE, &to_block!(&lh, LogHeader));
+    DISK.write(log_start + LOGSIZE + 1, &[7; BSIZE]);
+    lh.blocks[0] = base as u32 + 1;
+    lh.more = 1;
+    lh.seal();
+    DISK.write(log_start, &to_block!(&lh, LogHeader));
+    DISK.write(log_start + 1, &[8; BSIZE]);
+    BCACHE.init();
+    LOGGING.init().unwrap();
+    assert!(DISK.read(base)[0] == 7 && DISK.read(base + 1)[0] == 8);
+    assert!(from_block!(&DISK.read(log_start), LogHeader).n == 0);
+
+    // Not if it counts more segments than there are.
+    lh.more = 4;
+    lh.seal();
+    DISK.write(log_start, &to_block!(&lh, LogHeader));
+    BCACHE.init();
+    assert!(LOGGING.init().err() == Some(Error::Corrupt));
+  }
 }
@@ -0,0 +1,136 @@
+// Accounting of the memory held by the caches, the log and the handles of
+// the servers, against an optional budget.
+//
+// Nothing is refused when the budget is exceeded, as the callers have no
+// way to fail. Instead the block and inode caches evict an unused entry for
+// every new one, so they stop growing, and new transactions wait for the
+// outstanding ones to commit, which releases the blocks pinned by the log.
+// The budget is XV6FS_MEMORY_BUDGET bytes, unlimited if absent or 0.
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy)]
+pub enum Account {
+  // Buffers of the block cache, see buffer.rs.
+  Blocks = 0,
+  // Entries of the inode cache.
+  Inodes,
+  // Blocks written by transactions that have not committed yet, which
+  // cannot be evicted and are copied to as many log blocks at commit.
+  Log,
+  // Per-handle state of the servers, e.g. 9P fids.
+  Handles,
+}
+
+const NACCOUNTS: usize = 4;
+
+pub struct Memory {
+  budget: AtomicUsize,
+  used: [AtomicUsize; NACCOUNTS],
+}
+
+// Bytes charged to an account for as long as it lives.
+pub struct Charge {
+  account: Account,
+  bytes: usize,
+}
+
+lazy_static! {
+  pub static ref MEMORY: Memory = Memory::new(
+    env::var("XV6FS_MEMORY_BUDGET")
+      .ok()
+      .and_then(|s| s.parse().ok())
+      .unwrap_or(0)
+  );
+}
+
+impl Memory {
+  fn new(budget: usize) -> Self {
+    Memory {
+      budget: AtomicUsize::new(budget),
+      used: [
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+        AtomicUsize::new(0),
+      ],
+    }
+  }
+
+  // Set the budget in bytes, 0 for none.
+  pub fn set_budget(&self, bytes: usize) {
+    self.budget.store(bytes, Ordering::SeqCst);
+  }
+
+  pub fn budget(&self) -> usize {
+    self.budget.load(Ordering::SeqCst)
+  }
+
+  pub fn charge(&self, account: Account, bytes: usize) {
+    self.used[account as usize].fetch_add(bytes, Ordering::SeqCst);
+  }
+
+  pub fn release(&self, account: Account, bytes: usize) {
+    let old = self.used[account as usize].fetch_sub(bytes, Ordering::SeqCst);
+    assert!(old >= bytes);
+  }
+
+  // Return the bytes charged to `account`.
+  pub fn used(&self, account: Account) -> usize {
+    self.used[account as usize].load(Ordering::SeqCst)
+  }
+
+  // Return the bytes charged to all accounts.
+  pub fn total(&self) -> usize {
+    self.used.iter().map(|used| used.load(Ordering::SeqCst)).sum()
+  }
+
+  pub fn over_budget(&self) -> bool {
+    let budget = self.budget();
+    budget != 0 && self.total() > budget
+  }
+}
+
+impl Charge {
+  pub fn new(account: Account, bytes: usize) -> Self {
+    MEMORY.charge(account, bytes);
+    Charge { account, bytes }
+  }
+}
+
+impl Drop for Charge {
+  fn drop(&mut self) {
+    MEMORY.release(self.account, self.bytes);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use buffer::{BCACHE, Buf};
+  use logging::LOGGING;
+  use memory::{Account, MEMORY};
+  use std::mem::size_of;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+    let sb = BCACHE.sb();
+    let used = MEMORY.used(Account::Blocks);
+
+    // Room for a few more buffers only.
+    MEMORY.set_budget(MEMORY.total() + 4 * size_of::<Buf>());
+    {
+      let txn = LOGGING.new_txn();
+      for blockno in sb.data_start()..sb.nblocks as usize {
+        txn.read(blockno).unwrap();
+      }
+    }
+    let grown = MEMORY.used(Account::Blocks) - used;
+    MEMORY.set_budget(0);
+
+    assert!(grown <= 5 * size_of::<Buf>());
+    assert!(MEMORY.used(Account::Log) == 0);
+  }
+}
@@ -0,0 +1,498 @@
+// Creating file systems, for mkfs and embedders.
+//
+//   let mut disk = Disk::new(20000);
+//   mkfs::mkfs(&mut disk, &mkfs::Options::default())?;
+//   DISK.mount(disk);
+//   LOGGING.init()?;
+//   mkfs::populate(Path::new("image.json"))?;
+//
+// A new file system holds only the root directory and the inode of block
+// reference counts, see refcount.rs. The hash blocks, see integrity.rs,
+// then the bad-block table and the spare blocks, see badblock.rs, take the
+// end of the device.
+
+use disk::{BSIZE, Block, BlockDevice};
+use error::{Error, Result};
+use fs::{CASEFOLD, CHECKSUMS, DEFAULT_GID, DEFAULT_UID, DIRSIZE, Dirent,
+         DiskInode, FileType, IPB, LABELSIZE, LOGSIZE, NBADBLOCKS, NDIRECT,
+         SuperBlock, WHITEOUT, nbitmapblks, now};
+use inode::ICACHE;
+use integrity;
+use logging::LOGGING;
+use ops::{self, MAXWRITE};
+use std::cmp::max;
+use std::fs;
+use std::mem::{size_of, transmute};
+use std::path::Path;
+use std::result;
+use util::json::{self, Value};
+use util::sha256::sha256;
+
+// Inode of block reference counts.
+const REFINO: usize = 2;
+
+pub struct Options {
+  pub ninodes: usize,
+  // Inodes the inode table has room for, to grow to on a mounted file
+  // system with fs::grow_inodes. None past `ninodes` if fewer.
+  pub max_inodes: usize,
+  // Look names up regardless of their case, see fs::CASEFOLD.
+  pub case_insensitive: bool,
+  // Spare blocks that bad blocks are remapped to, none without a bad-block
+  // table.
+  pub spares: usize,
+  // Keep a hash tree over the data blocks, see integrity.rs.
+  pub integrity: bool,
+  // Segments of the log, of LOGSIZE blocks each, see logging.rs. The more,
+  // the more operations commit together, and the larger they may be.
+  pub log_segments: usize,
+  // Name of the file system, at most LABELSIZE bytes.
+  pub label: String,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      ninodes: 1000,
+      max_inodes: 0,
+      case_insensitive: false,
+      spares: 16,
+      integrity: false,
+      log_segments: 1,
+      label: String::new(),
+    }
+  }
+}
+
+fn str2u8(s: &str) -> [u8; DIRSIZE] {
+  let s_bytes = s.as_bytes();
+  let mut result: [u8; DIRSIZE] = [0; DIRSIZE];
+  for i in 0..s_bytes.len() {
+    result[i] = s_bytes[i];
+  }
+  result
+}
+
+// Copy `data` to `offset` of `image`.
+fn put(image: &mut [u8], offset: usize, data: &[u8]) {
+  image[offset..offset + data.len()].copy_from_slice(data);
+}
+
+// Create a file system spanning the whole of `device`. Only the blocks in
+// use are written, the others must be zeroed already, as those of
+// `Disk::new` are.
+pub fn mkfs<D: BlockDevice + ?Sized>(
+  device: &mut D,
+  opts: &Options,
+) -> Result<()> {
+  let nblocks = device.nblocks();
+  let max_inodes = max(opts.ninodes, opts.max_inodes);
+  let ninodeblks = (max_inodes / IPB + 1) as u32;
+  let nbitmapblks = nbitmapblks(nblocks) as u32;
+  let nlogs = (opts.log_segments * LOGSIZE) as u32;
+  let nmeta = 2 + nlogs + ninodeblks + nbitmapblks;
+  let nbad = if opts.spares > 0 { opts.spares + 1 } else { 0 };
+  let nhash = if opts.integrity {
+    integrity::nhashblks(nblocks)
+  } else {
+    0
+  };
+  let nreserved = nhash + nbad;
+
+  let mut sb = SuperBlock {
+    nblocks: nblocks as u32,
+    refino: REFINO as u32,
+    ninodes: opts.ninodes as u32,
+    nlogs,
+    log_start: 2,
+    inode_start: 2 + nlogs,
+    bmap_start: 2 + nlogs + ninodeblks,
+    flags: CHECKSUMS | if opts.case_insensitive { CASEFOLD } else { 0 },
+    badblk: if nbad > 0 {
+      nblocks.saturating_sub(nbad) as u32
+    } else {
+      0
+    },
+    nspares: opts.spares as u32,
+    hashblk: if nhash > 0 {
+      nblocks.saturating_sub(nreserved) as u32
+    } else {
+      0
+    },
+    root: [0; 32],
+    checksum: 0,
+    nextfree: 0,
+    label: [0; LABELSIZE],
+  };
+
+  let mut nfree = nmeta;
+  let inode_blk0 = nfree;
+  nfree += 1;
+
+  // The metadata, the root folder and the reserved blocks must fit, with
+  // a data block to spare.
+  if opts.ninodes <= REFINO || max_inodes > WHITEOUT as usize ||
+    opts.log_segments == 0 || nfree as usize + nreserved >= nblocks ||
+    nblocks > u32::max_value() as usize || opts.spares > NBADBLOCKS ||
+    opts.label.len() > LABELSIZE
+  {
+    return Err(Error::Invalid);
+  }
+  sb.label[..opts.label.len()].copy_from_slice(opts.label.as_bytes());
+
+  // Every block in use is built in memory and written at once.
+  let mut image = vec![0; nfree as usize * BSIZE];
+
+  // Write the super block.
+  sb.seal();
+  put(&mut image, BSIZE, &to_block!(&sb, SuperBlock));
+
+  // Write the root inode and folder.
+  let (sec, nsec) = now();
+  let mut iroot = DiskInode {
+    file_type: FileType::Directory,
+    flags: 0,
+    nonce: 0,
+    nlink: 1,
+    size: size_of::<Dirent>() as u32 * 2, /* two files in root folder: `.`
+                                           * and `..`. */
+    addrs: [0; NDIRECT + 1],
+    mode: 0o755,
+    dmode: 0,
+    uid: DEFAULT_UID,
+    gid: DEFAULT_GID,
+    duid: 0,
+    dgid: 0,
+    gen: 0,
+    major: 0,
+    minor: 0,
+    atime: sec,
+    atime_nsec: nsec,
+    mtime: sec,
+    mtime_nsec: nsec,
+    ctime: sec,
+    ctime_nsec: nsec,
+    dindirect: 0,
+    checksum: 0,
+    unused: 0,
+  };
+  iroot.addrs[0] = inode_blk0;
+  iroot.seal();
+
+  put(
+    &mut image,
+    sb.inode_start as usize * BSIZE + size_of::<DiskInode>(),
+    unsafe { &transmute::<_, [u8; size_of::<DiskInode>()]>(iroot) },
+  );
+
+  // Write the inode of block reference counts, empty until blocks are
+  // shared.
+  let mut irefs = DiskInode {
+    file_type: FileType::File,
+    flags: 0,
+    nonce: 0,
+    nlink: 1,
+    size: 0,
+    addrs: [0; NDIRECT + 1],
+    mode: 0o600,
+    dmode: 0,
+    uid: DEFAULT_UID,
+    gid: DEFAULT_GID,
+    duid: 0,
+    dgid: 0,
+    gen: 0,
+    major: 0,
+    minor: 0,
+    atime: sec,
+    atime_nsec: nsec,
+    mtime: sec,
+    mtime_nsec: nsec,
+    ctime: sec,
+    ctime_nsec: nsec,
+    dindirect: 0,
+    checksum: 0,
+    unused: 0,
+  };
+  irefs.seal();
+
+  put(
+    &mut image,
+    sb.inode_start as usize * BSIZE + REFINO * size_of::<DiskInode>(),
+    unsafe { &transmute::<_, [u8; size_of::<DiskInode>()]>(irefs) },
+  );
+
+  let dirents: [Dirent; 2] = [
+    Dirent {
+      inum: 1,
+      name: str2u8("."),
+    },
+    Dirent {
+      inum: 1,
+      name: str2u8(".."),
+    },
+  ];
+  put(&mut image, inode_blk0 as usize * BSIZE, unsafe {
+    &transmute::<_, [u8; size_of::<Dirent>() * 2]>(dirents)
+  });
+
+  // Write bitmap, over as many of its blocks as the blocks in use take.
+  let bitmap = sb.bmap_start as usize * BSIZE;
+  for i in 0..nfree as usize {
+    image[bitmap + i / 8] |= 1 << (i % 8);
+  }
+  // The reserved blocks are in use too. Their bits may be in other blocks
+  // of the bitmap, which are in the image as well, and the empty table is
+  // all zeros already.
+  for i in nblocks - nreserved..nblocks {
+    image[bitmap + i / 8] |= 1 << (i % 8);
+  }
+
+  // Hash the data blocks, all zeros but the root folder.
+  if opts.integrity {
+    let zeros = sha256(&[0; BSIZE]);
+    let mut hashes = vec![[0; 32]; nblocks];
+
+    for blockno in sb.data_start()..sb.data_end() {
+      let data = image.get(blockno * BSIZE..(blockno + 1) * BSIZE);
+      hashes[blockno] = data.map_or(zeros, sha256);
+    }
+    let (blocks, root) = integrity::tree(hashes);
+    for (i, block) in blocks.iter().enumerate() {
+      device.write(sb.hashblk as usize + i, block);
+    }
+    sb.root = root;
+    sb.seal();
+    put(&mut image, BSIZE, &to_block!(&sb, SuperBlock));
+  }
+
+  let mut block: Block = [0; BSIZE];
+  for (blockno, data) in image.chunks(BSIZE).enumerate() {
+    block.copy_from_slice(data);
+    device.write(blockno, &block);
+  }
+  Ok(())
+}
+
+// Add the entries of `manifest` to the mounted file system, in order, e.g.
+//
+//   {"entries": [
+//     {"path": "/bin", "type": "dir", "mode": "755"},
+//     {"path": "/bin/sh", "source": "build/sh", "mode": "755", "uid": 0},
+//     {"path": "/README"}
+//   ]}
+//
+// An entry is a file unless its type is "dir", whose content is copied from
+// the host file `source`, relative to the directory of the manifest, or is
+// empty without one. The mode is in octal, and the mode, uid and gid
+// default to those given by the parent directory. The parent of an entry
+// must come before it. Errors are described for the author of the
+// manifest.
+pub fn populate(manifest: &Path) -> result::Result<(), String> {
+  let text = fs::read_to_string(manifest).map_err(|e| e.to_string())?;
+  let manifest_dir = manifest.parent().unwrap_or(Path::new(""));
+  let root = json::parse(&text)?;
+  let entries = root
+    .get("entries")
+    .and_then(Value::as_array)
+    .ok_or("no entries array")?;
+
+  for entry in entries {
+    let path = entry
+      .get("path")
+      .and_then(Value::as_str)
+      .ok_or("entry without a path")?;
+    let file_type = match entry.get("type").map(Value::as_str) {
+      None | Some(Some("file")) => FileType::File,
+      Some(Some("dir")) => FileType::Directory,
+      _ => return Err(format!("{}: bad type", path)),
+    };
+    let mode = match entry.get("mode").map(Value::as_str) {
+      None => None,
+      Some(Some(mode)) => match u16::from_str_radix(mode, 8) {
+        Ok(mode) if mode & !0o7777 == 0 => Some(mode),
+        _ => return Err(format!("{}: bad mode", path)),
+      },
+      Some(None) => return Err(format!("{}: mode is not a string", path)),
+    };
+    let id = |key| match entry.get(key).map(Value::as_i64) {
+      None => Ok(None),
+      Some(Some(id)) if id >= 0 && id <= u32::max_value() as i64 => {
+        Ok(Some(id as u32))
+      },
+      _ => Err(format!("{}: bad {}", path, key)),
+    };
+    let (uid, gid) = (id("uid")?, id("gid")?);
+    let data = match entry.get("source").map(Value::as_str) {
+      None => vec![],
+      Some(Some(_)) if file_type == FileType::Directory => {
+        return Err(format!("{}: a directory has no source", path));
+      },
+      Some(Some(source)) => fs::read(manifest_dir.join(source))
+        .map_err(|e| format!("{}: {}", source, e))?,
+      Some(None) => return Err(format!("{}: source is not a string", path)),
+    };
+
+    {
+      let txn = LOGGING.new_txn();
+      let (dir, name) = ops::resolve_parent(&txn, path.as_bytes())
+        .map_err(|e| format!("{}: {:?}", path, e))?;
+      let inode = ops::create(&txn, &dir, &name, file_type)
+        .map_err(|e| format!("{}: {:?}", path, e))?;
+      let mut dinode = ICACHE.lock(&txn, &inode);
+
+      dinode.mode = mode.unwrap_or(dinode.mode);
+      dinode.uid = uid.unwrap_or(dinode.uid);
+      dinode.gid = gid.unwrap_or(dinode.gid);
+      dinode.update(&txn);
+    }
+    for (n, chunk) in data.chunks(MAXWRITE).enumerate() {
+      let txn = LOGGING.new_txn();
+      let inode = ops::resolve(&txn, path.as_bytes()).unwrap();
+      ops::write(&txn, &inode, n * MAXWRITE, chunk)
+        .map_err(|e| format!("{}: {:?}", path, e))?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use bitmap::Bitmap;
+  use buffer::BCACHE;
+  use disk::{BSIZE, Disk};
+  use error::Error;
+  use fs::{BPB, FileType, IPB, LABELSIZE, grow_inodes, usage};
+  use logging::LOGGING;
+  use mkfs::{self, Options};
+  use ops;
+  use std::env;
+  use std::fs;
+  use std::process;
+  use testfs;
+
+  #[test]
+  fn test() {
+    let mut disk = Disk::new(200);
+    let opts = Options {
+      ninodes: 20,
+      max_inodes: 0,
+      case_insensitive: false,
+      spares: 4,
+      integrity: false,
+      log_segments: 1,
+      label: "test".to_string(),
+    };
+    mkfs::mkfs(&mut disk, &opts).unwrap();
+    assert!(
+      mkfs::mkfs(&mut Disk::new(10), &opts).err() == Some(Error::Invalid)
+    );
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+    assert!(BCACHE.sb().label() == b"test" && BCACHE.sb().verify());
+    let mut sb = BCACHE.sb();
+    sb.label[0] = b'b';
+    assert!(!sb.verify());
+
+    let dir = env::temp_dir().join(format!("xv6fs-mkfs-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("hello"), b"hello").unwrap();
+    fs::write(
+      dir.join("image.json"),
+      r#"{"entries": [
+        {"path": "/d", "type": "dir", "mode": "700", "uid": 7},
+        {"path": "/d/f", "source": "hello"}
+      ]}"#,
+    ).unwrap();
+    mkfs::populate(&dir.join("image.json")).unwrap();
+    fs::write(dir.join("bad.json"), r#"{"entries": [{"path": "/x/y"}]}"#)
+      .unwrap();
+    assert!(mkfs::populate(&dir.join("bad.json")).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+
+    let txn = LOGGING.new_txn();
+    let d = ops::stat(&txn, &ops::resolve(&txn, b"/d").unwrap());
+    assert!(d.file_type == FileType::Directory);
+    assert!(d.mode == 0o700 && d.uid == 7);
+    let f = ops::resolve(&txn, b"/d/f").unwrap();
+    assert!(ops::read(&txn, &f, 0, 10).unwrap() == b"hello");
+  }
+
+  #[test]
+  fn test_grow_inodes() {
+    let mut disk = Disk::new(200);
+    let opts = Options {
+      ninodes: 5,
+      max_inodes: 20,
+      ..Options::default()
+    };
+    mkfs::mkfs(&mut disk, &opts).unwrap();
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+
+    let create = |name: &[u8]| {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let name = ops::to_name(name).unwrap();
+      ops::create(&txn, &root, &name, FileType::File).map(|_| ())
+    };
+    // Besides the root and the reference counts.
+    create(b"a").unwrap();
+    create(b"b").unwrap();
+    assert!(create(b"c").err() == Some(Error::NoSpace));
+
+    // The table grows into the room left for it, and stays grown.
+    assert!(grow_inodes(&LOGGING.new_txn(), 3).unwrap() == 8);
+    assert!(usage(&LOGGING.new_txn()).free_inodes == 3);
+    create(b"c").unwrap();
+
+    // Aborted, it takes the free inodes back.
+    let txn = LOGGING.new_txn();
+    assert!(grow_inodes(&txn, 2).unwrap() == 10);
+    assert!(usage(&txn).free_inodes == 4);
+    assert!(txn.abort());
+    assert!(usage(&LOGGING.new_txn()).free_inodes == 2);
+    assert!(BCACHE.sb().ninodes == 8);
+    assert!(grow_inodes(&LOGGING.new_txn(), 100).err() == Some(Error::NoSpace));
+    BCACHE.init();
+    assert!(BCACHE.sb().ninodes == 8 && BCACHE.sb().verify());
+    assert!(BCACHE.sb().max_inodes() >= 20);
+  }
+
+  #[test]
+  fn test_large() {
+    // The metadata takes more blocks than a bitmap block has bits for.
+    let nblocks = 3 * BPB;
+    let mut disk = Disk::new(nblocks);
+    let opts = Options {
+      ninodes: (BPB + 100) * IPB,
+      ..Options::default()
+    };
+    mkfs::mkfs(&mut disk, &opts).unwrap();
+    testfs::test::mount_disk(disk);
+    LOGGING.init().unwrap();
+
+    let txn = LOGGING.new_txn();
+    let sb = BCACHE.sb();
+    assert!(sb.data_start() > BPB);
+    assert!(usage(&txn).blocks == nblocks - sb.data_start() - 17);
+    assert!(usage(&txn).free_blocks == usage(&txn).blocks - 1);
+    assert!(Bitmap::verify(&txn).unwrap().is_ok());
+    let name = ops::to_name(b"f").unwrap();
+    let f = ops::create(&txn, &ops::root(), &name, FileType::File).unwrap();
+    ops::write(&txn, &f, 0, &[1; BSIZE]).unwrap();
+    assert!(Bitmap::verify(&txn).unwrap().is_ok());
+    drop(txn);
+
+    // Not too large for the device, nor the label.
+    let opts = Options {
+      ninodes: nblocks * IPB,
+      ..Options::default()
+    };
+    assert!(mkfs::mkfs(&mut Disk::new(nblocks), &opts).is_err());
+    let opts = Options {
+      label: "x".repeat(LABELSIZE + 1),
+      ..Options::default()
+    };
+    assert!(mkfs::mkfs(&mut Disk::new(nblocks), &opts).is_err());
+  }
+}
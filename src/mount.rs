@@ -0,0 +1,2419 @@
+// The FUSE frontend, for the daemon and embedders.
+//
+//   let handle = xv6fs::spawn_mount("fs.img", "mnt", &Options::default())?;
+//   ...
+//   handle.unmount();
+//
+// `mount` serves the image until the file system is unmounted, e.g. by
+// `fusermount -u` or as the process gets SIGINT or SIGTERM, while
+// `spawn_mount` serves it from a background thread for as long as the
+// returned handle lives. Either way the image is mounted as the disk of the
+// process, see DiskService, so only one can be at once, and saved to as it
+// is unmounted.
+
+use bitmap::Bitmap;
+use badblock;
+use batch;
+use coalesce::{Coalescer, Pending};
+use buffer::BCACHE;
+use crypt;
+use delalloc;
+use disk::{self, BSIZE, DISK, Disk, FileDisk};
+use error::{Error, Result};
+use fs::{self, DIRSIZE, DiskInode, ROOTINO, W_OK};
+use fuse::{self, BackgroundSession, FileType, FileAttr, Filesystem, Request};
+use fuse::{ReplyEmpty, ReplyData, ReplyEntry, ReplyAttr, ReplyDirectory,
+           ReplyCreate, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite,
+           ReplyXattr};
+use fuse::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+use image::Image;
+use inode::{ICACHE, Inode, UnlockedInode};
+use integrity;
+use legacy::Legacy;
+use lock::{self, Lock, Locks};
+use libc::{EEXIST, ENOENT, ENOTDIR, EROFS, EINVAL, ENOATTR, ENOTSUP, ERANGE,
+           EBUSY, EBADF, EAGAIN, ENOLCK, EPERM};
+use libc::{O_ACCMODE, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_TMPFILE,
+           O_TRUNC};
+use libc::{S_IFCHR, S_IFMT, S_IFREG};
+use libc::{SIGINT, SIGTERM, SIG_BLOCK, SIG_UNBLOCK, pthread_sigmask, sigaddset,
+           sigemptyset, sigset_t, sigwait};
+use logging::{LOGGING, Transaction};
+use objstore::{ObjectDisk, S3Store};
+use ops;
+use overlay::{self, Overlay};
+use qos::{self, ClientKey, Scheduler};
+use readahead::Tracker;
+use reclaim;
+use tune;
+use std::cell::RefCell;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+use std::str::from_utf8;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use time::Timespec;
+
+// Lock types of getlk and setlk, not in our libc yet.
+const F_RDLCK: u32 = 0;
+const F_WRLCK: u32 = 1;
+const F_UNLCK: u32 = 2;
+
+// xv6fs does not support file time stamp, use a dummy one.
+const DEFAULT_TIME: Timespec = Timespec { sec: 42, nsec: 42 };
+
+// Extended attributes standing in for the fscrypt ioctls, which the fuse
+// crate does not pass through. Both take a 32-byte key, raw or in hex.
+//
+// Setting XATTR_ENCRYPT on an empty directory encrypts everything created
+// below it, getting it yields the key id. Setting XATTR_KEY on any inode
+// provides a key after mounting, removing it from an encrypted inode
+// forgets the key of that inode.
+const XATTR_ENCRYPT: &str = "user.xv6fs.encrypt";
+const XATTR_KEY: &str = "user.xv6fs.key";
+
+// Likewise for FIFREEZE and FITHAW: setting XATTR_FREEZE on any inode to
+// "1" freezes the file system, so that the image can be copied, and "0"
+// thaws it.
+const XATTR_FREEZE: &str = "user.xv6fs.freeze";
+
+// Setting XATTR_RMTREE on a directory to the name of one of its entries
+// removes that entry and everything below it, see `xv6fs rm -r`.
+const XATTR_RMTREE: &str = "user.xv6fs.rmtree";
+
+// Likewise for copy_file_range and FICLONE: setting XATTR_CLONE on a file
+// to the path of another, from the root of the file system, makes it a
+// copy of that one, whose blocks it shares where it can, see
+// `ops::copy_range`.
+const XATTR_CLONE: &str = "user.xv6fs.clone";
+
+// Defaults of a directory for its new children, as "<octal mode> <uid>
+// <gid>", e.g. "2770 0 100". Removing it clears them.
+const XATTR_DEFAULTS: &str = "user.xv6fs.defaults";
+
+// Setting XATTR_SHRED to "1" makes the blocks of an inode be zeroed when
+// they are freed, and those of the children of a directory. It reads as
+// "1" while set, removing it or setting it to "0" clears it.
+const XATTR_SHRED: &str = "user.xv6fs.shred";
+
+// The counters of the scheduler, read on any inode, as one line of
+// "<client> <requests> <throttled> <in flight> <queued>" per client.
+const XATTR_QOS: &str = "user.xv6fs.qos";
+
+// The settings of the server, see tune.rs, read on any inode, and changed
+// by setting it to some of them, e.g. "log=debug readahead=0".
+const XATTR_CONFIG: &str = "user.xv6fs.config";
+
+// The readahead of the open handles, see readahead.rs, read on any inode,
+// as one line of "<handle> <inode> <pattern> <window>" per handle.
+const XATTR_READAHEAD: &str = "user.xv6fs.readahead";
+
+// The counters of the reclamation of removed files, see reclaim.rs, read on
+// any inode as "<pending> <inodes> <blocks>".
+const XATTR_RECLAIM: &str = "user.xv6fs.reclaim";
+
+thread_local! {
+  // Where reads across blocks are gathered, kept by each thread serving
+  // them rather than allocated for each.
+  static READ_BUF: RefCell<Vec<u8>> = RefCell::new(vec![]);
+}
+
+fn str2u8(s: &OsStr) -> Option<[u8; DIRSIZE]> {
+  let s_bytes = s.to_str()?.as_bytes();
+  if s_bytes.len() > DIRSIZE {
+    return None;
+  }
+
+  let mut result: [u8; DIRSIZE] = [0; DIRSIZE];
+  for i in 0..s_bytes.len() {
+    result[i] = s_bytes[i];
+  }
+  Some(result)
+}
+
+macro_rules! convert_name {
+  ($name:ident, $reply:ident) => ({
+    let name = str2u8($name);
+    if name.is_none() {
+      $reply.error(ENOENT);
+      return;
+    }
+    name.unwrap()
+  });
+}
+
+fn to_key(value: &[u8]) -> Option<crypt::Key> {
+  let mut key = [0; 32];
+
+  if value.len() == key.len() {
+    key.copy_from_slice(value);
+  } else if value.len() == 2 * key.len() {
+    let value = from_utf8(value).ok()?;
+    for i in 0..key.len() {
+      key[i] = u8::from_str_radix(value.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+  } else {
+    return None;
+  }
+  Some(key)
+}
+
+fn to_defaults(value: &[u8]) -> Option<ops::Defaults> {
+  let value = from_utf8(value).ok()?;
+  let fields: Vec<&str> = value.split_whitespace().collect();
+
+  if fields.len() != 3 {
+    return None;
+  }
+  Some(ops::Defaults {
+    mode: u16::from_str_radix(fields[0], 8).ok()?,
+    uid: fields[1].parse().ok()?,
+    gid: fields[2].parse().ok()?,
+  })
+}
+
+fn u82str(s_bytes: &[u8; DIRSIZE]) -> &OsStr {
+  OsStr::new(from_utf8(s_bytes).unwrap())
+}
+
+fn get_perm(inode: &DiskInode) -> u16 {
+  // Those of what it points to apply.
+  if inode.file_type == fs::FileType::Symlink {
+    0o777
+  } else if inode.is_read_only() {
+    inode.mode & !0o222
+  } else {
+    inode.mode
+  }
+}
+
+fn get_kind(inode: &DiskInode) -> FileType {
+  match inode.file_type {
+    fs::FileType::None => panic!("invalid file type"),
+    fs::FileType::Directory => FileType::Directory,
+    fs::FileType::File => FileType::RegularFile,
+    fs::FileType::Symlink => FileType::Symlink,
+    fs::FileType::Device => FileType::CharDevice,
+  }
+}
+
+// Return the rdev of device `major`:`minor`, encoded as the kernel does in
+// 32 bits.
+fn to_rdev(major: u16, minor: u16) -> u32 {
+  let (major, minor) = (major as u32, minor as u32);
+
+  (minor & 0xff) | (major & 0xfff) << 8 | (minor & !0xff) << 12
+}
+
+// Return the major and minor numbers of `rdev`, None if they do not fit.
+fn from_rdev(rdev: u32) -> Option<(u16, u16)> {
+  let major = (rdev >> 8) & 0xfff;
+  let minor = (rdev & 0xff) | (rdev >> 12) & !0xff;
+
+  if minor > u16::max_value() as u32 {
+    return None;
+  }
+  Some((major as u16, minor as u16))
+}
+
+// An ino handed to the kernel. Lookups hand out pointers to an inode with
+// a reference held until they are forgotten, readdir and the root odd
+// numbers holding the inode number and generation, which may go stale once
+// the inode is freed and its slot reused. Inodes of submounts, which never
+// change, are numbered apart, 2 modulo 4 unlike the aligned pointers.
+#[derive(Clone, Copy)]
+enum FuseInode {
+  Ptr(*const (Mutex<Inode>, usize)),
+  Inum(usize, u32 /* generation */),
+  Sub(usize /* index of the submount */, usize),
+}
+
+impl FuseInode {
+  fn new(x: u64) -> Self {
+    if x % 2 == 1 {
+      FuseInode::Inum(((x & 0xffffffff) as usize + 1) / 2, (x >> 32) as u32)
+    } else if x % 4 == 2 {
+      FuseInode::Sub((x >> 32) as usize, (x & 0xffffffff) as usize >> 2)
+    } else {
+      FuseInode::Ptr(x as *const _)
+    }
+  }
+
+  fn serialize(self) -> u64 {
+    match self {
+      FuseInode::Ptr(ptr) => ptr as u64,
+      FuseInode::Inum(inum, gen) => (gen as u64) << 32 | (inum as u64 * 2 - 1),
+      FuseInode::Sub(k, inum) => (k as u64) << 32 | (inum as u64) << 2 | 2,
+    }
+  }
+
+  fn get<'a>(self, txn: &Transaction<'a>) -> Result<UnlockedInode> {
+    match self {
+      FuseInode::Ptr(ptr) => {
+        let inode = UnlockedInode::assemble(ptr);
+        inode.clone().disassemble(); // disassemble again to retain a reference
+        Ok(inode)
+      },
+      FuseInode::Inum(inum, gen) => ops::get(txn, inum, gen),
+      // Everything else is served by `Submount`.
+      FuseInode::Sub(..) => Err(Error::ReadOnly),
+    }
+  }
+}
+
+macro_rules! get_inode {
+  ($ino:expr, $txn:expr, $reply:ident) => ({
+    match FuseInode::new($ino).get(&$txn) {
+      Ok(inode) => inode,
+      Err(e) => {
+        $reply.error(e.errno());
+        return;
+      },
+    }
+  });
+}
+
+// Return the value of `result`, or reply its error.
+macro_rules! try_reply {
+  ($result:expr, $reply:ident) => ({
+    match $result {
+      Ok(value) => value,
+      Err(e) => {
+        $reply.error(e.errno());
+        return;
+      },
+    }
+  });
+}
+
+// Like `try_reply`, but `txn` is aborted on an error, once `locked` are
+// released, so that nothing it wrote halfway is committed.
+macro_rules! try_abort {
+  ($result:expr, $txn:ident, $reply:ident $(, $locked:ident)*) => ({
+    match $result {
+      Ok(value) => value,
+      Err(e) => {
+        $(drop($locked);)*
+        $txn.abort();
+        $reply.error(e.errno());
+        return;
+      },
+    }
+  });
+}
+
+// Reply `value` to a getxattr of `size` bytes.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &str) {
+  if size == 0 {
+    reply.size(value.len() as u32);
+  } else if value.len() > size as usize {
+    reply.error(ERANGE);
+  } else {
+    reply.data(value.as_bytes());
+  }
+}
+
+// Inodes predating timestamps have them 0, and report DEFAULT_TIME.
+fn to_time(sec: u32, nsec: u32) -> Timespec {
+  if sec == 0 {
+    DEFAULT_TIME
+  } else {
+    Timespec::new(sec as i64, nsec as i32)
+  }
+}
+
+fn from_time(time: Timespec) -> (u32, u32) {
+  (time.sec as u32, time.nsec as u32)
+}
+
+fn create_attr(ino: u64, inode: &DiskInode) -> FileAttr {
+  let size = inode.size as u64;
+
+  FileAttr {
+    ino: ino,
+    size: size,
+    blocks: ((size as usize + BSIZE - 1) / BSIZE) as u64,
+    atime: to_time(inode.atime, inode.atime_nsec),
+    mtime: to_time(inode.mtime, inode.mtime_nsec),
+    ctime: to_time(inode.ctime, inode.ctime_nsec),
+    crtime: DEFAULT_TIME,
+    kind: get_kind(inode),
+    perm: get_perm(inode),
+    nlink: inode.nlink as u32,
+    uid: inode.uid,
+    gid: inode.gid,
+    rdev: if inode.file_type == fs::FileType::Device {
+      to_rdev(inode.major, inode.minor)
+    } else {
+      0
+    },
+    flags: 0,
+  }
+}
+
+// Give `inode`, just created in `parent` by the user `owner`, the
+// permission bits of `mode`, which the kernel applied the umask to
+// already, and `owner` as its uid and gid, unless `parent` has defaults,
+// which take precedence.
+fn init_inode<'a>(
+  txn: &Transaction<'a>,
+  parent: &DiskInode,
+  inode: &mut Inode,
+  mode: u32,
+  owner: (u32, u32),
+) {
+  if parent.flags & fs::IDEFAULTS == 0 {
+    inode.mode = (mode & 0o7777) as u16;
+    inode.uid = owner.0;
+    inode.gid = owner.1;
+    inode.update(txn);
+  }
+}
+
+// An image grafted at directory `at` of the mounted one, read-only.
+struct Submount {
+  at: usize,
+  image: Mutex<Image>,
+}
+
+impl Submount {
+  // Return the attributes of inode `inum` of the `k`-th submount.
+  fn attr(&self, k: usize, inum: usize) -> Result<FileAttr> {
+    let dinode = self.image.lock().unwrap().inode(inum)?;
+    let mut attr = create_attr(FuseInode::Sub(k, inum).serialize(), &dinode);
+
+    attr.perm &= !0o222;
+    Ok(attr)
+  }
+
+  // Like `ops::access`, on inode `inum`.
+  fn access(&self, inum: usize, uid: u32, gid: u32, mask: u32) -> Result<()> {
+    let dinode = self.image.lock().unwrap().inode(inum)?;
+
+    if mask & W_OK != 0 {
+      Err(Error::ReadOnly)
+    } else if !dinode.allows(uid, gid, mask) {
+      Err(Error::Denied)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+// Return the submount grafted at inode `inum`, if any.
+fn grafted(submounts: &[Submount], inum: usize) -> Option<usize> {
+  submounts.iter().position(|submount| submount.at == inum)
+}
+
+// Whether the kernel keeps the cached data of a file when it is opened.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum KeepCache {
+  Never,
+  Always,
+  // Only if its size has not changed since it was last opened, as the
+  // image may have been modified meanwhile, e.g. by offline tools.
+  Auto,
+}
+
+// The caching of the kernel, for opened files.
+struct Caching {
+  direct_io: bool,
+  keep_cache: KeepCache,
+  // Size of each inode when last opened, for KeepCache::Auto.
+  sizes: Mutex<HashMap<usize, u32>>,
+}
+
+impl Caching {
+  // Return the FOPEN_* flags of opening inode `inum` of size `size`.
+  fn open_flags(&self, inum: usize, size: u32) -> u32 {
+    let mut flags = 0;
+
+    if self.direct_io {
+      flags |= FOPEN_DIRECT_IO;
+    }
+    let keep = match self.keep_cache {
+      KeepCache::Never => false,
+      KeepCache::Always => true,
+      KeepCache::Auto => {
+        self.sizes.lock().unwrap().insert(inum, size) == Some(size)
+      },
+    };
+    if keep {
+      flags |= FOPEN_KEEP_CACHE;
+    }
+    flags
+  }
+}
+
+// The open files and directories, by handle. Each holds a reference to its
+// inode until it is released, so that reading and writing through it need
+// not look up its ino again, and a file removed meanwhile lives on.
+struct Handles {
+  next_fh: Mutex<u64>,
+  handles: Mutex<HashMap<u64, (UnlockedInode, u32 /* open flags */)>>,
+}
+
+impl Handles {
+  fn new() -> Self {
+    Handles {
+      next_fh: Mutex::new(1),
+      handles: Mutex::new(HashMap::new()),
+    }
+  }
+
+  // Return a new handle of `inode`, opened with `flags`.
+  fn open(&self, inode: UnlockedInode, flags: u32) -> u64 {
+    let mut next_fh = self.next_fh.lock().unwrap();
+    let fh = *next_fh;
+
+    *next_fh += 1;
+    self.handles.lock().unwrap().insert(fh, (inode, flags));
+    fh
+  }
+
+  // Return the inode of handle `fh` and the flags it was opened with.
+  fn get(&self, fh: u64) -> Option<(UnlockedInode, u32)> {
+    self.handles.lock().unwrap().get(&fh).cloned()
+  }
+
+  // Return the inode number of handle `fh`.
+  fn inum(&self, fh: u64) -> Option<usize> {
+    self.handles.lock().unwrap().get(&fh).map(|h| h.0.no())
+  }
+
+  // Forget handle `fh`, and return its inode, to drop in a transaction.
+  fn release(&self, fh: u64) -> Option<UnlockedInode> {
+    self.handles.lock().unwrap().remove(&fh).map(|h| h.0)
+  }
+
+  fn release_all(&self) -> Vec<UnlockedInode> {
+    let mut handles = self.handles.lock().unwrap();
+    handles.drain().map(|(_, h)| h.0).collect()
+  }
+}
+
+// Return the inode of handle `fh` of `handles`, or reply EBADF.
+macro_rules! get_handle {
+  ($handles:expr, $fh:expr, $reply:ident) => ({
+    match $handles.get($fh) {
+      Some(handle) => handle,
+      None => {
+        $reply.error(EBADF);
+        return;
+      },
+    }
+  });
+}
+
+// Return the inode number of handle `fh` of `handles`, or reply ENOLCK if
+// it has none, as the files of submounts.
+macro_rules! get_handle_inum {
+  ($handles:expr, $fh:expr, $reply:ident) => ({
+    match $handles.inum($fh) {
+      Some(inum) => inum,
+      None => {
+        $reply.error(ENOLCK);
+        return;
+      },
+    }
+  });
+}
+
+// Return the lock of a getlk or setlk of type `typ`, None if it is neither
+// a read nor a write lock.
+fn to_lock(
+  owner: u64,
+  pid: u32,
+  start: u64,
+  end: u64,
+  typ: u32,
+) -> Option<Lock> {
+  let kind = match typ {
+    F_RDLCK => lock::Kind::Read,
+    F_WRLCK => lock::Kind::Write,
+    _ => return None,
+  };
+
+  Some(Lock {
+    owner,
+    pid,
+    start,
+    end,
+    kind,
+  })
+}
+
+fn from_kind(kind: lock::Kind) -> u32 {
+  match kind {
+    lock::Kind::Read => F_RDLCK,
+    lock::Kind::Write => F_WRLCK,
+  }
+}
+
+// Write out `writes`, held back by `coalescer`, each in a transaction of
+// its own, and record the errors with their handles. Those of O_APPEND
+// handles go to the end of the file as it is then, whatever their offset,
+// so that appends from several writers do not overwrite each other.
+fn write_back(coalescer: &Coalescer, handles: &Handles, writes: Vec<Pending>) {
+  for write in writes {
+    let txn = LOGGING.new_txn();
+    let handle = handles.get(write.fh).ok_or(Error::Invalid);
+    let result = handle.and_then(|(inode, flags)| {
+      let mut inode = ICACHE.lock(&txn, &inode);
+      let offset = if flags & O_APPEND as u32 != 0 {
+        inode.size as usize
+      } else {
+        write.offset
+      };
+
+      match inode.write(&txn, offset, &write.data) {
+        Ok(n) if n == write.data.len() => Ok(()),
+        // Short of space, see Inode::write.
+        Ok(_) => Err(Error::NoSpace),
+        Err(e) => Err(e),
+      }
+    });
+
+    if let Err(e) = result {
+      coalescer.fail(write.fh, e);
+    }
+  }
+}
+
+// Write out the writes held back to `ino`, before another operation on it.
+fn settle(coalescer: &Coalescer, handles: &Handles, ino: u64) {
+  if coalescer.is_empty() {
+    return;
+  }
+  let inum = {
+    let txn = LOGGING.new_read_txn();
+    match FuseInode::new(ino).get(&txn) {
+      Ok(inode) => inode.no(),
+      Err(_) => return,
+    }
+  };
+  write_back(coalescer, handles, coalescer.take_inode(inum));
+}
+
+struct Xv6FS {
+  pool: Scheduler,
+  submounts: Arc<Vec<Submount>>,
+  ttl: Timespec,
+  caching: Arc<Caching>,
+  streams: Arc<Tracker>,
+  locks: Arc<Locks>,
+  coalescer: Arc<Coalescer>,
+  handles: Arc<Handles>,
+}
+
+impl Xv6FS {
+  fn new(opts: &Options, submounts: Vec<Submount>) -> Self {
+    let window = opts.coalesce;
+    let coalescer = Arc::new(Coalescer::new(window));
+    let handles = Arc::new(Handles::new());
+
+    // Writes held back too long are written out in the background.
+    if window > Duration::new(0, 0) {
+      let weak = (Arc::downgrade(&coalescer), Arc::downgrade(&handles));
+
+      thread::spawn(move || loop {
+        thread::sleep(window);
+        match (weak.0.upgrade(), weak.1.upgrade()) {
+          (Some(coalescer), Some(handles)) => {
+            write_back(&coalescer, &handles, coalescer.take_expired())
+          },
+          _ => return,
+        }
+      });
+    }
+    Xv6FS {
+      pool: Scheduler::new(opts.nthreads, opts.qos),
+      submounts: Arc::new(submounts),
+      ttl: Timespec::new(
+        opts.ttl.as_secs() as i64,
+        opts.ttl.subsec_nanos() as i32,
+      ),
+      caching: Arc::new(Caching {
+        direct_io: opts.direct_io,
+        keep_cache: opts.keep_cache,
+        sizes: Mutex::new(HashMap::new()),
+      }),
+      streams: Arc::new(Tracker::new()),
+      locks: Arc::new(Locks::new()),
+      coalescer,
+      handles,
+    }
+  }
+
+  // Return the client `req` is scheduled for.
+  fn client(&self, req: &Request) -> u32 {
+    match self.pool.limits().key {
+      ClientKey::Uid => req.uid(),
+      ClientKey::Pid => req.pid(),
+    }
+  }
+
+  // Write back what was written through `fh` and commit it, for flush and
+  // fsync.
+  fn commit(&self, req: &Request, fh: u64, reply: ReplyEmpty) {
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      write_back(&coalescer, &handles, coalescer.take(fh));
+      if let Some(e) = coalescer.error(fh) {
+        reply.error(e.errno());
+        return;
+      }
+      LOGGING.force_commit();
+      reply.ok();
+    });
+  }
+}
+
+impl Filesystem for Xv6FS {
+  fn lookup(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    reply: ReplyEntry,
+  ) {
+    info!("[lookup] parent={} name={:?}", parent, name);
+
+    let ttl = self.ttl;
+
+    let name = convert_name!(name, reply);
+    let submounts = self.submounts.clone();
+
+    if let FuseInode::Sub(k, dir) = FuseInode::new(parent) {
+      let submount = &submounts[k];
+      let inum = submount.image.lock().unwrap().lookup(dir, &name);
+
+      match inum.and_then(|inum| submount.attr(k, inum)) {
+        Ok(attr) => reply.entry(&ttl, &attr, 0),
+        Err(e) => reply.error(e.errno()),
+      }
+      return;
+    }
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_read_txn();
+      let mut pinode = ICACHE.lock(&txn, &get_inode!(parent, txn, reply));
+      let entry = pinode.as_directory().lookup(&txn, &name);
+      let inode = match try_reply!(entry, reply) {
+        Some((inode, _)) => inode,
+        None => {
+          reply.error(ENOENT);
+          return;
+        },
+      };
+      if let Some(k) = grafted(&submounts, inode.no()) {
+        match submounts[k].attr(k, ROOTINO) {
+          Ok(attr) => reply.entry(&ttl, &attr, 0),
+          Err(e) => reply.error(e.errno()),
+        }
+        return;
+      }
+      let dinode = ICACHE.lock(&txn, &inode);
+      let attr = create_attr(
+        FuseInode::Ptr(inode.disassemble()).serialize(),
+        &dinode,
+      );
+
+      reply.entry(&ttl, &attr, dinode.gen as u64);
+    });
+  }
+
+  fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+    info!("[forget] ino={} nlookup={}", ino, nlookup);
+
+    // Only lookups hold references, readdir and the root do not.
+    if let FuseInode::Ptr(ptr) = FuseInode::new(ino) {
+      // Create an outer txn for txns nested in `UnlockedInode::Drop`.
+      let _txn = LOGGING.new_txn();
+      for i in 0..nlookup {
+        let ino = UnlockedInode::assemble(ptr);
+
+        if i == 0 {
+          assert!(ino.refcnt() >= nlookup as usize);
+        }
+        if i == nlookup - 1 {
+          info!("{} refcnt left", ino.refcnt() - 1);
+        }
+      }
+    }
+  }
+
+  fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+    info!("[getattr] ino={}", ino);
+
+    let ttl = self.ttl;
+
+    if let FuseInode::Sub(k, inum) = FuseInode::new(ino) {
+      match self.submounts[k].attr(k, inum) {
+        Ok(attr) => reply.attr(&ttl, &attr),
+        Err(e) => reply.error(e.errno()),
+      }
+      return;
+    }
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      settle(&coalescer, &handles, ino);
+
+      let txn = LOGGING.new_read_txn();
+      let dinode = ICACHE.lock(&txn, &get_inode!(ino, txn, reply));
+      let attr = create_attr(ino, &dinode);
+
+      reply.attr(&ttl, &attr);
+    });
+  }
+
+  fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+    info!("[access] ino={} mask={}", ino, mask);
+
+    let (uid, gid) = (req.uid(), req.gid());
+
+    if let FuseInode::Sub(k, inum) = FuseInode::new(ino) {
+      match self.submounts[k].access(inum, uid, gid, mask) {
+        Ok(()) => reply.ok(),
+        Err(e) => reply.error(e.errno()),
+      }
+      return;
+    }
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_read_txn();
+      let inode = get_inode!(ino, txn, reply);
+
+      try_reply!(ops::access(&txn, &inode, uid, gid, mask), reply);
+      reply.ok();
+    });
+  }
+
+  fn setattr(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    atime: Option<Timespec>,
+    mtime: Option<Timespec>,
+    _fh: Option<u64>,
+    _crtime: Option<Timespec>,
+    _chgtime: Option<Timespec>,
+    _bkuptime: Option<Timespec>,
+    _flags: Option<u32>,
+    reply: ReplyAttr,
+  ) {
+    info!("[setattr] ino={} mode={:?} size={:?}", ino, mode, size);
+
+    let ttl = self.ttl;
+    let caller = req.uid();
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      settle(&coalescer, &handles, ino);
+
+      let txn = LOGGING.new_txn();
+      let inode = get_inode!(ino, txn, reply);
+
+      // Only root gives files away, and only the owner or root changes
+      // the mode or group.
+      let stat = ops::stat(&txn, &inode);
+      let owned = caller == 0 || caller == stat.uid;
+      let give = uid.map_or(false, |uid| uid != stat.uid);
+
+      if (mode.is_some() || gid.is_some()) && !owned || give && caller != 0 {
+        reply.error(EPERM);
+        return;
+      }
+      if let Some(size) = size {
+        try_abort!(ops::truncate(&txn, &inode, size as usize), txn, reply);
+      }
+      if let Some(mode) = mode {
+        try_reply!(ops::chmod(&txn, &inode, mode as u16), reply);
+      }
+      if uid.is_some() || gid.is_some() {
+        let (uid, gid) = (uid.unwrap_or(stat.uid), gid.unwrap_or(stat.gid));
+
+        try_reply!(ops::chown(&txn, &inode, uid, gid), reply);
+      }
+      if atime.is_some() || mtime.is_some() {
+        let (atime, mtime) = (atime.map(from_time), mtime.map(from_time));
+
+        try_reply!(ops::set_times(&txn, &inode, atime, mtime), reply);
+      }
+      let dinode = ICACHE.lock(&txn, &inode);
+      let attr = create_attr(ino, &dinode);
+
+      reply.attr(&ttl, &attr);
+    });
+  }
+
+  fn mkdir(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    mode: u32,
+    reply: ReplyEntry,
+  ) {
+    info!("[mkdir] parent={} name={:?} mode={:o}", parent, name, mode);
+
+    let ttl = self.ttl;
+    let owner = (req.uid(), req.gid());
+
+    let name = convert_name!(name, reply);
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let mut pinode = ICACHE.lock(&txn, &get_inode!(parent, txn, reply));
+
+      if pinode.is_read_only() {
+        reply.error(EROFS);
+        return;
+      }
+      let result = pinode.as_directory().create_entry(
+        &txn,
+        &name,
+        fs::FileType::Directory,
+      );
+      let inode = try_abort!(result, txn, reply, pinode);
+      let mut dinode = ICACHE.lock(&txn, &inode);
+
+      init_inode(&txn, &pinode, &mut dinode, mode, owner);
+
+      let attr = create_attr(
+        FuseInode::Ptr(inode.disassemble()).serialize(),
+        &dinode,
+      );
+      reply.entry(&ttl, &attr, dinode.gen as u64);
+    });
+  }
+
+  fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+    info!("[statfs] ino={}", ino);
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_read_txn();
+      let usage = fs::usage(&txn);
+
+      reply.statfs(
+        usage.blocks as u64,
+        usage.free_blocks as u64,
+        usage.free_blocks as u64,
+        usage.inodes as u64,
+        usage.free_inodes as u64,
+        BSIZE as u32,
+        DIRSIZE as u32,
+        BSIZE as u32,
+      );
+    });
+  }
+
+  fn symlink(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    link: &Path,
+    reply: ReplyEntry,
+  ) {
+    info!("[symlink] parent={} name={:?} link={:?}", parent, name, link);
+
+    let ttl = self.ttl;
+    let owner = (req.uid(), req.gid());
+
+    let name = convert_name!(name, reply);
+    let target = link.as_os_str().as_bytes().to_vec();
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let dir = get_inode!(parent, txn, reply);
+      let result = ops::symlink(&txn, &dir, &name, &target);
+      let inode = try_abort!(result, txn, reply);
+      let pinode = ICACHE.lock(&txn, &dir);
+      let mut dinode = ICACHE.lock(&txn, &inode);
+
+      init_inode(&txn, &pinode, &mut dinode, 0o777, owner);
+      drop(pinode);
+
+      let attr = create_attr(
+        FuseInode::Ptr(inode.disassemble()).serialize(),
+        &dinode,
+      );
+      reply.entry(&ttl, &attr, dinode.gen as u64);
+    });
+  }
+
+  fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+    info!("[readlink] ino={}", ino);
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let inode = get_inode!(ino, txn, reply);
+      let target = try_reply!(ops::readlink(&txn, &inode), reply);
+
+      reply.data(&target);
+    });
+  }
+
+  fn mknod(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    mode: u32,
+    rdev: u32,
+    reply: ReplyEntry,
+  ) {
+    info!("[mknod] parent={} name={:?} mode={:o}", parent, name, mode);
+
+    let ttl = self.ttl;
+    let owner = (req.uid(), req.gid());
+
+    let name = convert_name!(name, reply);
+    // Only character devices, and regular files as a plain create would.
+    let device = match mode & S_IFMT {
+      S_IFCHR => match from_rdev(rdev) {
+        Some(device) => Some(device),
+        None => {
+          reply.error(EINVAL);
+          return;
+        },
+      },
+      S_IFREG => None,
+      _ => {
+        reply.error(Error::Unsupported.errno());
+        return;
+      },
+    };
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let dir = get_inode!(parent, txn, reply);
+      let result = match device {
+        Some((major, minor)) => ops::mknod(&txn, &dir, &name, major, minor),
+        None => ops::create(&txn, &dir, &name, fs::FileType::File),
+      };
+      let inode = try_abort!(result, txn, reply);
+      let pinode = ICACHE.lock(&txn, &dir);
+      let mut dinode = ICACHE.lock(&txn, &inode);
+
+      init_inode(&txn, &pinode, &mut dinode, mode, owner);
+      drop(pinode);
+
+      let attr = create_attr(
+        FuseInode::Ptr(inode.disassemble()).serialize(),
+        &dinode,
+      );
+      reply.entry(&ttl, &attr, dinode.gen as u64);
+    });
+  }
+
+  fn unlink(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    reply: ReplyEmpty,
+  ) {
+    info!("[unlink] parent={} name={:?}", parent, name);
+
+    let name = convert_name!(name, reply);
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let pinode = get_inode!(parent, txn, reply);
+
+      try_reply!(ops::unlink(&txn, &pinode, &name), reply);
+      reply.ok();
+    });
+  }
+
+  fn rmdir(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    reply: ReplyEmpty,
+  ) {
+    info!("[rmdir] parent={} name={:?}", parent, name);
+
+    if name == "." || name == ".." {
+      reply.error(ENOENT);
+      return;
+    }
+    let name = convert_name!(name, reply);
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let pinode = get_inode!(parent, txn, reply);
+
+      try_reply!(ops::rmdir(&txn, &pinode, &name), reply);
+      reply.ok();
+    });
+  }
+
+  fn rename(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    newparent: u64,
+    newname: &OsStr,
+    reply: ReplyEmpty,
+  ) {
+    info!(
+      "[rename] parent={} name={:?} newparent={} newname={:?}",
+      parent,
+      name,
+      newparent,
+      newname
+    );
+
+    let name = convert_name!(name, reply);
+    let newname = convert_name!(newname, reply);
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let pinode = get_inode!(parent, txn, reply);
+      let npinode = get_inode!(newparent, txn, reply);
+
+      let result = ops::rename(&txn, &pinode, &name, &npinode, &newname);
+      try_abort!(result, txn, reply);
+      reply.ok();
+    });
+  }
+
+  fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    info!("[open] ino={} flags={}", ino, flags);
+
+    let caching = self.caching.clone();
+    let streams = self.streams.clone();
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+    let trunc = flags & O_TRUNC as u32 != 0;
+
+    if let FuseInode::Sub(..) = FuseInode::new(ino) {
+      if trunc {
+        reply.error(EROFS);
+        return;
+      }
+      let mut flags = if caching.direct_io { FOPEN_DIRECT_IO } else { 0 };
+
+      // Submounts never change.
+      if caching.keep_cache != KeepCache::Never {
+        flags |= FOPEN_KEEP_CACHE;
+      }
+      reply.opened(0, flags);
+      return;
+    }
+    self.pool.execute(self.client(req), move || {
+      if trunc {
+        settle(&coalescer, &handles, ino);
+      }
+      // Truncated in the transaction it is opened in.
+      let txn = LOGGING.new_txn();
+      let inode = get_inode!(ino, txn, reply);
+
+      if trunc {
+        try_abort!(ops::truncate(&txn, &inode, 0), txn, reply);
+      }
+      let size = ICACHE.lock(&txn, &inode).size;
+      let inum = inode.no();
+
+      let fh = handles.open(inode, flags);
+      streams.open(fh, inum);
+
+      reply.opened(fh, caching.open_flags(inum, size));
+    });
+  }
+
+  fn release(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    _flags: u32,
+    lock_owner: u64,
+    _flush: bool,
+    reply: ReplyEmpty,
+  ) {
+    info!("[release] ino={} fh={}", ino, fh);
+
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+
+    // Those of flock(2) go with the file.
+    if let Some(inum) = self.handles.inum(fh) {
+      self.locks.release(inum, lock_owner);
+    }
+    self.streams.release(fh);
+    self.pool.execute(self.client(req), move || {
+      write_back(&coalescer, &handles, coalescer.take(fh));
+      // Too late to report.
+      coalescer.error(fh);
+
+      // Which frees the file if it was removed meanwhile.
+      let _txn = LOGGING.new_txn();
+      handles.release(fh);
+      reply.ok();
+    });
+  }
+
+  fn flush(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    lock_owner: u64,
+    reply: ReplyEmpty,
+  ) {
+    info!("[flush] ino={} fh={}", ino, fh);
+
+    // A process loses its locks of fcntl(2) on a file as it closes any of
+    // its descriptors of it.
+    if let Some(inum) = self.handles.inum(fh) {
+      self.locks.release(inum, lock_owner);
+    }
+    self.commit(req, fh, reply);
+  }
+
+  fn fsync(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    _datasync: bool,
+    reply: ReplyEmpty,
+  ) {
+    info!("[fsync] ino={} fh={}", ino, fh);
+
+    // Unlike flush, it leaves the locks alone.
+    self.commit(req, fh, reply);
+  }
+
+  fn fsyncdir(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    _datasync: bool,
+    reply: ReplyEmpty,
+  ) {
+    info!("[fsyncdir] ino={} fh={}", ino, fh);
+
+    self.pool.execute(self.client(req), move || {
+      LOGGING.force_commit();
+      reply.ok();
+    });
+  }
+
+  fn getlk(
+    &mut self,
+    _req: &Request,
+    ino: u64,
+    fh: u64,
+    lock_owner: u64,
+    start: u64,
+    end: u64,
+    typ: u32,
+    pid: u32,
+    reply: ReplyLock,
+  ) {
+    info!("[getlk] ino={} fh={} start={} end={}", ino, fh, start, end);
+
+    let inum = get_handle_inum!(self.handles, fh, reply);
+    let lock = match to_lock(lock_owner, pid, start, end, typ) {
+      Some(lock) => lock,
+      None => {
+        reply.error(EINVAL);
+        return;
+      },
+    };
+
+    match self.locks.test(inum, &lock) {
+      Some(l) => reply.locked(l.start, l.end, from_kind(l.kind), l.pid),
+      None => reply.locked(start, end, F_UNLCK, 0),
+    }
+  }
+
+  fn setlk(
+    &mut self,
+    _req: &Request,
+    ino: u64,
+    fh: u64,
+    lock_owner: u64,
+    start: u64,
+    end: u64,
+    typ: u32,
+    pid: u32,
+    sleep: bool,
+    reply: ReplyEmpty,
+  ) {
+    info!("[setlk] ino={} fh={} start={} end={}", ino, fh, start, end);
+
+    let inum = get_handle_inum!(self.handles, fh, reply);
+
+    if typ == F_UNLCK {
+      self.locks.unlock(inum, lock_owner, start, end);
+      reply.ok();
+      return;
+    }
+    let lock = match to_lock(lock_owner, pid, start, end, typ) {
+      Some(lock) => lock,
+      None => {
+        reply.error(EINVAL);
+        return;
+      },
+    };
+    if !sleep {
+      if self.locks.set(inum, lock, false) {
+        reply.ok();
+      } else {
+        reply.error(EAGAIN);
+      }
+      return;
+    }
+    // Waited for in a thread of its own, as the one holding the lock may
+    // need the pool to get to releasing it.
+    let locks = self.locks.clone();
+
+    thread::spawn(move || {
+      locks.set(inum, lock, true);
+      reply.ok();
+    });
+  }
+
+  fn read(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    size: u32,
+    reply: ReplyData,
+  ) {
+    info!("[read] ino={} offset={} size={}", ino, offset, size);
+    assert!(offset >= 0);
+
+    if let FuseInode::Sub(k, inum) = FuseInode::new(ino) {
+      let mut image = self.submounts[k].image.lock().unwrap();
+
+      match image.read(inum, offset as usize, size as usize) {
+        Ok(data) => reply.data(&data),
+        Err(e) => reply.error(e.errno()),
+      }
+      return;
+    }
+    let streams = self.streams.clone();
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      settle(&coalescer, &handles, ino);
+
+      let txn = LOGGING.new_read_txn();
+      let (inode, _) = get_handle!(handles, fh, reply);
+      let mut inode = ICACHE.lock(&txn, &inode);
+
+      if !crypt::has_key(&inode) {
+        reply.error(Error::NoKey.errno());
+        return;
+      }
+      // A read within a block is replied from the cached block itself, the
+      // others from a buffer this thread keeps for them.
+      let (offset, size) = (offset as usize, size as usize);
+      let len = min(size, (inode.size as usize).saturating_sub(offset));
+      let mut reply = Some(reply);
+      let result = if offset % BSIZE + len <= BSIZE {
+        inode.read_with(&txn, offset, size, |chunk| {
+          reply.take().unwrap().data(chunk);
+        })
+      } else {
+        READ_BUF.with(|buf| {
+          let mut buf = buf.borrow_mut();
+
+          buf.resize(len, 0);
+          let n = inode.read_into(&txn, offset, &mut buf[..len])?;
+          reply.take().unwrap().data(&buf[..n]);
+          Ok(n)
+        })
+      };
+      match (result, reply) {
+        (Err(e), Some(reply)) => {
+          reply.error(e.errno());
+          return;
+        },
+        (Ok(_), Some(reply)) => reply.data(&[]),
+        _ => (),
+      }
+      let limit = BCACHE.readahead_limit();
+      let nblocks = (inode.size as usize + BSIZE - 1) / BSIZE;
+      let blocknos = streams
+        .access(fh, offset, size, limit)
+        .into_iter()
+        .filter(|n| *n < nblocks)
+        .filter_map(|n| inode.mapped_block(&txn, n).unwrap_or(None))
+        .collect();
+
+      BCACHE.readahead(blocknos);
+    });
+  }
+
+  fn write(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    data: &[u8],
+    _flags: u32,
+    reply: ReplyWrite,
+  ) {
+    info!("[write] ino={} offset={} size={}", ino, offset, data.len());
+    assert!(offset >= 0);
+
+    let data = Vec::from(data);
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      let inum = {
+        let txn = LOGGING.new_read_txn();
+        let (inode, flags) = get_handle!(handles, fh, reply);
+        let inode = ICACHE.lock(&txn, &inode);
+
+        if flags & O_ACCMODE as u32 == O_RDONLY as u32 {
+          reply.error(EBADF);
+          return;
+        }
+        if inode.is_read_only() {
+          reply.error(EROFS);
+          return;
+        }
+        if !crypt::has_key(&inode) {
+          reply.error(Error::NoKey.errno());
+          return;
+        }
+        inode.no()
+      };
+      let n = data.len();
+
+      // Small writes are held back, see coalesce.rs.
+      let writes = if n < BSIZE && coalescer.window() > Duration::new(0, 0) {
+        coalescer.write(Pending {
+          fh,
+          inum,
+          offset: offset as usize,
+          data,
+        })
+      } else {
+        let mut writes = coalescer.take_inode(inum);
+
+        writes.push(Pending {
+          fh,
+          inum,
+          offset: offset as usize,
+          data,
+        });
+        writes
+      };
+      write_back(&coalescer, &handles, writes);
+      match coalescer.error(fh) {
+        Some(e) => reply.error(e.errno()),
+        None => reply.written(n as u32),
+      }
+    });
+  }
+
+  fn destroy(&mut self, _req: &Request) {
+    write_back(&self.coalescer, &self.handles, self.coalescer.take_all());
+    {
+      let _txn = LOGGING.new_txn();
+      self.handles.release_all();
+    }
+    reclaim::stop();
+    delalloc::stop();
+    Bitmap::save();
+    LOGGING.stop_committer();
+    BCACHE.stop_writeback();
+    LOGGING.checkpoint();
+  }
+
+  fn opendir(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    info!("[opendir] ino={}", ino);
+
+    // Submounts are read by ino.
+    if let FuseInode::Sub(..) = FuseInode::new(ino) {
+      reply.opened(0, 0);
+      return;
+    }
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let inode = get_inode!(ino, txn, reply);
+
+      if ICACHE.lock(&txn, &inode).file_type != fs::FileType::Directory {
+        reply.error(ENOTDIR);
+        return;
+      }
+      reply.opened(handles.open(inode, flags), 0);
+    });
+  }
+
+  fn releasedir(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    _flags: u32,
+    reply: ReplyEmpty,
+  ) {
+    info!("[releasedir] ino={} fh={}", ino, fh);
+
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      let _txn = LOGGING.new_txn();
+      handles.release(fh);
+      reply.ok();
+    });
+  }
+
+  fn readdir(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    fh: u64,
+    offset: i64,
+    mut reply: ReplyDirectory,
+  ) {
+    info!("[readdir] ino={} offset={}", ino, offset);
+    assert!(offset >= 0);
+
+    // The offset of an entry is where the next one is, by its index in a
+    // submount and the byte offset of its dirent otherwise. Entries are
+    // added until the reply is full, and the kernel asks for more from
+    // there.
+    if let FuseInode::Sub(k, dir) = FuseInode::new(ino) {
+      let mut image = self.submounts[k].image.lock().unwrap();
+      let ents = match image.readdir(dir) {
+        Ok(ents) => ents,
+        Err(e) => {
+          reply.error(e.errno());
+          return;
+        },
+      };
+
+      let ents = ents.into_iter().enumerate().skip(offset as usize);
+      for (i, (inum, name)) in ents {
+        let kind = match image.inode(inum) {
+          Ok(dinode) => get_kind(&dinode),
+          Err(_) => continue,
+        };
+        if reply.add(
+          FuseInode::Sub(k, inum).serialize(),
+          i as i64 + 1,
+          kind,
+          u82str(&name),
+        ) {
+          break;
+        }
+      }
+      reply.ok();
+      return;
+    }
+    let submounts = self.submounts.clone();
+    let handles = self.handles.clone();
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_read_txn();
+      // Unlocked before the inodes they refer to are locked, as "." is the
+      // directory itself.
+      let ents = {
+        let (inode, _) = get_handle!(handles, fh, reply);
+        let mut inode = ICACHE.lock(&txn, &inode);
+        let ents = inode.as_directory().entries(&txn, offset as usize);
+        try_reply!(ents, reply)
+      };
+
+      for (i, (next, inum, name)) in ents.into_iter().enumerate() {
+        let inode = match ICACHE.get(inum) {
+          Some(inode) => inode,
+          // The cache is full, the kernel asks again for the rest.
+          None if i > 0 => break,
+          None => {
+            reply.error(EBUSY);
+            return;
+          },
+        };
+        let dinode = ICACHE.lock(&txn, &inode);
+        let ino = match grafted(&submounts, inum) {
+          Some(k) => FuseInode::Sub(k, ROOTINO),
+          None => FuseInode::Inum(inum, dinode.gen),
+        };
+        if reply.add(
+          ino.serialize(),
+          next as i64,
+          get_kind(&dinode),
+          u82str(&name),
+        ) {
+          break;
+        }
+      }
+      reply.ok();
+    });
+  }
+
+  fn create(
+    &mut self,
+    req: &Request,
+    parent: u64,
+    name: &OsStr,
+    mode: u32,
+    flags: u32,
+    reply: ReplyCreate,
+  ) {
+    info!("[create] parent={} name={:?} flags={}", parent, name, flags);
+
+    let ttl = self.ttl;
+    let owner = (req.uid(), req.gid());
+    let caching = self.caching.clone();
+    let streams = self.streams.clone();
+    let coalescer = self.coalescer.clone();
+    let handles = self.handles.clone();
+    let trunc = flags & O_TRUNC as u32 != 0;
+
+    let name = convert_name!(name, reply);
+
+    self.pool.execute(self.client(req), move || {
+      // The writes held back to a file about to be truncated go first.
+      if trunc && !coalescer.is_empty() {
+        let inum = {
+          let txn = LOGGING.new_read_txn();
+          let dir = get_inode!(parent, txn, reply);
+          let inum = ops::lookup(&txn, &dir, &name).map(|inode| inode.no());
+          inum
+        };
+        if let Ok(inum) = inum {
+          write_back(&coalescer, &handles, coalescer.take_inode(inum));
+        }
+      }
+      let txn = LOGGING.new_txn();
+
+      if flags & O_TMPFILE as u32 == O_TMPFILE as u32 {
+        let dir = get_inode!(parent, txn, reply);
+        let inode = try_abort!(ops::tmpfile(&txn, &dir), txn, reply);
+        let pinode = ICACHE.lock(&txn, &dir);
+        let mut dinode = ICACHE.lock(&txn, &inode);
+
+        init_inode(&txn, &pinode, &mut dinode, mode, owner);
+        drop(pinode);
+        let open_flags = caching.open_flags(inode.no(), dinode.size);
+        let fh = handles.open(inode.clone(), flags);
+        streams.open(fh, inode.no());
+        let attr = create_attr(
+          FuseInode::Ptr(inode.disassemble()).serialize(),
+          &dinode,
+        );
+        reply.created(&ttl, &attr, dinode.gen as u64, fh, open_flags);
+        return;
+      }
+
+      let mut pinode = ICACHE.lock(&txn, &get_inode!(parent, txn, reply));
+      let create_flag = flags & O_CREAT as u32 != 0;
+      let exist_flag = flags & (O_CREAT | O_EXCL) as u32 != 0;
+
+      match try_reply!(pinode.as_directory().lookup(&txn, &name), reply) {
+        Some((inode, _)) => {
+          let file_type = ICACHE.lock(&txn, &inode).file_type;
+
+          if exist_flag || file_type != fs::FileType::File {
+            reply.error(EEXIST);
+            return;
+          }
+          if trunc {
+            let result = ops::truncate(&txn, &inode, 0);
+            try_abort!(result, txn, reply, pinode);
+          }
+          let dinode = ICACHE.lock(&txn, &inode);
+          let open_flags = caching.open_flags(inode.no(), dinode.size);
+          let fh = handles.open(inode.clone(), flags);
+          streams.open(fh, inode.no());
+          let attr = create_attr(
+            FuseInode::Ptr(inode.disassemble()).serialize(),
+            &dinode,
+          );
+          reply.created(&ttl, &attr, dinode.gen as u64, fh, open_flags);
+        },
+        None => {
+          if !create_flag {
+            reply.error(ENOENT);
+            return;
+          }
+          if pinode.is_read_only() {
+            reply.error(EROFS);
+            return;
+          }
+          let result =
+            pinode.as_directory().create_entry(&txn, &name, fs::FileType::File);
+          let inode = try_abort!(result, txn, reply, pinode);
+          let mut dinode = ICACHE.lock(&txn, &inode);
+
+          init_inode(&txn, &pinode, &mut dinode, mode, owner);
+
+          let open_flags = caching.open_flags(inode.no(), dinode.size);
+          let fh = handles.open(inode.clone(), flags);
+          streams.open(fh, inode.no());
+          let attr = create_attr(
+            FuseInode::Ptr(inode.disassemble()).serialize(),
+            &dinode,
+          );
+          reply.created(&ttl, &attr, dinode.gen as u64, fh, open_flags);
+        },
+      };
+    });
+  }
+
+  fn link(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    newparent: u64,
+    newname: &OsStr,
+    reply: ReplyEntry,
+  ) {
+    info!(
+      "[link] ino={} newparent={} newname={:?}",
+      ino,
+      newparent,
+      newname
+    );
+
+    let ttl = self.ttl;
+
+    let newname = convert_name!(newname, reply);
+
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let inode = get_inode!(ino, txn, reply);
+      let dir = get_inode!(newparent, txn, reply);
+
+      try_abort!(ops::link(&txn, &inode, &dir, &newname), txn, reply);
+      let dinode = ICACHE.lock(&txn, &inode);
+      let attr = create_attr(
+        FuseInode::Ptr(inode.clone().disassemble()).serialize(),
+        &dinode,
+      );
+      reply.entry(&ttl, &attr, dinode.gen as u64);
+    });
+  }
+
+  fn setxattr(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    name: &OsStr,
+    value: &[u8],
+    _flags: u32,
+    _position: u32,
+    reply: ReplyEmpty,
+  ) {
+    info!("[setxattr] ino={} name={:?}", ino, name);
+
+    if name == XATTR_FREEZE {
+      // Not run in the pool, whose threads may all be waiting for the thaw.
+      match value {
+        b"1" if !LOGGING.freeze() => reply.error(EBUSY),
+        b"0" if !LOGGING.thaw() => reply.error(EINVAL),
+        b"1" | b"0" => reply.ok(),
+        _ => reply.error(EINVAL),
+      }
+      return;
+    }
+    if name == XATTR_CONFIG {
+      match from_utf8(value).map_err(|_| Error::Invalid).and_then(tune::apply) {
+        Ok(()) => reply.ok(),
+        Err(e) => reply.error(e.errno()),
+      }
+      return;
+    }
+    if name == XATTR_RMTREE {
+      let name = match ops::to_name(value) {
+        Ok(name) => name,
+        Err(e) => {
+          reply.error(e.errno());
+          return;
+        },
+      };
+      self.pool.execute(self.client(req), move || {
+        let dir = {
+          let txn = LOGGING.new_txn();
+          let dir = get_inode!(ino, txn, reply).no();
+          dir
+        };
+        match batch::run(|b| b.remove_tree(dir, &name)) {
+          Ok(()) => reply.ok(),
+          Err(e) => reply.error(e.errno()),
+        }
+      });
+      return;
+    }
+    if name == XATTR_CLONE {
+      let path = value.to_vec();
+      let coalescer = self.coalescer.clone();
+      let handles = self.handles.clone();
+
+      self.pool.execute(self.client(req), move || {
+        let src = {
+          let txn = LOGGING.new_read_txn();
+          let src = try_reply!(ops::resolve(&txn, &path), reply).no();
+          src
+        };
+        settle(&coalescer, &handles, ino);
+        write_back(&coalescer, &handles, coalescer.take_inode(src));
+
+        // Emptied first, then copied MAXWRITE bytes at a time, each in a
+        // transaction of its own.
+        let size = {
+          let txn = LOGGING.new_txn();
+          let dst = get_inode!(ino, txn, reply);
+          let src = try_reply!(ops::resolve(&txn, &path), reply);
+
+          try_reply!(ops::truncate(&txn, &dst, 0), reply);
+          let size = ops::stat(&txn, &src).size as usize;
+          size
+        };
+        let mut offset = 0;
+        while offset < size {
+          let txn = LOGGING.new_txn();
+          let dst = get_inode!(ino, txn, reply);
+          let src = try_reply!(ops::resolve(&txn, &path), reply);
+          let copied = ops::copy_range(
+            &txn,
+            &src,
+            offset,
+            &dst,
+            offset,
+            ops::MAXWRITE,
+          );
+
+          match try_reply!(copied, reply) {
+            0 => break,
+            n => offset += n,
+          }
+        }
+        reply.ok();
+      });
+      return;
+    }
+    if name == XATTR_DEFAULTS {
+      let defaults = match to_defaults(value) {
+        Some(defaults) => defaults,
+        None => {
+          reply.error(EINVAL);
+          return;
+        },
+      };
+      self.pool.execute(self.client(req), move || {
+        let txn = LOGGING.new_txn();
+        let inode = get_inode!(ino, txn, reply);
+
+        match ops::set_defaults(&txn, &inode, Some(defaults)) {
+          Ok(()) => reply.ok(),
+          Err(e) => reply.error(e.errno()),
+        }
+      });
+      return;
+    }
+    if name == XATTR_SHRED {
+      let shred = match value {
+        b"1" => true,
+        b"0" => false,
+        _ => {
+          reply.error(EINVAL);
+          return;
+        },
+      };
+      self.pool.execute(self.client(req), move || {
+        let txn = LOGGING.new_txn();
+        let inode = get_inode!(ino, txn, reply);
+
+        match ops::set_shred(&txn, &inode, shred) {
+          Ok(()) => reply.ok(),
+          Err(e) => reply.error(e.errno()),
+        }
+      });
+      return;
+    }
+    let encrypt = match name.to_str() {
+      Some(XATTR_ENCRYPT) => true,
+      Some(XATTR_KEY) => false,
+      _ => {
+        reply.error(ENOTSUP);
+        return;
+      },
+    };
+    let key = match to_key(value) {
+      Some(key) => key,
+      None => {
+        reply.error(EINVAL);
+        return;
+      },
+    };
+
+    self.pool.execute(self.client(req), move || {
+      let result = if encrypt {
+        let txn = LOGGING.new_txn();
+        let inode = get_inode!(ino, txn, reply);
+        let result = crypt::encrypt_dir(&txn, &inode, key);
+        result
+      } else {
+        crypt::add_key(key).map(|_| ())
+      };
+
+      match result {
+        Ok(()) => reply.ok(),
+        Err(e) => reply.error(e.errno()),
+      }
+    });
+  }
+
+  fn getxattr(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    name: &OsStr,
+    size: u32,
+    reply: ReplyXattr,
+  ) {
+    info!("[getxattr] ino={} name={:?}", ino, name);
+
+    let value = match name.to_str() {
+      Some(XATTR_QOS) => Some(
+        self
+          .pool
+          .stats()
+          .iter()
+          .map(|c| {
+            format!(
+              "{} {} {} {} {}\n",
+              c.client,
+              c.requests,
+              c.throttled,
+              c.inflight,
+              c.queued
+            )
+          })
+          .collect(),
+      ),
+      Some(XATTR_CONFIG) => Some(tune::settings()),
+      Some(XATTR_READAHEAD) => Some(
+        self
+          .streams
+          .stats()
+          .iter()
+          .map(|s| format!("{} {} {} {}\n", s.fh, s.inum, s.pattern, s.window))
+          .collect(),
+      ),
+      Some(XATTR_RECLAIM) => {
+        let stats = reclaim::stats();
+        Some(format!("{} {} {}\n", stats.pending, stats.inodes, stats.blocks))
+      },
+      _ => None,
+    };
+    if let Some(value) = value {
+      reply_xattr(reply, size, &value);
+      return;
+    }
+    if let FuseInode::Sub(..) = FuseInode::new(ino) {
+      reply.error(ENOATTR);
+      return;
+    }
+    let name = match name.to_str() {
+      Some(XATTR_ENCRYPT) => XATTR_ENCRYPT,
+      Some(XATTR_DEFAULTS) => XATTR_DEFAULTS,
+      Some(XATTR_SHRED) => XATTR_SHRED,
+      _ => {
+        reply.error(ENOATTR);
+        return;
+      },
+    };
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let inode = get_inode!(ino, txn, reply);
+      let value = if name == XATTR_SHRED {
+        if !ops::is_shredded(&txn, &inode) {
+          reply.error(ENOATTR);
+          return;
+        }
+        "1".to_string()
+      } else if name == XATTR_DEFAULTS {
+        match ops::defaults(&txn, &inode) {
+          Ok(Some(d)) => format!("{:o} {} {}", d.mode, d.uid, d.gid),
+          Ok(None) => {
+            reply.error(ENOATTR);
+            return;
+          },
+          Err(e) => {
+            reply.error(e.errno());
+            return;
+          },
+        }
+      } else {
+        let dinode = ICACHE.lock(&txn, &inode);
+
+        if !dinode.is_encrypted() {
+          reply.error(ENOATTR);
+          return;
+        }
+        format!("{}", dinode.key_id())
+      };
+      reply_xattr(reply, size, &value);
+    });
+  }
+
+  fn removexattr(
+    &mut self,
+    req: &Request,
+    ino: u64,
+    name: &OsStr,
+    reply: ReplyEmpty,
+  ) {
+    info!("[removexattr] ino={} name={:?}", ino, name);
+
+    if name == XATTR_DEFAULTS || name == XATTR_SHRED {
+      let defaults = name == XATTR_DEFAULTS;
+
+      self.pool.execute(self.client(req), move || {
+        let txn = LOGGING.new_txn();
+        let inode = get_inode!(ino, txn, reply);
+        let result = if defaults {
+          ops::set_defaults(&txn, &inode, None)
+        } else {
+          ops::set_shred(&txn, &inode, false)
+        };
+
+        match result {
+          Ok(()) => reply.ok(),
+          Err(e) => reply.error(e.errno()),
+        }
+      });
+      return;
+    }
+    if name != XATTR_KEY {
+      reply.error(ENOTSUP);
+      return;
+    }
+    self.pool.execute(self.client(req), move || {
+      let txn = LOGGING.new_txn();
+      let dinode = ICACHE.lock(&txn, &get_inode!(ino, txn, reply));
+
+      if dinode.is_encrypted() && crypt::remove_key(dinode.key_id()) {
+        reply.ok();
+      } else {
+        reply.error(ENOATTR);
+      }
+    });
+  }
+}
+
+
+pub struct Options {
+  // Threads serving requests.
+  pub nthreads: usize,
+  // Refuse every change with EROFS, and never write to the image, see
+  // DiskService::set_read_only.
+  pub read_only: bool,
+  // See DiskService::start_flusher.
+  pub sync_interval: Option<Duration>,
+  // Keep the changes to a local image in this delta, see overlay.rs.
+  pub overlay: Option<PathBuf>,
+  // Images grafted read-only at directories of the mounted one, whose own
+  // entries they hide, as (directory, image) pairs.
+  pub submounts: Vec<(String, PathBuf)>,
+  // How long the kernel may cache attributes and lookups.
+  pub ttl: Duration,
+  // Bypass the page cache of the kernel for reads and writes.
+  pub direct_io: bool,
+  pub keep_cache: KeepCache,
+  // Limits on the requests of each client, see qos.rs.
+  pub qos: qos::Limits,
+  // How long small writes may be held back, none if 0, see coalesce.rs.
+  pub coalesce: Duration,
+  // How long transactions that end may wait to be committed together, by
+  // a background thread, none if 0, see Logging::start_committer.
+  pub commit_window: Duration,
+  // How many blocks they may write before, half the log if 0.
+  pub commit_blocks: usize,
+  // How often the blocks committed are written out by a background thread
+  // rather than at once, none if 0, see Cache::start_writeback. The blocks
+  // written to files are then allocated as they are committed, see
+  // delalloc.rs.
+  pub write_back: Duration,
+  // Serve a local image from its file rather than from memory, holding
+  // back at most this many writes, see FileDisk.
+  pub file_backed: Option<usize>,
+  // Have the image drop the blocks freed, see Bitmap::set_discard.
+  pub discard: bool,
+}
+
+impl Default for Options {
+  // Those of the daemon, which follow XV6FS_READ_ONLY and
+  // XV6FS_SYNC_INTERVAL.
+  fn default() -> Self {
+    Options {
+      nthreads: 10,
+      read_only: env::var_os("XV6FS_READ_ONLY").is_some(),
+      sync_interval: disk::sync_interval(),
+      overlay: None,
+      submounts: vec![],
+      ttl: Duration::from_secs(1),
+      direct_io: false,
+      keep_cache: KeepCache::Never,
+      qos: qos::Limits::default(),
+      coalesce: Duration::from_millis(10),
+      commit_window: Duration::from_secs(0),
+      commit_blocks: 0,
+      write_back: Duration::from_secs(0),
+      file_backed: None,
+      discard: false,
+    }
+  }
+}
+
+// A file system mounted by `spawn_mount`, unmounted when dropped.
+pub struct MountHandle {
+  session: Option<BackgroundSession<'static>>,
+}
+
+impl MountHandle {
+  pub fn unmount(self) {}
+}
+
+impl Drop for MountHandle {
+  fn drop(&mut self) {
+    // Stop serving before the disk goes away.
+    self.session = None;
+    DISK.unmount();
+  }
+}
+
+// Open the submounts of `opts`, once the disk is mounted.
+fn open_submounts(opts: &Options) -> io::Result<Vec<Submount>> {
+  let mut submounts = vec![];
+
+  for &(ref dir, ref image) in &opts.submounts {
+    let txn = LOGGING.new_txn();
+    let inode = ops::resolve(&txn, dir.as_bytes())
+      .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+
+    if ops::stat(&txn, &inode).file_type != fs::FileType::Directory {
+      return Err(io::Error::from_raw_os_error(ENOTDIR));
+    }
+    submounts.push(Submount {
+      at: inode.no(),
+      image: Mutex::new(Image::open(image)?),
+    });
+  }
+  Ok(submounts)
+}
+
+// Mount `fsimg` as the disk, either a local image file or
+// `s3://bucket/prefix` for an image created in an object store, and open
+// the submounts.
+fn open(fsimg: &str, opts: &Options) -> io::Result<Vec<Submount>> {
+  // Blocks of a local image that could not be read, see badblock.rs.
+  let mut unreadable = vec![];
+  // An image of upstream xv6, served from a copy, see legacy.rs.
+  let mut legacy = None;
+
+  if fsimg.starts_with("s3://") {
+    let mut parts = fsimg["s3://".len()..].splitn(2, '/');
+    let bucket = parts.next().unwrap();
+    let prefix = parts.next().unwrap_or("");
+    let store = S3Store::from_env(bucket).ok_or(io::Error::new(
+      io::ErrorKind::NotFound,
+      "S3 is not configured",
+    ))?;
+
+    DISK.mount(ObjectDisk::open(store, prefix, opts.read_only)?);
+  } else {
+    if !Path::new(fsimg).is_file() {
+      return Err(io::Error::new(io::ErrorKind::NotFound, "no such image"));
+    }
+    if let Some(ref delta) = opts.overlay {
+      DISK.mount(Overlay::open(fsimg, delta)?);
+    } else if overlay::is_clone(fsimg) {
+      DISK.mount(Overlay::open_clone(fsimg)?);
+    } else if let Some(image) = Legacy::open(fsimg)? {
+      let disk = image
+        .format()
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+
+      warn!("serving {:?} image {} read-only", image.layout(), fsimg);
+      DISK.mount(disk);
+      legacy = Some(image);
+    } else if let Some(capacity) = opts.file_backed {
+      DISK.mount(FileDisk::open(fsimg, capacity)?);
+    } else {
+      let disk = Disk::load(fsimg)?;
+
+      unreadable = disk.unreadable().to_vec();
+      DISK.mount(disk);
+    }
+  }
+  // That of a legacy image is its copy, read-only anyway.
+  if opts.read_only && legacy.is_none() {
+    DISK.set_read_only(true);
+  }
+  if let Err(e) = LOGGING.init() {
+    DISK.unmount();
+    return Err(io::Error::from_raw_os_error(e.errno()));
+  }
+  // Refuse data that was tampered with, see integrity.rs.
+  let tampered = match integrity::verify() {
+    Ok(blocknos) => blocknos.len(),
+    Err(Error::Io) => 1,
+    Err(_) => 0,
+  };
+  if tampered > 0 {
+    DISK.unmount();
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "image fails verification",
+    ));
+  }
+  Bitmap::set_discard(opts.discard);
+  if !DISK.is_read_only() {
+    reclaim::start();
+    if opts.commit_window > Duration::from_secs(0) {
+      LOGGING.start_committer(opts.commit_window, opts.commit_blocks);
+    }
+    if opts.write_back > Duration::from_secs(0) {
+      BCACHE.start_writeback(opts.write_back);
+      delalloc::start();
+    }
+    ICACHE.reclaim_orphans();
+    badblock::remap_all(&unreadable);
+  }
+  if let Some(legacy) = legacy {
+    if let Err(e) = legacy.copy(true) {
+      DISK.unmount();
+      return Err(io::Error::from_raw_os_error(e.errno()));
+    }
+  }
+
+  let submounts = match open_submounts(opts) {
+    Ok(submounts) => submounts,
+    Err(e) => {
+      DISK.unmount();
+      return Err(e);
+    },
+  };
+  if let Some(interval) = opts.sync_interval {
+    DISK.start_flusher(interval);
+  }
+  Ok(submounts)
+}
+
+// Serve `fsimg` at `mountpoint` until it is unmounted.
+pub fn mount<P: AsRef<Path>>(
+  fsimg: &str,
+  mountpoint: P,
+  opts: &Options,
+) -> io::Result<()> {
+  let submounts = open(fsimg, opts)?;
+  let xv6fs = Xv6FS::new(opts, submounts);
+  let signals = unmount_on_signal(mountpoint.as_ref());
+  let result = fuse::mount(xv6fs, &mountpoint, &[]);
+  // Saved to the image, see Disk::flush.
+  DISK.unmount();
+  unsafe {
+    pthread_sigmask(SIG_UNBLOCK, &signals, ptr::null_mut());
+  }
+  result
+}
+
+// Unmount `mountpoint` as the process gets SIGINT or SIGTERM, so that the
+// image is saved as `mount` returns rather than losing what was written
+// since the last flush. The threads spawned from now on leave them to a
+// thread of its own. Return the signals, blocked until `mount` returns.
+fn unmount_on_signal(mountpoint: &Path) -> sigset_t {
+  let mountpoint = mountpoint.to_path_buf();
+  let mut signals: sigset_t = unsafe { mem::zeroed() };
+
+  unsafe {
+    sigemptyset(&mut signals);
+    sigaddset(&mut signals, SIGINT);
+    sigaddset(&mut signals, SIGTERM);
+    pthread_sigmask(SIG_BLOCK, &signals, ptr::null_mut());
+  }
+  thread::spawn(move || loop {
+    let mut signal = 0;
+
+    if unsafe { sigwait(&signals, &mut signal) } != 0 {
+      return;
+    }
+    info!("unmounting {} on signal {}", mountpoint.display(), signal);
+    match Command::new("fusermount").arg("-u").arg(&mountpoint).status() {
+      Ok(ref status) if status.success() => return,
+      Ok(status) => {
+        warn!("cannot unmount {}: {}", mountpoint.display(), status)
+      },
+      Err(e) => warn!("cannot unmount {}: {}", mountpoint.display(), e),
+    }
+  });
+  signals
+}
+
+// Serve `fsimg` at `mountpoint` from a background thread.
+pub fn spawn_mount<P: AsRef<Path>>(
+  fsimg: &str,
+  mountpoint: P,
+  opts: &Options,
+) -> io::Result<MountHandle> {
+  let submounts = open(fsimg, opts)?;
+  let xv6fs = Xv6FS::new(opts, submounts);
+
+  match unsafe { fuse::spawn_mount(xv6fs, &mountpoint, &[]) } {
+    Ok(session) => Ok(MountHandle {
+      session: Some(session),
+    }),
+    Err(e) => {
+      DISK.unmount();
+      Err(e)
+    },
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use fuse::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+  use fs::FileType;
+  use logging::LOGGING;
+  use coalesce::{Coalescer, Pending};
+  use libc::{O_APPEND, O_WRONLY};
+  use mount::{self, Caching, FuseInode, Handles, KeepCache};
+  use ops;
+  use std::collections::HashMap;
+  use std::sync::Mutex;
+  use std::time::Duration;
+  use testfs;
+
+  #[test]
+  fn test() {
+    match FuseInode::new(FuseInode::Inum(3, 7).serialize()) {
+      FuseInode::Inum(3, 7) => (),
+      _ => panic!("not Inum(3, 7)"),
+    }
+    match FuseInode::new(FuseInode::Sub(2, 5).serialize()) {
+      FuseInode::Sub(2, 5) => (),
+      _ => panic!("not Sub(2, 5)"),
+    }
+    let x = Box::new(0u64);
+    match FuseInode::new(&*x as *const u64 as u64) {
+      FuseInode::Ptr(_) => (),
+      _ => panic!("not Ptr"),
+    }
+
+    // The high bits of the minor number go above the major number.
+    assert!(mount::to_rdev(4, 300) == 0x10042c);
+    assert!(mount::from_rdev(0x10042c) == Some((4, 300)));
+    assert!(mount::from_rdev(0xfff00000) == None);
+  }
+
+  #[test]
+  fn test_caching() {
+    let caching = Caching {
+      direct_io: true,
+      keep_cache: KeepCache::Auto,
+      sizes: Mutex::new(HashMap::new()),
+    };
+
+    assert!(caching.open_flags(3, 10) == FOPEN_DIRECT_IO);
+    assert!(caching.open_flags(3, 10) == FOPEN_DIRECT_IO | FOPEN_KEEP_CACHE);
+    assert!(caching.open_flags(3, 20) == FOPEN_DIRECT_IO);
+    assert!(caching.open_flags(4, 20) == FOPEN_DIRECT_IO);
+  }
+
+  #[test]
+  fn test_handles() {
+    testfs::test::mount();
+    let handles = Handles::new();
+    let name = ops::to_name(b"f").unwrap();
+    let (fh, inum) = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+      let inum = file.no();
+
+      (handles.open(file, 2), inum)
+    };
+    assert!(handles.get(fh).map(|(inode, flags)| (inode.no(), flags))
+      == Some((inum, 2)));
+
+    // The handle keeps a removed file alive until it is released.
+    {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+
+      ops::unlink(&txn, &root, &name).unwrap();
+      let (file, _) = handles.get(fh).unwrap();
+      assert!(ops::read(&txn, &file, 0, 1).unwrap().is_empty());
+      handles.release(fh);
+      assert!(handles.get(fh).is_none());
+    }
+  }
+
+  #[test]
+  fn test_append() {
+    testfs::test::mount();
+    let coalescer = Coalescer::new(Duration::from_secs(60));
+    let handles = Handles::new();
+    let name = ops::to_name(b"log").unwrap();
+    let (append, plain) = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+
+      let append = handles.open(file.clone(), (O_WRONLY | O_APPEND) as u32);
+      (append, handles.open(file, O_WRONLY as u32))
+    };
+    let write = |fh, data: &[u8]| Pending {
+      fh,
+      inum: 0,
+      offset: 0,
+      data: data.to_vec(),
+    };
+
+    // Appends land at the end whatever the offset they were made at.
+    mount::write_back(&coalescer, &handles, vec![write(plain, b"ab")]);
+    mount::write_back(&coalescer, &handles, vec![write(append, b"cd")]);
+    mount::write_back(&coalescer, &handles, vec![write(append, b"e")]);
+    mount::write_back(&coalescer, &handles, vec![write(plain, b"x")]);
+    {
+      let txn = LOGGING.new_txn();
+      let file = ops::resolve(&txn, b"/log").unwrap();
+
+      assert!(ops::read(&txn, &file, 0, 10).unwrap() == b"xbcde");
+      handles.release_all();
+    }
+  }
+}
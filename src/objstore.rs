@@ -0,0 +1,491 @@
+// A block device backed by an object store, e.g. S3.
+//
+// The device is sliced into objects of BPO consecutive blocks, named
+// `<prefix>/<index>` with the index in 8 hex digits, next to a
+// `<prefix>/meta` object recording the geometry. Objects never written read
+// as zeros. Recently used objects are kept in a write-back cache, which is
+// written out on eviction and on `flush`.
+
+use disk::{BSIZE, Block, BlockDevice};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::str;
+use time;
+use util::sha256::{hmac_sha256, sha256, to_hex};
+
+// Number of blocks per object.
+pub const BPO: usize = 128;
+
+// Number of objects kept in the cache by default.
+const NCACHED: usize = 64;
+
+// Number of attempts of an object store request before giving up.
+const NRETRIES: usize = 3;
+
+pub trait ObjectStore: Send + Sync {
+  fn get(&mut self, key: &str) -> io::Result<Option<Vec<u8>>>;
+  fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()>;
+}
+
+// Objects stored as files under a directory.
+pub struct DirStore {
+  root: PathBuf,
+}
+
+impl DirStore {
+  pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+    DirStore { root: root.into() }
+  }
+}
+
+impl ObjectStore for DirStore {
+  fn get(&mut self, key: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut data = vec![];
+
+    match fs::File::open(self.root.join(key)) {
+      Ok(mut f) => f.read_to_end(&mut data)?,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e),
+    };
+    Ok(Some(data))
+  }
+
+  fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+    let path = self.root.join(key);
+    let tmp = self.root.join(format!("{}.tmp", key));
+
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::File::create(&tmp)?.write_all(data)?;
+    fs::rename(&tmp, &path)
+  }
+}
+
+// S3-compatible store speaking plain HTTP with path-style addressing and
+// AWS signature version 4.
+pub struct S3Store {
+  host: String,
+  bucket: String,
+  region: String,
+  access_key: String,
+  secret_key: String,
+}
+
+impl S3Store {
+  // `endpoint` is like `http://localhost:9000`, HTTPS is not supported.
+  pub fn new(
+    endpoint: &str,
+    bucket: &str,
+    region: &str,
+    access_key: &str,
+    secret_key: &str,
+  ) -> Option<Self> {
+    if !endpoint.starts_with("http://") {
+      return None;
+    }
+    Some(S3Store {
+      host: endpoint["http://".len()..].trim_matches('/').to_string(),
+      bucket: bucket.to_string(),
+      region: region.to_string(),
+      access_key: access_key.to_string(),
+      secret_key: secret_key.to_string(),
+    })
+  }
+
+  // Configured by AWS_ENDPOINT_URL, AWS_REGION (default us-east-1),
+  // AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+  pub fn from_env(bucket: &str) -> Option<Self> {
+    S3Store::new(
+      &env::var("AWS_ENDPOINT_URL").ok()?,
+      bucket,
+      &env::var("AWS_REGION").unwrap_or("us-east-1".to_string()),
+      &env::var("AWS_ACCESS_KEY_ID").ok()?,
+      &env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+    )
+  }
+
+  fn request(
+    &self,
+    method: &str,
+    key: &str,
+    body: &[u8],
+  ) -> io::Result<(u16, Vec<u8>)> {
+    let date = time::strftime("%Y%m%dT%H%M%SZ", &time::now_utc()).unwrap();
+    let day = &date[..8];
+    let path = format!("/{}/{}", self.bucket, key);
+    let payload_hash = to_hex(&sha256(body));
+
+    let canonical = format!(
+      "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n\
+       host;x-amz-content-sha256;x-amz-date\n{}",
+      method,
+      path,
+      self.host,
+      payload_hash,
+      date,
+      payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", day, self.region);
+    let to_sign = format!(
+      "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+      date,
+      scope,
+      to_hex(&sha256(canonical.as_bytes()))
+    );
+    let mut signing_key = hmac_sha256(
+      format!("AWS4{}", self.secret_key).as_bytes(),
+      day.as_bytes(),
+    );
+    for part in &[self.region.as_str(), "s3", "aws4_request"] {
+      signing_key = hmac_sha256(&signing_key, part.as_bytes());
+    }
+    let signature = to_hex(&hmac_sha256(&signing_key, to_sign.as_bytes()));
+
+    let mut stream = TcpStream::connect(&self.host)?;
+    write!(
+      stream,
+      "{} {} HTTP/1.1\r\nHost: {}\r\nx-amz-date: {}\r\n\
+       x-amz-content-sha256: {}\r\nAuthorization: AWS4-HMAC-SHA256 \
+       Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+       Signature={}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+      method,
+      path,
+      self.host,
+      date,
+      payload_hash,
+      self.access_key,
+      scope,
+      signature,
+      body.len()
+    )?;
+    stream.write_all(body)?;
+
+    let mut resp = vec![];
+    stream.read_to_end(&mut resp)?;
+    parse_response(&resp)
+      .ok_or(io::Error::new(io::ErrorKind::InvalidData, "bad response"))
+  }
+}
+
+fn find(data: &[u8], pat: &[u8]) -> Option<usize> {
+  data.windows(pat.len()).position(|w| w == pat)
+}
+
+fn parse_response(resp: &[u8]) -> Option<(u16, Vec<u8>)> {
+  let end = find(resp, b"\r\n\r\n")?;
+  let head = str::from_utf8(&resp[..end]).ok()?;
+  let mut body = &resp[end + 4..];
+  let mut lines = head.split("\r\n");
+  let status = lines.next()?.split_whitespace().nth(1)?.parse().ok()?;
+  let chunked = lines.any(|line| {
+    line.to_ascii_lowercase().starts_with("transfer-encoding:") &&
+      line.to_ascii_lowercase().contains("chunked")
+  });
+
+  if !chunked {
+    return Some((status, body.to_vec()));
+  }
+  let mut result = vec![];
+  loop {
+    let i = find(body, b"\r\n")?;
+    let size = str::from_utf8(&body[..i]).ok()?.split(';').next()?;
+    let size = usize::from_str_radix(size.trim(), 16).ok()?;
+    body = &body[i + 2..];
+    if size == 0 {
+      return Some((status, result));
+    }
+    result.extend_from_slice(body.get(..size)?);
+    body = body.get(size + 2..)?;
+  }
+}
+
+impl ObjectStore for S3Store {
+  fn get(&mut self, key: &str) -> io::Result<Option<Vec<u8>>> {
+    match self.request("GET", key, &[])? {
+      (200, body) => Ok(Some(body)),
+      (404, _) => Ok(None),
+      (status, _) => Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("GET {} failed with status {}", key, status),
+      )),
+    }
+  }
+
+  fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+    match self.request("PUT", key, data)? {
+      (200, _) => Ok(()),
+      (status, _) => Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("PUT {} failed with status {}", key, status),
+      )),
+    }
+  }
+}
+
+struct Object {
+  blocks: Vec<Block>,
+  dirty: bool,
+  // Last time this object was used, for LRU eviction.
+  tick: u64,
+}
+
+pub struct ObjectDisk<S: ObjectStore> {
+  store: S,
+  prefix: String,
+  nblocks: usize,
+  // Writes of a read-only device stay in the cache and are never stored,
+  // so that several hosts can share the same objects.
+  read_only: bool,
+  capacity: usize,
+  cache: HashMap<usize, Object>,
+  tick: u64,
+}
+
+// Run `f` up to NRETRIES times. `BlockDevice` has no way to report errors,
+// so we panic in the end.
+fn retry<T, F: FnMut() -> io::Result<T>>(mut f: F) -> T {
+  let mut result = f();
+
+  for _ in 1..NRETRIES {
+    match result {
+      Ok(x) => return x,
+      Err(e) => warn!("object store: {}, retrying", e),
+    }
+    result = f();
+  }
+  result.unwrap()
+}
+
+impl<S: ObjectStore> ObjectDisk<S> {
+  fn new(store: S, prefix: &str, nblocks: usize, read_only: bool) -> Self {
+    ObjectDisk {
+      store,
+      prefix: prefix.trim_matches('/').to_string(),
+      nblocks,
+      read_only,
+      capacity: NCACHED,
+      cache: HashMap::new(),
+      tick: 0,
+    }
+  }
+
+  // Create an empty device of `nblocks` blocks.
+  pub fn create(
+    mut store: S,
+    prefix: &str,
+    nblocks: usize,
+  ) -> io::Result<Self> {
+    let key = ObjectDisk::<S>::join(prefix.trim_matches('/'), "meta");
+
+    store.put(&key, format!("{} {}\n", nblocks, BPO).as_bytes())?;
+    Ok(ObjectDisk::new(store, prefix, nblocks, false))
+  }
+
+  // Open a device created by `create`.
+  pub fn open(mut store: S, prefix: &str, read_only: bool) -> io::Result<Self> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad meta");
+    let key = ObjectDisk::<S>::join(prefix.trim_matches('/'), "meta");
+    let meta = store.get(&key)?.ok_or(invalid())?;
+    let meta = String::from_utf8(meta).map_err(|_| invalid())?;
+    let fields: Vec<usize> = meta
+      .split_whitespace()
+      .map(|s| s.parse().map_err(|_| invalid()))
+      .collect::<io::Result<_>>()?;
+
+    if fields.len() != 2 || fields[1] != BPO {
+      return Err(invalid());
+    }
+    Ok(ObjectDisk::new(store, prefix, fields[0], read_only))
+  }
+
+  // Keep at most `capacity` objects in the cache.
+  pub fn set_capacity(&mut self, capacity: usize) {
+    assert!(capacity > 0);
+    self.capacity = capacity;
+  }
+
+  fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+      name.to_string()
+    } else {
+      format!("{}/{}", prefix, name)
+    }
+  }
+
+  fn key(&self, index: usize) -> String {
+    ObjectDisk::<S>::join(&self.prefix, &format!("{:08x}", index))
+  }
+
+  fn store_object(&mut self, index: usize) {
+    let key = self.key(index);
+    let mut data = Vec::with_capacity(BPO * BSIZE);
+
+    for block in &self.cache[&index].blocks {
+      data.extend_from_slice(block);
+    }
+    let store = &mut self.store;
+    retry(|| store.put(&key, &data));
+    self.cache.get_mut(&index).unwrap().dirty = false;
+  }
+
+  // Evict the least recently used object, preferring clean ones. Dirty
+  // objects of a read-only device are never evicted.
+  fn evict(&mut self) {
+    let victim = self
+      .cache
+      .iter()
+      .filter(|&(_, obj)| !(self.read_only && obj.dirty))
+      .min_by_key(|&(_, obj)| (obj.dirty, obj.tick))
+      .map(|(index, _)| *index);
+
+    if let Some(index) = victim {
+      if self.cache[&index].dirty {
+        self.store_object(index);
+      }
+      self.cache.remove(&index);
+    }
+  }
+
+  fn object(&mut self, index: usize) -> &mut Object {
+    self.tick += 1;
+
+    if !self.cache.contains_key(&index) {
+      if self.cache.len() >= self.capacity {
+        self.evict();
+      }
+
+      let key = self.key(index);
+      let store = &mut self.store;
+      let data = retry(|| store.get(&key));
+      let mut blocks = vec![[0; BSIZE]; BPO];
+
+      if let Some(data) = data {
+        // The last object may be short, the rest reads as zeros.
+        assert!(
+          data.len() <= BPO * BSIZE && data.len() % BSIZE == 0,
+          "corrupt object {}",
+          key
+        );
+        for (block, chunk) in blocks.iter_mut().zip(data.chunks(BSIZE)) {
+          block.copy_from_slice(chunk);
+        }
+      }
+      self.cache.insert(
+        index,
+        Object {
+          blocks,
+          dirty: false,
+          tick: 0,
+        },
+      );
+    }
+
+    let obj = self.cache.get_mut(&index).unwrap();
+    obj.tick = self.tick;
+    obj
+  }
+}
+
+impl<S: ObjectStore> BlockDevice for ObjectDisk<S> {
+  fn nblocks(&self) -> usize {
+    self.nblocks
+  }
+
+  fn read(&mut self, blockno: usize) -> Block {
+    assert!(blockno < self.nblocks);
+    self.object(blockno / BPO).blocks[blockno % BPO]
+  }
+
+  fn write(&mut self, blockno: usize, data: &Block) {
+    assert!(blockno < self.nblocks);
+    let obj = self.object(blockno / BPO);
+
+    obj.blocks[blockno % BPO] = *data;
+    obj.dirty = true;
+  }
+
+  fn flush(&mut self) {
+    if self.read_only {
+      return;
+    }
+    let mut dirty: Vec<usize> = self
+      .cache
+      .iter()
+      .filter(|&(_, obj)| obj.dirty)
+      .map(|(index, _)| *index)
+      .collect();
+
+    dirty.sort();
+    for index in dirty {
+      self.store_object(index);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use disk::{BSIZE, BlockDevice};
+  use objstore::{BPO, ObjectDisk, ObjectStore};
+  use std::collections::HashMap;
+  use std::io;
+  use std::sync::{Arc, Mutex};
+
+  #[derive(Clone)]
+  struct MemStore {
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+  }
+
+  impl ObjectStore for MemStore {
+    fn get(&mut self, key: &str) -> io::Result<Option<Vec<u8>>> {
+      Ok(self.objects.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&mut self, key: &str, data: &[u8]) -> io::Result<()> {
+      self.objects.lock().unwrap().insert(key.to_string(), data.to_vec());
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test() {
+    let store = MemStore {
+      objects: Arc::new(Mutex::new(HashMap::new())),
+    };
+    let nblocks = BPO * 10;
+
+    {
+      let mut disk = ObjectDisk::create(store.clone(), "img", nblocks).unwrap();
+      disk.set_capacity(2);
+      for i in 0..10 {
+        disk.write(i * BPO + 1, &[i as u8 + 1; BSIZE]);
+      }
+      // Only objects evicted so far are stored.
+      assert!(store.objects.lock().unwrap().len() == 1 + 8);
+      disk.flush();
+      assert!(store.objects.lock().unwrap().len() == 1 + 10);
+    }
+
+    {
+      let mut disk = ObjectDisk::open(store.clone(), "img", true).unwrap();
+      assert!(disk.nblocks() == nblocks);
+      disk.set_capacity(2);
+      for i in 0..10 {
+        assert!(disk.read(i * BPO)[0] == 0);
+        assert!(disk.read(i * BPO + 1)[0] == i as u8 + 1);
+      }
+      // A read-only device keeps its writes to itself.
+      disk.write(1, &[42; BSIZE]);
+      for i in 1..10 {
+        disk.read(i * BPO);
+      }
+      disk.flush();
+      assert!(disk.read(1)[0] == 42);
+    }
+
+    let mut disk = ObjectDisk::open(store.clone(), "img", false).unwrap();
+    assert!(disk.read(1)[0] == 1);
+  }
+}
@@ -0,0 +1,1737 @@
+// File system operations on top of inodes and directories, shared by the
+// frontends that do not go through FUSE (e.g. the 9P server).
+//
+// Every operation runs inside the caller's transaction, and returned
+// `UnlockedInode`s must be dropped before that transaction ends.
+
+use buffer::BCACHE;
+use crypt;
+use disk::BSIZE;
+use error::{Error, Result};
+use fs::{DIRSIZE, Dirent, DiskInode, FileType, IDEFAULTS, IORPHAN, ISHRED,
+         MAXFILESIZE, ROOTINO, WHITEOUT, W_OK};
+use inode::{ICACHE, Inode, UnlockedInode};
+use logging::Transaction;
+use refcount;
+use std::cmp::min;
+use std::mem::{size_of, transmute};
+
+// Largest write a single transaction can absorb: 8 data blocks, plus the
+// doubly indirect block and the two indirect blocks under it a write may
+// span, the bitmap block and the inode block stay below MAXOPBLOCKS in
+// logging.rs.
+pub const MAXWRITE: usize = 4096;
+
+pub struct Stat {
+  pub inum: usize,
+  pub file_type: FileType,
+  pub nlink: u16,
+  pub size: u32,
+  pub mode: u16,
+  pub uid: u32,
+  pub gid: u32,
+  pub gen: u32,
+  // Of a Device.
+  pub major: u16,
+  pub minor: u16,
+  // Seconds and nanoseconds since the epoch, see DiskInode.
+  pub atime: (u32, u32),
+  pub mtime: (u32, u32),
+  pub ctime: (u32, u32),
+}
+
+// Mode and owner of the children created in a directory from then on.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Defaults {
+  pub mode: u16,
+  pub uid: u32,
+  pub gid: u32,
+}
+
+// Convert `s` into its on-disk dirent form.
+pub fn to_name(s: &[u8]) -> Result<[u8; DIRSIZE]> {
+  if s.is_empty() || s.contains(&0) || s.contains(&b'/') {
+    return Err(Error::Invalid);
+  }
+  if s.len() > DIRSIZE {
+    return Err(Error::NameTooLong);
+  }
+
+  let mut result: [u8; DIRSIZE] = [0; DIRSIZE];
+  result[..s.len()].copy_from_slice(s);
+  Ok(result)
+}
+
+// Strip the zero padding of an on-disk dirent name.
+pub fn from_name(name: &[u8; DIRSIZE]) -> &[u8] {
+  let len = name.iter().position(|c| *c == 0).unwrap_or(DIRSIZE);
+  &name[..len]
+}
+
+pub fn root() -> UnlockedInode {
+  ICACHE.get(ROOTINO).unwrap()
+}
+
+// Resolve `path`, e.g. "/a/b/c", one component at a time starting from the
+// root.
+pub fn resolve<'a>(
+  txn: &Transaction<'a>,
+  path: &[u8],
+) -> Result<UnlockedInode> {
+  let mut inode = root();
+
+  for name in path.split(|c| *c == b'/').filter(|s| !s.is_empty()) {
+    inode = lookup(txn, &inode, &to_name(name)?)?;
+  }
+  Ok(inode)
+}
+
+// Resolve the directory containing `path`, and return it together with the
+// last component of `path`.
+pub fn resolve_parent<'a>(
+  txn: &Transaction<'a>,
+  path: &[u8],
+) -> Result<(UnlockedInode, [u8; DIRSIZE])> {
+  let mut names: Vec<&[u8]> =
+    path.split(|c| *c == b'/').filter(|s| !s.is_empty()).collect();
+  let name = to_name(names.pop().ok_or(Error::Invalid)?)?;
+  let mut inode = root();
+
+  for name in names {
+    inode = lookup(txn, &inode, &to_name(name)?)?;
+  }
+  Ok((inode, name))
+}
+
+pub fn stat<'a>(txn: &Transaction<'a>, inode: &UnlockedInode) -> Stat {
+  let dinode = ICACHE.lock(txn, inode);
+
+  Stat {
+    inum: inode.no(),
+    file_type: dinode.file_type,
+    nlink: dinode.nlink,
+    size: dinode.size,
+    mode: dinode.mode,
+    uid: dinode.uid,
+    gid: dinode.gid,
+    gen: dinode.gen,
+    major: dinode.major,
+    minor: dinode.minor,
+    atime: (dinode.atime, dinode.atime_nsec),
+    mtime: (dinode.mtime, dinode.mtime_nsec),
+    ctime: (dinode.ctime, dinode.ctime_nsec),
+  }
+}
+
+// Check that user `uid` of group `gid` may access `inode` as `mask`, made
+// of R_OK, W_OK and X_OK, asks. Fail with ReadOnly to write it if it is
+// read-only, and Denied if its mode does not allow it.
+pub fn access<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  uid: u32,
+  gid: u32,
+  mask: u32,
+) -> Result<()> {
+  let dinode = ICACHE.lock(txn, inode);
+
+  if mask & W_OK != 0 && dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  if !dinode.allows(uid, gid, mask) {
+    return Err(Error::Denied);
+  }
+  Ok(())
+}
+
+// Return inode `inum` for a handle taken when it had generation `gen`, or
+// Stale if it has been freed, and maybe reused, since.
+pub fn get<'a>(
+  txn: &Transaction<'a>,
+  inum: usize,
+  gen: u32,
+) -> Result<UnlockedInode> {
+  // Those out of range are merely stale.
+  if inum > 0 && inum < BCACHE.sb().ninodes as usize {
+    ICACHE.verify(txn, inum)?;
+  }
+  ICACHE.get_live(txn, inum, gen).ok_or(Error::Stale)
+}
+
+// Return the defaults of directory `dir`, see DiskInode::inherit_defaults.
+pub fn defaults<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+) -> Result<Option<Defaults>> {
+  let dinode = ICACHE.lock(txn, dir);
+
+  if dinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if dinode.flags & IDEFAULTS == 0 {
+    return Ok(None);
+  }
+  Ok(Some(Defaults {
+    mode: dinode.dmode,
+    uid: dinode.duid,
+    gid: dinode.dgid,
+  }))
+}
+
+// Set or clear the defaults of directory `dir`. Existing children keep
+// theirs.
+pub fn set_defaults<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  defaults: Option<Defaults>,
+) -> Result<()> {
+  let mut dinode = ICACHE.lock(txn, dir);
+
+  if dinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  match defaults {
+    Some(defaults) => {
+      if defaults.mode & !0o7777 != 0 {
+        return Err(Error::Invalid);
+      }
+      dinode.flags |= IDEFAULTS;
+      dinode.dmode = defaults.mode;
+      dinode.duid = defaults.uid;
+      dinode.dgid = defaults.gid;
+    },
+    None => {
+      dinode.flags &= !IDEFAULTS;
+      dinode.dmode = 0;
+      dinode.duid = 0;
+      dinode.dgid = 0;
+    },
+  }
+  dinode.update(txn);
+  Ok(())
+}
+
+// Return true if the blocks of `inode` are zeroed when they are freed.
+pub fn is_shredded<'a>(txn: &Transaction<'a>, inode: &UnlockedInode) -> bool {
+  ICACHE.lock(txn, inode).is_shredded()
+}
+
+// Make the blocks of `inode` be zeroed when they are freed, or not. For a
+// directory, this holds for the children created in it from then on too.
+pub fn set_shred<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  shred: bool,
+) -> Result<()> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  // Shredding writes around the log, see integrity.rs.
+  if shred && BCACHE.sb().hashblk != 0 {
+    return Err(Error::Unsupported);
+  }
+  if shred {
+    dinode.flags |= ISHRED;
+  } else {
+    dinode.flags &= !ISHRED;
+  }
+  dinode.update(txn);
+  Ok(())
+}
+
+// Set the permission bits of `inode` to those of `mode`.
+pub fn chmod<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  mode: u16,
+) -> Result<()> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  // Those of symbolic links are never used.
+  if dinode.file_type == FileType::Symlink {
+    return Ok(());
+  }
+  dinode.mode = mode & 0o7777;
+  dinode.update(txn);
+  Ok(())
+}
+
+// Set the access and modification times of `inode`, those given.
+pub fn set_times<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  atime: Option<(u32, u32)>,
+  mtime: Option<(u32, u32)>,
+) -> Result<()> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  if let Some((sec, nsec)) = atime {
+    dinode.atime = sec;
+    dinode.atime_nsec = nsec;
+  }
+  if let Some((sec, nsec)) = mtime {
+    dinode.mtime = sec;
+    dinode.mtime_nsec = nsec;
+  }
+  dinode.update(txn);
+  Ok(())
+}
+
+// Set the owner of `inode`.
+pub fn chown<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  uid: u32,
+  gid: u32,
+) -> Result<()> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  dinode.uid = uid;
+  dinode.gid = gid;
+  dinode.update(txn);
+  Ok(())
+}
+
+pub fn lookup<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<UnlockedInode> {
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  match pinode.as_directory().lookup(txn, name)? {
+    Some((inode, _)) => Ok(inode),
+    None => Err(Error::NotFound),
+  }
+}
+
+// Create a new file or directory named `name` in `dir`.
+pub fn create<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+  file_type: FileType,
+) -> Result<UnlockedInode> {
+  assert!(file_type != FileType::None);
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if pinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  pinode.as_directory().create_entry(txn, name, file_type)
+}
+
+// Create a symbolic link named `name` in `dir` to `target`, which is kept
+// as it is, resolved by whoever follows the link.
+pub fn symlink<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+  target: &[u8],
+) -> Result<UnlockedInode> {
+  if target.is_empty() {
+    return Err(Error::NotFound);
+  }
+  if target.len() > MAXWRITE {
+    return Err(Error::NameTooLong);
+  }
+  let inode = create(txn, dir, name, FileType::Symlink)?;
+
+  ICACHE.lock(txn, &inode).write(txn, 0, target)?;
+  Ok(inode)
+}
+
+// Create a device node named `name` in `dir`, for the character device
+// `major`:`minor`. What it reads and writes is up to whoever opens it, not
+// the file system.
+pub fn mknod<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+  major: u16,
+  minor: u16,
+) -> Result<UnlockedInode> {
+  let inode = create(txn, dir, name, FileType::Device)?;
+  {
+    let mut dinode = ICACHE.lock(txn, &inode);
+
+    dinode.major = major;
+    dinode.minor = minor;
+    dinode.update(txn);
+  }
+  Ok(inode)
+}
+
+// Return the target of symbolic link `inode`.
+pub fn readlink<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+) -> Result<Vec<u8>> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.file_type != FileType::Symlink {
+    return Err(Error::Invalid);
+  }
+  let size = dinode.size as usize;
+  dinode.read(txn, 0, size)
+}
+
+// Create a file without a name for `dir`, like O_TMPFILE. It is freed once
+// the last reference to it is dropped, or at the next mount if a crash
+// comes first, unless it is given a name by `link_tmpfile`.
+pub fn tmpfile<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+) -> Result<UnlockedInode> {
+  let pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if pinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+
+  let inode = ICACHE.alloc(txn, FileType::File).ok_or(Error::NoSpace)?;
+  let inodeno = inode.no();
+  let mut dinode = ICACHE.lock(txn, &inode);
+
+  dinode.flags |= IORPHAN;
+  dinode.inherit_defaults(&pinode);
+  dinode.inherit_shred(&pinode);
+  crypt::inherit(&pinode, &mut dinode, inodeno);
+  dinode.update(txn);
+  Ok(inode)
+}
+
+// Name the file `inode` made by `tmpfile` `name` in `dir`, like linkat.
+pub fn link_tmpfile<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<()> {
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if pinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  if pinode.as_directory().lookup(txn, name)?.is_some() {
+    return Err(Error::Exists);
+  }
+
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if !dinode.is_orphan() {
+    return Err(Error::Unsupported);
+  }
+  // Its content stays encrypted as it was created.
+  if dinode.is_encrypted() != pinode.is_encrypted() ||
+    dinode.is_encrypted() && dinode.key_id() != pinode.key_id()
+  {
+    return Err(Error::Invalid);
+  }
+  dinode.flags &= !IORPHAN;
+  dinode.nlink = 1;
+  dinode.update(txn);
+  pinode.as_directory().link(txn, name, inode.no() as u16)
+}
+
+// Link `inode` as `name` in `dir`, another name of the file, or its first
+// one if it was created by `tmpfile`.
+pub fn link<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<()> {
+  if ICACHE.lock(txn, inode).is_orphan() {
+    return link_tmpfile(txn, inode, dir, name);
+  }
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if pinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  pinode.as_directory().link_existing(txn, name, &mut dinode)
+}
+
+// Add a whiteout of `name` to `dir`, so that it is known to be absent until
+// something is created under that name again.
+pub fn whiteout<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<()> {
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if pinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  pinode.as_directory().link(txn, name, WHITEOUT)
+}
+
+// Return true if `dir` has a whiteout of `name`.
+pub fn is_whiteout<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<bool> {
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  Ok(pinode.as_directory().find_whiteout(txn, name)?.is_some())
+}
+
+// Remove the file named `name` from `dir`.
+pub fn unlink<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<()> {
+  remove(txn, dir, name, FileType::File)
+}
+
+// Like `unlink`, but the blocks of the file are zeroed when it is freed.
+pub fn shred<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<()> {
+  let inode = lookup(txn, dir, name)?;
+
+  if stat(txn, &inode).file_type == FileType::Directory {
+    return Err(Error::IsDir);
+  }
+  set_shred(txn, &inode, true)?;
+  unlink(txn, dir, name)
+}
+
+// Remove the empty directory named `name` from `dir`.
+pub fn rmdir<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<()> {
+  if from_name(name) == b"." || from_name(name) == b".." {
+    return Err(Error::Invalid);
+  }
+  remove(txn, dir, name, FileType::Directory)
+}
+
+fn remove<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+  file_type: FileType,
+) -> Result<()> {
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if file_type == FileType::Directory {
+    pinode.as_directory().rmdir(txn, name)?;
+  } else {
+    pinode.as_directory().unlink(txn, name)?;
+  }
+  Ok(())
+}
+
+// Rename `name` in `dir` to `newname` in `newdir`, replacing an existing
+// file or empty directory at the destination.
+pub fn rename<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+  newdir: &UnlockedInode,
+  newname: &[u8; DIRSIZE],
+) -> Result<()> {
+  if from_name(name) == b"." || from_name(name) == b".." ||
+    from_name(newname) == b"." || from_name(newname) == b".."
+  {
+    return Err(Error::Invalid);
+  }
+
+  let inode = lookup(txn, dir, name)?;
+
+  if dir.no() != newdir.no() {
+    let file_type = stat(txn, &inode).file_type;
+
+    // Refuse to move a directory into its own subtree. The ancestors are
+    // resolved before any directory is locked, as `lookup` locks them one
+    // at a time.
+    if file_type == FileType::Directory {
+      let dotdot = to_name(b"..").unwrap();
+      let mut cur = newdir.clone();
+
+      while cur.no() != ROOTINO {
+        if cur.no() == inode.no() {
+          return Err(Error::Invalid);
+        }
+        cur = lookup(txn, &cur, &dotdot)?;
+      }
+    }
+  }
+
+  // Lock both parents in inode number order to avoid deadlocking with a
+  // concurrent rename in the opposite direction.
+  let (mut pinode, mut npinode) = if dir.no() == newdir.no() {
+    (ICACHE.lock(txn, dir), None)
+  } else if dir.no() < newdir.no() {
+    let pinode = ICACHE.lock(txn, dir);
+    (pinode, Some(ICACHE.lock(txn, newdir)))
+  } else {
+    let npinode = ICACHE.lock(txn, newdir);
+    (ICACHE.lock(txn, dir), Some(npinode))
+  };
+
+  if pinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  if let Some(ref npinode) = npinode {
+    if npinode.file_type != FileType::Directory {
+      return Err(Error::NotDir);
+    }
+  }
+
+  let (inode, offset) = pinode
+    .as_directory()
+    .lookup(txn, name)?
+    .ok_or(Error::NotFound)?;
+  let mut dinode = ICACHE.lock(txn, &inode);
+
+  if pinode.is_read_only() || dinode.is_read_only() ||
+    npinode.as_ref().map_or(false, |npinode| npinode.is_read_only())
+  {
+    return Err(Error::ReadOnly);
+  }
+  let target = match npinode {
+    Some(ref mut npinode) => npinode.as_directory().lookup(txn, newname)?,
+    None => pinode.as_directory().lookup(txn, newname)?,
+  };
+  // On a case insensitive file system `newname` may find the entry being
+  // renamed, whose name then only changes in case.
+  let target = target.filter(|&(ref tinode, toffset)| {
+    name == newname || tinode.no() != inode.no() || npinode.is_some() ||
+      toffset != offset
+  });
+  if let Some((tinode, toffset)) = target {
+    if tinode.no() == inode.no() {
+      return Ok(());
+    }
+
+    let mut tdinode = ICACHE.lock(txn, &tinode);
+    let npinode: &mut Inode = match npinode {
+      Some(ref mut npinode) => npinode,
+      None => &mut pinode,
+    };
+
+    match (dinode.file_type, tdinode.file_type) {
+      (FileType::Directory, FileType::Directory) => (),
+      (_, FileType::Directory) => return Err(Error::IsDir),
+      (FileType::Directory, _) => return Err(Error::NotDir),
+      _ => (),
+    }
+    if tdinode.file_type == FileType::Directory {
+      if !tdinode.as_directory().is_empty(txn)? {
+        return Err(Error::NotEmpty);
+      }
+      npinode.nlink -= 1; // for `..`
+      npinode.update(txn);
+    }
+    tdinode.nlink -= 1;
+    tdinode.update(txn);
+    clear_entry(txn, npinode, toffset)?;
+  }
+
+  match npinode {
+    None => {
+      // Like `link` in the other case, replace a whiteout of `newname`.
+      if let Some(woffset) =
+        pinode.as_directory().find_whiteout(txn, newname)?
+      {
+        clear_entry(txn, &mut pinode, woffset)?;
+      }
+      let mut data = pinode.read(txn, offset, size_of::<Dirent>())?;
+      let ent: *mut Dirent = &mut data[0] as *mut u8 as *mut _;
+
+      unsafe {
+        (*ent).name = *newname;
+      }
+      pinode.write(txn, offset, data.as_slice())?;
+    },
+    Some(ref mut npinode) => {
+      npinode.as_directory().link(txn, newname, inode.no() as u16)?;
+      clear_entry(txn, &mut pinode, offset)?;
+
+      if dinode.file_type == FileType::Directory {
+        let dotdot = to_name(b"..").unwrap();
+        let (_, doffset) = dinode
+          .as_directory()
+          .lookup(txn, &dotdot)?
+          .ok_or(Error::Corrupt)?;
+        let ent_bytes: [u8; size_of::<Dirent>()] = unsafe {
+          transmute(Dirent {
+            inum: npinode.no() as u16,
+            name: dotdot,
+          })
+        };
+
+        dinode.write(txn, doffset, &ent_bytes)?;
+        pinode.nlink -= 1;
+        pinode.update(txn);
+        npinode.nlink += 1;
+        npinode.update(txn);
+      }
+    },
+  }
+  Ok(())
+}
+
+// Read at most `n` bytes at `offset`. Reading past the end of file yields
+// an empty buffer.
+pub fn read<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  offset: usize,
+  n: usize,
+) -> Result<Vec<u8>> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.file_type == FileType::Directory {
+    return Err(Error::IsDir);
+  }
+  if !crypt::has_key(&dinode) {
+    return Err(Error::NoKey);
+  }
+  let size = dinode.size as usize;
+  if offset >= size {
+    return Ok(vec![]);
+  }
+  let n = if n > size - offset { size - offset } else { n };
+  dinode.read(txn, offset, n)
+}
+
+// Write `data` at `offset`, at most MAXWRITE bytes at a time.
+pub fn write<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  offset: usize,
+  data: &[u8],
+) -> Result<usize> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  check_write(&dinode, offset, data.len())?;
+  dinode.write(txn, offset, data)
+}
+
+// Check that `n` bytes may be written to `dinode` at `offset`.
+fn check_write(dinode: &DiskInode, offset: usize, n: usize) -> Result<()> {
+  if dinode.file_type == FileType::Directory {
+    return Err(Error::IsDir);
+  }
+  if dinode.file_type == FileType::Device {
+    return Err(Error::Unsupported);
+  }
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  if !crypt::has_key(dinode) {
+    return Err(Error::NoKey);
+  }
+  if n > MAXWRITE {
+    return Err(Error::Invalid);
+  }
+  if offset.saturating_add(n) > MAXFILESIZE {
+    return Err(Error::NoSpace);
+  }
+  Ok(())
+}
+
+// Copy at most `n` bytes, at most MAXWRITE, of `src` at `src_offset` to
+// `dst` at `dst_offset`, like copy_file_range(2), and return how many, as
+// few as there are up to the end of `src`. Where the file system supports
+// it and neither file is encrypted, the whole blocks of `src` the range
+// covers are shared with `dst` rather than copied, provided the offsets
+// are as far into their blocks.
+pub fn copy_range<'a>(
+  txn: &Transaction<'a>,
+  src: &UnlockedInode,
+  src_offset: usize,
+  dst: &UnlockedInode,
+  dst_offset: usize,
+  n: usize,
+) -> Result<usize> {
+  if n > MAXWRITE {
+    return Err(Error::Invalid);
+  }
+  let data = read(txn, src, src_offset, n)?;
+
+  // Also within a file, whose blocks are not locked twice.
+  if src.no() == dst.no() || !refcount::supported() {
+    return write(txn, dst, dst_offset, &data);
+  }
+  let sinode = ICACHE.lock(txn, src);
+  let mut dinode = ICACHE.lock(txn, dst);
+
+  check_write(&dinode, dst_offset, data.len())?;
+  if sinode.is_encrypted() || dinode.is_encrypted()
+    || src_offset % BSIZE != dst_offset % BSIZE
+  {
+    return dinode.write(txn, dst_offset, &data);
+  }
+
+  // The head and tail of the range are copied, and the blocks in between
+  // shared.
+  let mut i = 0;
+  while i < data.len() {
+    let offset = dst_offset + i;
+    let len = min(BSIZE - offset % BSIZE, data.len() - i);
+    let shared = if len == BSIZE {
+      sinode.mapped_block(txn, (src_offset + i) / BSIZE)?
+    } else {
+      None
+    };
+
+    match shared {
+      Some(blockno) => {
+        dinode.share_block(txn, offset / BSIZE, blockno)?;
+        if offset + len > dinode.size as usize {
+          dinode.size = (offset + len) as u32;
+          dinode.update(txn);
+        }
+      },
+      // Also a hole of `src`, which reads as zeros.
+      None => {
+        dinode.write(txn, offset, &data[i..i + len])?;
+      },
+    }
+    i += len;
+  }
+  Ok(data.len())
+}
+
+// Set the size of `inode` to `size`, dropping its data past it or growing
+// it with zeros.
+pub fn truncate<'a>(
+  txn: &Transaction<'a>,
+  inode: &UnlockedInode,
+  size: usize,
+) -> Result<()> {
+  let mut dinode = ICACHE.lock(txn, inode);
+
+  if dinode.file_type == FileType::Directory {
+    return Err(Error::IsDir);
+  }
+  if dinode.is_read_only() {
+    return Err(Error::ReadOnly);
+  }
+  if !crypt::has_key(&dinode) {
+    return Err(Error::NoKey);
+  }
+  if size > MAXFILESIZE {
+    return Err(Error::NoSpace);
+  }
+  dinode.truncate(txn, size)
+}
+
+// Return the names of the whiteouts of `dir`.
+pub fn whiteouts<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+) -> Result<Vec<[u8; DIRSIZE]>> {
+  let mut dinode = ICACHE.lock(txn, dir);
+
+  if dinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  dinode.as_directory().whiteouts(txn)
+}
+
+// Enumerate the entries of `dir`, including `.` and `..`.
+pub fn readdir<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+) -> Result<Vec<(UnlockedInode, [u8; DIRSIZE])>> {
+  let mut dinode = ICACHE.lock(txn, dir);
+
+  if dinode.file_type != FileType::Directory {
+    return Err(Error::NotDir);
+  }
+  dinode.as_directory().enumerate(txn)
+}
+
+// Clear the dirent at `offset` of directory `pinode`, see
+// Directory::clear.
+pub fn clear_entry<'a>(
+  txn: &Transaction<'a>,
+  pinode: &mut Inode,
+  offset: usize,
+) -> Result<()> {
+  pinode.as_directory().clear(txn, offset)
+}
+
+#[cfg(test)]
+mod test {
+  use buffer::BCACHE;
+  use disk::BSIZE;
+  use error::Error;
+  use fs::{self, CASEFOLD, Dirent, DiskInode, FileType, IPB, IREADONLY,
+           MAXFILESIZE, NDIRECT, NINDIRECT, R_OK, W_OK, X_OK};
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use ops;
+  use std::io::{IoSlice, IoSliceMut};
+  use std::mem::size_of;
+  use std::thread;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let a = ops::to_name(b"a").unwrap();
+    let b = ops::to_name(b"b").unwrap();
+    let f = ops::to_name(b"f").unwrap();
+
+    let dir = ops::create(&txn, &root, &a, FileType::Directory).unwrap();
+    let file = ops::create(&txn, &dir, &f, FileType::File).unwrap();
+    assert!(ops::write(&txn, &file, 0, b"hello").unwrap() == 5);
+    assert!(ops::read(&txn, &file, 1, 100).unwrap() == b"ello");
+    assert!(ops::create(&txn, &dir, &f, FileType::File).err() ==
+      Some(Error::Exists));
+    assert!(ops::rmdir(&txn, &root, &a).err() == Some(Error::NotEmpty));
+
+    ops::create(&txn, &root, &b, FileType::Directory).unwrap();
+    ops::rename(&txn, &dir, &f, &root, &f).unwrap();
+    ops::rename(&txn, &root, &a, &root, &b).unwrap();
+    assert!(ops::lookup(&txn, &root, &a).err() == Some(Error::NotFound));
+    assert!(ops::stat(&txn, &root).nlink == 2);
+
+    let dir = ops::resolve(&txn, b"/b/").unwrap();
+    assert!(ops::resolve_parent(&txn, b"/b/x").unwrap().0.no() == dir.no());
+    assert!(ops::resolve(&txn, b"/a/x").err() == Some(Error::NotFound));
+    assert!(ops::readdir(&txn, &dir).unwrap().len() == 2);
+    assert!(ops::lookup(&txn, &dir, &ops::to_name(b"..").unwrap())
+      .unwrap()
+      .no() == root.no());
+
+    ops::unlink(&txn, &root, &f).unwrap();
+    ops::rmdir(&txn, &root, &b).unwrap();
+    assert!(ops::readdir(&txn, &root).unwrap().len() == 2);
+  }
+
+  #[test]
+  fn test_read_with() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let f = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &ops::root(), &f, FileType::File).unwrap();
+    let data: Vec<u8> = (0..3 * BSIZE).map(|i| i as u8).collect();
+    ops::write(&txn, &file, 0, &data[..BSIZE]).unwrap();
+    ops::truncate(&txn, &file, 3 * BSIZE).unwrap();
+
+    // A piece per block, the holes as zeros.
+    let mut chunks = vec![];
+    let n = ICACHE
+      .lock(&txn, &file)
+      .read_with(&txn, 10, 4 * BSIZE, |chunk| chunks.push(chunk.to_vec()))
+      .unwrap();
+    assert!(n == 3 * BSIZE - 10);
+    assert!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<_>>() ==
+      vec![BSIZE - 10, BSIZE, BSIZE]);
+    assert!(chunks[0] == &data[10..BSIZE]);
+    assert!(chunks[1].iter().all(|&byte| byte == 0));
+    assert!(chunks[2].iter().all(|&byte| byte == 0));
+
+    // Or into a buffer, as much as the file fills.
+    let mut buf = vec![1; 4 * BSIZE];
+    let n = ICACHE.lock(&txn, &file).read_into(&txn, BSIZE - 10, &mut buf);
+    assert!(n.unwrap() == 2 * BSIZE + 10);
+    assert!(buf[..10] == data[BSIZE - 10..BSIZE]);
+    assert!(buf[10..2 * BSIZE + 10].iter().all(|&byte| byte == 0));
+    assert!(buf[2 * BSIZE + 10] == 1);
+  }
+
+  #[test]
+  fn test_vectored() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let f = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &ops::root(), &f, FileType::File).unwrap();
+    let mut inode = ICACHE.lock(&txn, &file);
+
+    // Pieces go one after the other, across blocks.
+    let (a, b) = (vec![1; BSIZE - 3], vec![2; 10]);
+    let bufs = [IoSlice::new(&a), IoSlice::new(&[]), IoSlice::new(&b)];
+    assert!(inode.write_vectored(&txn, 0, &bufs).unwrap() == BSIZE + 7);
+    assert!(inode.size as usize == BSIZE + 7);
+
+    // And are filled until the file ends.
+    let (mut x, mut y, mut z) = ([0; 5], [0; BSIZE], [0; 5]);
+    let n = {
+      let mut bufs = [
+        IoSliceMut::new(&mut x),
+        IoSliceMut::new(&mut y),
+        IoSliceMut::new(&mut z),
+      ];
+      inode.read_vectored(&txn, BSIZE - 5, &mut bufs).unwrap()
+    };
+    assert!(n == 12 && x == [1, 1, 2, 2, 2] && y[..7] == [2; 7]);
+    assert!(y[7] == 0 && z == [0; 5]);
+  }
+
+  #[test]
+  fn test_tmpfile() {
+    testfs::test::mount();
+
+    let inum = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let f = ops::to_name(b"f").unwrap();
+
+      let tmp = ops::tmpfile(&txn, &root).unwrap();
+      ops::write(&txn, &tmp, 0, b"hello").unwrap();
+      assert!(ops::readdir(&txn, &root).unwrap().len() == 2);
+      ops::link_tmpfile(&txn, &tmp, &root, &f).unwrap();
+      assert!(ops::link_tmpfile(&txn, &tmp, &root, &f).err() ==
+        Some(Error::Exists));
+      let file = ops::resolve(&txn, b"/f").unwrap();
+      assert!(ops::read(&txn, &file, 0, 5).unwrap() == b"hello");
+
+      // Pretend to crash while it is still in use, by leaking enough
+      // references for the cache not to free it.
+      let tmp = ops::tmpfile(&txn, &root).unwrap();
+      let inum = tmp.no();
+      tmp.clone().disassemble();
+      tmp.disassemble();
+      ICACHE.init();
+      inum
+    };
+
+    assert!(ICACHE.reclaim_orphans() == 1);
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    assert!(ops::tmpfile(&txn, &root).unwrap().no() == inum);
+    assert!(ops::resolve(&txn, b"/f").is_ok());
+  }
+
+  #[test]
+  fn test_defaults() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let d = ops::to_name(b"d").unwrap();
+    let f = ops::to_name(b"f").unwrap();
+    let defaults = ops::Defaults {
+      mode: 0o2770,
+      uid: 0,
+      gid: 100,
+    };
+
+    let dir = ops::create(&txn, &root, &d, FileType::Directory).unwrap();
+    assert!(ops::defaults(&txn, &dir).unwrap() == None);
+    ops::set_defaults(&txn, &dir, Some(defaults)).unwrap();
+
+    let file = ops::create(&txn, &dir, &d, FileType::File).unwrap();
+    let stat = ops::stat(&txn, &file);
+    assert!((stat.mode, stat.uid, stat.gid) == (0o2660, 0, 100));
+    ops::unlink(&txn, &dir, &d).unwrap();
+
+    let subdir = ops::create(&txn, &dir, &d, FileType::Directory).unwrap();
+    assert!(ops::stat(&txn, &subdir).mode == 0o2770);
+    assert!(ops::defaults(&txn, &subdir).unwrap() == Some(defaults));
+
+    ops::set_defaults(&txn, &dir, None).unwrap();
+    let file = ops::create(&txn, &dir, &f, FileType::File).unwrap();
+    assert!(ops::stat(&txn, &file).mode == 0o644);
+  }
+
+  #[test]
+  fn test_whiteout() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let a = ops::to_name(b"a").unwrap();
+    let b = ops::to_name(b"b").unwrap();
+
+    ops::whiteout(&txn, &root, &a).unwrap();
+    assert!(ops::whiteout(&txn, &root, &a).err() == Some(Error::Exists));
+    assert!(ops::lookup(&txn, &root, &a).err() == Some(Error::NotFound));
+    assert!(ops::is_whiteout(&txn, &root, &a).unwrap());
+    assert!(ops::readdir(&txn, &root).unwrap().len() == 2);
+    assert!(ops::whiteouts(&txn, &root).unwrap() == vec![a]);
+
+    // Creating or renaming something as `a` replaces the whiteout.
+    ops::create(&txn, &root, &a, FileType::File).unwrap();
+    assert!(!ops::is_whiteout(&txn, &root, &a).unwrap());
+    assert!(ops::whiteout(&txn, &root, &a).err() == Some(Error::Exists));
+    ops::whiteout(&txn, &root, &b).unwrap();
+    ops::rename(&txn, &root, &a, &root, &b).unwrap();
+    assert!(ops::whiteouts(&txn, &root).unwrap().is_empty());
+    assert!(ops::lookup(&txn, &root, &b).is_ok());
+    ops::unlink(&txn, &root, &b).unwrap();
+  }
+
+  #[test]
+  fn test_shred() {
+    testfs::test::mount();
+
+    let d = ops::to_name(b"d").unwrap();
+    let f = ops::to_name(b"f").unwrap();
+    let g = ops::to_name(b"g").unwrap();
+    let blocks = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let dir = ops::create(&txn, &root, &d, FileType::Directory).unwrap();
+      ops::set_shred(&txn, &dir, true).unwrap();
+      let mut blocks = vec![];
+
+      for &(dir, name) in &[(&dir, &f), (&root, &f), (&root, &g)] {
+        let file = ops::create(&txn, dir, name, FileType::File).unwrap();
+        ops::write(&txn, &file, 0, b"secret").unwrap();
+        blocks.push(ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap()[0]);
+      }
+      assert!(ops::is_shredded(&txn, &ops::resolve(&txn, b"/d/f").unwrap()));
+      assert!(!ops::is_shredded(&txn, &ops::resolve(&txn, b"/f").unwrap()));
+      assert!(ops::shred(&txn, &root, &d).err() == Some(Error::IsDir));
+
+      ops::unlink(&txn, &dir, &f).unwrap();
+      ops::unlink(&txn, &root, &f).unwrap();
+      ops::shred(&txn, &root, &g).unwrap();
+      blocks
+    };
+
+    let txn = LOGGING.new_txn();
+    assert!(txn.read(blocks[0]).unwrap().data[..6] == [0; 6]);
+    assert!(&txn.read(blocks[1]).unwrap().data[..6] == b"secret");
+    assert!(txn.read(blocks[2]).unwrap().data[..6] == [0; 6]);
+  }
+
+  #[test]
+  fn test_generation() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let f = ops::to_name(b"f").unwrap();
+
+    let (inum, gen) = {
+      let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+      let stat = ops::stat(&txn, &file);
+      (stat.inum, stat.gen)
+    };
+    assert!(ops::get(&txn, inum, gen).is_ok());
+    ops::unlink(&txn, &root, &f).unwrap();
+    assert!(ops::get(&txn, inum, gen).err() == Some(Error::Stale));
+
+    // The slot is reused by the next file.
+    let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+    let stat = ops::stat(&txn, &file);
+    assert!(stat.inum == inum && stat.gen == gen + 1);
+    assert!(ops::get(&txn, inum, gen).err() == Some(Error::Stale));
+    assert!(ops::get(&txn, inum, gen + 1).unwrap().no() == file.no());
+  }
+
+  #[test]
+  fn test_casefold() {
+    testfs::test::mount_with_flags(CASEFOLD);
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let upper = ops::to_name(b"README").unwrap();
+    let lower = ops::to_name(b"readme").unwrap();
+    let mixed = ops::to_name(b"ReadMe").unwrap();
+
+    let file = ops::create(&txn, &root, &upper, FileType::File).unwrap();
+    assert!(ops::lookup(&txn, &root, &lower).unwrap().no() == file.no());
+    assert!(ops::create(&txn, &root, &lower, FileType::File).err() ==
+      Some(Error::Exists));
+
+    // Names are kept as created, and may change case.
+    ops::rename(&txn, &root, &lower, &root, &mixed).unwrap();
+    assert!(ops::lookup(&txn, &root, &upper).unwrap().no() == file.no());
+    assert!(ops::readdir(&txn, &root).unwrap().iter().any(
+      |&(_, ref name)| ops::from_name(name) == b"ReadMe",
+    ));
+    ops::unlink(&txn, &root, &upper).unwrap();
+  }
+
+  #[test]
+  fn test_link() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let f = ops::to_name(b"f").unwrap();
+    let d = ops::to_name(b"d").unwrap();
+    let g = ops::to_name(b"g").unwrap();
+    let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+    let dir = ops::create(&txn, &root, &d, FileType::Directory).unwrap();
+
+    // Both names are the same file, which outlives either.
+    ops::write(&txn, &file, 0, b"abc").unwrap();
+    ops::link(&txn, &file, &dir, &g).unwrap();
+    assert!(ops::stat(&txn, &file).nlink == 2);
+    ops::unlink(&txn, &root, &f).unwrap();
+    let other = ops::resolve(&txn, b"/d/g").unwrap();
+    assert!(other.no() == file.no());
+    assert!(ops::read(&txn, &other, 0, 3).unwrap() == b"abc");
+    assert!(ops::stat(&txn, &other).nlink == 1);
+
+    let taken = ops::link(&txn, &file, &dir, &g);
+    assert!(taken.err() == Some(Error::Exists));
+    let linked = ops::link(&txn, &dir, &root, &g);
+    assert!(linked.err() == Some(Error::IsDir));
+    let linked = ops::link(&txn, &file, &file, &f);
+    assert!(linked.err() == Some(Error::NotDir));
+  }
+
+  #[test]
+  fn test_usage() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let f = ops::to_name(b"f").unwrap();
+    let before = fs::usage(&txn);
+
+    assert!(before.free_blocks < before.blocks);
+    assert!(before.free_inodes < before.inodes);
+    let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+    ops::write(&txn, &file, 0, &[1; 3 * BSIZE]).unwrap();
+    let addrs = ICACHE.lock(&txn, &file).addrs;
+    assert!(addrs[1] == addrs[0] + 1 && addrs[2] == addrs[0] + 2);
+    let after = fs::usage(&txn);
+    assert!(after.free_blocks == before.free_blocks - 3);
+    assert!(after.free_inodes == before.free_inodes - 1);
+    assert!(after.blocks == before.blocks && after.inodes == before.inodes);
+  }
+
+  #[test]
+  fn test_alloc_inode() {
+    testfs::test::mount();
+
+    let create = |name: &[u8]| {
+      let txn = LOGGING.new_txn();
+      let name = ops::to_name(name).unwrap();
+      ops::create(&txn, &ops::root(), &name, FileType::File).unwrap().no()
+    };
+    let free = fs::usage(&LOGGING.new_txn()).free_inodes;
+
+    // Taken in order, but one freed is taken again first.
+    let (a, b) = (create(b"a"), create(b"b"));
+    assert!(b == a + 1);
+    {
+      let txn = LOGGING.new_txn();
+      ops::unlink(&txn, &ops::root(), &ops::to_name(b"a").unwrap()).unwrap();
+    }
+    assert!(fs::usage(&LOGGING.new_txn()).free_inodes == free - 1);
+    assert!(create(b"c") == a);
+    assert!(create(b"d") == b + 1);
+    assert!(fs::usage(&LOGGING.new_txn()).free_inodes == free - 3);
+  }
+
+  #[test]
+  fn test_symlink() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let l = ops::to_name(b"l").unwrap();
+    let f = ops::to_name(b"f").unwrap();
+    let link = ops::symlink(&txn, &root, &l, b"../a/b").unwrap();
+    let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+
+    assert!(ops::readlink(&txn, &link).unwrap() == b"../a/b");
+    let stat = ops::stat(&txn, &link);
+    assert!(stat.file_type == FileType::Symlink && stat.mode == 0o777);
+    assert!(ops::readlink(&txn, &file).err() == Some(Error::Invalid));
+    let empty = ops::symlink(&txn, &root, &f, b"");
+    assert!(empty.err() == Some(Error::NotFound));
+    let taken = ops::symlink(&txn, &root, &f, b"x");
+    assert!(taken.err() == Some(Error::Exists));
+
+    assert!(ops::rmdir(&txn, &root, &l).err() == Some(Error::NotDir));
+    ops::unlink(&txn, &root, &l).unwrap();
+    assert!(ops::lookup(&txn, &root, &l).err() == Some(Error::NotFound));
+  }
+
+  #[test]
+  fn test_indirect() {
+    testfs::test::mount();
+
+    // The blocks past the direct ones are read back through the indirect
+    // block, on either side of the first of them.
+    let txn = LOGGING.new_txn();
+    let name = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &ops::root(), &name, FileType::File).unwrap();
+    let offset = NDIRECT * BSIZE - 1;
+    let data: Vec<u8> = (0..2 * BSIZE).map(|i| i as u8).collect();
+
+    assert!(ops::write(&txn, &file, offset, &data) == Ok(data.len()));
+    assert!(ICACHE.lock(&txn, &file).addrs[NDIRECT] != 0);
+    assert!(ops::read(&txn, &file, offset, data.len()).unwrap() == data);
+  }
+
+  #[test]
+  fn test_copy_range() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let a = ops::to_name(b"a").unwrap();
+    let b = ops::to_name(b"b").unwrap();
+    let src = ops::create(&txn, &root, &a, FileType::File).unwrap();
+    let dst = ops::create(&txn, &root, &b, FileType::File).unwrap();
+    let data: Vec<u8> = (0..3 * BSIZE + 10).map(|i| i as u8).collect();
+
+    ops::write(&txn, &src, 0, &data).unwrap();
+    ops::write(&txn, &dst, 0, &[9; 100]).unwrap();
+
+    // The whole blocks in the middle are shared, the rest copied.
+    let n = ops::copy_range(&txn, &src, 10, &dst, 10, ops::MAXWRITE).unwrap();
+    assert!(n == data.len() - 10);
+    let copy = ops::read(&txn, &dst, 0, ops::MAXWRITE).unwrap();
+    assert!(copy[..10] == [9; 10] && copy[10..] == data[10..]);
+    let (first, second) = {
+      let sinode = ICACHE.lock(&txn, &src);
+      let dinode = ICACHE.lock(&txn, &dst);
+      let shared = |n| sinode.addrs[n] == dinode.addrs[n];
+      (shared(0), shared(1) && shared(2))
+    };
+    assert!(!first && second);
+
+    // Writing either file leaves the other one alone.
+    ops::write(&txn, &dst, BSIZE, &[7; 10]).unwrap();
+    assert!(ops::read(&txn, &src, BSIZE, 10).unwrap() == &data[BSIZE..][..10]);
+
+    // Past the end, leaving a hole.
+    let off = ops::copy_range(&txn, &src, 0, &dst, 2 * ops::MAXWRITE, 1);
+    assert!(off == Ok(1));
+    let stat = ops::stat(&txn, &dst);
+    assert!(stat.size as usize == 2 * ops::MAXWRITE + 1);
+    let gap = ops::read(&txn, &dst, data.len(), ops::MAXWRITE).unwrap();
+    assert!(gap.iter().all(|&x| x == 0));
+  }
+
+  #[test]
+  fn test_entries() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    for name in [b"a", b"b", b"c"].iter() {
+      let name = ops::to_name(*name).unwrap();
+      ops::create(&txn, &root, &name, FileType::File).unwrap();
+    }
+    ops::unlink(&txn, &root, &ops::to_name(b"b").unwrap()).unwrap();
+
+    // ".", "..", "a" and "c", each with where the next one is.
+    let mut dir = ICACHE.lock(&txn, &root);
+    let ents = dir.as_directory().entries(&txn, 0).unwrap();
+    let names: Vec<u8> = ents.iter().map(|ent| ent.2[0]).collect();
+    assert!(names == b"..ac");
+    assert!(ents[0].0 == size_of::<Dirent>());
+    let rest = dir.as_directory().entries(&txn, ents[2].0).unwrap();
+    assert!(rest.len() == 1 && rest[0] == ents[3]);
+    assert!(dir.as_directory().entries(&txn, ents[3].0).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_shrink() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let dir = ops::to_name(b"d").unwrap();
+    let dir = ops::create(&txn, &root, &dir, FileType::Directory).unwrap();
+    let file = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &dir, &file, FileType::File).unwrap();
+    let before = (ops::stat(&txn, &dir).size, fs::usage(&txn).free_blocks);
+
+    // Grown to blocks of dirents, it shrinks back once the last go.
+    let names: Vec<_> = (0..3 * BSIZE / size_of::<Dirent>())
+      .map(|i| ops::to_name(format!("l{}", i).as_bytes()).unwrap())
+      .collect();
+    for name in &names {
+      ops::link(&txn, &file, &dir, name).unwrap();
+    }
+    assert!(ops::stat(&txn, &dir).size as usize > 3 * BSIZE);
+    ops::unlink(&txn, &dir, &names[0]).unwrap();
+    assert!(ops::stat(&txn, &dir).size as usize > 3 * BSIZE);
+    for name in names[1..].iter().rev() {
+      ops::unlink(&txn, &dir, name).unwrap();
+    }
+    let after = (ops::stat(&txn, &dir).size, fs::usage(&txn).free_blocks);
+    assert!(after == before);
+  }
+
+  #[test]
+  fn test_access() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let name = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+
+    // 0o644, owned by 1000:1000.
+    ops::access(&txn, &file, 1000, 1000, R_OK | W_OK).unwrap();
+    ops::access(&txn, &file, 1001, 1000, R_OK).unwrap();
+    let denied = ops::access(&txn, &file, 1001, 1000, W_OK);
+    assert!(denied.err() == Some(Error::Denied));
+    let denied = ops::access(&txn, &file, 0, 0, X_OK);
+    assert!(denied.err() == Some(Error::Denied));
+    ops::access(&txn, &file, 0, 0, R_OK | W_OK).unwrap();
+    ops::access(&txn, &root, 0, 0, X_OK).unwrap();
+
+    ICACHE.lock(&txn, &file).mode = 0o750;
+    ops::access(&txn, &file, 1001, 1000, R_OK | X_OK).unwrap();
+    ops::access(&txn, &file, 0, 0, X_OK).unwrap();
+    let denied = ops::access(&txn, &file, 1001, 1001, R_OK);
+    assert!(denied.err() == Some(Error::Denied));
+  }
+
+  #[test]
+  fn test_chmod() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let name = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+
+    // The file type bits are not kept.
+    ops::chmod(&txn, &file, 0o100600).unwrap();
+    assert!(ops::stat(&txn, &file).mode == 0o600);
+    ICACHE.lock(&txn, &file).flags |= IREADONLY;
+    let denied = ops::chmod(&txn, &file, 0o644);
+    assert!(denied.err() == Some(Error::ReadOnly));
+  }
+
+  #[test]
+  fn test_times() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let name = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+
+    let stat = ops::stat(&txn, &file);
+    assert!(stat.mtime.0 > 0 && stat.atime == stat.mtime);
+    ops::set_times(&txn, &file, Some((1, 2)), Some((3, 4))).unwrap();
+    let stat = ops::stat(&txn, &file);
+    assert!(stat.atime == (1, 2) && stat.mtime == (3, 4));
+    assert!(stat.ctime.0 > 3);
+
+    // Writes change the modification time, and the directory's too.
+    ops::set_times(&txn, &root, None, Some((3, 4))).unwrap();
+    ops::write(&txn, &file, 0, b"x").unwrap();
+    assert!(ops::stat(&txn, &file).mtime.0 > 3);
+    assert!(ops::stat(&txn, &file).atime == (1, 2));
+    ops::unlink(&txn, &root, &name).unwrap();
+    assert!(ops::stat(&txn, &root).mtime.0 > 3);
+  }
+
+  #[test]
+  fn test_mknod() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let name = ops::to_name(b"tty").unwrap();
+    let dev = ops::mknod(&txn, &root, &name, 4, 300).unwrap();
+
+    let stat = ops::stat(&txn, &dev);
+    assert!(stat.file_type == FileType::Device);
+    assert!((stat.major, stat.minor) == (4, 300) && stat.size == 0);
+    let write = ops::write(&txn, &dev, 0, b"x");
+    assert!(write.err() == Some(Error::Unsupported));
+    let taken = ops::mknod(&txn, &root, &name, 1, 3);
+    assert!(taken.err() == Some(Error::Exists));
+    ops::unlink(&txn, &root, &name).unwrap();
+  }
+
+  #[test]
+  fn test_truncate() {
+    testfs::test::mount();
+
+    let f = ops::to_name(b"f").unwrap();
+    {
+      let txn = LOGGING.new_txn();
+      ops::create(&txn, &ops::root(), &f, FileType::File).unwrap();
+    }
+    let data = vec![7; 14 * BSIZE];
+    for (i, chunk) in data.chunks(ops::MAXWRITE).enumerate() {
+      let txn = LOGGING.new_txn();
+      let file = ops::resolve(&txn, b"/f").unwrap();
+      ops::write(&txn, &file, i * ops::MAXWRITE, chunk).unwrap();
+    }
+
+    // Shrinking frees the blocks past the end, the indirect one among them.
+    let txn = LOGGING.new_txn();
+    let file = ops::resolve(&txn, b"/f").unwrap();
+    ops::truncate(&txn, &file, BSIZE + 10).unwrap();
+    assert!(ops::stat(&txn, &file).size == BSIZE as u32 + 10);
+    {
+      let dinode = ICACHE.lock(&txn, &file);
+      assert!(dinode.data_blocks(&txn).unwrap().len() == 2);
+      assert!(dinode.addrs[NDIRECT] == 0);
+    }
+    assert!(ops::read(&txn, &file, 0, 2 * BSIZE).unwrap() == [7; BSIZE + 10]);
+
+    // Growing leaves a hole of zeros, and so does the rest of a block.
+    ops::truncate(&txn, &file, 3 * BSIZE).unwrap();
+    let read = ops::read(&txn, &file, BSIZE + 10, 3 * BSIZE).unwrap();
+    assert!(read == vec![0; 2 * BSIZE - 10]);
+    ops::write(&txn, &file, 2 * BSIZE + 5, b"x").unwrap();
+    let read = ops::read(&txn, &file, 2 * BSIZE, 10).unwrap();
+    assert!(read == b"\0\0\0\0\0x\0\0\0\0");
+    assert!(ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap().len() == 3);
+
+    // So does writing past the end, and reading it allocates nothing.
+    ops::write(&txn, &file, 6 * BSIZE, b"y").unwrap();
+    assert!(ops::stat(&txn, &file).size == 6 * BSIZE as u32 + 1);
+    let free = fs::usage(&txn).free_blocks;
+    let read = ops::read(&txn, &file, 3 * BSIZE, 3 * BSIZE + 1).unwrap();
+    assert!(read[..3 * BSIZE] == [0; 3 * BSIZE][..] && read[3 * BSIZE] == b'y');
+    assert!(fs::usage(&txn).free_blocks == free);
+    assert!(ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap().len() == 4);
+
+    let big = ops::truncate(&txn, &file, MAXFILESIZE + 1);
+    assert!(big.err() == Some(Error::NoSpace));
+    let root = ops::truncate(&txn, &ops::root(), 0);
+    assert!(root.err() == Some(Error::IsDir));
+  }
+
+  #[test]
+  fn test_no_space() {
+    testfs::test::mount();
+
+    let f = ops::to_name(b"f").unwrap();
+    let file = {
+      let txn = LOGGING.new_txn();
+      ops::create(&txn, &ops::root(), &f, FileType::File).unwrap()
+    };
+
+    // Writes take what is left, the last one maybe short, and then fail.
+    let mut size = 0;
+    loop {
+      let txn = LOGGING.new_txn();
+      match ops::write(&txn, &file, size, &[1; ops::MAXWRITE]) {
+        Ok(n) if n == ops::MAXWRITE => size += n,
+        Ok(n) => {
+          size += n;
+          break;
+        },
+        Err(e) => {
+          assert!(e == Error::NoSpace);
+          break;
+        },
+      }
+    }
+    let txn = LOGGING.new_txn();
+    let write = ops::write(&txn, &file, size, b"x");
+    assert!(write.err() == Some(Error::NoSpace));
+    assert!(fs::usage(&txn).free_blocks == 0);
+    assert!(ops::stat(&txn, &file).size as usize == size);
+    assert!(ops::read(&txn, &file, size - 10, 10).unwrap() == [1; 10]);
+
+    ops::truncate(&txn, &file, 0).unwrap();
+    assert!(ops::write(&txn, &file, 0, b"x") == Ok(1));
+  }
+
+  #[test]
+  fn test_dindirect() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let name = ops::to_name(b"f").unwrap();
+    let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+    let free = fs::usage(&txn).free_blocks;
+
+    // Past the indirect blocks, through the doubly indirect block.
+    let offset = (NDIRECT + NINDIRECT + 3 * NINDIRECT) * BSIZE - 2;
+    ops::truncate(&txn, &file, MAXFILESIZE - 3).unwrap();
+    ops::write(&txn, &file, offset, b"abcd").unwrap();
+    ops::write(&txn, &file, MAXFILESIZE - 3, b"xyz").unwrap();
+    assert!(ops::stat(&txn, &file).size as usize == MAXFILESIZE);
+    assert!(ops::read(&txn, &file, offset - 1, 6).unwrap() == b"\0abcd\0");
+    assert!(ops::read(&txn, &file, MAXFILESIZE - 3, 10).unwrap() == b"xyz");
+    assert!(ops::read(&txn, &file, 1000 * BSIZE, 2).unwrap() == [0; 2]);
+    let blocks = ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap();
+    assert!(blocks.len() == 3);
+    // The doubly indirect block and three indirect blocks under it.
+    assert!(fs::usage(&txn).free_blocks == free - 7);
+    let big = ops::write(&txn, &file, MAXFILESIZE, b"!");
+    assert!(big.err() == Some(Error::NoSpace));
+
+    // Shrinking frees the indirect blocks left empty, and the doubly
+    // indirect one once none is left.
+    ops::truncate(&txn, &file, offset + 1).unwrap();
+    assert!(fs::usage(&txn).free_blocks == free - 3);
+    ops::truncate(&txn, &file, BSIZE).unwrap();
+    assert!(ICACHE.lock(&txn, &file).dindirect == 0);
+    assert!(fs::usage(&txn).free_blocks == free);
+  }
+
+  #[test]
+  fn test_corrupt() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let f = ops::to_name(b"f").unwrap();
+    let d = ops::to_name(b"d").unwrap();
+    let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+    let dir = ops::create(&txn, &root, &d, FileType::Directory).unwrap();
+
+    // Block numbers past the end fail the operations that follow them.
+    ops::write(&txn, &file, 0, &[1; 2 * BSIZE]).unwrap();
+    {
+      let mut dinode = ICACHE.lock(&txn, &file);
+      dinode.addrs[1] = 1 << 30;
+      dinode.update(&txn);
+    }
+    assert!(ops::read(&txn, &file, 0, 10).unwrap() == [1; 10]);
+    assert!(ops::read(&txn, &file, BSIZE, 10).err() == Some(Error::Corrupt));
+    let written = ops::write(&txn, &file, BSIZE, b"x");
+    assert!(written.err() == Some(Error::Corrupt));
+    {
+      let mut dinode = ICACHE.lock(&txn, &dir);
+      dinode.addrs[0] = 1 << 30;
+      dinode.update(&txn);
+    }
+    assert!(ops::readdir(&txn, &dir).err() == Some(Error::Corrupt));
+    let lookup = ops::lookup(&txn, &dir, &f);
+    assert!(lookup.err() == Some(Error::Corrupt));
+
+    // So do dirents of inodes past the last, here in place of `.`.
+    let e = ops::to_name(b"e").unwrap();
+    let dir = ops::create(&txn, &root, &e, FileType::Directory).unwrap();
+    let mut ent = [0; size_of::<Dirent>()];
+
+    ent[1] = 0x70;
+    ICACHE.lock(&txn, &dir).write(&txn, 0, &ent).unwrap();
+    assert!(ops::readdir(&txn, &dir).err() == Some(Error::Corrupt));
+  }
+
+  #[test]
+  fn test_checksum() {
+    testfs::test::mount();
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let f = ops::to_name(b"f").unwrap();
+    let stat = {
+      let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+      ops::stat(&txn, &file)
+    };
+    assert!(ops::get(&txn, stat.inum, stat.gen).is_ok());
+
+    // An inode changed behind our back is not referenced any more.
+    {
+      let mut buf = txn.read(BCACHE.sb().iblock(stat.inum)).unwrap();
+      buf.data[stat.inum % IPB * size_of::<DiskInode>() + 8] ^= 1;
+      txn.write(&mut buf);
+    }
+    assert!(ops::lookup(&txn, &root, &f).err() == Some(Error::Corrupt));
+    let got = ops::get(&txn, stat.inum, stat.gen);
+    assert!(got.err() == Some(Error::Corrupt));
+    assert!(ops::readdir(&txn, &root).err() == Some(Error::Corrupt));
+  }
+
+  #[test]
+  fn test_concurrent_create() {
+    testfs::test::mount();
+
+    // Every name is created by exactly one of the threads racing for it.
+    let threads: Vec<_> = (0..8)
+      .map(|i| {
+        thread::spawn(move || {
+          let mut created = 0;
+
+          for n in 0..5 {
+            let txn = LOGGING.new_txn();
+            let root = ops::root();
+            let name = ops::to_name(format!("f{}", n).as_bytes()).unwrap();
+            let file_type = if i % 2 == 0 {
+              FileType::File
+            } else {
+              FileType::Directory
+            };
+
+            let result = ops::create(&txn, &root, &name, file_type);
+
+            match result {
+              Ok(_) => created += 1,
+              Err(e) => assert!(e == Error::Exists),
+            }
+          }
+          created
+        })
+      })
+      .collect();
+    let created: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+    assert!(created == 5);
+
+    let txn = LOGGING.new_txn();
+    let root = ops::root();
+    let mut names: Vec<_> = ops::readdir(&txn, &root)
+      .unwrap()
+      .into_iter()
+      .map(|(_, name)| name)
+      .collect();
+    let n = names.len();
+
+    names.sort();
+    names.dedup();
+    assert!(n == 7 && names.len() == n);
+  }
+}
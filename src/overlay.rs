@@ -0,0 +1,349 @@
+// A copy-on-write view of an image, which leaves the image itself pristine.
+//
+// Blocks written through an `Overlay` are kept in a delta file instead,
+// and read back from there, so that the changes can later be committed to
+// the base image, or discarded by deleting the delta. A delta is
+//
+//   "XV6DELTA" | nblocks (u64) | SHA-256 of the base | records
+//
+// with one record of blockno (u64) and block per block written, all little
+// endian. The digest ties the delta to the base it was created for, which
+// must not change meanwhile.
+//
+// A clone is a delta that names its base, so that it mounts as an image of
+// its own, e.g. one writable copy of a large base per student, which only
+// takes the blocks written to it:
+//
+//   "XV6CLONE" | length of the path (u64) | path of the base | delta
+
+use disk::{self, BSIZE, Block, BlockDevice, Disk};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use util::sha256::{Digest, sha256};
+
+const MAGIC: &[u8; 8] = b"XV6DELTA";
+const CLONE_MAGIC: &[u8; 8] = b"XV6CLONE";
+const HEADER: usize = 8 + 8 + 32;
+const RECORD: usize = 8 + BSIZE;
+
+pub struct Overlay {
+  base: Disk,
+  delta: BTreeMap<usize, Block>,
+  path: PathBuf,
+  digest: Digest,
+  // Written to since it was opened or last saved.
+  dirty: bool,
+  // The absolute path of the base of a clone.
+  clone_of: Option<PathBuf>,
+  // Held on the delta, see `disk::lock`, the base being locked shared.
+  _lock: Option<File>,
+}
+
+fn invalid(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+  (0..8).fold(0, |x, i| x | (data[offset + i] as u64) << (8 * i))
+}
+
+fn put_u64(buf: &mut Vec<u8>, x: u64) {
+  for i in 0..8 {
+    buf.push((x >> (8 * i)) as u8);
+  }
+}
+
+// Load `path` as an image, shared, returning it and its digest.
+fn load_base(path: &Path) -> io::Result<(Disk, Digest)> {
+  let base = Disk::load_shared(path)?;
+  let digest = sha256(&fs::read(path)?);
+  Ok((base, digest))
+}
+
+impl Overlay {
+  // Create an empty delta for the image `base` at `delta`.
+  pub fn create<P: AsRef<Path>, Q: AsRef<Path>>(
+    base: P,
+    delta: Q,
+  ) -> io::Result<()> {
+    let (base, digest) = load_base(base.as_ref())?;
+
+    Overlay {
+      base,
+      delta: BTreeMap::new(),
+      path: delta.as_ref().to_path_buf(),
+      digest,
+      dirty: true,
+      clone_of: None,
+      _lock: disk::lock(&delta, false)?,
+    }.save()
+  }
+
+  // Create a clone of the image `base` at `clone`.
+  pub fn create_clone<P: AsRef<Path>, Q: AsRef<Path>>(
+    base: P,
+    clone: Q,
+  ) -> io::Result<()> {
+    let path = fs::canonicalize(base)?;
+    if is_clone(&path) {
+      return Err(invalid("a clone cannot be cloned"));
+    }
+    let (base, digest) = load_base(&path)?;
+
+    Overlay {
+      base,
+      delta: BTreeMap::new(),
+      path: clone.as_ref().to_path_buf(),
+      digest,
+      dirty: true,
+      clone_of: Some(path),
+      _lock: disk::lock(&clone, false)?,
+    }.save()
+  }
+
+  // Open the image `base` with the changes of `delta`.
+  pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(
+    base: P,
+    delta: Q,
+  ) -> io::Result<Self> {
+    let lock = disk::lock(&delta, false)?;
+    let data = fs::read(&delta)?;
+    Overlay::from_delta(base.as_ref(), delta.as_ref(), &data, None, lock)
+  }
+
+  // Open the clone at `clone`.
+  pub fn open_clone<P: AsRef<Path>>(clone: P) -> io::Result<Self> {
+    let lock = disk::lock(&clone, false)?;
+    let data = fs::read(&clone)?;
+
+    if data.len() < 16 || &data[..8] != CLONE_MAGIC {
+      return Err(invalid("not a clone"));
+    }
+    let len = u64_at(&data, 8) as usize;
+    if data.len() - 16 < len {
+      return Err(invalid("not a clone"));
+    }
+    let base = PathBuf::from(OsStr::from_bytes(&data[16..16 + len]));
+    let delta = &data[16 + len..];
+
+    let clone_of = Some(base.clone());
+    Overlay::from_delta(&base, clone.as_ref(), delta, clone_of, lock)
+  }
+
+  // Open the image `base` with the changes of `data`, read from `delta`.
+  fn from_delta(
+    base: &Path,
+    delta: &Path,
+    data: &[u8],
+    clone_of: Option<PathBuf>,
+    lock: Option<File>,
+  ) -> io::Result<Self> {
+    let (base, digest) = load_base(base)?;
+
+    if data.len() < HEADER || &data[..8] != MAGIC ||
+      (data.len() - HEADER) % RECORD != 0
+    {
+      return Err(invalid("not a delta"));
+    }
+    if u64_at(&data, 8) as usize != base.nblocks() || data[16..48] != digest {
+      return Err(invalid("delta of another image"));
+    }
+
+    let mut overlay = Overlay {
+      base,
+      delta: BTreeMap::new(),
+      path: delta.to_path_buf(),
+      digest,
+      dirty: false,
+      clone_of,
+      _lock: lock,
+    };
+    for record in data[HEADER..].chunks(RECORD) {
+      let blockno = u64_at(record, 0) as usize;
+      let mut block = [0; BSIZE];
+
+      if blockno >= overlay.base.nblocks() {
+        return Err(invalid("block out of range"));
+      }
+      block.copy_from_slice(&record[8..]);
+      overlay.delta.insert(blockno, block);
+    }
+    Ok(overlay)
+  }
+
+  // Return the number of blocks changed.
+  pub fn changed(&self) -> usize {
+    self.delta.len()
+  }
+
+  // Write the changes to the base, which is saved like `Disk::save` does,
+  // and remove the delta. The base of a clone is shared with the others.
+  pub fn commit(mut self) -> io::Result<()> {
+    if self.clone_of.is_some() {
+      return Err(invalid("a clone cannot be committed"));
+    }
+    self.base.lock_exclusive()?;
+    for (blockno, block) in &self.delta {
+      self.base.write(*blockno, block);
+    }
+    let base = self.base.path().unwrap().to_path_buf();
+    self.base.save(&base)?;
+    fs::remove_file(&self.path)
+  }
+
+  // Write the delta through a temporary file renamed over it.
+  fn save(&mut self) -> io::Result<()> {
+    let mut tmp = self.path.as_os_str().to_os_string();
+
+    tmp.push(".tmp");
+    {
+      let mut f = File::create(&tmp)?;
+      let mut data = Vec::with_capacity(HEADER + self.delta.len() * RECORD);
+
+      if let Some(ref base) = self.clone_of {
+        let base = base.as_os_str().as_bytes();
+
+        data.extend_from_slice(CLONE_MAGIC);
+        put_u64(&mut data, base.len() as u64);
+        data.extend_from_slice(base);
+      }
+      data.extend_from_slice(MAGIC);
+      put_u64(&mut data, self.base.nblocks() as u64);
+      data.extend_from_slice(&self.digest);
+      for (blockno, block) in &self.delta {
+        put_u64(&mut data, *blockno as u64);
+        data.extend_from_slice(block);
+      }
+      f.write_all(&data)?;
+      f.sync_all()?;
+    }
+    fs::rename(&tmp, &self.path)?;
+    self.dirty = false;
+    Ok(())
+  }
+}
+
+// Return true if `path` is a clone.
+pub fn is_clone<P: AsRef<Path>>(path: P) -> bool {
+  let mut magic = [0; 8];
+
+  match File::open(path) {
+    Ok(mut f) => {
+      io::Read::read_exact(&mut f, &mut magic).is_ok() && &magic == CLONE_MAGIC
+    },
+    Err(_) => false,
+  }
+}
+
+// Remove `delta`, dropping its changes, after checking that it is one.
+pub fn discard<P: AsRef<Path>>(delta: P) -> io::Result<()> {
+  let _lock = disk::lock(&delta, false)?;
+  let mut magic = [0; 8];
+  io::Read::read_exact(&mut File::open(&delta)?, &mut magic)?;
+
+  if &magic != MAGIC {
+    return Err(invalid("not a delta"));
+  }
+  fs::remove_file(delta)
+}
+
+impl BlockDevice for Overlay {
+  fn nblocks(&self) -> usize {
+    self.base.nblocks()
+  }
+
+  fn read(&mut self, blockno: usize) -> Block {
+    match self.delta.get(&blockno) {
+      Some(block) => *block,
+      None => self.base.read(blockno),
+    }
+  }
+
+  fn write(&mut self, blockno: usize, data: &Block) {
+    self.delta.insert(blockno, *data);
+    self.dirty = true;
+  }
+
+  fn flush(&mut self) {
+    if self.dirty {
+      if let Err(e) = self.save() {
+        warn!("cannot save {}: {}", self.path.display(), e);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use disk::{BSIZE, BlockDevice, Disk};
+  use overlay::{self, Overlay};
+  use std::env;
+  use std::fs;
+  use std::process;
+
+  #[test]
+  fn test() {
+    let dir = env::temp_dir().join(format!("xv6fs-delta-{}", process::id()));
+    let (base, delta) = (dir.join("base.img"), dir.join("delta"));
+
+    fs::create_dir_all(&dir).unwrap();
+    Disk::new(4).save(&base).unwrap();
+    Overlay::create(&base, &delta).unwrap();
+
+    {
+      let mut o = Overlay::open(&base, &delta).unwrap();
+      o.write(1, &[1; BSIZE]);
+      o.flush();
+    }
+    let mut o = Overlay::open(&base, &delta).unwrap();
+    assert!(o.changed() == 1);
+    assert!(o.read(1)[..] == [1; BSIZE][..]);
+    assert!(o.read(2)[..] == [0; BSIZE][..]);
+    assert!(fs::read(&base).unwrap() == vec![0; 4 * BSIZE]);
+
+    o.commit().unwrap();
+    assert!(fs::read(&base).unwrap()[BSIZE..2 * BSIZE] == [1; BSIZE][..]);
+    assert!(!delta.exists());
+
+    // A delta of the old base no longer applies.
+    Overlay::create(&base, &delta).unwrap();
+    Disk::new(4).save(&base).unwrap();
+    assert!(Overlay::open(&base, &delta).is_err());
+    assert!(overlay::discard(&base).is_err());
+    overlay::discard(&delta).unwrap();
+    assert!(!delta.exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_clone() {
+    let dir = env::temp_dir().join(format!("xv6fs-clone-{}", process::id()));
+    let (base, a, b) = (dir.join("base.img"), dir.join("a"), dir.join("b"));
+
+    fs::create_dir_all(&dir).unwrap();
+    Disk::new(4).save(&base).unwrap();
+    Overlay::create_clone(&base, &a).unwrap();
+    Overlay::create_clone(&base, &b).unwrap();
+    assert!(overlay::is_clone(&a) && !overlay::is_clone(&base));
+
+    {
+      let mut o = Overlay::open_clone(&a).unwrap();
+      o.write(1, &[1; BSIZE]);
+      o.flush();
+    }
+    let mut o = Overlay::open_clone(&a).unwrap();
+    assert!(o.read(1)[..] == [1; BSIZE][..]);
+    assert!(Overlay::open_clone(&b).unwrap().read(1)[..] == [0; BSIZE][..]);
+    assert!(fs::read(&a).unwrap().len() < 4 * BSIZE);
+    assert!(o.commit().is_err());
+    assert!(Overlay::open_clone(&base).is_err());
+    assert!(Overlay::create_clone(&a, &dir.join("c")).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
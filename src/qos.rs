@@ -0,0 +1,332 @@
+// Fair scheduling of the requests of a server among its clients.
+//
+// Without limits, requests go straight to the pool of workers. With them,
+// the requests of each client, a uid or a pid, are queued apart and handed
+// to the workers in turn, as long as the client has fewer than
+// `concurrency` of them in flight and has not used up its `rate` requests
+// per second, which may be spent in bursts of up to a second. A runaway
+// client then only ever waits on itself, without holding up the workers.
+
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClientKey {
+  Uid,
+  Pid,
+}
+
+#[derive(Clone, Copy)]
+pub struct Limits {
+  pub key: ClientKey,
+  // Requests per second of each client.
+  pub rate: Option<u32>,
+  // Requests of each client in flight at once.
+  pub concurrency: Option<usize>,
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Limits {
+      key: ClientKey::Uid,
+      rate: None,
+      concurrency: None,
+    }
+  }
+}
+
+// Counters of a client since the server started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientStats {
+  pub client: u32,
+  pub requests: u64,
+  // Requests that had to wait for the limits of their client.
+  pub throttled: u64,
+  pub inflight: usize,
+  pub queued: usize,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Client {
+  queue: VecDeque<Job>,
+  inflight: usize,
+  // Requests it may start now, up to `rate`, and when they were counted.
+  tokens: f64,
+  refilled: Instant,
+  requests: u64,
+  throttled: u64,
+}
+
+struct State {
+  clients: HashMap<u32, Client>,
+  // Clients with queued requests, in the order they are served.
+  ready: VecDeque<u32>,
+  // A thread waits for tokens to serve them.
+  ticking: bool,
+}
+
+struct Inner {
+  pool: Mutex<ThreadPool>,
+  limits: Limits,
+  state: Mutex<State>,
+}
+
+pub struct Scheduler {
+  inner: Arc<Inner>,
+}
+
+impl Client {
+  fn new(limits: &Limits) -> Self {
+    Client {
+      queue: VecDeque::new(),
+      inflight: 0,
+      tokens: limits.rate.unwrap_or(0) as f64,
+      refilled: Instant::now(),
+      requests: 0,
+      throttled: 0,
+    }
+  }
+
+  // Return how long until it may start a request, None if it has too many
+  // in flight.
+  fn wait(&mut self, limits: &Limits) -> Option<Duration> {
+    if let Some(concurrency) = limits.concurrency {
+      if self.inflight >= concurrency {
+        return None;
+      }
+    }
+    let rate = match limits.rate {
+      Some(rate) => rate as f64,
+      None => return Some(Duration::from_secs(0)),
+    };
+    let now = Instant::now();
+    let elapsed = now - self.refilled;
+    let elapsed =
+      elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+
+    self.tokens = (self.tokens + elapsed * rate).min(rate);
+    self.refilled = now;
+    if self.tokens >= 1.0 {
+      Some(Duration::from_secs(0))
+    } else {
+      let ms = (1.0 - self.tokens) / rate * 1e3;
+      Some(Duration::from_millis(ms as u64 + 1))
+    }
+  }
+}
+
+impl Inner {
+  // Account for the end of a request of `client`.
+  fn done(inner: &Arc<Inner>, client: u32) {
+    let mut state = inner.state.lock().unwrap();
+    state.clients.get_mut(&client).unwrap().inflight -= 1;
+  }
+
+  // Hand the queued requests allowed to run to the workers.
+  fn dispatch(inner: &Arc<Inner>) {
+    let mut state = inner.state.lock().unwrap();
+    let mut next_token: Option<Duration> = None;
+    let mut blocked = VecDeque::new();
+
+    while let Some(id) = state.ready.pop_front() {
+      let client = state.clients.get_mut(&id).unwrap();
+
+      match client.wait(&inner.limits) {
+        Some(wait) if wait == Duration::from_secs(0) => {
+          let job = client.queue.pop_front().unwrap();
+
+          client.inflight += 1;
+          client.tokens -= 1.0;
+          if !client.queue.is_empty() {
+            // Served again after the others.
+            state.ready.push_back(id);
+          }
+          let done = inner.clone();
+          inner.pool.lock().unwrap().execute(move || {
+            job();
+            Inner::done(&done, id);
+            Inner::dispatch(&done);
+          });
+        },
+        Some(wait) => {
+          next_token = Some(next_token.map_or(wait, |next| min(next, wait)));
+          blocked.push_back(id);
+        },
+        // Served again once one of its requests is done.
+        None => blocked.push_back(id),
+      }
+    }
+    state.ready = blocked;
+
+    if let Some(wait) = next_token {
+      if !state.ticking {
+        let inner = inner.clone();
+
+        state.ticking = true;
+        thread::spawn(move || {
+          thread::sleep(wait);
+          inner.state.lock().unwrap().ticking = false;
+          Inner::dispatch(&inner);
+        });
+      }
+    }
+  }
+}
+
+impl Scheduler {
+  pub fn new(nworkers: usize, limits: Limits) -> Self {
+    Scheduler {
+      inner: Arc::new(Inner {
+        pool: Mutex::new(ThreadPool::new(nworkers)),
+        limits,
+        state: Mutex::new(State {
+          clients: HashMap::new(),
+          ready: VecDeque::new(),
+          ticking: false,
+        }),
+      }),
+    }
+  }
+
+  pub fn limits(&self) -> &Limits {
+    &self.inner.limits
+  }
+
+  // Run `job` on behalf of `client` once its limits allow.
+  pub fn execute<F: FnOnce() + Send + 'static>(&self, client: u32, job: F) {
+    let limits = &self.inner.limits;
+    let unlimited = limits.rate.is_none() && limits.concurrency.is_none();
+    let mut job: Option<Job> = Some(Box::new(job));
+    {
+      let mut state = self.inner.state.lock().unwrap();
+      let state = &mut *state;
+      let entry = state
+        .clients
+        .entry(client)
+        .or_insert_with(|| Client::new(limits));
+
+      entry.requests += 1;
+      if unlimited {
+        entry.inflight += 1;
+      } else {
+        if !entry.queue.is_empty() ||
+          entry.wait(limits) != Some(Duration::from_secs(0))
+        {
+          entry.throttled += 1;
+          info!("throttling client {}", client);
+        }
+        if entry.queue.is_empty() {
+          state.ready.push_back(client);
+        }
+        entry.queue.push_back(job.take().unwrap());
+      }
+    }
+    if let Some(job) = job {
+      let done = self.inner.clone();
+      self.inner.pool.lock().unwrap().execute(move || {
+        job();
+        Inner::done(&done, client);
+      });
+    } else {
+      Inner::dispatch(&self.inner);
+    }
+  }
+
+  // Return the counters of every client seen so far, by client.
+  pub fn stats(&self) -> Vec<ClientStats> {
+    let state = self.inner.state.lock().unwrap();
+    let mut stats: Vec<ClientStats> = state
+      .clients
+      .iter()
+      .map(|(id, client)| ClientStats {
+        client: *id,
+        requests: client.requests,
+        throttled: client.throttled,
+        inflight: client.inflight,
+        queued: client.queue.len(),
+      })
+      .collect();
+
+    stats.sort_by_key(|stats| stats.client);
+    stats
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use qos::{ClientKey, Limits, Scheduler};
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  #[test]
+  fn test() {
+    let scheduler = Scheduler::new(
+      4,
+      Limits {
+        key: ClientKey::Uid,
+        rate: None,
+        concurrency: Some(1),
+      },
+    );
+    let (send, recv) = mpsc::channel();
+    let running = Arc::new(AtomicUsize::new(0));
+
+    // Client 1 floods the workers with slow requests, one at a time.
+    for _ in 0..4 {
+      let running = running.clone();
+      let send = send.clone();
+      scheduler.execute(1, move || {
+        assert!(running.fetch_add(1, Ordering::SeqCst) == 0);
+        thread::sleep(Duration::from_millis(50));
+        running.fetch_sub(1, Ordering::SeqCst);
+        send.send(1).unwrap();
+      });
+    }
+    // Client 2 gets through meanwhile.
+    let send2 = send.clone();
+    scheduler.execute(2, move || send2.send(2).unwrap());
+    assert!(recv.recv().unwrap() == 2);
+    for _ in 0..4 {
+      assert!(recv.recv().unwrap() == 1);
+    }
+
+    let stats = scheduler.stats();
+    assert!(stats.len() == 2);
+    assert!(stats[0].client == 1 && stats[0].requests == 4);
+    assert!(stats[0].throttled == 3 && stats[0].queued == 0);
+    assert!(stats[1].throttled == 0);
+  }
+
+  #[test]
+  fn test_rate() {
+    let scheduler = Scheduler::new(
+      2,
+      Limits {
+        key: ClientKey::Pid,
+        rate: Some(20),
+        concurrency: None,
+      },
+    );
+    let (send, recv) = mpsc::channel();
+    let start = Instant::now();
+
+    // A burst of 20, then 10 more at 20 per second.
+    for _ in 0..30 {
+      let send = send.clone();
+      scheduler.execute(7, move || send.send(()).unwrap());
+    }
+    for _ in 0..30 {
+      recv.recv().unwrap();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(400));
+    assert!(scheduler.stats()[0].throttled == 10);
+  }
+}
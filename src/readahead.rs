@@ -0,0 +1,278 @@
+// Readahead of files, scaled to how each open handle reads them.
+//
+// A handle that reads on where its last read ended is sequential, and its
+// window, the blocks read ahead of it, doubles from MIN_WINDOW with every
+// such read up to the readahead limit of the block cache. One that reads
+// at a constant stride is strided, and has the blocks of its next reads
+// along the stride read ahead instead. Any other read makes it random and
+// closes its window, so that the small cache is not filled with blocks
+// nobody reads, until it settles into one of the others again.
+//
+// Inodes are read ahead the same way when read on sequentially, whoever
+// reads them, see `Window`.
+
+use disk::BSIZE;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+// Blocks read ahead of a handle once it turns sequential or strided.
+const MIN_WINDOW: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pattern {
+  // Not read twice yet.
+  Unknown,
+  Sequential,
+  // Each read that many bytes after the previous one.
+  Strided(i64),
+  Random,
+}
+
+impl fmt::Display for Pattern {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Pattern::Unknown => write!(f, "unknown"),
+      Pattern::Sequential => write!(f, "sequential"),
+      Pattern::Strided(stride) => write!(f, "strided:{}", stride),
+      Pattern::Random => write!(f, "random"),
+    }
+  }
+}
+
+// The state of an open handle.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+  pub fh: u64,
+  pub inum: usize,
+  pub pattern: Pattern,
+  // Blocks read ahead of it.
+  pub window: usize,
+}
+
+struct Stream {
+  inum: usize,
+  // Offset and length of the last read.
+  last: Option<(usize, usize)>,
+  // Distance between the last two reads.
+  stride: Option<i64>,
+  pattern: Pattern,
+  window: usize,
+  // Blocks of the file before this one were read ahead already.
+  ahead: usize,
+}
+
+// The sequential readahead of an inode, kept with it in the inode cache.
+#[derive(Default)]
+pub struct Window {
+  // Where the last read ended.
+  next: Option<usize>,
+  size: usize,
+  ahead: usize,
+}
+
+impl Window {
+  // Account for a read of `len` bytes at `offset`, and return the blocks
+  // of the file to read ahead, by index, at most `limit`.
+  pub fn access(
+    &mut self,
+    offset: usize,
+    len: usize,
+    limit: usize,
+  ) -> Vec<usize> {
+    let end = offset + len;
+
+    if self.next.replace(end) != Some(offset) || len == 0 {
+      self.size = 0;
+      self.ahead = 0;
+      return vec![];
+    }
+    self.size = min(max(self.size * 2, MIN_WINDOW), limit);
+
+    let next = (end + BSIZE - 1) / BSIZE;
+    let from = max(next, self.ahead);
+    let to = next + self.size;
+
+    self.ahead = max(self.ahead, to);
+    (from..max(from, to)).collect()
+  }
+}
+
+// The open handles of a server.
+pub struct Tracker {
+  streams: Mutex<HashMap<u64, Stream>>,
+}
+
+impl Tracker {
+  pub fn new() -> Self {
+    Tracker {
+      streams: Mutex::new(HashMap::new()),
+    }
+  }
+
+  // Track `fh`, a new handle of inode `inum`.
+  pub fn open(&self, fh: u64, inum: usize) {
+    self.streams.lock().unwrap().insert(
+      fh,
+      Stream {
+        inum,
+        last: None,
+        stride: None,
+        pattern: Pattern::Unknown,
+        window: 0,
+        ahead: 0,
+      },
+    );
+  }
+
+  pub fn release(&self, fh: u64) {
+    self.streams.lock().unwrap().remove(&fh);
+  }
+
+  // Account for a read of `len` bytes at `offset` through `fh`, and return
+  // the blocks of the file to read ahead, by index, at most `limit`.
+  pub fn access(
+    &self,
+    fh: u64,
+    offset: usize,
+    len: usize,
+    limit: usize,
+  ) -> Vec<usize> {
+    let mut streams = self.streams.lock().unwrap();
+    let stream = match streams.get_mut(&fh) {
+      Some(stream) => stream,
+      None => return vec![],
+    };
+    let pattern = match stream.last {
+      None => Pattern::Unknown,
+      Some((last, last_len)) => {
+        let stride = offset as i64 - last as i64;
+        let pattern = if offset == last + last_len {
+          Pattern::Sequential
+        } else if stream.stride == Some(stride) {
+          Pattern::Strided(stride)
+        } else {
+          Pattern::Random
+        };
+
+        stream.stride = Some(stride);
+        pattern
+      },
+    };
+
+    stream.window = match pattern {
+      Pattern::Unknown | Pattern::Random => 0,
+      _ if pattern == stream.pattern => {
+        min(max(stream.window * 2, MIN_WINDOW), limit)
+      },
+      _ => min(MIN_WINDOW, limit),
+    };
+    stream.pattern = pattern;
+    stream.last = Some((offset, len));
+
+    let mut blocks = vec![];
+    match pattern {
+      Pattern::Sequential => {
+        let from = max((offset + len + BSIZE - 1) / BSIZE, stream.ahead);
+        let to = (offset + len + BSIZE - 1) / BSIZE + stream.window;
+
+        blocks.extend(from..max(from, to));
+        stream.ahead = max(stream.ahead, to);
+      },
+      Pattern::Strided(stride) => {
+        let mut next = offset as i64;
+
+        while blocks.len() < stream.window {
+          next += stride;
+          if next < 0 {
+            break;
+          }
+          let first = next as usize / BSIZE;
+          let last = (next as usize + max(len, 1) - 1) / BSIZE;
+          let n = min(last - first + 1, stream.window - blocks.len());
+          blocks.extend(first..first + n);
+        }
+      },
+      _ => (),
+    }
+    blocks
+  }
+
+  // Return the state of every open handle, by handle.
+  pub fn stats(&self) -> Vec<Stats> {
+    let streams = self.streams.lock().unwrap();
+    let mut stats: Vec<Stats> = streams
+      .iter()
+      .map(|(fh, stream)| Stats {
+        fh: *fh,
+        inum: stream.inum,
+        pattern: stream.pattern,
+        window: stream.window,
+      })
+      .collect();
+
+    stats.sort_by_key(|stats| stats.fh);
+    stats
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use disk::BSIZE;
+  use readahead::{Pattern, Tracker, Window};
+
+  #[test]
+  fn test() {
+    let tracker = Tracker::new();
+    let fh = 1;
+
+    tracker.open(fh, 7);
+
+    // Sequential reads grow the window, and every block is asked for once.
+    assert!(tracker.access(fh, 0, BSIZE, 64).is_empty());
+    assert!(tracker.access(fh, BSIZE, BSIZE, 64) == vec![2, 3, 4, 5]);
+    assert!(tracker.access(fh, 2 * BSIZE, BSIZE, 64) == vec![6, 7, 8, 9, 10]);
+    assert!(tracker.stats()[0].window == 8);
+    assert!(tracker.stats()[0].pattern == Pattern::Sequential);
+
+    // A jump turns readahead off, a second one the same stride on again.
+    assert!(tracker.access(fh, 100 * BSIZE, BSIZE, 64).is_empty());
+    assert!(tracker.stats()[0].window == 0);
+    assert!(tracker.access(fh, 50 * BSIZE, BSIZE, 64).is_empty());
+    let blocks = tracker.access(fh, 0, BSIZE, 64);
+    let stride = -50 * BSIZE as i64;
+    assert!(tracker.stats()[0].pattern == Pattern::Strided(stride));
+    assert!(blocks.is_empty());
+    let fh2 = 2;
+    tracker.open(fh2, 8);
+    tracker.access(fh2, 0, 10, 64);
+    tracker.access(fh2, 100 * BSIZE, 10, 64);
+    let blocks = tracker.access(fh2, 200 * BSIZE, 10, 64);
+    assert!(blocks == vec![300, 400, 500, 600]);
+    assert!(tracker.stats()[1].pattern.to_string() == "strided:51200");
+
+    // The limit of the cache holds, and released handles are gone.
+    assert!(tracker.access(fh2, 300 * BSIZE, 10, 0).is_empty());
+    tracker.release(fh);
+    assert!(tracker.stats().len() == 1);
+    assert!(tracker.access(fh, BSIZE, BSIZE, 64).is_empty());
+  }
+
+  #[test]
+  fn test_window() {
+    let mut window = Window::default();
+
+    // Reads on where the last one ended double the window.
+    assert!(window.access(0, 100, 64).is_empty());
+    assert!(window.access(100, BSIZE - 100, 64) == vec![1, 2, 3, 4]);
+    assert!(window.access(BSIZE, BSIZE, 64) == vec![5, 6, 7, 8, 9]);
+    // Up to the limit, past the blocks read ahead already.
+    assert!(window.access(2 * BSIZE, 10, 6).is_empty());
+    assert!(window.access(2 * BSIZE + 10, 3 * BSIZE - 10, 6) == vec![10]);
+
+    // Others close it.
+    assert!(window.access(0, BSIZE, 64).is_empty());
+    assert!(window.access(BSIZE, BSIZE, 64) == vec![2, 3, 4, 5]);
+  }
+}
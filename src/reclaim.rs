@@ -0,0 +1,229 @@
+// Reclamation of removed files in the background, so that removing a large
+// file takes as long as removing a small one, and no transaction frees more
+// than BATCH of its blocks.
+//
+// Once the worker is started, the last reference to an inode without links
+// and with more than BATCH blocks is dropped without freeing anything: the
+// inode is marked IORPHAN and queued. The worker then frees the blocks of
+// the queued inodes from their end, BATCH at a time each in turn, in a
+// transaction each, and the inode itself along with its last blocks. A
+// crash in between leaves it IORPHAN, so that it is queued again as the
+// orphans are reclaimed at the next mount.
+
+use inode::ICACHE;
+use logging::LOGGING;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+// Blocks freed in a transaction.
+pub const BATCH: usize = 32;
+
+// Counters of the worker since the server started.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+  // Inodes waiting to be reclaimed.
+  pub pending: usize,
+  pub inodes: usize,
+  pub blocks: usize,
+}
+
+struct State {
+  // Inodes are deferred to the worker, see `start`.
+  enabled: bool,
+  queue: VecDeque<usize>,
+  // The inode the worker frees blocks of.
+  current: Option<usize>,
+  stats: Stats,
+}
+
+lazy_static! {
+  static ref STATE: Mutex<State> = Mutex::new(State {
+    enabled: false,
+    queue: VecDeque::new(),
+    current: None,
+    stats: Stats::default(),
+  });
+  static ref CHANGED: Condvar = Condvar::new();
+}
+
+static WORKER: Once = Once::new();
+
+// Start reclaiming large files in the background.
+pub fn start() {
+  STATE.lock().unwrap().enabled = true;
+  WORKER.call_once(|| {
+    thread::spawn(work);
+  });
+}
+
+// Stop reclaiming in the background, once the worker is done with the
+// batch at hand, e.g. before unmounting. The inodes still queued are left
+// to the next mount.
+pub fn stop() {
+  let mut state = STATE.lock().unwrap();
+
+  state.enabled = false;
+  state.queue.clear();
+  while state.current.is_some() {
+    state = CHANGED.wait(state).unwrap();
+  }
+}
+
+// Wait for the worker to reclaim every inode queued.
+pub fn wait() {
+  let mut state = STATE.lock().unwrap();
+
+  while state.current.is_some() || state.enabled && !state.queue.is_empty() {
+    state = CHANGED.wait(state).unwrap();
+  }
+}
+
+pub fn stats() -> Stats {
+  let state = STATE.lock().unwrap();
+  let mut stats = state.stats.clone();
+
+  stats.pending = state.queue.len() + state.current.map_or(0, |_| 1);
+  stats
+}
+
+// Return true if inode `inum` is queued or being reclaimed.
+pub fn is_pending(inum: usize) -> bool {
+  let state = STATE.lock().unwrap();
+
+  state.current == Some(inum) || state.queue.contains(&inum)
+}
+
+// Called as the last reference to inode `inum` without links, which has
+// `nblocks` data blocks, is dropped. Return true if it is left to the
+// worker, which the caller marks as an orphan, false to free it at once.
+pub fn defer(inum: usize, nblocks: usize) -> bool {
+  let mut state = STATE.lock().unwrap();
+
+  // That of the worker, which frees the inode with its last blocks.
+  if state.current == Some(inum) {
+    return nblocks > 0;
+  }
+  if state.queue.contains(&inum) {
+    return true;
+  }
+  if !state.enabled || nblocks <= BATCH {
+    return false;
+  }
+  state.queue.push_back(inum);
+  CHANGED.notify_all();
+  true
+}
+
+fn work() {
+  loop {
+    let inum = {
+      let mut state = STATE.lock().unwrap();
+
+      while !state.enabled || state.queue.is_empty() {
+        state = CHANGED.wait(state).unwrap();
+      }
+      let inum = state.queue.pop_front().unwrap();
+      state.current = Some(inum);
+      inum
+    };
+    let (freed, more) = step(inum);
+    let mut state = STATE.lock().unwrap();
+
+    state.current = None;
+    state.stats.blocks += freed;
+    if !more {
+      state.stats.inodes += 1;
+    } else if state.enabled {
+      state.queue.push_back(inum);
+    }
+    CHANGED.notify_all();
+  }
+}
+
+// Free the last BATCH blocks of inode `inum`. Return how many, and whether
+// there are more.
+fn step(inum: usize) -> (usize, bool) {
+  let txn = LOGGING.new_txn();
+  let inode = match ICACHE.get(inum) {
+    Some(inode) => inode,
+    // The cache is full, try again later.
+    None => {
+      thread::sleep(Duration::from_millis(10));
+      return (0, true);
+    },
+  };
+  // Likewise until the reference that queued it is dropped, so that ours
+  // is the last one.
+  if inode.refcnt() > 1 {
+    drop(inode);
+    thread::sleep(Duration::from_millis(1));
+    return (0, true);
+  }
+  let mut dinode = ICACHE.lock(&txn, &inode);
+  let freed = dinode.free_last_blocks(&txn, 0, BATCH);
+  let more = dinode.data_blocks(&txn).map_or(0, |blocks| blocks.len()) > 0;
+
+  // Dropping the last reference frees the inode once no block is left.
+  drop(dinode);
+  drop(inode);
+  (freed, more)
+}
+
+#[cfg(test)]
+mod test {
+  use disk::BSIZE;
+  use error::Error;
+  use fs::FileType;
+  use logging::LOGGING;
+  use ops;
+  use reclaim::{self, BATCH};
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+    reclaim::start();
+
+    let name = ops::to_name(b"big").unwrap();
+    let data = vec![1; 100 * BSIZE];
+    let (inum, gen) = {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+      let stat = ops::stat(&txn, &file);
+      (stat.inum, stat.gen)
+    };
+    for (i, chunk) in data.chunks(ops::MAXWRITE).enumerate() {
+      let txn = LOGGING.new_txn();
+      let file = ops::resolve(&txn, b"/big").unwrap();
+
+      ops::write(&txn, &file, i * ops::MAXWRITE, chunk).unwrap();
+    }
+    let before = reclaim::stats();
+    {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+
+      ops::unlink(&txn, &root, &name).unwrap();
+    }
+    reclaim::wait();
+    let stats = reclaim::stats();
+    assert!(stats.pending == 0 && stats.inodes == before.inodes + 1);
+    assert!(stats.blocks - before.blocks == 100);
+
+    // The inode is gone, its slot and blocks are free again.
+    {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      assert!(ops::get(&txn, inum, gen).err() == Some(Error::Stale));
+      let file = ops::create(&txn, &root, &name, FileType::File).unwrap();
+      assert!(file.no() == inum);
+    }
+    reclaim::stop();
+
+    // Small files are freed at once.
+    assert!(!reclaim::defer(inum, BATCH));
+  }
+}
@@ -0,0 +1,83 @@
+// Reference counts of data blocks shared by several inodes, e.g. a file
+// and its copies in snapshots.
+//
+// The counts are stored as one u16 per block in the data blocks of inode
+// `sb.refino`, which is created by mkfs and never linked in any directory.
+// A count of n means n references besides the first one, so that blocks
+// which were never shared need no entry at all.
+
+use buffer::BCACHE;
+use disk::BSIZE;
+use error::{Error, Result};
+use fs::MAXFILESIZE;
+use inode::ICACHE;
+use logging::Transaction;
+use std::mem::transmute;
+
+// Number of counts per block.
+const CPB: usize = BSIZE / 2;
+
+// Most extra references a block can have.
+pub const MAXREFS: usize = u16::max_value() as usize;
+
+// Return true if blocks of this file system can be shared, i.e. it was
+// made with a reference count inode that can cover all of its blocks.
+pub fn supported() -> bool {
+  let sb = BCACHE.sb();
+
+  sb.refino != 0 && sb.nblocks as usize <= MAXFILESIZE / BSIZE * CPB
+}
+
+// Return the number of extra references of `blockno`.
+pub fn get<'a>(txn: &Transaction<'a>, blockno: usize) -> usize {
+  if !supported() {
+    return 0;
+  }
+  let table = ICACHE.get(BCACHE.sb().refino as usize).unwrap();
+  let table = ICACHE.lock(txn, &table);
+
+  match table.mapped_block(txn, blockno / CPB).unwrap() {
+    None => 0,
+    Some(b) => {
+      let buf = txn.read(b).unwrap();
+      let counts: &[u16; CPB] = unsafe { transmute(&buf.data) };
+      counts[blockno % CPB] as usize
+    },
+  }
+}
+
+// Add a reference to `blockno`. NoSpace if the block of its count cannot
+// be allocated, TooManyLinks if it has MAXREFS already.
+pub fn inc<'a>(txn: &Transaction<'a>, blockno: usize) -> Result<()> {
+  assert!(supported());
+  let table = ICACHE.get(BCACHE.sb().refino as usize).unwrap();
+  let mut table = ICACHE.lock(txn, &table);
+  let b = table.nth_block(txn, blockno / CPB)?.unwrap();
+  let mut buf = txn.read(b).unwrap();
+  let counts: &mut [u16; CPB] = unsafe { transmute(&mut buf.data) };
+
+  if counts[blockno % CPB] as usize == MAXREFS {
+    return Err(Error::TooManyLinks);
+  }
+  counts[blockno % CPB] += 1;
+  txn.write(&mut buf);
+  table.update(txn);
+  Ok(())
+}
+
+// Drop a reference to `blockno`. Return true if it was the last one, so
+// that the block should be freed.
+pub fn release<'a>(txn: &Transaction<'a>, blockno: usize) -> bool {
+  if get(txn, blockno) == 0 {
+    return true;
+  }
+  let table = ICACHE.get(BCACHE.sb().refino as usize).unwrap();
+  let table = ICACHE.lock(txn, &table);
+  let b = table.mapped_block(txn, blockno / CPB).unwrap().unwrap();
+  let mut buf = txn.read(b).unwrap();
+  let counts: &mut [u16; CPB] = unsafe { transmute(&mut buf.data) };
+
+  counts[blockno % CPB] -= 1;
+  txn.write(&mut buf);
+  false
+}
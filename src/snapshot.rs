@@ -0,0 +1,382 @@
+// Read-only snapshots of the whole tree, exposed as `/.snapshots/<name>`.
+//
+// A snapshot copies every directory and inode of the tree, but the copies
+// of files share their data blocks with the originals, which are then
+// copied on write by whichever side is modified first (see refcount.rs).
+// Copies are flagged IREADONLY, and so is `/.snapshots` itself.
+//
+// Both creating and deleting a snapshot take many transactions, during
+// which no other thread may start one, see Logging::exclusive, so that a
+// snapshot is of the tree at one point in time. A crash in the middle
+// leaves a partial snapshot behind, which can be deleted, and may leak
+// blocks, but it never frees a block still in use.
+
+use bitmap::Bitmap;
+use delalloc;
+use error::{Error, Result};
+use fs::{DIRSIZE, FileType, IREADONLY, NDIRECT, NINDIRECT, ROOTINO};
+use inode::{ICACHE, UnlockedInode};
+use logging::{LOGGING, Transaction};
+use ops;
+use refcount;
+use std::mem::transmute;
+
+// Name of the directory holding all snapshots in the root.
+pub const SNAPDIR: &[u8] = b".snapshots";
+
+// Number of blocks whose reference count is bumped per transaction.
+const NREFS: usize = 8;
+
+// Number of indirect blocks copied per transaction.
+const NINDIRECTS: usize = 8;
+
+// Return the snapshot directory, creating it if asked to.
+fn snapdir<'a>(txn: &Transaction<'a>, create: bool) -> Result<UnlockedInode> {
+  let root = ops::root();
+  let name = ops::to_name(SNAPDIR).unwrap();
+  let inode = match ops::lookup(txn, &root, &name) {
+    Err(Error::NotFound) if create => return mkdir(txn, &root, &name),
+    result => result?,
+  };
+
+  // Something else that happens to be named the same.
+  if !ICACHE.lock(txn, &inode).is_read_only() {
+    return Err(Error::Exists);
+  }
+  Ok(inode)
+}
+
+// Create a read-only directory `name` in `dir`.
+fn mkdir<'a>(
+  txn: &Transaction<'a>,
+  dir: &UnlockedInode,
+  name: &[u8; DIRSIZE],
+) -> Result<UnlockedInode> {
+  let mut pinode = ICACHE.lock(txn, dir);
+
+  if pinode.as_directory().lookup(txn, name)?.is_some() {
+    return Err(Error::Exists);
+  }
+
+  let inode = ICACHE
+    .alloc(txn, FileType::Directory)
+    .ok_or(Error::NoSpace)?;
+  let inodeno = inode.no();
+  let mut dinode = ICACHE.lock(txn, &inode);
+
+  dinode.nlink = 1;
+  dinode.flags |= IREADONLY;
+  dinode.update(txn);
+
+  dinode.as_directory().link(
+    txn,
+    &ops::to_name(b".").unwrap(),
+    inodeno as u16,
+  )?;
+  dinode.as_directory().link(
+    txn,
+    &ops::to_name(b"..").unwrap(),
+    pinode.no() as u16,
+  )?;
+  pinode.as_directory().link(txn, name, inodeno as u16)?;
+
+  pinode.nlink += 1; // for `..`
+  pinode.update(txn);
+  Ok(inode)
+}
+
+// Return the inode numbers, names and types of the entries of directory
+// `dir`, without `.` and `..`.
+fn entries(dir: usize) -> Result<Vec<(usize, [u8; DIRSIZE], FileType)>> {
+  let txn = LOGGING.new_txn();
+  let mut result = vec![];
+
+  for (inode, name) in ops::readdir(&txn, &ICACHE.get(dir).unwrap())? {
+    if ops::from_name(&name) != b"." && ops::from_name(&name) != b".." {
+      result.push((inode.no(), name, ops::stat(&txn, &inode).file_type));
+    }
+  }
+  Ok(result)
+}
+
+// Copy the doubly indirect block of file `src`, and the indirect blocks in
+// it, into blocks of their own, in transactions of their own, as they are
+// too many for one. Return the copy, 0 if there is none.
+fn copy_dindirect(src: usize) -> Result<u32> {
+  let indirects = {
+    let txn = LOGGING.new_txn();
+    let inode = ICACHE.get(src).unwrap();
+    let dinode = ICACHE.lock(&txn, &inode);
+    let mut indirects = vec![];
+
+    if dinode.dindirect == 0 {
+      return Ok(0);
+    }
+    for i in 0..NINDIRECT {
+      let first = NDIRECT + NINDIRECT + i * NINDIRECT;
+      let mut a = [0; NINDIRECT];
+
+      for (j, blockno) in a.iter_mut().enumerate() {
+        *blockno = dinode
+          .mapped_block(&txn, first + j)?
+          .map_or(0, |blockno| blockno as u32);
+      }
+      indirects.push(a);
+    }
+    indirects
+  };
+  let mut dindirect = [0; NINDIRECT];
+
+  for (i, chunk) in indirects.chunks(NINDIRECTS).enumerate() {
+    let txn = LOGGING.new_txn();
+
+    for (j, a) in chunk.iter().enumerate() {
+      if a.iter().all(|&blockno| blockno == 0) {
+        continue;
+      }
+      let indirect = Bitmap::alloc(&txn)?;
+      let mut buf = txn.read(indirect).unwrap();
+
+      buf.data = unsafe { transmute(*a) };
+      txn.write(&mut buf);
+      dindirect[i * NINDIRECTS + j] = indirect as u32;
+    }
+  }
+  let txn = LOGGING.new_txn();
+  let copy = Bitmap::alloc(&txn)?;
+  let mut buf = txn.read(copy).unwrap();
+
+  buf.data = unsafe { transmute(dindirect) };
+  txn.write(&mut buf);
+  Ok(copy as u32)
+}
+
+// Copy file or symbolic link `src` as `name` in directory `dst`, sharing its
+// data blocks.
+fn copy_file(src: usize, dst: usize, name: &[u8; DIRSIZE]) -> Result<()> {
+  let blocks = {
+    let txn = LOGGING.new_txn();
+    let inode = ICACHE.get(src).unwrap();
+    let blocks = ICACHE.lock(&txn, &inode).data_blocks(&txn)?;
+    blocks
+  };
+
+  // Take the extra references before anything points to the blocks.
+  for chunk in blocks.chunks(NREFS) {
+    let txn = LOGGING.new_txn();
+    for blockno in chunk {
+      refcount::inc(&txn, *blockno)?;
+    }
+  }
+  let dindirect = copy_dindirect(src)?;
+
+  let txn = LOGGING.new_txn();
+  let inode = ICACHE.get(src).unwrap();
+  let dinode = ICACHE.lock(&txn, &inode);
+  let copy = ICACHE.alloc(&txn, dinode.file_type).ok_or(Error::NoSpace)?;
+  let mut dcopy = ICACHE.lock(&txn, &copy);
+
+  dcopy.nlink = 1;
+  dcopy.flags = dinode.flags | IREADONLY;
+  dcopy.nonce = dinode.nonce;
+  dcopy.size = dinode.size;
+  dcopy.mode = dinode.mode;
+  dcopy.uid = dinode.uid;
+  dcopy.gid = dinode.gid;
+  dcopy.major = dinode.major;
+  dcopy.minor = dinode.minor;
+  dcopy.atime = dinode.atime;
+  dcopy.atime_nsec = dinode.atime_nsec;
+  dcopy.mtime = dinode.mtime;
+  dcopy.mtime_nsec = dinode.mtime_nsec;
+  dcopy.addrs[..NDIRECT].copy_from_slice(&dinode.addrs[..NDIRECT]);
+  if dinode.addrs[NDIRECT] != 0 {
+    // Indirect blocks are never shared.
+    let indirect = Bitmap::alloc(&txn)?;
+    let mut buf = txn.read(indirect).unwrap();
+    let a: &mut [u32; NINDIRECT] = unsafe { transmute(&mut buf.data) };
+
+    for (n, blockno) in a.iter_mut().enumerate() {
+      *blockno = dinode
+        .mapped_block(&txn, NDIRECT + n)?
+        .map_or(0, |blockno| blockno as u32);
+    }
+    txn.write(&mut buf);
+    dcopy.addrs[NDIRECT] = indirect as u32;
+  }
+  dcopy.dindirect = dindirect;
+  dcopy.update(&txn);
+
+  let dir = ICACHE.get(dst).unwrap();
+  let mut pinode = ICACHE.lock(&txn, &dir);
+  pinode.as_directory().link(&txn, name, copy.no() as u16)
+}
+
+// Copy the content of directory `src` into directory `dst`.
+fn copy_dir(src: usize, dst: usize) -> Result<()> {
+  for (inum, name, file_type) in entries(src)? {
+    if src == ROOTINO && ops::from_name(&name) == SNAPDIR {
+      continue;
+    }
+    match file_type {
+      FileType::Directory => {
+        let subdir = {
+          let txn = LOGGING.new_txn();
+          let subdir = mkdir(&txn, &ICACHE.get(dst).unwrap(), &name)?.no();
+          subdir
+        };
+        copy_dir(inum, subdir)?;
+      },
+      _ => copy_file(inum, dst, &name)?,
+    }
+  }
+  Ok(())
+}
+
+// Remove `name` from directory `dir`, recursively.
+fn remove_tree(dir: usize, name: &[u8; DIRSIZE]) -> Result<()> {
+  let (inum, file_type) = {
+    let txn = LOGGING.new_txn();
+    let inode = ops::lookup(&txn, &ICACHE.get(dir).unwrap(), name)?;
+    let file_type = ops::stat(&txn, &inode).file_type;
+    (inode.no(), file_type)
+  };
+
+  if file_type == FileType::Directory {
+    for (_, child, _) in entries(inum)? {
+      remove_tree(inum, &child)?;
+    }
+  }
+
+  let txn = LOGGING.new_txn();
+  let dir = ICACHE.get(dir).unwrap();
+  let mut pinode = ICACHE.lock(&txn, &dir);
+  let (inode, offset) = pinode
+    .as_directory()
+    .lookup(&txn, name)?
+    .ok_or(Error::NotFound)?;
+  // Freed as soon as the last reference to `inode` is dropped.
+  let mut dinode = ICACHE.lock(&txn, &inode);
+
+  if file_type == FileType::Directory {
+    pinode.nlink -= 1; // for `..`
+    pinode.update(&txn);
+  }
+  dinode.nlink -= 1;
+  dinode.update(&txn);
+  ops::clear_entry(&txn, &mut pinode, offset)
+}
+
+// Take a snapshot of the current tree named `name`.
+pub fn create(name: &[u8]) -> Result<()> {
+  let name = ops::to_name(name)?;
+
+  if !refcount::supported() {
+    return Err(Error::Unsupported);
+  }
+  let _exclusive = LOGGING.exclusive();
+  // Not to miss the blocks not allocated yet, see delalloc.rs.
+  delalloc::sync();
+  let dst = {
+    let txn = LOGGING.new_txn();
+    let dst = mkdir(&txn, &snapdir(&txn, true)?, &name)?.no();
+    dst
+  };
+  copy_dir(ROOTINO, dst)
+}
+
+// Return the names of all snapshots.
+pub fn list() -> Result<Vec<Vec<u8>>> {
+  let snapdir = {
+    let txn = LOGGING.new_txn();
+    let snapdir = match snapdir(&txn, false) {
+      Ok(inode) => inode.no(),
+      Err(Error::NotFound) => return Ok(vec![]),
+      Err(e) => return Err(e),
+    };
+    snapdir
+  };
+
+  Ok(
+    entries(snapdir)?
+      .iter()
+      .map(|&(_, ref name, _)| ops::from_name(name).to_vec())
+      .collect(),
+  )
+}
+
+// Delete snapshot `name`, releasing the blocks it shares.
+pub fn delete(name: &[u8]) -> Result<()> {
+  let name = ops::to_name(name)?;
+  let _exclusive = LOGGING.exclusive();
+  let snapdir = {
+    let txn = LOGGING.new_txn();
+    let snapdir = snapdir(&txn, false)?.no();
+    snapdir
+  };
+
+  remove_tree(snapdir, &name)
+}
+
+#[cfg(test)]
+mod test {
+  use error::Error;
+  use fs::FileType;
+  use inode::ICACHE;
+  use logging::LOGGING;
+  use ops;
+  use refcount;
+  use snapshot;
+  use testfs;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+
+    {
+      let txn = LOGGING.new_txn();
+      let root = ops::root();
+      let f = ops::to_name(b"f").unwrap();
+      let d = ops::to_name(b"d").unwrap();
+
+      let file = ops::create(&txn, &root, &f, FileType::File).unwrap();
+      ops::write(&txn, &file, 0, b"hello").unwrap();
+      let dir = ops::create(&txn, &root, &d, FileType::Directory).unwrap();
+      let file = ops::create(&txn, &dir, &f, FileType::File).unwrap();
+      ops::write(&txn, &file, 0, b"world").unwrap();
+    }
+
+    snapshot::create(b"s").unwrap();
+    assert!(snapshot::create(b"s").err() == Some(Error::Exists));
+    assert!(snapshot::list().unwrap() == vec![b"s".to_vec()]);
+
+    {
+      let txn = LOGGING.new_txn();
+      let file = ops::resolve(&txn, b"/f").unwrap();
+      let copy = ops::resolve(&txn, b"/.snapshots/s/f").unwrap();
+      let (dir, name) = ops::resolve_parent(&txn, b"/.snapshots/s/d").unwrap();
+
+      ops::write(&txn, &file, 0, b"HELLO").unwrap();
+      assert!(ops::read(&txn, &file, 0, 5).unwrap() == b"HELLO");
+      assert!(ops::read(&txn, &copy, 0, 5).unwrap() == b"hello");
+      assert!(ops::write(&txn, &copy, 0, b"x").err() ==
+        Some(Error::ReadOnly));
+      assert!(ops::rmdir(&txn, &dir, &name).err() == Some(Error::ReadOnly));
+      let copy = ops::resolve(&txn, b"/.snapshots/s/d/f").unwrap();
+      let blockno = ICACHE.lock(&txn, &copy).data_blocks(&txn).unwrap()[0];
+      assert!(ops::read(&txn, &copy, 0, 5).unwrap() == b"world");
+      assert!(refcount::get(&txn, blockno) == 1);
+    }
+
+    snapshot::delete(b"s").unwrap();
+    assert!(snapshot::list().unwrap().is_empty());
+
+    let txn = LOGGING.new_txn();
+    let file = ops::resolve(&txn, b"/d/f").unwrap();
+    let blockno = ICACHE.lock(&txn, &file).data_blocks(&txn).unwrap()[0];
+    assert!(refcount::get(&txn, blockno) == 0);
+    assert!(ops::read(&txn, &file, 0, 5).unwrap() == b"world");
+    ops::write(&txn, &file, 0, b"W").unwrap();
+    assert!(ops::read(&txn, &file, 0, 5).unwrap() == b"World");
+  }
+}
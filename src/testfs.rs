@@ -1,12 +1,17 @@
 #[cfg(test)]
 pub mod test {
   use std::mem::size_of;
-  use disk::{BSIZE, Disk, Block};
+  use bitmap::Bitmap;
+  use buffer::BCACHE;
+  use disk::{BSIZE, DISK, Disk, Block};
+  use inode::ICACHE;
+  use logging::LOGGING;
   use fs::{SuperBlock, DiskInode, FileType, Dirent, IPB, BPB, LOGSIZE,
-           NDIRECT, DIRSIZE};
+           NDIRECT, DIRSIZE, DEFAULT_UID, DEFAULT_GID, CHECKSUMS, LABELSIZE};
 
   const NBLOCKS: usize = 200;
   const NINODES: usize = 20;
+  const REFINO: usize = 2;
 
   fn str2u8(s: &str) -> [u8; DIRSIZE] {
     let s_bytes = s.as_bytes();
@@ -17,8 +22,13 @@ pub mod test {
     result
   }
 
-  #[allow(unused_unsafe)]
   pub fn create() -> (Disk, usize) {
+    create_with_flags(0)
+  }
+
+  // Create a file system with super block flags `flags`.
+  #[allow(unused_unsafe)]
+  pub fn create_with_flags(flags: u32) -> (Disk, usize) {
     let mut b: [u8; NBLOCKS * BSIZE] = [0; NBLOCKS * BSIZE];
     let ptr = &mut b[0] as *mut u8;
 
@@ -26,19 +36,28 @@ pub mod test {
     let nbitmapblks = (NBLOCKS / BPB + 1) as u32;
     let nmeta = 2 + LOGSIZE as u32 + ninodeblks + nbitmapblks;
 
-    let sb = SuperBlock {
+    let mut sb = SuperBlock {
       nblocks: NBLOCKS as u32,
-      unused: 0,
+      refino: REFINO as u32,
       ninodes: NINODES as u32,
       nlogs: LOGSIZE as u32,
       log_start: 2,
       inode_start: 2 + LOGSIZE as u32,
       bmap_start: 2 + LOGSIZE as u32 + ninodeblks,
+      flags: flags | CHECKSUMS,
+      badblk: 0,
+      nspares: 0,
+      hashblk: 0,
+      root: [0; 32],
+      checksum: 0,
+      nextfree: 0,
+      label: [0; LABELSIZE],
     };
 
     let mut nfree = nmeta;
 
     // Write the super block.
+    sb.seal();
     unsafe {
       *(ptr.add(BSIZE) as *mut _) = to_block!(&sb, SuperBlock);
     }
@@ -46,15 +65,34 @@ pub mod test {
     // Write the root inode and folder.
     let mut iroot = DiskInode {
       file_type: FileType::Directory,
-      unused1: 0,
-      unused2: 0,
+      flags: 0,
+      nonce: 0,
       nlink: 1,
       size: size_of::<Dirent>() as u32 * 2, /* two files in root folder: `.`
                                              * and `..`. */
       addrs: [0; NDIRECT + 1],
+      mode: 0o755,
+      dmode: 0,
+      uid: DEFAULT_UID,
+      gid: DEFAULT_GID,
+      duid: 0,
+      dgid: 0,
+      gen: 0,
+      major: 0,
+      minor: 0,
+      atime: 0,
+      atime_nsec: 0,
+      mtime: 0,
+      mtime_nsec: 0,
+      ctime: 0,
+      ctime_nsec: 0,
+      dindirect: 0,
+      checksum: 0,
+      unused: 0,
     };
     let inode_blk0 = nfree;
     iroot.addrs[0] = inode_blk0;
+    iroot.seal();
     nfree += 1;
 
     unsafe {
@@ -62,6 +100,41 @@ pub mod test {
           *mut _) = iroot;
     }
 
+    // Write the inode of block reference counts.
+    let mut irefs = DiskInode {
+      file_type: FileType::File,
+      flags: 0,
+      nonce: 0,
+      nlink: 1,
+      size: 0,
+      addrs: [0; NDIRECT + 1],
+      mode: 0o600,
+      dmode: 0,
+      uid: DEFAULT_UID,
+      gid: DEFAULT_GID,
+      duid: 0,
+      dgid: 0,
+      gen: 0,
+      major: 0,
+      minor: 0,
+      atime: 0,
+      atime_nsec: 0,
+      mtime: 0,
+      mtime_nsec: 0,
+      ctime: 0,
+      ctime_nsec: 0,
+      dindirect: 0,
+      checksum: 0,
+      unused: 0,
+    };
+    irefs.seal();
+
+    unsafe {
+      *(ptr.add(
+        sb.inode_start as usize * BSIZE + REFINO * size_of::<DiskInode>(),
+      ) as *mut _) = irefs;
+    }
+
     let dirents: [Dirent; 2] = [
       Dirent {
         inum: 1,
@@ -100,4 +173,23 @@ pub mod test {
 
     (Disk::from(disk), nfree as usize)
   }
+
+  // Mount a fresh file system with empty caches.
+  pub fn mount() {
+    mount_with_flags(0);
+  }
+
+  pub fn mount_with_flags(flags: u32) {
+    mount_disk(create_with_flags(flags).0);
+  }
+
+  // Mount `disk` with empty caches.
+  pub fn mount_disk(disk: Disk) {
+    DISK.mount(disk);
+    BCACHE.init();
+    // Inodes left by other tests are dropped, which takes a transaction.
+    let txn = LOGGING.new_txn();
+    ICACHE.init();
+    Bitmap::init(&txn);
+  }
 }
@@ -0,0 +1,201 @@
+// Settings of a live server, changed without remounting, e.g. through the
+// XATTR_CONFIG attribute of the daemon. They read as
+//
+//   log=info bcache.meta=256 bcache.data=2048 bcache.bytes=0 icache=256
+//   sync=5 readahead=256
+//
+// and are changed with any of those pairs, separated by spaces.
+// `bcache.bytes` is the most bytes the block cache takes, none if 0, `sync`
+// the interval of the flusher in seconds, none if 0, and `readahead` the
+// most blocks read ahead at once, none if 0. The caches shrink as entries
+// are evicted for new ones, down to minimums that let every transaction
+// hold its blocks and inodes.
+//
+// The log level only applies to the logger of `init_logger`.
+
+use buffer::BCACHE;
+use disk::DISK;
+use error::{Error, Result};
+use fs::LOGSIZE;
+use inode::ICACHE;
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord,
+          MaxLogLevelFilter};
+use std::cmp::max;
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const MIN_INODES: usize = 64;
+
+lazy_static! {
+  // Set by `init_logger`.
+  static ref MAX_LEVEL: Mutex<Option<MaxLogLevelFilter>> = Mutex::new(None);
+}
+
+struct Logger;
+
+impl Log for Logger {
+  fn enabled(&self, metadata: &LogMetadata) -> bool {
+    metadata.level() <= log::max_log_level()
+  }
+
+  fn log(&self, record: &LogRecord) {
+    if self.enabled(record.metadata()) {
+      eprintln!("{}:{}: {}", record.level(), record.target(), record.args());
+    }
+  }
+}
+
+// Log to stderr at the level of RUST_LOG, like env_logger does, but at one
+// level for every module, e.g. the most verbose of "xv6fs=debug,fuse=warn".
+// Errors only by default.
+pub fn init_logger() {
+  let level = env::var("RUST_LOG")
+    .unwrap_or_default()
+    .split(',')
+    .filter_map(|directive| directive.rsplit('=').next()?.parse().ok())
+    .max()
+    .unwrap_or(LogLevelFilter::Error);
+
+  let _ = log::set_logger(|max| {
+    max.set(level);
+    *MAX_LEVEL.lock().unwrap() = Some(max);
+    Box::new(Logger)
+  });
+}
+
+enum Setting {
+  Log(LogLevelFilter),
+  MetaBlocks(usize),
+  DataBlocks(usize),
+  Bytes(usize),
+  Inodes(usize),
+  Sync(u64),
+  Readahead(usize),
+}
+
+// Blocks a cache tier holds at least, twice the log of the mounted file
+// system, whose blocks are pinned until they are committed.
+fn min_blocks() -> usize {
+  2 * max(LOGSIZE, BCACHE.sb().nlogs as usize)
+}
+
+fn parse(pair: &str) -> Result<Setting> {
+  let mut parts = pair.splitn(2, '=');
+  let key = parts.next().unwrap();
+  let value = parts.next().ok_or(Error::Invalid)?;
+  let number = || value.parse::<usize>().map_err(|_| Error::Invalid);
+  let at_least = |min| match number()? {
+    n if n >= min => Ok(n),
+    _ => Err(Error::Invalid),
+  };
+
+  match key {
+    "log" => match value.parse() {
+      Ok(level) if MAX_LEVEL.lock().unwrap().is_some() => {
+        Ok(Setting::Log(level))
+      },
+      _ => Err(Error::Invalid),
+    },
+    "bcache.meta" => Ok(Setting::MetaBlocks(at_least(min_blocks())?)),
+    "bcache.data" => Ok(Setting::DataBlocks(at_least(min_blocks())?)),
+    "bcache.bytes" => match number()? {
+      0 => Ok(Setting::Bytes(0)),
+      _ => Ok(Setting::Bytes(at_least(BCACHE.bytes(2 * min_blocks()))?)),
+    },
+    "icache" => Ok(Setting::Inodes(at_least(MIN_INODES)?)),
+    "sync" => Ok(Setting::Sync(number()? as u64)),
+    "readahead" => Ok(Setting::Readahead(number()?)),
+    _ => Err(Error::Invalid),
+  }
+}
+
+// Return the current settings.
+pub fn settings() -> String {
+  let (meta, data) = BCACHE.capacities();
+  let sync = DISK.flush_interval().map_or(0, |interval| interval.as_secs());
+
+  format!(
+    "log={} bcache.meta={} bcache.data={} bcache.bytes={} icache={} sync={} \
+     readahead={}",
+    log::max_log_level().to_string().to_lowercase(),
+    meta,
+    data,
+    BCACHE.limit(),
+    ICACHE.capacity(),
+    sync,
+    BCACHE.readahead_limit()
+  )
+}
+
+// Apply `settings`, none of them if any is invalid.
+pub fn apply(settings: &str) -> Result<()> {
+  let settings = settings
+    .split_whitespace()
+    .map(parse)
+    .collect::<Result<Vec<_>>>()?;
+
+  for setting in settings {
+    match setting {
+      Setting::Log(level) => {
+        MAX_LEVEL.lock().unwrap().as_ref().unwrap().set(level)
+      },
+      Setting::MetaBlocks(n) => {
+        BCACHE.set_capacities(n, BCACHE.capacities().1)
+      },
+      Setting::DataBlocks(n) => {
+        BCACHE.set_capacities(BCACHE.capacities().0, n)
+      },
+      Setting::Bytes(n) => BCACHE.set_limit(n),
+      Setting::Inodes(n) => ICACHE.set_capacity(n),
+      Setting::Sync(0) => DISK.set_flush_interval(None),
+      Setting::Sync(secs) => {
+        DISK.set_flush_interval(Some(Duration::from_secs(secs)))
+      },
+      Setting::Readahead(n) => BCACHE.set_readahead_limit(n),
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use buffer::BCACHE;
+  use error::Error;
+  use inode::ICACHE;
+  use testfs;
+  use tune;
+
+  #[test]
+  fn test() {
+    testfs::test::mount();
+    let (meta, data) = BCACHE.capacities();
+    let (icache, readahead) = (ICACHE.capacity(), BCACHE.readahead_limit());
+
+    tune::apply("bcache.data=300 icache=100 readahead=0").unwrap();
+    assert!(BCACHE.capacities() == (meta, 300));
+    let bytes = format!("bcache.bytes={}", BCACHE.bytes(1000));
+    tune::apply(&bytes).unwrap();
+    assert!(tune::settings().contains(&format!(" {} ", bytes)));
+    tune::apply("bcache.bytes=0").unwrap();
+    assert!(ICACHE.capacity() == 100);
+    assert!(tune::settings().contains(" icache=100 "));
+    assert!(tune::settings().ends_with(" readahead=0"));
+
+    // Nothing is applied when anything is invalid.
+    let bad = [
+      "icache=200 bcache.meta=1",
+      "icache=200 color=red",
+      "icache",
+      "icache=200 bcache.bytes=1",
+    ];
+    for settings in &bad {
+      assert!(tune::apply(settings).err() == Some(Error::Invalid));
+    }
+    assert!(ICACHE.capacity() == 100);
+
+    BCACHE.set_capacities(meta, data);
+    ICACHE.set_capacity(icache);
+    BCACHE.set_readahead_limit(readahead);
+  }
+}
@@ -0,0 +1,107 @@
+// ChaCha20 (RFC 7539).
+
+pub type Key = [u8; 32];
+pub type Nonce = [u8; 12];
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+  s[a] = s[a].wrapping_add(s[b]);
+  s[d] = (s[d] ^ s[a]).rotate_left(16);
+  s[c] = s[c].wrapping_add(s[d]);
+  s[b] = (s[b] ^ s[c]).rotate_left(12);
+  s[a] = s[a].wrapping_add(s[b]);
+  s[d] = (s[d] ^ s[a]).rotate_left(8);
+  s[c] = s[c].wrapping_add(s[d]);
+  s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn le32(b: &[u8]) -> u32 {
+  b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+// Return the `counter`th 64-byte block of keystream.
+pub fn block(key: &Key, nonce: &Nonce, counter: u32) -> [u8; 64] {
+  let mut init = [0u32; 16];
+
+  init[0] = 0x61707865;
+  init[1] = 0x3320646e;
+  init[2] = 0x79622d32;
+  init[3] = 0x6b206574;
+  for i in 0..8 {
+    init[4 + i] = le32(&key[4 * i..]);
+  }
+  init[12] = counter;
+  for i in 0..3 {
+    init[13 + i] = le32(&nonce[4 * i..]);
+  }
+
+  let mut s = init;
+  for _ in 0..10 {
+    quarter_round(&mut s, 0, 4, 8, 12);
+    quarter_round(&mut s, 1, 5, 9, 13);
+    quarter_round(&mut s, 2, 6, 10, 14);
+    quarter_round(&mut s, 3, 7, 11, 15);
+    quarter_round(&mut s, 0, 5, 10, 15);
+    quarter_round(&mut s, 1, 6, 11, 12);
+    quarter_round(&mut s, 2, 7, 8, 13);
+    quarter_round(&mut s, 3, 4, 9, 14);
+  }
+
+  let mut result = [0; 64];
+  for i in 0..16 {
+    let x = s[i].wrapping_add(init[i]);
+    for j in 0..4 {
+      result[4 * i + j] = (x >> (8 * j)) as u8;
+    }
+  }
+  result
+}
+
+// Xor `data` with the keystream, starting at byte `offset` of it.
+pub fn xor(key: &Key, nonce: &Nonce, offset: usize, data: &mut [u8]) {
+  let mut i = 0;
+
+  while i < data.len() {
+    let pos = offset + i;
+    let keystream = block(key, nonce, (pos / 64) as u32);
+
+    for j in (pos % 64)..64 {
+      if i == data.len() {
+        break;
+      }
+      data[i] ^= keystream[j];
+      i += 1;
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use util::chacha20::{block, xor};
+
+  #[test]
+  fn test() {
+    let mut key = [0; 32];
+    for i in 0..32 {
+      key[i] = i as u8;
+    }
+    let nonce = [0, 0, 0, 9, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+
+    // RFC 7539 2.3.2.
+    assert!(
+      block(&key, &nonce, 1)[..16] ==
+        [
+          0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd,
+          0x1f, 0xa3, 0x20, 0x71, 0xc4,
+        ]
+    );
+
+    let mut whole = [42; 150];
+    let mut parts = [42; 150];
+    xor(&key, &nonce, 10, &mut whole);
+    xor(&key, &nonce, 10, &mut parts[..60]);
+    xor(&key, &nonce, 70, &mut parts[60..]);
+    assert!(whole[..] == parts[..]);
+    xor(&key, &nonce, 10, &mut whole);
+    assert!(whole.iter().all(|b| *b == 42));
+  }
+}
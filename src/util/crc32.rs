@@ -0,0 +1,27 @@
+// CRC-32 (IEEE 802.3), as used by zlib and ext4's metadata_csum.
+
+const POLY: u32 = 0xedb88320;
+
+pub fn crc32(data: &[u8]) -> u32 {
+  let mut crc = !0u32;
+
+  for &byte in data {
+    crc ^= byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 != 0 { crc >> 1 ^ POLY } else { crc >> 1 };
+    }
+  }
+  !crc
+}
+
+#[cfg(test)]
+mod test {
+  use util::crc32::crc32;
+
+  #[test]
+  fn test() {
+    assert!(crc32(b"") == 0);
+    assert!(crc32(b"123456789") == 0xcbf43926);
+    assert!(crc32(&[0; 32]) == 0x190a55ad);
+  }
+}
@@ -0,0 +1,325 @@
+// A small JSON (RFC 8259) parser, for manifests, configuration and the
+// requests of the servers, and the quoting of the strings they reply with.
+// Numbers are integers only, which is all we need.
+
+use std::char;
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+  Null,
+  Bool(bool),
+  Number(i64),
+  String(String),
+  Array(Vec<Value>),
+  // Members in the order they appear.
+  Object(Vec<(String, Value)>),
+}
+
+impl Value {
+  // Return member `key` of an object.
+  pub fn get(&self, key: &str) -> Option<&Value> {
+    match *self {
+      Value::Object(ref members) => {
+        members.iter().find(|&&(ref k, _)| k == key).map(|&(_, ref v)| v)
+      },
+      _ => None,
+    }
+  }
+
+  pub fn as_bool(&self) -> Option<bool> {
+    match *self {
+      Value::Bool(b) => Some(b),
+      _ => None,
+    }
+  }
+
+  pub fn as_i64(&self) -> Option<i64> {
+    match *self {
+      Value::Number(n) => Some(n),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match *self {
+      Value::String(ref s) => Some(s),
+      _ => None,
+    }
+  }
+
+  pub fn as_array(&self) -> Option<&[Value]> {
+    match *self {
+      Value::Array(ref values) => Some(values),
+      _ => None,
+    }
+  }
+}
+
+struct Parser<'a> {
+  s: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn error<T>(&self, what: &str) -> Result<T, String> {
+    Err(format!("{} at byte {}", what, self.pos))
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.pos < self.s.len() &&
+      (self.s[self.pos] == b' ' || self.s[self.pos] == b'\t' ||
+         self.s[self.pos] == b'\n' || self.s[self.pos] == b'\r')
+    {
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&mut self) -> Option<u8> {
+    self.skip_whitespace();
+    self.s.get(self.pos).cloned()
+  }
+
+  fn expect(&mut self, c: u8) -> Result<(), String> {
+    if self.peek() != Some(c) {
+      return self.error(&format!("expected '{}'", c as char));
+    }
+    self.pos += 1;
+    Ok(())
+  }
+
+  fn keyword(&mut self, word: &str, value: Value) -> Result<Value, String> {
+    if !self.s[self.pos..].starts_with(word.as_bytes()) {
+      return self.error("unexpected character");
+    }
+    self.pos += word.len();
+    Ok(value)
+  }
+
+  fn value(&mut self) -> Result<Value, String> {
+    match self.peek() {
+      Some(b'{') => self.object(),
+      Some(b'[') => self.array(),
+      Some(b'"') => Ok(Value::String(self.string()?)),
+      Some(b't') => self.keyword("true", Value::Bool(true)),
+      Some(b'f') => self.keyword("false", Value::Bool(false)),
+      Some(b'n') => self.keyword("null", Value::Null),
+      Some(b'-') | Some(b'0'..=b'9') => self.number(),
+      Some(_) => self.error("unexpected character"),
+      None => self.error("unexpected end"),
+    }
+  }
+
+  fn object(&mut self) -> Result<Value, String> {
+    let mut members = vec![];
+
+    self.expect(b'{')?;
+    if self.peek() == Some(b'}') {
+      self.pos += 1;
+      return Ok(Value::Object(members));
+    }
+    loop {
+      if self.peek() != Some(b'"') {
+        return self.error("expected a member name");
+      }
+      let key = self.string()?;
+      self.expect(b':')?;
+      members.push((key, self.value()?));
+      match self.peek() {
+        Some(b',') => self.pos += 1,
+        Some(b'}') => {
+          self.pos += 1;
+          return Ok(Value::Object(members));
+        },
+        _ => return self.error("expected ',' or '}'"),
+      }
+    }
+  }
+
+  fn array(&mut self) -> Result<Value, String> {
+    let mut values = vec![];
+
+    self.expect(b'[')?;
+    if self.peek() == Some(b']') {
+      self.pos += 1;
+      return Ok(Value::Array(values));
+    }
+    loop {
+      values.push(self.value()?);
+      match self.peek() {
+        Some(b',') => self.pos += 1,
+        Some(b']') => {
+          self.pos += 1;
+          return Ok(Value::Array(values));
+        },
+        _ => return self.error("expected ',' or ']'"),
+      }
+    }
+  }
+
+  fn hex4(&mut self) -> Result<u32, String> {
+    let digits = match self.s.get(self.pos..self.pos + 4) {
+      Some(digits) => digits,
+      None => return self.error("unexpected end"),
+    };
+    let mut n = 0;
+
+    for c in digits {
+      n = n * 16 + match (*c as char).to_digit(16) {
+        Some(d) => d,
+        None => return self.error("bad escape"),
+      };
+    }
+    self.pos += 4;
+    Ok(n)
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    let mut bytes = vec![];
+
+    self.expect(b'"')?;
+    loop {
+      let c = match self.s.get(self.pos) {
+        Some(c) => *c,
+        None => return self.error("unterminated string"),
+      };
+      self.pos += 1;
+      match c {
+        b'"' => break,
+        b'\\' => {
+          let c = match self.s.get(self.pos) {
+            Some(c) => *c,
+            None => return self.error("unterminated string"),
+          };
+          self.pos += 1;
+          let decoded = match c {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+              let mut n = self.hex4()?;
+              // A character outside the BMP, as a surrogate pair.
+              if n >= 0xd800 && n < 0xdc00 &&
+                self.s[self.pos..].starts_with(b"\\u")
+              {
+                self.pos += 2;
+                let low = self.hex4()?;
+                n = 0x10000 + ((n - 0xd800) << 10) + low.wrapping_sub(0xdc00);
+              }
+              match char::from_u32(n) {
+                Some(c) => c,
+                None => return self.error("bad escape"),
+              }
+            },
+            _ => return self.error("bad escape"),
+          };
+          let mut buf = [0; 4];
+          bytes.extend_from_slice(decoded.encode_utf8(&mut buf).as_bytes());
+        },
+        0..=0x1f => return self.error("control character in string"),
+        _ => bytes.push(c),
+      }
+    }
+    match String::from_utf8(bytes) {
+      Ok(s) => Ok(s),
+      Err(_) => self.error("invalid UTF-8"),
+    }
+  }
+
+  fn number(&mut self) -> Result<Value, String> {
+    let start = self.pos;
+
+    if self.s[self.pos] == b'-' {
+      self.pos += 1;
+    }
+    while self.pos < self.s.len() && self.s[self.pos].is_ascii_digit() {
+      self.pos += 1;
+    }
+    match self.s.get(self.pos) {
+      Some(b'.') | Some(b'e') | Some(b'E') => {
+        return self.error("only integers are supported")
+      },
+      _ => (),
+    }
+    let digits = String::from_utf8_lossy(&self.s[start..self.pos]);
+    match digits.parse() {
+      Ok(n) => Ok(Value::Number(n)),
+      Err(_) => {
+        self.pos = start;
+        self.error("bad number")
+      },
+    }
+  }
+}
+
+pub fn parse(s: &str) -> Result<Value, String> {
+  let mut parser = Parser {
+    s: s.as_bytes(),
+    pos: 0,
+  };
+  let value = parser.value()?;
+
+  if parser.peek().is_some() {
+    return parser.error("trailing characters");
+  }
+  Ok(value)
+}
+
+// Return `s` as a JSON string.
+pub fn quote(s: &str) -> String {
+  let mut result = String::from("\"");
+
+  for c in s.chars() {
+    match c {
+      '"' => result.push_str("\\\""),
+      '\\' => result.push_str("\\\\"),
+      c if (c as u32) < 0x20 => {
+        result.push_str(&format!("\\u{:04x}", c as u32))
+      },
+      c => result.push(c),
+    }
+  }
+  result.push('"');
+  result
+}
+
+#[cfg(test)]
+mod test {
+  use util::json::{Value, parse, quote};
+
+  #[test]
+  fn test() {
+    let value = parse(
+      r#" {"a": [1, -2, true, null], "b": {"c": "x\"\u00e9\ud83d\ude00"}} "#,
+    ).unwrap();
+
+    assert!(
+      value.get("a").unwrap().as_array().unwrap() ==
+        &[
+          Value::Number(1),
+          Value::Number(-2),
+          Value::Bool(true),
+          Value::Null,
+        ]
+    );
+    assert!(
+      value.get("b").unwrap().get("c").unwrap().as_str() ==
+        Some("x\"\u{e9}\u{1f600}")
+    );
+    assert!(value.get("d").is_none());
+
+    assert!(parse("[1,]").is_err());
+    assert!(parse("{\"a\" 1}").is_err());
+    assert!(parse("1.5").is_err());
+    assert!(parse("\"abc").is_err());
+    assert!(parse("[] x").is_err());
+
+    let s = "a\"\\\n\u{e9}";
+    assert!(quote(s) == "\"a\\\"\\\\\\u000a\u{e9}\"");
+    assert!(parse(&quote(s)).unwrap().as_str() == Some(s));
+  }
+}
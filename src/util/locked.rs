@@ -44,6 +44,24 @@ impl<T: Sized, U: Copy> UnlockedItem<T, U> {
     }
   }
 
+  // Like `acquire`, but returns None at once if it is locked already.
+  pub fn try_acquire<'a>(&self) -> Option<LockedItem<'a, T, U>> {
+    unsafe {
+      let ptr = Arc::into_raw(self.x.clone());
+      match (*ptr).0.try_lock() {
+        Ok(x) => Some(LockedItem {
+          ptr: ptr,
+          x: Some(x),
+          no: self.x.1,
+        }),
+        Err(_) => {
+          drop(Arc::from_raw(ptr));
+          None
+        },
+      }
+    }
+  }
+
   // Returns the reference count of this unlocked item.
   // Notice the reference storing in the container is excluded.
   pub fn refcnt(&self) -> usize {
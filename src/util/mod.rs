@@ -1,3 +1,7 @@
 #[macro_use]
 pub mod cast;
+pub mod chacha20;
+pub mod crc32;
+pub mod json;
 pub mod locked;
+pub mod sha256;